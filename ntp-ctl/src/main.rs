@@ -1,10 +1,15 @@
 #![forbid(unsafe_code)]
 
-use std::path::PathBuf;
+use std::{net::Ipv4Addr, path::PathBuf};
 
 use clap::{Parser, Subcommand};
-use ntp_daemon::{Config, ConfigUpdate, ObservableState};
+use ntp_daemon::{
+    observer::{ObservablePeerHistory, ObservablePeerState},
+    protocol::{Client, ClientError, Request},
+    AddPeer, Config, ConfigUpdate,
+};
 use ntp_metrics_exporter::Metrics;
+use ntp_proto::{ReferenceId, SelectionStatus, SystemSnapshot};
 
 #[derive(Parser)]
 #[command(version = "0.2.0", about = "Query and configure the ntpd-rs daemon")]
@@ -29,15 +34,50 @@ struct Cli {
 #[derive(Subcommand)]
 enum Command {
     #[command(about = "Information about the peers the daemon is currently connected with")]
-    Peers,
+    Peers {
+        /// Print the peers as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
     #[command(about = "Information about the state of the daemon itself")]
-    System,
+    System {
+        /// Print the state as JSON instead of a list of fields
+        #[arg(long)]
+        json: bool,
+    },
     #[command(
         about = "Information about the state of the daemon and peers in the prometheus export format"
     )]
     Prometheus,
+    #[command(about = "The latest measurements of a peer, and what the daemon did with them")]
+    History {
+        /// Address of the peer, the port may be left out
+        peer: String,
+
+        /// Print the measurements as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    #[command(about = "Print the events of the daemon as they happen, one JSON object per line")]
+    Events,
     #[command(about = "Adjust configuration (e.g. loglevel) of the daemon")]
     Config(ConfigUpdate),
+    #[command(about = "Add or remove peers of the daemon")]
+    Peer {
+        #[command(subcommand)]
+        command: PeerCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum PeerCommand {
+    #[command(about = "Add a peer")]
+    Add(AddPeer),
+    #[command(about = "Remove all peers with the given address")]
+    Remove {
+        /// Address of the peer, or of the key exchange server for an nts-server peer
+        addr: String,
+    },
 }
 
 #[tokio::main]
@@ -69,12 +109,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     let socket_path = match cli.command {
-        Command::Peers | Command::System | Command::Prometheus => &observation,
-        Command::Config(_) => &configuration,
+        Command::Peers { .. }
+        | Command::System { .. }
+        | Command::Prometheus
+        | Command::History { .. }
+        | Command::Events => &observation,
+        Command::Config(_) | Command::Peer { .. } => &configuration,
     };
 
-    let mut stream = match tokio::net::UnixStream::connect(socket_path).await {
-        Ok(stream) => stream,
+    let mut client = match Client::connect(socket_path).await {
+        Ok(client) => client,
         Err(e) => {
             eprintln!("Could not open socket at {}: {}", socket_path.display(), e);
             std::process::exit(1);
@@ -82,44 +126,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     let exit_code = match cli.command {
-        Command::Peers => {
-            let mut msg = Vec::with_capacity(16 * 1024);
-            match ntp_daemon::sockets::read_json::<ObservableState>(&mut stream, &mut msg).await {
-                Ok(output) => {
+        Command::Peers { json } => match client.get_peers().await {
+            Ok(peers) => {
+                if json {
                     // Unwrap here is fine as our serializer is infallible.
-                    println!("{}", serde_json::to_string_pretty(&output.peers).unwrap());
-
-                    0
+                    println!("{}", serde_json::to_string_pretty(&peers).unwrap());
+                } else {
+                    print_peers(&peers);
                 }
-                Err(e) => {
-                    eprintln!("Failed to read state from observation socket: {}", e);
 
-                    1
-                }
+                0
             }
-        }
-        Command::System => {
-            let mut msg = Vec::with_capacity(16 * 1024);
-            match ntp_daemon::sockets::read_json::<ObservableState>(&mut stream, &mut msg).await {
-                Ok(output) => {
-                    // Unwrap here is fine as our serializer is infallible.
-                    println!("{}", serde_json::to_string_pretty(&output.system).unwrap());
+            Err(e) => {
+                eprintln!("Failed to read state from observation socket: {}", e);
 
-                    0
+                1
+            }
+        },
+        Command::System { json } => match client.get_system().await {
+            Ok(system) => {
+                if json {
+                    // Unwrap here is fine as our serializer is infallible.
+                    println!("{}", serde_json::to_string_pretty(&system).unwrap());
+                } else {
+                    print_system(&system);
                 }
-                Err(e) => {
-                    eprintln!("Failed to read state from observation socket: {}", e);
 
-                    1
-                }
+                0
             }
-        }
-        Command::Prometheus => {
-            let mut stream = tokio::net::UnixStream::connect(observation).await?;
+            Err(e) => {
+                eprintln!("Failed to read state from observation socket: {}", e);
 
-            let mut msg = Vec::with_capacity(16 * 1024);
-            let output: ObservableState =
-                ntp_daemon::sockets::read_json(&mut stream, &mut msg).await?;
+                1
+            }
+        },
+        Command::Prometheus => {
+            let output = client.get_state().await?;
 
             let metrics = Metrics::default();
             metrics.fill(&output);
@@ -131,17 +173,281 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             0
         }
-        Command::Config(config_update) => {
-            match ntp_daemon::sockets::write_json(&mut stream, &config_update).await {
-                Ok(_) => 0,
-                Err(e) => {
-                    eprintln!("Failed to update configuration: {}", e);
+        Command::History { peer, json } => match client.get_history(&peer).await {
+            Ok(history) if history.is_empty() => {
+                eprintln!("No measurements of peer {}", peer);
 
-                    1
+                1
+            }
+            Ok(history) => {
+                if json {
+                    // Unwrap here is fine as our serializer is infallible.
+                    println!("{}", serde_json::to_string_pretty(&history).unwrap());
+                } else {
+                    print_history(&history);
                 }
+
+                0
             }
+            Err(e) => {
+                eprintln!("Failed to read history from observation socket: {}", e);
+
+                1
+            }
+        },
+        Command::Events => events(&mut client).await,
+        Command::Config(config_update) => {
+            configure(&mut client, Request::UpdateConfig(config_update)).await
         }
+        Command::Peer {
+            command: PeerCommand::Add(add_peer),
+        } => configure(&mut client, Request::AddPeer(add_peer)).await,
+        Command::Peer {
+            command: PeerCommand::Remove { addr },
+        } => configure(&mut client, Request::RemovePeer(addr)).await,
     };
 
     std::process::exit(exit_code);
 }
+
+/// Print the peers as a table in the style of ntpq, marked with the outcome of the clock selection
+fn print_peers(peers: &[ObservablePeerState]) {
+    println!(
+        "  {:<25} {:<15} {:>2} {:>5} {:>5} {:>12} {:>12} {:>12}",
+        "ADDRESS", "REFID", "ST", "REACH", "POLL", "OFFSET (ms)", "DELAY (ms)", "JITTER (ms)"
+    );
+
+    let mut unobserved = 0;
+    for peer in peers {
+        match peer {
+            ObservablePeerState::Nothing => unobserved += 1,
+            ObservablePeerState::Observable {
+                timedata,
+                reachability,
+                poll_interval,
+                stratum,
+                reference_id,
+                selection,
+                address,
+                ..
+            } => {
+                println!(
+                    "{} {:<25} {:<15} {:>2} {:>5o} {:>5} {:>12.3} {:>12.3} {:>12.3}",
+                    selection_marker(*selection),
+                    address,
+                    format_reference_id(*reference_id, *stratum),
+                    stratum,
+                    reachability.register(),
+                    poll_interval.as_system_duration().as_secs(),
+                    timedata.offset.to_seconds() * 1e3,
+                    timedata.delay.to_seconds() * 1e3,
+                    timedata.jitter.to_seconds() * 1e3,
+                );
+            }
+        }
+    }
+
+    if unobserved > 0 {
+        println!("({} peers without measurements)", unobserved);
+    }
+}
+
+/// The tally codes of ntpq, where rejected peers are left unmarked
+fn selection_marker(selection: Option<SelectionStatus>) -> char {
+    match selection {
+        Some(SelectionStatus::SystemPeer) => '*',
+        Some(SelectionStatus::Survivor) => '+',
+        Some(SelectionStatus::Candidate) => '-',
+        Some(SelectionStatus::Falseticker) => 'x',
+        Some(
+            SelectionStatus::Unusable
+            | SelectionStatus::Unsynchronized
+            | SelectionStatus::TooDistant,
+        )
+        | None => ' ',
+    }
+}
+
+fn print_system(system: &SystemSnapshot) {
+    let time = &system.time_snapshot;
+
+    println!("Stratum:           {}", system.stratum);
+    println!(
+        "Reference id:      {}",
+        // the reference id is that of the time source of the system peer
+        format_reference_id(system.reference_id, system.stratum.saturating_sub(1))
+    );
+    println!(
+        "Poll interval:     {} s",
+        time.poll_interval.as_system_duration().as_secs()
+    );
+    println!(
+        "Precision:         {:.6} ms",
+        time.precision.to_seconds() * 1e3
+    );
+    println!(
+        "Root delay:        {:.3} ms",
+        time.root_delay.to_seconds() * 1e3
+    );
+    println!(
+        "Root dispersion:   {:.3} ms",
+        time.root_dispersion.to_seconds() * 1e3
+    );
+    println!("Leap indicator:    {:?}", time.leap_indicator);
+    println!(
+        "Accumulated steps: {:.3} ms",
+        time.accumulated_steps.to_seconds() * 1e3
+    );
+    if let Some(tai_offset) = time.tai_offset {
+        println!("TAI offset:        {} s", tai_offset);
+    }
+}
+
+/// Reference ids of primary servers are ASCII codes such as `GPS`, as are kiss codes.
+/// Other servers use the IPv4 address of their time source, or a hash of its IPv6 address.
+fn format_reference_id(reference_id: ReferenceId, stratum: u8) -> String {
+    let bytes = reference_id.to_bytes();
+
+    if stratum <= 1 || reference_id == ReferenceId::NONE {
+        let code: String = bytes
+            .iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| {
+                if byte.is_ascii_graphic() {
+                    *byte as char
+                } else {
+                    '?'
+                }
+            })
+            .collect();
+
+        format!(".{}.", code)
+    } else {
+        Ipv4Addr::from(bytes).to_string()
+    }
+}
+
+/// Print the measurements of every peer as a table, with the age relative to the latest measurement
+fn print_history(history: &[ObservablePeerHistory]) {
+    for peer in history {
+        println!("{}", peer.address);
+        println!(
+            "{:>10} {:>12} {:>12}  DECISION",
+            "AGE (s)", "OFFSET (ms)", "DELAY (ms)"
+        );
+
+        let latest = match peer.measurements.back() {
+            Some(measurement) => measurement.localtime,
+            None => continue,
+        };
+
+        for measurement in &peer.measurements {
+            println!(
+                "{:>10.1} {:>12.3} {:>12.3}  {:?}",
+                (latest - measurement.localtime).to_seconds(),
+                measurement.offset.to_seconds() * 1e3,
+                measurement.delay.to_seconds() * 1e3,
+                measurement.decision,
+            );
+        }
+        println!();
+    }
+}
+
+async fn events(client: &mut Client) -> i32 {
+    if let Err(e) = client.subscribe().await {
+        eprintln!("Failed to subscribe to events: {}", e);
+
+        return 1;
+    }
+
+    loop {
+        match client.next_event().await {
+            Ok(event) => {
+                // Unwrap here is fine as our serializer is infallible.
+                println!("{}", serde_json::to_string(&event).unwrap());
+            }
+            Err(ClientError::Closed) => return 0,
+            Err(e) => {
+                eprintln!("Failed to read events from observation socket: {}", e);
+
+                return 1;
+            }
+        }
+    }
+}
+
+async fn configure(client: &mut Client, request: Request) -> i32 {
+    match client.change(&request).await {
+        Ok(()) => {
+            println!("Change applied");
+
+            0
+        }
+        Err(ClientError::Protocol(e)) => {
+            eprintln!("Change not applied: {}", e);
+
+            1
+        }
+        Err(e) => {
+            eprintln!("Failed to update configuration: {}", e);
+
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    #[test]
+    fn test_format_reference_id() {
+        assert_eq!(
+            format_reference_id(ReferenceId::from_bytes(*b"GPS\0"), 1),
+            ".GPS."
+        );
+        assert_eq!(
+            format_reference_id(ReferenceId::from_bytes(*b"PPS\0"), 0),
+            ".PPS."
+        );
+
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        assert_eq!(
+            format_reference_id(ReferenceId::from_ip(ip), 2),
+            "127.0.0.1"
+        );
+
+        assert_eq!(format_reference_id(ReferenceId::NONE, 16), ".XNON.");
+
+        assert_eq!(
+            format_reference_id(ReferenceId::from_bytes([b'A', 0x07, b' ', 0xff]), 1),
+            ".A???."
+        );
+    }
+
+    #[test]
+    fn test_selection_marker() {
+        let statuses = [
+            SelectionStatus::Unusable,
+            SelectionStatus::Unsynchronized,
+            SelectionStatus::TooDistant,
+            SelectionStatus::Falseticker,
+            SelectionStatus::Candidate,
+            SelectionStatus::Survivor,
+            SelectionStatus::SystemPeer,
+        ];
+
+        // the statuses are ordered from least to most trusted
+        assert!(statuses.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let markers: String = statuses
+            .iter()
+            .map(|status| selection_marker(Some(*status)))
+            .collect();
+        assert_eq!(markers, "   x-+*");
+        assert_eq!(selection_marker(None), ' ');
+    }
+}
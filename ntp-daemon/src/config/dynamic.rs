@@ -1,15 +1,23 @@
-use crate::sockets::create_unix_socket;
+use crate::protocol::{ProtocolError, ProtocolResult, Request, Response};
+use crate::sockets::{create_unix_socket, MAX_CONNECTIONS};
 use crate::tracing::ReloadHandle;
-use ntp_proto::{NtpDuration, StepThreshold};
-use std::os::unix::fs::PermissionsExt;
-use tokio::task::JoinHandle;
-use tracing::error;
+use ntp_proto::{LeapSecondsFile, NtpDuration, StepThreshold};
+use std::{os::unix::fs::PermissionsExt, path::PathBuf, sync::Arc};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-use clap::Args;
+use clap::{Args, ValueEnum};
 use serde::{Deserialize, Serialize};
 
-use super::{CombinedSystemConfig, ConfigureConfig};
+use super::{
+    CombinedSystemConfig, Config, ConfigureConfig, PeerConfig, ServerConfig, SymmetricKeys,
+};
 
 fn parse_env_filter(input: &str) -> Result<String, tracing_subscriber::filter::ParseError> {
     // run the parser to error on any invalid input
@@ -34,6 +42,87 @@ pub struct ConfigUpdate {
     /// during startup, use startup_panic_threshold
     #[arg(long)]
     pub panic_threshold: Option<f64>,
+
+    /// Reload the full configuration file. Peers and servers that were added or
+    /// removed are started or stopped, the others keep running.
+    #[arg(long)]
+    #[serde(default)]
+    pub reload: bool,
+}
+
+/// How a peer added with [`AddPeer`] is connected to, like the `mode` of a peer in the
+/// configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PeerMode {
+    Server,
+    NtsServer,
+    Pool,
+}
+
+#[derive(Debug, Args, Serialize, Deserialize)]
+pub struct AddPeer {
+    /// Address of the peer, or of the key exchange server for an nts-server peer
+    pub addr: String,
+
+    /// How to connect to the peer
+    #[arg(long, value_enum, default_value = "server")]
+    pub mode: PeerMode,
+
+    /// The maximum number of peers to use from a pool
+    #[arg(long)]
+    pub max_peers: Option<usize>,
+}
+
+impl AddPeer {
+    fn peer_config(&self) -> Result<PeerConfig, String> {
+        let addr_field = match self.mode {
+            PeerMode::NtsServer => "ke_addr",
+            PeerMode::Server | PeerMode::Pool => "addr",
+        };
+
+        let mut fields = serde_json::Map::new();
+        fields.insert(addr_field.into(), self.addr.clone().into());
+        fields.insert("mode".into(), serde_json::to_value(self.mode).unwrap());
+        if let Some(max_peers) = self.max_peers {
+            fields.insert("max_peers".into(), max_peers.into());
+        }
+
+        // go through the deserializer of the configuration file, so the same checks apply
+        PeerConfig::deserialize(&serde_json::Value::Object(fields)).map_err(|e| e.to_string())
+    }
+}
+
+/// The result of applying a change, with the error message if it could not be applied
+pub type ConfigResponse = Result<(), String>;
+
+/// The command line arguments with which the configuration was loaded, which
+/// are used again when the configuration is reloaded
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    pub file: Option<PathBuf>,
+    pub peers: Vec<PeerConfig>,
+    pub servers: Vec<ServerConfig>,
+    /// Whether the log filter was given on the command line, in which
+    /// case the log filter in the configuration file is not used
+    pub log_filter_override: bool,
+}
+
+/// The parts of a reloaded configuration that are applied by the system
+#[derive(Debug)]
+pub struct ConfigReload {
+    pub peers: Vec<PeerConfig>,
+    pub servers: Vec<ServerConfig>,
+    pub keys: SymmetricKeys,
+    pub leap_seconds: Option<LeapSecondsFile>,
+}
+
+/// Changes to the configuration that are applied by the system
+#[derive(Debug)]
+pub enum ConfigChange {
+    Reload(ConfigReload),
+    AddPeer(PeerConfig, oneshot::Sender<ConfigResponse>),
+    RemovePeer(String, oneshot::Sender<ConfigResponse>),
 }
 
 // Deal with reloading not being possible during testing.
@@ -47,13 +136,24 @@ impl LogReloader for ReloadHandle {
     }
 }
 
-pub async fn spawn<H: LogReloader + Send + 'static>(
+/// Spawn the dynamic configurator, which applies changes sent over the configuration
+/// socket, and reloads the configuration when asked to over that socket or by SIGHUP
+pub async fn spawn<H: LogReloader + Send + Sync + 'static>(
     config: ConfigureConfig,
+    source: ConfigSource,
     system_config_sender: tokio::sync::watch::Sender<CombinedSystemConfig>,
+    config_change_sender: mpsc::Sender<ConfigChange>,
     log_reload_handle: H,
+    shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 ) -> JoinHandle<std::io::Result<()>> {
     tokio::spawn(async move {
-        let result = dynamic_configuration(config, system_config_sender, log_reload_handle).await;
+        let configurator = Configurator {
+            source,
+            system_config_sender,
+            config_change_sender,
+            log_reload_handle,
+        };
+        let result = dynamic_configuration(config, configurator, shutdown_receiver).await;
         if let Err(ref e) = result {
             error!("Abnormal termination of dynamic configurator: {}", e);
         }
@@ -61,53 +161,220 @@ pub async fn spawn<H: LogReloader + Send + 'static>(
     })
 }
 
-async fn dynamic_configuration<H: LogReloader>(
-    config: ConfigureConfig,
+struct Configurator<H: LogReloader> {
+    source: ConfigSource,
     system_config_sender: tokio::sync::watch::Sender<CombinedSystemConfig>,
+    config_change_sender: mpsc::Sender<ConfigChange>,
     log_reload_handle: H,
-) -> std::io::Result<()> {
-    let path = match config.path {
-        Some(path) => path,
-        None => return Ok(()),
-    };
+}
 
-    let peers_listener = create_unix_socket(&path)?;
+impl<H: LogReloader> Configurator<H> {
+    async fn handle(&self, request: Request) -> ProtocolResult<Response> {
+        info!(?request, "dynamic config update");
 
-    // this binary needs to run as root to be able to adjust the system clock.
-    // by default, the socket inherits root permissions, but the client should not need
-    // elevated permissions to read from the socket. So we explicitly set the permissions
-    let permissions: std::fs::Permissions = PermissionsExt::from_mode(config.mode);
-    std::fs::set_permissions(&path, permissions)?;
+        let result = match request {
+            Request::UpdateConfig(operation) => self.update(operation).await,
+            Request::AddPeer(add_peer) => {
+                let config = add_peer
+                    .peer_config()
+                    .map_err(ProtocolError::InvalidRequest)?;
+                self.apply(|reply| ConfigChange::AddPeer(config, reply))
+                    .await
+            }
+            Request::RemovePeer(addr) => {
+                self.apply(|reply| ConfigChange::RemovePeer(addr, reply))
+                    .await
+            }
+            Request::GetState
+            | Request::GetSystem
+            | Request::GetPeers
+            | Request::GetServers
+            | Request::GetHistory(_)
+            | Request::Subscribe => {
+                return Err(ProtocolError::UnsupportedRequest);
+            }
+        };
 
-    let mut msg = Vec::with_capacity(16 * 1024);
+        match result {
+            Ok(()) => Ok(Response::Applied),
+            Err(error) => {
+                warn!(%error, "Could not apply dynamic config update");
+                Err(ProtocolError::Failed(error))
+            }
+        }
+    }
 
-    loop {
-        let (mut stream, _addr) = peers_listener.accept().await?;
+    /// Let the system apply a change, and wait for the result
+    async fn apply(
+        &self,
+        change: impl FnOnce(oneshot::Sender<ConfigResponse>) -> ConfigChange,
+    ) -> ConfigResponse {
+        let (reply_sender, reply_receiver) = oneshot::channel();
 
-        let operation: ConfigUpdate = crate::sockets::read_json(&mut stream, &mut msg).await?;
+        if self
+            .config_change_sender
+            .send(change(reply_sender))
+            .await
+            .is_err()
+        {
+            return Err("the system has stopped".into());
+        }
 
-        tracing::info!(?operation, "dynamic config update");
+        reply_receiver
+            .await
+            .unwrap_or_else(|_| Err("the system has stopped".into()))
+    }
 
+    async fn update(&self, operation: ConfigUpdate) -> ConfigResponse {
         if let Some(filter) = operation.log_filter {
-            log_reload_handle.update_log(EnvFilter::new(filter));
+            self.log_reload_handle.update_log(EnvFilter::new(filter));
         }
 
         if let Some(panic_threshold) = operation.panic_threshold {
-            system_config_sender.send_modify(|config| {
+            self.system_config_sender.send_modify(|config| {
                 config.system.panic_threshold = StepThreshold {
                     forward: Some(NtpDuration::from_seconds(panic_threshold)),
                     backward: Some(NtpDuration::from_seconds(panic_threshold)),
                 };
             });
         }
+
+        if operation.reload {
+            self.reload().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn reload(&self) -> ConfigResponse {
+        let config = match Config::from_args(
+            self.source.file.as_ref(),
+            self.source.peers.clone(),
+            self.source.servers.clone(),
+        )
+        .await
+        {
+            Ok(config) => config,
+            Err(error) => {
+                warn!(%error, "Could not reload the configuration, keeping the current configuration");
+                return Err(error.to_string());
+            }
+        };
+
+        config.check();
+
+        if !self.source.log_filter_override {
+            if let Some(filter) = config.log_filter {
+                self.log_reload_handle.update_log(filter);
+            }
+        }
+
+        self.system_config_sender.send_replace(config.system);
+
+        let reload = ConfigReload {
+            peers: config.peers,
+            servers: config.servers,
+            keys: config.keys,
+            leap_seconds: config.leap_seconds,
+        };
+
+        if self
+            .config_change_sender
+            .send(ConfigChange::Reload(reload))
+            .await
+            .is_err()
+        {
+            warn!("Could not apply the reloaded peers and servers, the system has stopped");
+            return Err("the system has stopped".into());
+        }
+
+        info!("Reloaded configuration");
+
+        Ok(())
+    }
+}
+
+async fn accept(listener: Option<&UnixListener>) -> std::io::Result<UnixStream> {
+    match listener {
+        Some(listener) => Ok(listener.accept().await?.0),
+        // without a configuration socket, only SIGHUP triggers a reload
+        None => std::future::pending().await,
     }
 }
 
+async fn dynamic_configuration<H: LogReloader + Send + Sync + 'static>(
+    config: ConfigureConfig,
+    configurator: Configurator<H>,
+    mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let listener = match &config.path {
+        Some(path) => {
+            let listener = create_unix_socket(path)?;
+
+            // this binary needs to run as root to be able to adjust the system clock.
+            // by default, the socket inherits root permissions, but the client should not need
+            // elevated permissions to read from the socket. So we explicitly set the permissions
+            let permissions: std::fs::Permissions = PermissionsExt::from_mode(config.mode);
+            std::fs::set_permissions(path, permissions)?;
+
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let configurator = Arc::new(configurator);
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+
+    loop {
+        tokio::select! {
+            result = accept(listener.as_ref()) => {
+                let stream = result?;
+
+                let permit = match connections.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        warn!("too many configuration socket connections, closing a new connection");
+                        continue;
+                    }
+                };
+
+                // a client may keep its connection open, so serve each client separately
+                let configurator = configurator.clone();
+                tokio::spawn(async move {
+                    let result = crate::protocol::serve(stream, None, |request| {
+                        let configurator = configurator.clone();
+                        async move { configurator.handle(request).await }
+                    })
+                    .await;
+                    drop(permit);
+
+                    if let Err(error) = result {
+                        warn!(?error, "configuration socket connection failed");
+                    }
+                });
+            }
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading configuration");
+                // failures are logged by the reload itself
+                let _ = configurator.reload().await;
+            }
+            _ = shutdown_receiver.changed(), if shutdown_receiver.has_changed().is_ok() => break,
+        }
+    }
+
+    if let Some(path) = &config.path {
+        crate::sockets::remove_unix_socket(path);
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
-    use crate::sockets::write_json;
+    use crate::protocol::{Client, ClientError};
 
     use super::*;
 
@@ -127,25 +394,32 @@ mod tests {
             mode: 0o700,
         };
 
-        let handle = spawn(config, system_config_sender, TestLogReloader {}).await;
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+        let (config_change_sender, _) = mpsc::channel(1);
+
+        let handle = spawn(
+            config,
+            ConfigSource::default(),
+            system_config_sender,
+            config_change_sender,
+            TestLogReloader {},
+            shutdown_receiver,
+        )
+        .await;
 
         // Ensure client has started.
         tokio::time::sleep(Duration::from_millis(10)).await;
 
-        let mut stream = tokio::net::UnixStream::connect(path).await.unwrap();
+        let mut client = Client::connect(&path).await.unwrap();
 
-        write_json(
-            &mut stream,
-            &ConfigUpdate {
+        client
+            .change(&Request::UpdateConfig(ConfigUpdate {
                 log_filter: Some("info".into()),
                 panic_threshold: Some(600.),
-            },
-        )
-        .await
-        .unwrap();
-
-        // Ensure message is handled.
-        tokio::time::sleep(Duration::from_millis(10)).await;
+                reload: false,
+            }))
+            .await
+            .unwrap();
 
         assert_eq!(
             system_config_receiver
@@ -156,6 +430,184 @@ mod tests {
             Some(NtpDuration::from_seconds(600.))
         );
 
+        // the socket is removed on shutdown
+        shutdown_sender.send_replace(true);
+        handle.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let config_path = std::env::temp_dir().join("ntp-test-reload-config-1.toml");
+        std::fs::write(
+            &config_path,
+            "[[peers]]\naddr = \"127.0.0.1\"\n[system]\nsend-timestamps = false",
+        )
+        .unwrap();
+
+        let (system_config_sender, system_config_receiver) =
+            tokio::sync::watch::channel(CombinedSystemConfig::default());
+        let (config_change_sender, mut config_change_receiver) = mpsc::channel(1);
+
+        let path = std::env::temp_dir().join("ntp-test-stream-5");
+        let config = ConfigureConfig {
+            path: Some(path.clone()),
+            mode: 0o700,
+        };
+        let source = ConfigSource {
+            file: Some(config_path.clone()),
+            ..Default::default()
+        };
+
+        let handle = spawn(
+            config,
+            source,
+            system_config_sender,
+            config_change_sender,
+            TestLogReloader {},
+            tokio::sync::watch::channel(false).1,
+        )
+        .await;
+
+        // Ensure client has started.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut client = Client::connect(&path).await.unwrap();
+
+        let request = Request::UpdateConfig(ConfigUpdate {
+            log_filter: None,
+            panic_threshold: None,
+            reload: true,
+        });
+        let reply = tokio::spawn(async move { client.change(&request).await });
+
+        let change = tokio::time::timeout(Duration::from_secs(1), config_change_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let reload = match change {
+            ConfigChange::Reload(reload) => reload,
+            other => panic!("unexpected change {other:?}"),
+        };
+
+        assert_eq!(
+            reload.peers,
+            vec![PeerConfig::try_from("127.0.0.1").unwrap()]
+        );
+        assert!(reload.servers.is_empty());
+        assert!(!system_config_receiver.borrow().send_timestamps);
+        reply.await.unwrap().unwrap();
+
+        handle.abort();
+        std::fs::remove_file(&config_path).unwrap();
+    }
+
+    #[test]
+    fn test_add_peer_config() {
+        let add_peer = |addr: &str, mode, max_peers| {
+            AddPeer {
+                addr: addr.into(),
+                mode,
+                max_peers,
+            }
+            .peer_config()
+        };
+
+        assert_eq!(
+            add_peer("127.0.0.1", PeerMode::Server, None),
+            Ok(PeerConfig::try_from("127.0.0.1").unwrap())
+        );
+
+        let pool = match add_peer("pool.example.com", PeerMode::Pool, Some(4)) {
+            Ok(PeerConfig::Pool(pool)) => pool,
+            other => panic!("expected a pool, got {other:?}"),
+        };
+        assert_eq!(pool.addr.to_string(), "pool.example.com:123");
+        assert_eq!(pool.max_peers, 4);
+
+        let nts = match add_peer("nts.example.com", PeerMode::NtsServer, None) {
+            Ok(PeerConfig::Nts(nts)) => nts,
+            other => panic!("expected an nts peer, got {other:?}"),
+        };
+        assert_eq!(nts.ke_addr.to_string(), "nts.example.com:4460");
+
+        assert!(add_peer("127.0.0.1", PeerMode::Server, Some(4)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_remove_peer() {
+        let (system_config_sender, _) =
+            tokio::sync::watch::channel(CombinedSystemConfig::default());
+        let (config_change_sender, mut config_change_receiver) = mpsc::channel(1);
+
+        let path = std::env::temp_dir().join("ntp-test-stream-6");
+        let config = ConfigureConfig {
+            path: Some(path.clone()),
+            mode: 0o700,
+        };
+
+        let handle = spawn(
+            config,
+            ConfigSource::default(),
+            system_config_sender,
+            config_change_sender,
+            TestLogReloader {},
+            tokio::sync::watch::channel(false).1,
+        )
+        .await;
+
+        // play the part of the system
+        let system = tokio::spawn(async move {
+            while let Some(change) = config_change_receiver.recv().await {
+                match change {
+                    ConfigChange::AddPeer(config, reply) => {
+                        assert_eq!(config, PeerConfig::try_from("127.0.0.1").unwrap());
+                        reply.send(Ok(())).unwrap();
+                    }
+                    ConfigChange::RemovePeer(addr, reply) => {
+                        reply.send(Err(format!("no peer {addr}"))).unwrap();
+                    }
+                    other => panic!("unexpected change {other:?}"),
+                }
+            }
+        });
+
+        // Ensure client has started.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut client = Client::connect(&path).await.unwrap();
+
+        let add_peer = |mode, max_peers| {
+            Request::AddPeer(AddPeer {
+                addr: "127.0.0.1".into(),
+                mode,
+                max_peers,
+            })
+        };
+
+        client
+            .change(&add_peer(PeerMode::Server, None))
+            .await
+            .unwrap();
+
+        // invalid peers are rejected before they reach the system
+        assert!(matches!(
+            client.change(&add_peer(PeerMode::Server, Some(2))).await,
+            Err(ClientError::Protocol(ProtocolError::InvalidRequest(_)))
+        ));
+
+        assert!(matches!(
+            client.change(&Request::RemovePeer("127.0.0.2".into())).await,
+            Err(ClientError::Protocol(ProtocolError::Failed(error))) if error == "no peer 127.0.0.2"
+        ));
+
+        // the state of the daemon is only available on the observation socket
+        assert!(matches!(
+            client.get_peers().await,
+            Err(ClientError::Protocol(ProtocolError::UnsupportedRequest))
+        ));
+
         handle.abort();
+        system.abort();
     }
 }
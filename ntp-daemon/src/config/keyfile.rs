@@ -0,0 +1,183 @@
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use ntp_proto::{InvalidKeyError, SymmetricKey, SymmetricKeyAlgorithm};
+use thiserror::Error;
+use tokio::{fs::read_to_string, io};
+
+/// The symmetric keys used to authenticate NTP packets, indexed by key id
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymmetricKeys {
+    keys: HashMap<u32, SymmetricKey>,
+}
+
+#[derive(Error, Debug)]
+pub enum KeyfileError {
+    #[error("io error while reading keyfile: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line}: expected a key id, an algorithm and a secret")]
+    InvalidFormat { line: usize },
+    #[error("line {line}: invalid key id")]
+    InvalidKeyId { line: usize },
+    #[error("line {line}: secrets longer than 20 characters must be hexadecimal")]
+    InvalidSecret { line: usize },
+    #[error("line {line}: {source}")]
+    InvalidKey {
+        line: usize,
+        source: InvalidKeyError,
+    },
+    #[error("line {line}: duplicate key id {key_id}")]
+    DuplicateKeyId { line: usize, key_id: u32 },
+}
+
+impl SymmetricKeys {
+    pub fn get(&self, key_id: u32) -> Option<&SymmetricKey> {
+        self.keys.get(&key_id)
+    }
+
+    pub async fn from_file(file: impl AsRef<Path>) -> Result<Self, KeyfileError> {
+        let contents = read_to_string(file).await?;
+        contents.parse()
+    }
+}
+
+/// Decode a secret the way ntpd does: secrets of up to 20 characters are used as-is,
+/// longer secrets are hexadecimal
+fn parse_secret(secret: &str) -> Option<Vec<u8>> {
+    if secret.len() <= 20 {
+        return Some(secret.as_bytes().to_vec());
+    }
+
+    // an odd length leaves a single character at the end, which `get` rejects
+    (0..secret.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(secret.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses a keyfile with a key on every line, in the format `<key id> <algorithm> <secret>`,
+/// which is compatible with the keyfiles of ntpd. Everything after a `#` is a comment.
+impl FromStr for SymmetricKeys {
+    type Err = KeyfileError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        let mut keys = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default();
+
+            let parts: Vec<_> = line.split_whitespace().collect();
+            let (key_id, algorithm, secret) = match parts[..] {
+                [] => continue,
+                [key_id, algorithm, secret] => (key_id, algorithm, secret),
+                _ => return Err(KeyfileError::InvalidFormat { line: line_number }),
+            };
+
+            let key_id: u32 = key_id
+                .parse()
+                .map_err(|_| KeyfileError::InvalidKeyId { line: line_number })?;
+            let algorithm: SymmetricKeyAlgorithm =
+                algorithm
+                    .parse()
+                    .map_err(|source| KeyfileError::InvalidKey {
+                        line: line_number,
+                        source,
+                    })?;
+            let secret =
+                parse_secret(secret).ok_or(KeyfileError::InvalidSecret { line: line_number })?;
+
+            let key = SymmetricKey::new(key_id, algorithm, secret).map_err(|source| {
+                KeyfileError::InvalidKey {
+                    line: line_number,
+                    source,
+                }
+            })?;
+
+            if keys.insert(key_id, key).is_some() {
+                return Err(KeyfileError::DuplicateKeyId {
+                    line: line_number,
+                    key_id,
+                });
+            }
+        }
+
+        Ok(SymmetricKeys { keys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keyfile() {
+        let keys: SymmetricKeys = r#"
+            # ntpd style keyfile
+            1 MD5 secret
+            2 SHA1 0123456789abcdef0123456789abcdef01234567 # a hexadecimal secret
+
+            3 AES128CMAC 000102030405060708090a0b0c0d0e0f
+            "#
+        .parse()
+        .unwrap();
+
+        let key = keys.get(1).unwrap();
+        assert_eq!(key.algorithm(), SymmetricKeyAlgorithm::Md5);
+        assert_eq!(
+            key,
+            &SymmetricKey::new(1, SymmetricKeyAlgorithm::Md5, b"secret".to_vec()).unwrap()
+        );
+
+        let key = keys.get(2).unwrap();
+        assert_eq!(key.algorithm(), SymmetricKeyAlgorithm::Sha1);
+        assert_eq!(
+            key,
+            &SymmetricKey::new(
+                2,
+                SymmetricKeyAlgorithm::Sha1,
+                vec![
+                    0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89,
+                    0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67
+                ]
+            )
+            .unwrap()
+        );
+
+        let key = keys.get(3).unwrap();
+        assert_eq!(key.algorithm(), SymmetricKeyAlgorithm::Aes128Cmac);
+        assert_eq!(
+            key,
+            &SymmetricKey::new(3, SymmetricKeyAlgorithm::Aes128Cmac, (0..16).collect()).unwrap()
+        );
+
+        assert!(keys.get(4).is_none());
+    }
+
+    #[test]
+    fn test_parse_keyfile_errors() {
+        let error = "1 MD5".parse::<SymmetricKeys>().unwrap_err();
+        assert!(matches!(error, KeyfileError::InvalidFormat { line: 1 }));
+
+        let error = "\nfoo MD5 secret".parse::<SymmetricKeys>().unwrap_err();
+        assert!(matches!(error, KeyfileError::InvalidKeyId { line: 2 }));
+
+        let error = "1 SHA256 secret".parse::<SymmetricKeys>().unwrap_err();
+        assert!(matches!(error, KeyfileError::InvalidKey { line: 1, .. }));
+
+        let error = "0 MD5 secret".parse::<SymmetricKeys>().unwrap_err();
+        assert!(matches!(error, KeyfileError::InvalidKey { line: 1, .. }));
+
+        let error = "1 SHA1 this-is-not-a-hexadecimal-secret"
+            .parse::<SymmetricKeys>()
+            .unwrap_err();
+        assert!(matches!(error, KeyfileError::InvalidSecret { line: 1 }));
+
+        let error = "1 MD5 secret\n1 SHA1 secret"
+            .parse::<SymmetricKeys>()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            KeyfileError::DuplicateKeyId { line: 2, key_id: 1 }
+        ));
+    }
+}
@@ -1,18 +1,27 @@
 pub mod dynamic;
 pub mod format;
+mod keyfile;
 mod peer;
+mod refclock;
 mod server;
 pub mod subnet;
 
+pub use keyfile::*;
 use ntp_os_clock::UnixNtpClock;
 pub use peer::*;
+pub use refclock::*;
 pub use server::*;
 
 use clap::Parser;
-use ntp_proto::{DefaultTimeSyncController, SystemConfig, TimeSyncController};
+use ntp_proto::{
+    DefaultTimeSyncController, LeapSecondsFile, LeapSecondsFileError, NtpClock, SystemConfig,
+    TimeSyncController,
+};
+use ntp_udp::InterfaceName;
 use serde::{de, Deserialize, Deserializer};
 use std::{
     io::ErrorKind,
+    net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
 };
@@ -102,7 +111,7 @@ pub struct CmdArgs {
     pub servers: Vec<ServerConfig>,
 }
 
-#[derive(Deserialize, Debug, Default, Copy, Clone)]
+#[derive(Deserialize, Debug, Copy, Clone)]
 pub struct CombinedSystemConfig {
     #[serde(flatten)]
     pub system: SystemConfig,
@@ -111,6 +120,24 @@ pub struct CombinedSystemConfig {
         UnixNtpClock,
         PeerIndex,
     >>::AlgorithmConfig,
+    /// Use the time at which the kernel sent a poll, instead of the time just before handing
+    /// the poll to the kernel
+    #[serde(rename = "send-timestamps", default = "default_send_timestamps")]
+    pub send_timestamps: bool,
+}
+
+const fn default_send_timestamps() -> bool {
+    true
+}
+
+impl Default for CombinedSystemConfig {
+    fn default() -> Self {
+        Self {
+            system: Default::default(),
+            algorithm: Default::default(),
+            send_timestamps: default_send_timestamps(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -120,8 +147,29 @@ pub struct Config {
     pub peers: Vec<PeerConfig>,
     #[serde(alias = "server", default)]
     pub servers: Vec<ServerConfig>,
+    #[serde(alias = "refclock", default)]
+    pub refclocks: Vec<RefClockConfig>,
+    #[serde(rename = "nts-ke-server", default)]
+    pub nts_ke: Vec<NtsKeConfig>,
+    #[serde(default)]
+    pub keyset: KeysetConfig,
+    #[serde(default)]
+    pub keyfile: Option<PathBuf>,
+    /// The symmetric keys loaded from the keyfile
+    #[serde(skip)]
+    pub keys: SymmetricKeys,
+    #[serde(default)]
+    pub leap_seconds_file: Option<PathBuf>,
+    /// The leap seconds loaded from the leap seconds file
+    #[serde(skip)]
+    pub leap_seconds: Option<LeapSecondsFile>,
+    /// File in which the frequency of the clock is kept across restarts
+    #[serde(default)]
+    pub drift_file: Option<PathBuf>,
     #[serde(default)]
     pub system: CombinedSystemConfig,
+    #[serde(default)]
+    pub clock: ClockConfig,
     #[serde(deserialize_with = "deserialize_option_env_filter", default)]
     pub log_filter: Option<EnvFilter>,
     #[serde(default)]
@@ -135,6 +183,17 @@ pub struct Config {
     pub configure: ConfigureConfig,
 }
 
+/// The clock that is disciplined by the daemon
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ClockConfig {
+    /// The system clock
+    #[default]
+    System,
+    /// A ptp hardware clock, such as the clock of a network card
+    Ptp { path: PathBuf },
+}
+
 const fn default_observe_permissions() -> u32 {
     0o777
 }
@@ -177,6 +236,60 @@ impl Default for ConfigureConfig {
     }
 }
 
+/// Where the timestamps of packets to and from peers or clients are taken
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum TimestampingMode {
+    /// By the kernel
+    #[default]
+    Software,
+    /// By the network card behind the interface, falling back to the kernel when the network
+    /// card cannot. Without an interface, the interface of the local address is used.
+    Hardware { interface: Option<InterfaceName> },
+}
+
+impl<'de> Deserialize<'de> for TimestampingMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        enum Mode {
+            Software,
+            Hardware,
+        }
+
+        // the interface is a separate option
+        Ok(match Mode::deserialize(deserializer)? {
+            Mode::Software => TimestampingMode::Software,
+            Mode::Hardware => TimestampingMode::Hardware { interface: None },
+        })
+    }
+}
+
+impl TimestampingMode {
+    /// Combine the `timestamping` and `interface` options of a peer or server
+    pub(crate) fn with_interface<E: de::Error>(
+        timestamping: Option<TimestampingMode>,
+        interface: Option<InterfaceName>,
+    ) -> Result<Self, E> {
+        match (timestamping.unwrap_or_default(), interface) {
+            (TimestampingMode::Hardware { .. }, interface) => {
+                Ok(TimestampingMode::Hardware { interface })
+            }
+            (TimestampingMode::Software, None) => Ok(TimestampingMode::Software),
+            (TimestampingMode::Software, Some(_)) => Err(E::custom(
+                "an interface can only be given with hardware timestamping",
+            )),
+        }
+    }
+}
+
+fn deserialize_interface<E: de::Error>(raw: &str) -> Result<InterfaceName, E> {
+    raw.parse()
+        .map_err(|_| E::invalid_value(de::Unexpected::Str(raw), &"the name of a network interface"))
+}
+
 #[cfg(feature = "sentry")]
 #[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "kebab-case")]
@@ -197,6 +310,17 @@ pub enum ConfigError {
     Io(#[from] io::Error),
     #[error("config toml parsing error: {0}")]
     Toml(#[from] toml::de::Error),
+    #[error("error while loading keyfile: {0}")]
+    Keyfile(#[from] KeyfileError),
+    #[error("error while loading leap seconds file: {0}")]
+    LeapSecondsFile(#[from] LeapSecondsFileError),
+    #[error("peer {addr} uses key id {key_id}, which is not in the keyfile")]
+    UnknownKeyId {
+        addr: NormalizedAddress,
+        key_id: u32,
+    },
+    #[error("server {addr} broadcasts with key id {key_id}, which is not in the keyfile")]
+    UnknownBroadcastKeyId { addr: SocketAddr, key_id: u32 },
 }
 
 impl Config {
@@ -256,6 +380,22 @@ impl Config {
             config.servers = servers;
         }
 
+        if let Some(keyfile) = &config.keyfile {
+            config.keys = SymmetricKeys::from_file(keyfile).await?;
+        }
+
+        if let Some(leap_seconds_file) = &config.leap_seconds_file {
+            config.leap_seconds = Some(read_to_string(leap_seconds_file).await?.parse()?);
+        }
+
+        for peer in &config.peers {
+            check_peer_key(peer, &config.keys)?;
+        }
+
+        for server in &config.servers {
+            check_server_key(server, &config.keys)?;
+        }
+
         Ok(config)
     }
 
@@ -266,13 +406,60 @@ impl Config {
         // using those fields should always work. This is also
         // probably a good policy in general (config should always work
         // but we may panic here to protect the user from themselves)
-        if self.peers.is_empty() {
+        let sources = self.peers.len() + self.refclocks.len();
+
+        if sources == 0 {
             warn!("No peers configured. Daemon will not do anything.");
         }
 
-        if self.peers.len() < self.system.system.min_intersection_survivors {
+        if sources < self.system.system.min_intersection_survivors {
             warn!("Fewer peers configured than are required to agree on the current time. Daemon will not do anything.");
         }
+
+        if let Some(leap_seconds) = &self.leap_seconds {
+            if let Ok(now) = UnixNtpClock::new().now() {
+                if leap_seconds.is_expired(now) {
+                    warn!("The leap seconds file has expired and will not be used. Please install an up to date version.");
+                }
+            }
+        }
+    }
+}
+
+/// Check that a peer only uses a key of the keyfile. Peers that are added or
+/// reloaded at runtime are checked against the keys the daemon currently uses.
+pub fn check_peer_key(peer: &PeerConfig, keys: &SymmetricKeys) -> Result<(), ConfigError> {
+    let (addr, key_id) = match peer {
+        PeerConfig::Standard(StandardPeerConfig {
+            addr,
+            key_id: Some(key_id),
+            ..
+        })
+        | PeerConfig::Symmetric(SymmetricPeerConfig {
+            addr,
+            key_id: Some(key_id),
+        }) => (addr, *key_id),
+        PeerConfig::Broadcast(BroadcastPeerConfig { addr, key_id }) => (addr, *key_id),
+        _ => return Ok(()),
+    };
+
+    match keys.get(key_id) {
+        Some(_) => Ok(()),
+        None => Err(ConfigError::UnknownKeyId {
+            addr: addr.clone(),
+            key_id,
+        }),
+    }
+}
+
+/// Check that a server only broadcasts with a key of the keyfile
+pub fn check_server_key(server: &ServerConfig, keys: &SymmetricKeys) -> Result<(), ConfigError> {
+    match server.broadcast_key_id {
+        Some(key_id) if keys.get(key_id).is_none() => Err(ConfigError::UnknownBroadcastKeyId {
+            addr: server.addr,
+            key_id,
+        }),
+        _ => Ok(()),
     }
 }
 
@@ -291,6 +478,8 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
             })]
         );
 
@@ -301,6 +490,8 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
             })]
         );
 
@@ -311,6 +502,8 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
             })]
         );
 
@@ -321,6 +514,8 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
             })]
         );
         assert_eq!(
@@ -340,10 +535,30 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
             })]
         );
         assert!(config.system.system.panic_threshold.forward.is_none());
         assert!(config.system.system.panic_threshold.backward.is_none());
+        assert!(config.system.send_timestamps);
+
+        let config: Config =
+            toml::from_str("[[peers]]\naddr = \"example.com\"\n[system]\nsend-timestamps = false")
+                .unwrap();
+        assert!(!config.system.send_timestamps);
+        assert_eq!(config.clock, ClockConfig::System);
+
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[clock]\ntype = \"ptp\"\npath = \"/dev/ptp0\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config.clock,
+            ClockConfig::Ptp {
+                path: PathBuf::from("/dev/ptp0")
+            }
+        );
 
         let config: Config = toml::from_str(
             r#"
@@ -374,6 +589,8 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com", 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
             })]
         );
     }
@@ -441,6 +658,88 @@ mod tests {
         assert_eq!(config.peers.len(), 2);
     }
 
+    #[tokio::test]
+    async fn test_keyfile_config() {
+        let keyfile = env::temp_dir().join("ntp-test-keyfile-1");
+        std::fs::write(&keyfile, "1 MD5 secret\n").unwrap();
+
+        let config_path = env::temp_dir().join("ntp-test-keyfile-config-1.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "keyfile = \"{}\"\n[[peers]]\naddr = \"example.com\"\nkey_id = 1",
+                keyfile.display()
+            ),
+        )
+        .unwrap();
+
+        let config = Config::from_args(Some(&config_path), vec![], vec![])
+            .await
+            .unwrap();
+        assert!(config.keys.get(1).is_some());
+
+        std::fs::write(
+            &config_path,
+            format!(
+                "keyfile = \"{}\"\n[[peers]]\naddr = \"example.com\"\nkey_id = 2",
+                keyfile.display()
+            ),
+        )
+        .unwrap();
+
+        let result = Config::from_args(Some(&config_path), vec![], vec![]).await;
+        assert!(matches!(
+            result,
+            Err(ConfigError::UnknownKeyId { key_id: 2, .. })
+        ));
+
+        std::fs::remove_file(&keyfile).unwrap();
+        std::fs::remove_file(&config_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_leap_seconds_file_config() {
+        let leap_seconds_file = env::temp_dir().join("ntp-test-leap-seconds-1");
+        std::fs::write(
+            &leap_seconds_file,
+            "#$ 3960835200\n#@ 3991593600\n3692217600 37\n#h 0 0 0 0 0\n",
+        )
+        .unwrap();
+
+        let config_path = env::temp_dir().join("ntp-test-leap-seconds-config-1.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "leap-seconds-file = \"{}\"\n[[peers]]\naddr = \"example.com\"",
+                leap_seconds_file.display()
+            ),
+        )
+        .unwrap();
+
+        // the hash of the file does not match its contents
+        let result = Config::from_args(Some(&config_path), vec![], vec![]).await;
+        assert!(matches!(
+            result,
+            Err(ConfigError::LeapSecondsFile(
+                LeapSecondsFileError::HashMismatch
+            ))
+        ));
+
+        std::fs::write(
+            &leap_seconds_file,
+            include_str!("../../../ntp-proto/testdata/leap-seconds.list"),
+        )
+        .unwrap();
+
+        let config = Config::from_args(Some(&config_path), vec![], vec![])
+            .await
+            .unwrap();
+        assert!(config.leap_seconds.is_some());
+
+        std::fs::remove_file(&leap_seconds_file).unwrap();
+        std::fs::remove_file(&config_path).unwrap();
+    }
+
     #[test]
     fn clap_no_arguments() {
         use clap::Parser;
@@ -506,6 +805,8 @@ mod tests {
             parsed_empty.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("foo.nl", 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
             })]
         );
         assert!(parsed_empty.config.is_none());
@@ -519,9 +820,13 @@ mod tests {
             vec![
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("foo.rs", 123),
+                    key_id: None,
+                    timestamping: TimestampingMode::Software,
                 }),
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("spam.nl", 123),
+                    key_id: None,
+                    timestamping: TimestampingMode::Software,
                 }),
             ]
         );
@@ -6,6 +6,7 @@ use serde::{
     Deserialize, Deserializer,
 };
 
+use super::{deserialize_interface, TimestampingMode};
 use crate::keyexchange::certificates_from_file;
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
@@ -16,6 +17,10 @@ pub enum PeerHostMode {
     NtsServer,
     #[serde(alias = "pool")]
     Pool,
+    #[serde(alias = "symmetric")]
+    Symmetric,
+    #[serde(alias = "broadcast")]
+    Broadcast,
 }
 
 impl Default for PeerHostMode {
@@ -27,6 +32,11 @@ impl Default for PeerHostMode {
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct StandardPeerConfig {
     pub addr: NormalizedAddress,
+    /// The id of the key in the keyfile used to authenticate packets to and from this peer
+    #[serde(default)]
+    pub key_id: Option<u32>,
+    #[serde(default)]
+    pub timestamping: TimestampingMode,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -39,6 +49,25 @@ pub struct NtsPeerConfig {
 pub struct PoolPeerConfig {
     pub addr: NormalizedAddress,
     pub max_peers: usize,
+    #[serde(default)]
+    pub timestamping: TimestampingMode,
+}
+
+/// A symmetric active association, in which both sides can synchronize to each other
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct SymmetricPeerConfig {
+    pub addr: NormalizedAddress,
+    #[serde(default)]
+    pub key_id: Option<u32>,
+}
+
+/// A client of the broadcast server that sends to `addr`, a broadcast address or
+/// multicast group. Anyone on the network can send broadcasts, so they must be
+/// authenticated with a key.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct BroadcastPeerConfig {
+    pub addr: NormalizedAddress,
+    pub key_id: u32,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -46,6 +75,8 @@ pub enum PeerConfig {
     Standard(StandardPeerConfig),
     Nts(NtsPeerConfig),
     Pool(PoolPeerConfig),
+    Symmetric(SymmetricPeerConfig),
+    Broadcast(BroadcastPeerConfig),
     // Consul(ConsulPeerConfig),
 }
 
@@ -53,6 +84,36 @@ impl PeerConfig {
     pub(crate) fn try_from_str(value: &str) -> Result<Self, std::io::Error> {
         Self::try_from(value)
     }
+
+    /// Whether this peer connects to the given address, written like in the configuration file
+    pub(crate) fn has_address(&self, address: &str) -> bool {
+        let (addr, default_port) = match self {
+            PeerConfig::Standard(StandardPeerConfig { addr, .. })
+            | PeerConfig::Pool(PoolPeerConfig { addr, .. })
+            | PeerConfig::Symmetric(SymmetricPeerConfig { addr, .. })
+            | PeerConfig::Broadcast(BroadcastPeerConfig { addr, .. }) => {
+                (addr, NormalizedAddress::NTP_DEFAULT_PORT)
+            }
+            PeerConfig::Nts(NtsPeerConfig { ke_addr, .. }) => {
+                (ke_addr, NormalizedAddress::NTS_KE_DEFAULT_PORT)
+            }
+        };
+
+        match NormalizedAddress::from_string_help(address.to_string(), default_port) {
+            Ok((server_name, port)) => addr.server_name == server_name && addr.port == port,
+            Err(_) => false,
+        }
+    }
+
+    /// The id of the key in the keyfile with which the packets of this peer are authenticated
+    pub(crate) fn key_id(&self) -> Option<u32> {
+        match self {
+            PeerConfig::Standard(StandardPeerConfig { key_id, .. })
+            | PeerConfig::Symmetric(SymmetricPeerConfig { key_id, .. }) => *key_id,
+            PeerConfig::Broadcast(BroadcastPeerConfig { key_id, .. }) => Some(*key_id),
+            PeerConfig::Pool(_) | PeerConfig::Nts(_) => None,
+        }
+    }
 }
 
 /// A normalized address has a host and a port part. However, the host may be
@@ -187,6 +248,8 @@ impl TryFrom<&str> for StandardPeerConfig {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         Ok(Self {
             addr: NormalizedAddress::from_string_ntp(value.to_string())?,
+            key_id: None,
+            timestamping: TimestampingMode::default(),
         })
     }
 }
@@ -225,6 +288,9 @@ impl<'de> Deserialize<'de> for PeerConfig {
                 let mut addr = None;
                 let mut mode = None;
                 let mut max_peers = None;
+                let mut key_id = None;
+                let mut timestamping = None;
+                let mut interface = None;
                 while let Some(key) = map.next_key::<&str>()? {
                     match key {
                         "addr" => {
@@ -271,10 +337,38 @@ impl<'de> Deserialize<'de> for PeerConfig {
                             }
                             max_peers = Some(map.next_value()?);
                         }
+                        "key_id" => {
+                            if key_id.is_some() {
+                                return Err(de::Error::duplicate_field("key_id"));
+                            }
+                            key_id = Some(map.next_value()?);
+                        }
+                        "timestamping" => {
+                            if timestamping.is_some() {
+                                return Err(de::Error::duplicate_field("timestamping"));
+                            }
+                            timestamping = Some(map.next_value()?);
+                        }
+                        "interface" => {
+                            if interface.is_some() {
+                                return Err(de::Error::duplicate_field("interface"));
+                            }
+                            let raw: String = map.next_value()?;
+
+                            interface = Some(deserialize_interface(&raw)?);
+                        }
                         _ => {
                             return Err(de::Error::unknown_field(
                                 key,
-                                &["addr", "mode", "max_peers", "ke_addr"],
+                                &[
+                                    "addr",
+                                    "mode",
+                                    "max_peers",
+                                    "ke_addr",
+                                    "key_id",
+                                    "timestamping",
+                                    "interface",
+                                ],
                             ));
                         }
                     }
@@ -285,11 +379,24 @@ impl<'de> Deserialize<'de> for PeerConfig {
                 let unknown_field =
                     |field, valid_fields| Err(de::Error::unknown_field(field, valid_fields));
 
+                // our sockets to peers are not bound to the address of an interface, so hardware
+                // timestamping needs to be told which interface to use
+                let peer_timestamping =
+                    |timestamping, interface| match TimestampingMode::with_interface(
+                        timestamping,
+                        interface,
+                    )? {
+                        TimestampingMode::Hardware { interface: None } => {
+                            Err(de::Error::missing_field("interface"))
+                        }
+                        timestamping => Ok(timestamping),
+                    };
+
                 match mode {
                     PeerHostMode::Server => {
                         let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
 
-                        let valid_fields = &["addr", "mode"];
+                        let valid_fields = &["addr", "mode", "key_id", "timestamping", "interface"];
                         if max_peers.is_some() {
                             unknown_field("max_peers", valid_fields)
                         } else if ke_addr.is_some() {
@@ -297,7 +404,13 @@ impl<'de> Deserialize<'de> for PeerConfig {
                         } else if opt_certificate_path.is_some() {
                             unknown_field("certificate", valid_fields)
                         } else {
-                            Ok(PeerConfig::Standard(StandardPeerConfig { addr }))
+                            let timestamping = peer_timestamping(timestamping, interface)?;
+
+                            Ok(PeerConfig::Standard(StandardPeerConfig {
+                                addr,
+                                key_id,
+                                timestamping,
+                            }))
                         }
                     }
                     PeerHostMode::NtsServer => {
@@ -306,6 +419,12 @@ impl<'de> Deserialize<'de> for PeerConfig {
                         let valid_fields = &["mode", "ke_addr", "certificate"];
                         if max_peers.is_some() {
                             unknown_field("max_peers", valid_fields)
+                        } else if key_id.is_some() {
+                            unknown_field("key_id", valid_fields)
+                        } else if timestamping.is_some() {
+                            unknown_field("timestamping", valid_fields)
+                        } else if interface.is_some() {
+                            unknown_field("interface", valid_fields)
                         } else {
                             let certificates: Arc<[Certificate]> =
                                 if let Some(certificate_path) = opt_certificate_path {
@@ -332,15 +451,60 @@ impl<'de> Deserialize<'de> for PeerConfig {
                     PeerHostMode::Pool => {
                         let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
 
-                        let valid_fields = &["addr", "mode", "max_peers"];
+                        let valid_fields =
+                            &["addr", "mode", "max_peers", "timestamping", "interface"];
                         if ke_addr.is_some() {
                             unknown_field("ke_addr", valid_fields)
                         } else if opt_certificate_path.is_some() {
                             unknown_field("certificate", valid_fields)
+                        } else if key_id.is_some() {
+                            unknown_field("key_id", valid_fields)
                         } else {
                             let max_peers = max_peers.unwrap_or(1);
+                            let timestamping = peer_timestamping(timestamping, interface)?;
+
+                            Ok(PeerConfig::Pool(PoolPeerConfig {
+                                addr,
+                                max_peers,
+                                timestamping,
+                            }))
+                        }
+                    }
+                    PeerHostMode::Symmetric => {
+                        let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
 
-                            Ok(PeerConfig::Pool(PoolPeerConfig { addr, max_peers }))
+                        let valid_fields = &["addr", "mode", "key_id"];
+                        if max_peers.is_some() {
+                            unknown_field("max_peers", valid_fields)
+                        } else if ke_addr.is_some() {
+                            unknown_field("ke_addr", valid_fields)
+                        } else if opt_certificate_path.is_some() {
+                            unknown_field("certificate", valid_fields)
+                        } else if timestamping.is_some() {
+                            unknown_field("timestamping", valid_fields)
+                        } else if interface.is_some() {
+                            unknown_field("interface", valid_fields)
+                        } else {
+                            Ok(PeerConfig::Symmetric(SymmetricPeerConfig { addr, key_id }))
+                        }
+                    }
+                    PeerHostMode::Broadcast => {
+                        let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
+                        let key_id = key_id.ok_or_else(|| de::Error::missing_field("key_id"))?;
+
+                        let valid_fields = &["addr", "mode", "key_id"];
+                        if max_peers.is_some() {
+                            unknown_field("max_peers", valid_fields)
+                        } else if ke_addr.is_some() {
+                            unknown_field("ke_addr", valid_fields)
+                        } else if opt_certificate_path.is_some() {
+                            unknown_field("certificate", valid_fields)
+                        } else if timestamping.is_some() {
+                            unknown_field("timestamping", valid_fields)
+                        } else if interface.is_some() {
+                            unknown_field("interface", valid_fields)
+                        } else {
+                            Ok(PeerConfig::Broadcast(BroadcastPeerConfig { addr, key_id }))
                         }
                     }
                 }
@@ -360,6 +524,8 @@ mod tests {
             PeerConfig::Standard(c) => c.addr.to_string(),
             PeerConfig::Nts(c) => c.ke_addr.to_string(),
             PeerConfig::Pool(c) => c.addr.to_string(),
+            PeerConfig::Symmetric(c) => c.addr.to_string(),
+            PeerConfig::Broadcast(c) => c.addr.to_string(),
         }
     }
 
@@ -397,6 +563,86 @@ mod tests {
         assert_eq!(peer_addr(&test.peer), "example.com:123");
         assert!(matches!(test.peer, PeerConfig::Standard(_)));
 
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            key_id = 42
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            test.peer,
+            PeerConfig::Standard(StandardPeerConfig {
+                key_id: Some(42),
+                ..
+            })
+        ));
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            timestamping = "hardware"
+            interface = "eth0"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            test.peer,
+            PeerConfig::Standard(StandardPeerConfig {
+                timestamping: TimestampingMode::Hardware { interface: Some(interface) },
+                ..
+            }) if interface.to_string() == "eth0"
+        ));
+
+        // hardware timestamping needs to know the interface
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            timestamping = "hardware"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            interface = "eth0"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            timestamping = "hardware"
+            interface = "a-very-long-interface-name"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            timestamping = "firmware"
+            "#,
+        );
+        assert!(test.is_err());
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            key_id = 42
+            "#,
+        );
+        assert!(test.is_err());
+
         let test: TestConfig = toml::from_str(
             r#"
             [peer]
@@ -409,6 +655,26 @@ mod tests {
         if let PeerConfig::Pool(config) = test.peer {
             assert_eq!(config.addr.to_string(), "example.com:123");
             assert_eq!(config.max_peers, 1);
+            assert_eq!(config.timestamping, TimestampingMode::Software);
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            timestamping = "hardware"
+            interface = "eth0"
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Pool(config) = test.peer {
+            assert_eq!(
+                config.timestamping,
+                TimestampingMode::Hardware {
+                    interface: Some("eth0".parse().unwrap())
+                }
+            );
         }
 
         let test: TestConfig = toml::from_str(
@@ -438,6 +704,68 @@ mod tests {
         if let PeerConfig::Nts(config) = test.peer {
             assert_eq!(config.ke_addr.to_string(), "example.com:4460");
         }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Symmetric"
+            key_id = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(peer_addr(&test.peer), "example.com:123");
+        assert!(matches!(
+            test.peer,
+            PeerConfig::Symmetric(SymmetricPeerConfig {
+                key_id: Some(1),
+                ..
+            })
+        ));
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Symmetric"
+            max_peers = 4
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "224.0.1.1"
+            mode = "Broadcast"
+            key_id = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(peer_addr(&test.peer), "224.0.1.1:123");
+        assert!(matches!(
+            test.peer,
+            PeerConfig::Broadcast(BroadcastPeerConfig { key_id: 1, .. })
+        ));
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "224.0.1.1"
+            mode = "Broadcast"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            ke_addr = "example.com"
+            mode = "NtsServer"
+            timestamping = "hardware"
+            "#,
+        );
+        assert!(test.is_err());
     }
 
     #[test]
@@ -478,6 +806,22 @@ mod tests {
         assert!(matches!(peer, PeerConfig::Standard(_)));
     }
 
+    #[test]
+    fn test_peer_has_address() {
+        let peer = PeerConfig::try_from("example.com").unwrap();
+        assert!(peer.has_address("example.com"));
+        assert!(peer.has_address("example.com:123"));
+        assert!(!peer.has_address("example.com:4460"));
+        assert!(!peer.has_address("example.org"));
+
+        let peer = PeerConfig::Nts(NtsPeerConfig {
+            ke_addr: NormalizedAddress::new_unchecked("example.com", 4460),
+            certificates: Arc::from([]),
+        });
+        assert!(peer.has_address("example.com"));
+        assert!(!peer.has_address("example.com:123"));
+    }
+
     #[test]
     fn test_normalize_addr() {
         let addr = NormalizedAddress::from_string_ntp("[::1]:456".into()).unwrap();
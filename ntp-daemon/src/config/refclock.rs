@@ -0,0 +1,202 @@
+use std::{fmt, path::PathBuf};
+
+use ntp_proto::ReferenceId;
+use serde::{de, Deserialize, Deserializer};
+
+fn deserialize_reference_id<'de, D>(deserializer: D) -> Result<ReferenceId, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name: String = Deserialize::deserialize(deserializer)?;
+
+    if name.len() > 4 || !name.is_ascii() {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Str(&name),
+            &"at most 4 ascii characters",
+        ));
+    }
+
+    let mut bytes = [0; 4];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+
+    Ok(ReferenceId::from_bytes(bytes))
+}
+
+fn default_shm_reference_id() -> ReferenceId {
+    ReferenceId::from_bytes(*b"SHM\0")
+}
+
+fn default_pps_reference_id() -> ReferenceId {
+    ReferenceId::from_bytes(*b"PPS\0")
+}
+
+fn default_pps_precision() -> i8 {
+    -20
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ShmRefClockConfig {
+    /// Number of the shared memory segment, the key of the segment is `0x4e545030 + unit`
+    pub unit: u32,
+    /// The reference id that we report to our clients while synchronized to this clock
+    #[serde(
+        default = "default_shm_reference_id",
+        deserialize_with = "deserialize_reference_id"
+    )]
+    pub reference_id: ReferenceId,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PpsRefClockConfig {
+    /// The pulse per second device, such as `/dev/pps0`
+    pub path: PathBuf,
+    /// Precision of the pulses, as a power of two in seconds
+    #[serde(default = "default_pps_precision")]
+    pub precision: i8,
+    /// Let the kernel discipline the clock directly from the pulses, besides using them as a
+    /// source for the clock algorithm
+    #[serde(default)]
+    pub kernel_discipline: bool,
+    /// The reference id that we report to our clients while synchronized to this clock
+    #[serde(
+        default = "default_pps_reference_id",
+        deserialize_with = "deserialize_reference_id"
+    )]
+    pub reference_id: ReferenceId,
+}
+
+/// A local reference clock, such as a GPS receiver, from which the time is read directly
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum RefClockConfig {
+    /// A shared memory segment in the format of the SHM driver of ntpd, as written by
+    /// gpsd and ptp4l
+    Shm(ShmRefClockConfig),
+    /// A pulse per second device, read through the interface of RFC 2783. The pulses only
+    /// mark the start of a second, so another source is needed to number the seconds.
+    Pps(PpsRefClockConfig),
+}
+
+impl RefClockConfig {
+    pub fn reference_id(&self) -> ReferenceId {
+        match self {
+            RefClockConfig::Shm(config) => config.reference_id,
+            RefClockConfig::Pps(config) => config.reference_id,
+        }
+    }
+}
+
+impl fmt::Display for RefClockConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefClockConfig::Shm(config) => write!(f, "SHM({})", config.unit),
+            RefClockConfig::Pps(config) => write!(f, "PPS({})", config.path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        refclock: RefClockConfig,
+    }
+
+    #[test]
+    fn test_deserialize_shm() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            type = "shm"
+            unit = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock,
+            RefClockConfig::Shm(ShmRefClockConfig {
+                unit: 2,
+                reference_id: ReferenceId::from_bytes(*b"SHM\0"),
+            })
+        );
+        assert_eq!(test.refclock.to_string(), "SHM(2)");
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            type = "shm"
+            unit = 0
+            reference-id = "GPS"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock.reference_id(),
+            ReferenceId::from_bytes(*b"GPS\0")
+        );
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [refclock]
+            type = "shm"
+            unit = 0
+            reference-id = "TOOLONG"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [refclock]
+            type = "shm"
+            unit = 0
+            mode = 1
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_pps() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            type = "pps"
+            path = "/dev/pps0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock,
+            RefClockConfig::Pps(PpsRefClockConfig {
+                path: PathBuf::from("/dev/pps0"),
+                precision: -20,
+                kernel_discipline: false,
+                reference_id: ReferenceId::from_bytes(*b"PPS\0"),
+            })
+        );
+        assert_eq!(test.refclock.to_string(), "PPS(/dev/pps0)");
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            type = "pps"
+            path = "/dev/pps1"
+            precision = -24
+            kernel-discipline = true
+            "#,
+        )
+        .unwrap();
+        match test.refclock {
+            RefClockConfig::Pps(config) => {
+                assert_eq!(config.precision, -24);
+                assert!(config.kernel_discipline);
+            }
+            config => panic!("expected a pps config, got {config:?}"),
+        }
+    }
+}
@@ -1,16 +1,21 @@
 use std::{
     fmt,
     net::{AddrParseError, SocketAddr},
+    path::PathBuf,
     str::FromStr,
     time::Duration,
 };
 
+use ntp_proto::{LeapSmearConfig, NtpDuration};
 use serde::{
     de::{self, MapAccess, Visitor},
     Deserialize, Deserializer,
 };
 
-use crate::{config::subnet::IpSubnet, ipfilter::IpFilter};
+use crate::{
+    config::{deserialize_interface, subnet::IpSubnet, TimestampingMode},
+    ipfilter::IpFilter,
+};
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize)]
 pub enum FilterAction {
@@ -27,8 +32,19 @@ pub struct ServerConfig {
     pub allowlist_action: FilterAction,
     pub rate_limiting_cache_size: usize,
     pub rate_limiting_cutoff: Duration,
+    /// Broadcast or multicast address to which the server periodically sends its time
+    pub broadcast_addr: Option<SocketAddr>,
+    pub broadcast_interval: Duration,
+    /// The id of the key in the keyfile used to authenticate broadcasts, required when
+    /// broadcasting
+    pub broadcast_key_id: Option<u32>,
+    pub timestamping: TimestampingMode,
+    /// Smear leap seconds in the time served to clients
+    pub leap_smear: Option<LeapSmearConfig>,
 }
 
+const DEFAULT_BROADCAST_INTERVAL: Duration = Duration::from_secs(64);
+
 impl ServerConfig {
     pub(crate) fn try_from_str(value: &str) -> Result<Self, <Self as TryFrom<&str>>::Error> {
         Self::try_from(value)
@@ -47,6 +63,11 @@ impl TryFrom<&str> for ServerConfig {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cache_size: Default::default(),
             rate_limiting_cutoff: Default::default(),
+            broadcast_addr: None,
+            broadcast_interval: DEFAULT_BROADCAST_INTERVAL,
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::default(),
+            leap_smear: None,
         })
     }
 }
@@ -79,6 +100,12 @@ impl<'de> Deserialize<'de> for ServerConfig {
                 let mut allowlist_action = None;
                 let mut denylist = None;
                 let mut denylist_action = None;
+                let mut broadcast_addr = None;
+                let mut broadcast_interval = None;
+                let mut broadcast_key_id = None;
+                let mut timestamping = None;
+                let mut interface = None;
+                let mut leap_smear = None;
                 while let Some(key) = map.next_key::<&str>()? {
                     match key {
                         "addr" => {
@@ -127,6 +154,63 @@ impl<'de> Deserialize<'de> for ServerConfig {
 
                             rate_limiting_cutoff = Some(Duration::from_millis(map.next_value()?));
                         }
+                        "broadcast-addr" => {
+                            if broadcast_addr.is_some() {
+                                return Err(de::Error::duplicate_field("broadcast-addr"));
+                            }
+
+                            broadcast_addr = Some(map.next_value::<SocketAddr>()?);
+                        }
+                        "broadcast-interval-secs" => {
+                            if broadcast_interval.is_some() {
+                                return Err(de::Error::duplicate_field("broadcast-interval-secs"));
+                            }
+
+                            let secs: u64 = map.next_value()?;
+                            if secs == 0 {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Unsigned(secs),
+                                    &"a positive number of seconds",
+                                ));
+                            }
+                            broadcast_interval = Some(Duration::from_secs(secs));
+                        }
+                        "broadcast-key-id" => {
+                            if broadcast_key_id.is_some() {
+                                return Err(de::Error::duplicate_field("broadcast-key-id"));
+                            }
+
+                            broadcast_key_id = Some(map.next_value::<u32>()?);
+                        }
+                        "timestamping" => {
+                            if timestamping.is_some() {
+                                return Err(de::Error::duplicate_field("timestamping"));
+                            }
+
+                            timestamping = Some(map.next_value::<TimestampingMode>()?);
+                        }
+                        "interface" => {
+                            if interface.is_some() {
+                                return Err(de::Error::duplicate_field("interface"));
+                            }
+
+                            let raw: String = map.next_value()?;
+                            interface = Some(deserialize_interface(&raw)?);
+                        }
+                        "leap-smear" => {
+                            if leap_smear.is_some() {
+                                return Err(de::Error::duplicate_field("leap-smear"));
+                            }
+
+                            let config: LeapSmearConfig = map.next_value()?;
+                            if config.window <= NtpDuration::ZERO {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Float(config.window.to_seconds()),
+                                    &"a positive number of seconds",
+                                ));
+                            }
+                            leap_smear = Some(config);
+                        }
                         _ => {
                             return Err(de::Error::unknown_field(
                                 key,
@@ -138,6 +222,12 @@ impl<'de> Deserialize<'de> for ServerConfig {
                                     "denylist-action",
                                     "rate-limiting-cache-size",
                                     "rate-limiting-cutoff-ms",
+                                    "broadcast-addr",
+                                    "broadcast-interval-secs",
+                                    "broadcast-key-id",
+                                    "timestamping",
+                                    "interface",
+                                    "leap-smear",
                                 ],
                             ));
                         }
@@ -164,6 +254,12 @@ impl<'de> Deserialize<'de> for ServerConfig {
 
                 let rate_limiting_cache_size = rate_limiting_cache_size.unwrap_or_default();
                 let rate_limiting_cutoff = rate_limiting_cutoff.unwrap_or_default();
+                let broadcast_interval = broadcast_interval.unwrap_or(DEFAULT_BROADCAST_INTERVAL);
+                if broadcast_addr.is_some() && broadcast_key_id.is_none() {
+                    // clients only accept authenticated broadcasts
+                    return Err(de::Error::missing_field("broadcast-key-id"));
+                }
+                let timestamping = TimestampingMode::with_interface(timestamping, interface)?;
 
                 Ok(ServerConfig {
                     addr,
@@ -173,6 +269,11 @@ impl<'de> Deserialize<'de> for ServerConfig {
                     denylist_action,
                     rate_limiting_cache_size,
                     rate_limiting_cutoff,
+                    broadcast_addr,
+                    broadcast_interval,
+                    broadcast_key_id,
+                    timestamping,
+                    leap_smear,
                 })
             }
         }
@@ -181,8 +282,74 @@ impl<'de> Deserialize<'de> for ServerConfig {
     }
 }
 
+const fn default_key_exchange_timeout_ms() -> u64 {
+    1000
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NtsKeConfig {
+    pub addr: SocketAddr,
+    pub cert_chain_path: PathBuf,
+    pub private_key_path: PathBuf,
+    #[serde(default = "default_key_exchange_timeout_ms")]
+    pub key_exchange_timeout_ms: u64,
+}
+
+fn deserialize_key_rotation_interval_secs<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs: u64 = Deserialize::deserialize(deserializer)?;
+
+    // the keys would be rotated continuously
+    if secs == 0 {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(secs),
+            &"a positive number of seconds",
+        ));
+    }
+
+    Ok(secs)
+}
+
+const fn default_key_rotation_interval_secs() -> u64 {
+    // one day
+    86400
+}
+
+const fn default_key_retention_count() -> usize {
+    7
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct KeysetConfig {
+    #[serde(
+        default = "default_key_rotation_interval_secs",
+        deserialize_with = "deserialize_key_rotation_interval_secs"
+    )]
+    pub key_rotation_interval_secs: u64,
+    #[serde(default = "default_key_retention_count")]
+    pub key_retention_count: usize,
+    #[serde(default)]
+    pub key_storage_path: Option<PathBuf>,
+}
+
+impl Default for KeysetConfig {
+    fn default() -> Self {
+        Self {
+            key_rotation_interval_secs: default_key_rotation_interval_secs(),
+            key_retention_count: default_key_retention_count(),
+            key_storage_path: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use ntp_proto::LeapSmearShape;
+
     use super::*;
 
     #[test]
@@ -216,5 +383,188 @@ mod tests {
             test.server.rate_limiting_cutoff,
             Duration::from_millis(1000)
         );
+        assert_eq!(test.server.broadcast_addr, None);
+        assert_eq!(test.server.timestamping, TimestampingMode::Software);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "192.168.1.1:123"
+            timestamping = "hardware"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.server.timestamping,
+            TimestampingMode::Hardware { interface: None }
+        );
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            timestamping = "hardware"
+            interface = "eth0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.server.timestamping,
+            TimestampingMode::Hardware {
+                interface: Some("eth0".parse().unwrap())
+            }
+        );
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            interface = "eth0"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            broadcast-addr = "192.168.1.255:123"
+            broadcast-interval-secs = 16
+            broadcast-key-id = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.server.broadcast_addr,
+            Some("192.168.1.255:123".parse().unwrap())
+        );
+        assert_eq!(test.server.broadcast_interval, Duration::from_secs(16));
+        assert_eq!(test.server.broadcast_key_id, Some(1));
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            broadcast-addr = "192.168.1.255:123"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            broadcast-interval-secs = 0
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            leap-smear = { window = 3600, shape = "cosine" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.server.leap_smear,
+            Some(LeapSmearConfig {
+                window: NtpDuration::from_seconds(3600.0),
+                shape: LeapSmearShape::Cosine,
+            })
+        );
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            leap-smear = {}
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.server.leap_smear, Some(LeapSmearConfig::default()));
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            leap-smear = { window = 0 }
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_nts_ke() {
+        #[derive(Deserialize, Debug)]
+        #[serde(rename_all = "kebab-case")]
+        struct TestConfig {
+            nts_ke_server: NtsKeConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [nts-ke-server]
+            addr = "0.0.0.0:4460"
+            cert-chain-path = "/foo/bar/fullchain.pem"
+            private-key-path = "/foo/bar/key.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.nts_ke_server.addr, "0.0.0.0:4460".parse().unwrap());
+        assert_eq!(
+            test.nts_ke_server.cert_chain_path,
+            PathBuf::from("/foo/bar/fullchain.pem")
+        );
+        assert_eq!(
+            test.nts_ke_server.private_key_path,
+            PathBuf::from("/foo/bar/key.pem")
+        );
+        assert_eq!(test.nts_ke_server.key_exchange_timeout_ms, 1000);
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [nts-ke-server]
+            addr = "0.0.0.0:4460"
+            cert-chain-path = "/foo/bar/fullchain.pem"
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_keyset() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            keyset: KeysetConfig,
+        }
+
+        let test: TestConfig = toml::from_str("[keyset]").unwrap();
+        assert_eq!(test.keyset, KeysetConfig::default());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [keyset]
+            key-rotation-interval-secs = 3600
+            key-retention-count = 24
+            key-storage-path = "/var/lib/ntpd-rs/keyset"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.keyset.key_rotation_interval_secs, 3600);
+        assert_eq!(test.keyset.key_retention_count, 24);
+        assert_eq!(
+            test.keyset.key_storage_path,
+            Some(PathBuf::from("/var/lib/ntpd-rs/keyset"))
+        );
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [keyset]
+            key-rotation-interval-secs = 0
+            "#,
+        );
+        assert!(test.is_err());
     }
 }
@@ -0,0 +1,98 @@
+use std::path::Path;
+
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+/// The kernel does not accept frequency offsets beyond 500 ppm
+const MAX_FREQUENCY_PPM: f64 = 500.0;
+
+/// Load the clock frequency (in seconds per second) from a drift file. Like with ntpd, the
+/// file contains the frequency offset of the clock in parts per million.
+///
+/// A missing or invalid file is not an error: the frequency is then measured anew.
+pub(crate) async fn load(path: &Path) -> Option<f64> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            info!(?path, "no drift file found, measuring the clock frequency");
+            return None;
+        }
+        Err(error) => {
+            warn!(?error, ?path, "could not read drift file");
+            return None;
+        }
+    };
+
+    match parse(&data) {
+        Some(frequency) => {
+            info!(
+                ?path,
+                ppm = frequency * 1e6,
+                "loaded clock frequency from drift file"
+            );
+            Some(frequency)
+        }
+        None => {
+            warn!(?path, "drift file does not contain a valid frequency");
+            None
+        }
+    }
+}
+
+fn parse(data: &str) -> Option<f64> {
+    let ppm: f64 = data.trim().parse().ok()?;
+
+    if ppm.is_finite() && ppm.abs() <= MAX_FREQUENCY_PPM {
+        Some(ppm * 1e-6)
+    } else {
+        None
+    }
+}
+
+/// Store the clock frequency (in seconds per second) to a drift file
+pub(crate) async fn store(path: &Path, frequency: f64) -> std::io::Result<()> {
+    let data = format!("{:.3}\n", frequency * 1e6);
+
+    // write to a temporary file first, so a crash never leaves a partially written file behind
+    let tmp_path = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(data.as_bytes()).await?;
+    file.sync_all().await?;
+
+    tokio::fs::rename(&tmp_path, path).await
+}
+
+pub(crate) async fn store_or_warn(path: &Path, frequency: f64) {
+    if let Err(error) = store(path, frequency).await {
+        warn!(?error, ?path, "could not store drift file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("12.345\n"), Some(12.345e-6));
+        assert_eq!(parse("-3"), Some(-3e-6));
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("fast"), None);
+        assert_eq!(parse("NaN"), None);
+        assert_eq!(parse("501"), None);
+    }
+
+    #[tokio::test]
+    async fn test_store_load() {
+        let path = std::env::temp_dir().join("ntp-test-drift-1");
+
+        store(&path, -17.25e-6).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "-17.250\n");
+
+        let frequency = load(&path).await.unwrap();
+        assert!((frequency - -17.25e-6).abs() < 1e-12);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(load(&path).await, None);
+    }
+}
@@ -1,14 +1,28 @@
 use std::{
     future::Future,
     io::{BufRead, BufReader, IoSlice, Read, Write},
+    net::SocketAddr,
+    ops::ControlFlow,
     path::Path,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use ntp_proto::{KeyExchangeClient, KeyExchangeError, KeyExchangeResult};
-use rustls::Certificate;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use ntp_proto::{
+    KeyExchangeClient, KeyExchangeError, KeyExchangeResult, KeyExchangeServer, KeySet,
+};
+use rustls::{Certificate, PrivateKey};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    sync::watch,
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::config::NtsKeConfig;
 
 pub(crate) async fn key_exchange(
     server_name: String,
@@ -36,6 +50,54 @@ pub(crate) async fn key_exchange(
     BoundKeyExchangeClient::new(socket, server_name, config)?.await
 }
 
+// adapter between AsyncWrite and std::io::Write
+struct Writer<'a, 'b, T> {
+    io: &'a mut T,
+    cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, T: AsyncWrite + Unpin> Write for Writer<'a, 'b, T> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match Pin::<&mut T>::new(self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        match Pin::<&mut T>::new(self.io).poll_write_vectored(self.cx, bufs) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match Pin::<&mut T>::new(self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+// adapter between AsyncRead and std::io::Read
+struct Reader<'a, 'b, T> {
+    io: &'a mut T,
+    cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, T: AsyncRead + Unpin> Read for Reader<'a, 'b, T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::<&mut T>::new(self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
 pub(crate) struct BoundKeyExchangeClient<IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
@@ -76,38 +138,7 @@ impl<IO> BoundKeyExchangeClientData<IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    // adapter between AsyncWrite and std::io::Write
     fn do_write(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<usize>> {
-        struct Writer<'a, 'b, T> {
-            io: &'a mut T,
-            cx: &'a mut Context<'b>,
-        }
-
-        impl<'a, 'b, T: AsyncWrite + Unpin> Write for Writer<'a, 'b, T> {
-            #[inline]
-            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-                match Pin::<&mut T>::new(self.io).poll_write(self.cx, buf) {
-                    Poll::Ready(result) => result,
-                    Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
-                }
-            }
-
-            #[inline]
-            fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
-                match Pin::<&mut T>::new(self.io).poll_write_vectored(self.cx, bufs) {
-                    Poll::Ready(result) => result,
-                    Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
-                }
-            }
-
-            fn flush(&mut self) -> std::io::Result<()> {
-                match Pin::<&mut T>::new(self.io).poll_flush(self.cx) {
-                    Poll::Ready(result) => result,
-                    Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
-                }
-            }
-        }
-
         let mut writer = Writer {
             io: &mut self.io,
             cx,
@@ -119,24 +150,7 @@ where
         }
     }
 
-    // adapter between AsyncRead and std::io::Read
     fn do_read(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<usize>> {
-        struct Reader<'a, 'b, T> {
-            io: &'a mut T,
-            cx: &'a mut Context<'b>,
-        }
-
-        impl<'a, 'b, T: AsyncRead + Unpin> Read for Reader<'a, 'b, T> {
-            fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-                let mut buf = ReadBuf::new(buf);
-                match Pin::<&mut T>::new(self.io).poll_read(self.cx, &mut buf) {
-                    Poll::Ready(Ok(())) => Ok(buf.filled().len()),
-                    Poll::Ready(Err(e)) => Err(e),
-                    Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
-                }
-            }
-        }
-
         let mut reader = Reader {
             io: &mut self.io,
             cx,
@@ -216,6 +230,278 @@ where
     }
 }
 
+pub(crate) async fn spawn(
+    nts_ke_config: NtsKeConfig,
+    keyset: watch::Receiver<Arc<KeySet>>,
+    shutdown_receiver: watch::Receiver<bool>,
+) -> std::io::Result<JoinHandle<std::io::Result<()>>> {
+    // load the certificates and bind the listener before spawning, so configuration errors
+    // are reported on startup and the server accepts connections once this returns
+    let tls_config = tls_server_config(&nts_ke_config)?;
+    let timeout = Duration::from_millis(nts_ke_config.key_exchange_timeout_ms);
+    let listener = TcpListener::bind(nts_ke_config.addr).await?;
+
+    Ok(tokio::spawn(async move {
+        let result = key_exchange_server(
+            keyset,
+            listener,
+            nts_ke_config.addr,
+            tls_config,
+            timeout,
+            shutdown_receiver,
+        )
+        .await;
+
+        if let Err(ref e) = result {
+            error!(error = ?e, "Abnormal termination of NTS KE server");
+        }
+
+        result
+    }))
+}
+
+fn tls_server_config(nts_ke_config: &NtsKeConfig) -> std::io::Result<rustls::ServerConfig> {
+    let cert_chain_path = &nts_ke_config.cert_chain_path;
+    let cert_chain = certificates_from_file(cert_chain_path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("error reading certificate chain {cert_chain_path:?}: {e}"),
+        )
+    })?;
+
+    let private_key_path = &nts_ke_config.private_key_path;
+    let private_key = private_key_from_file(private_key_path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("error reading private key {private_key_path:?}: {e}"),
+        )
+    })?;
+
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, private_key)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+async fn key_exchange_server(
+    keyset: watch::Receiver<Arc<KeySet>>,
+    listener: TcpListener,
+    address: SocketAddr,
+    config: rustls::ServerConfig,
+    timeout: Duration,
+    mut shutdown_receiver: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let config = Arc::new(config);
+
+    info!(?address, "NTS KE server listening");
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_receiver.changed(), if shutdown_receiver.has_changed().is_ok() => {
+                // key exchanges in progress are finished by their own tasks
+                info!(?address, "NTS KE server stopped");
+                return Ok(());
+            }
+        };
+
+        let (stream, peer_address) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // e.g. running out of file descriptors; not a reason to stop the server
+                warn!(error = ?e, "could not accept NTS KE connection");
+                continue;
+            }
+        };
+
+        let config = config.clone();
+        let keyset = keyset.borrow().clone();
+
+        tokio::spawn(async move {
+            let result =
+                tokio::time::timeout(timeout, BoundKeyExchangeServer::new(stream, config, keyset))
+                    .await;
+
+            match result {
+                Err(_) => debug!(?peer_address, "NTS KE timed out"),
+                Ok(Err(e)) => debug!(?peer_address, error = ?e, "NTS KE failed"),
+                Ok(Ok(())) => debug!(?peer_address, "NTS KE completed"),
+            }
+        });
+    }
+}
+
+pub(crate) struct BoundKeyExchangeServer<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    io: IO,
+    state: BoundKeyExchangeServerState,
+    need_flush: bool,
+}
+
+enum BoundKeyExchangeServerState {
+    /// Busy handling the client's request
+    Active(Box<KeyExchangeServer>),
+    /// The response is queued, write out any remaining data
+    Closing(Box<rustls::ServerConnection>),
+    /// Only used while transitioning between the states above
+    Invalid,
+}
+
+impl<IO> BoundKeyExchangeServer<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(io: IO, config: Arc<rustls::ServerConfig>, keyset: Arc<KeySet>) -> Self {
+        let state = match KeyExchangeServer::new(config, keyset) {
+            Ok(server) => BoundKeyExchangeServerState::Active(Box::new(server)),
+            Err(e) => {
+                // only fails on invalid tls configuration, which we validated on startup
+                unreachable!("could not create key exchange server: {e}")
+            }
+        };
+
+        Self {
+            io,
+            state,
+            need_flush: false,
+        }
+    }
+
+    fn wants_write(&mut self) -> bool {
+        match &mut self.state {
+            BoundKeyExchangeServerState::Active(server) => server.wants_write(),
+            BoundKeyExchangeServerState::Closing(connection) => connection.wants_write(),
+            BoundKeyExchangeServerState::Invalid => false,
+        }
+    }
+
+    fn do_write(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<usize>> {
+        let mut writer = Writer {
+            io: &mut self.io,
+            cx,
+        };
+
+        let result = match &mut self.state {
+            BoundKeyExchangeServerState::Active(server) => server.write_socket(&mut writer),
+            BoundKeyExchangeServerState::Closing(connection) => connection.write_tls(&mut writer),
+            BoundKeyExchangeServerState::Invalid => Ok(0),
+        };
+
+        match result {
+            Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn do_read(
+        server: &mut KeyExchangeServer,
+        io: &mut IO,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<usize>> {
+        let mut reader = Reader { io, cx };
+
+        match server.read_socket(&mut reader) {
+            Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl<IO> Future for BoundKeyExchangeServer<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    type Output = Result<(), KeyExchangeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let mut write_blocks = false;
+        let mut read_blocks = false;
+
+        loop {
+            while !write_blocks && this.wants_write() {
+                match this.do_write(cx) {
+                    Poll::Ready(Ok(_)) => {
+                        this.need_flush = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                    Poll::Pending => {
+                        write_blocks = true;
+                        break;
+                    }
+                }
+            }
+
+            if !write_blocks && this.need_flush {
+                match Pin::new(&mut this.io).poll_flush(cx) {
+                    Poll::Ready(Ok(())) => {
+                        this.need_flush = false;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                    Poll::Pending => {
+                        write_blocks = true;
+                    }
+                }
+            }
+
+            match std::mem::replace(&mut this.state, BoundKeyExchangeServerState::Invalid) {
+                BoundKeyExchangeServerState::Active(mut server) => {
+                    loop {
+                        if read_blocks || !server.wants_read() {
+                            this.state = BoundKeyExchangeServerState::Active(server);
+                            break;
+                        }
+
+                        match Self::do_read(&mut server, &mut this.io, cx) {
+                            Poll::Ready(Ok(_)) => match (*server).progress() {
+                                ControlFlow::Continue(next) => server = Box::new(next),
+                                ControlFlow::Break(Ok(connection)) => {
+                                    this.state =
+                                        BoundKeyExchangeServerState::Closing(Box::new(connection));
+                                    break;
+                                }
+                                ControlFlow::Break(Err(e)) => return Poll::Ready(Err(e)),
+                            },
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                            Poll::Pending => {
+                                read_blocks = true;
+                                this.state = BoundKeyExchangeServerState::Active(server);
+                                break;
+                            }
+                        }
+                    }
+
+                    if let BoundKeyExchangeServerState::Active(server) = &mut this.state {
+                        if (write_blocks || !server.wants_write())
+                            && (read_blocks || !server.wants_read())
+                        {
+                            return Poll::Pending;
+                        }
+                    }
+                }
+                BoundKeyExchangeServerState::Closing(connection) => {
+                    this.state = BoundKeyExchangeServerState::Closing(connection);
+
+                    if !this.wants_write() && !this.need_flush {
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    if write_blocks {
+                        return Poll::Pending;
+                    }
+                }
+                BoundKeyExchangeServerState::Invalid => {
+                    unreachable!("key exchange server polled in invalid state")
+                }
+            }
+        }
+    }
+}
+
 pub(crate) fn certificates_from_file(path: &Path) -> std::io::Result<Vec<Certificate>> {
     let file = std::fs::File::open(path)?;
     let reader = BufReader::new(file);
@@ -223,6 +509,31 @@ pub(crate) fn certificates_from_file(path: &Path) -> std::io::Result<Vec<Certifi
     certificates_from_bufread(reader)
 }
 
+fn private_key_from_file(path: &Path) -> std::io::Result<PrivateKey> {
+    let file = std::fs::File::open(path)?;
+    let reader = BufReader::new(file);
+
+    private_key_from_bufread(reader)
+}
+
+fn private_key_from_bufread(mut reader: impl BufRead) -> std::io::Result<PrivateKey> {
+    use rustls_pemfile::{read_one, Item};
+
+    for item in std::iter::from_fn(|| read_one(&mut reader).transpose()) {
+        match item? {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "no private key found",
+    ))
+}
+
 fn certificates_from_bufread(mut reader: impl BufRead) -> std::io::Result<Vec<Certificate>> {
     use rustls_pemfile::{read_one, Item};
 
@@ -239,7 +550,12 @@ fn certificates_from_bufread(mut reader: impl BufRead) -> std::io::Result<Vec<Ce
 
 #[cfg(test)]
 mod tests {
-    use super::certificates_from_bufread;
+    use std::path::PathBuf;
+
+    use aead::Aead;
+    use tokio::net::TcpStream;
+
+    use super::*;
 
     #[test]
     fn nos_nl_pem() {
@@ -256,4 +572,111 @@ mod tests {
 
         assert_eq!(certificates.len(), 3);
     }
+
+    #[test]
+    fn end_key_pem() {
+        let input = include_bytes!("../testdata/certificates/end.key");
+        assert!(private_key_from_bufread(input.as_slice()).is_ok());
+
+        let input = include_bytes!("../testdata/certificates/end.pem");
+        assert!(private_key_from_bufread(input.as_slice()).is_err());
+    }
+
+    fn test_nts_ke_config(addr: &str) -> NtsKeConfig {
+        let testdata = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/certificates");
+
+        NtsKeConfig {
+            addr: addr.parse().unwrap(),
+            cert_chain_path: testdata.join("end.fullchain.pem"),
+            private_key_path: testdata.join("end.key"),
+            key_exchange_timeout_ms: 1000,
+        }
+    }
+
+    fn test_client_config() -> rustls::ClientConfig {
+        let ca = include_bytes!("../testdata/certificates/testca.pem");
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in certificates_from_bufread(ca.as_slice()).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    #[tokio::test]
+    async fn key_exchange_roundtrip() {
+        let keyset = Arc::new(KeySet::new());
+        let (_sender, receiver) = watch::channel(keyset.clone());
+        let (_shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let config = test_nts_ke_config("127.0.0.1:5431");
+        let _server = spawn(config, receiver, shutdown_receiver).await.unwrap();
+
+        let stream = TcpStream::connect("127.0.0.1:5431").await.unwrap();
+        let result =
+            BoundKeyExchangeClient::new(stream, "localhost".to_string(), test_client_config())
+                .unwrap()
+                .await
+                .unwrap();
+
+        assert_eq!(result.remote, "localhost");
+        assert_eq!(result.port, 123);
+
+        let mut nts = result.nts;
+        let cookie = nts.get_cookie().unwrap();
+        let decoded = keyset.decode_cookie(&cookie).unwrap();
+
+        // the keys in the cookie must match the keys the client derived
+        let (c2s, s2c) = nts.get_keys();
+        let nonce = Default::default();
+
+        let ciphertext = c2s.encrypt(&nonce, b"client".as_slice()).unwrap();
+        let plaintext = decoded.c2s().decrypt(&nonce, ciphertext.as_slice());
+        assert_eq!(plaintext.unwrap(), b"client");
+
+        let ciphertext = decoded.s2c().encrypt(&nonce, b"server".as_slice()).unwrap();
+        let plaintext = s2c.decrypt(&nonce, ciphertext.as_slice());
+        assert_eq!(plaintext.unwrap(), b"server");
+    }
+
+    #[tokio::test]
+    async fn key_exchange_invalid_certificate_path() {
+        let (_, receiver) = watch::channel(Arc::new(KeySet::new()));
+        let (_, shutdown_receiver) = watch::channel(false);
+        let mut config = test_nts_ke_config("127.0.0.1:5432");
+        config.private_key_path = PathBuf::from("/does/not/exist.key");
+
+        assert!(spawn(config, receiver, shutdown_receiver).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn key_exchange_address_in_use() {
+        let (_, receiver) = watch::channel(Arc::new(KeySet::new()));
+        let (_, shutdown_receiver) = watch::channel(false);
+        let config = test_nts_ke_config("127.0.0.1:5434");
+
+        // the address is bound before spawning, so the error is reported right away
+        let _listener = TcpListener::bind("127.0.0.1:5434").await.unwrap();
+        assert!(spawn(config, receiver, shutdown_receiver).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn key_exchange_shutdown() {
+        let (_sender, receiver) = watch::channel(Arc::new(KeySet::new()));
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let config = test_nts_ke_config("127.0.0.1:5433");
+        let server = spawn(config, receiver, shutdown_receiver).await.unwrap();
+        assert!(TcpStream::connect("127.0.0.1:5433").await.is_ok());
+
+        shutdown_sender.send_replace(true);
+        let result = tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap();
+        assert!(result.unwrap().is_ok());
+
+        // the server no longer listens
+        assert!(TcpStream::connect("127.0.0.1:5433").await.is_err());
+    }
 }
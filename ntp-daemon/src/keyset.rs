@@ -0,0 +1,308 @@
+use std::{
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use ntp_proto::{KeySet, KeySetProvider};
+use tokio::{io::AsyncWriteExt, sync::watch, task::JoinHandle};
+use tracing::{info, warn};
+
+use crate::config::KeysetConfig;
+
+/// Spawn a task that periodically rotates the master keys used to encrypt NTS cookies.
+/// The current key set is published on the returned channel, which is shared by the
+/// key exchange servers and the NTP servers.
+///
+/// When a storage path is configured, the key set is loaded from and stored to that
+/// file. Servers that share the file use the same keys. The key set is stored again when
+/// the task is shut down, after which the returned handle completes.
+pub(crate) async fn spawn(
+    config: KeysetConfig,
+    mut shutdown_receiver: watch::Receiver<bool>,
+) -> (watch::Receiver<Arc<KeySet>>, JoinHandle<()>) {
+    let history = config.key_retention_count;
+    let interval = Duration::from_secs(config.key_rotation_interval_secs);
+
+    let mut provider = match &config.key_storage_path {
+        Some(path) => {
+            let _lock = lock_or_warn(path).await;
+            match load(path, history).await {
+                Ok(provider) => {
+                    info!(?path, "loaded NTS keyset");
+                    provider
+                }
+                Err(error) => {
+                    if error.kind() != std::io::ErrorKind::NotFound {
+                        warn!(
+                            ?error,
+                            ?path,
+                            "could not load NTS keyset, generating a new one"
+                        );
+                    }
+
+                    let provider = KeySetProvider::new(history);
+                    store_or_warn(path, &provider).await;
+                    provider
+                }
+            }
+        }
+        None => KeySetProvider::new(history),
+    };
+
+    let (sender, receiver) = watch::channel(provider.get());
+
+    let handle = tokio::spawn(async move {
+        loop {
+            let next_rotation = provider.rotated_at() + interval;
+            let wait = next_rotation
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+
+            // never wait longer than the interval, even when the clock jumps back
+            tokio::select! {
+                _ = tokio::time::sleep(wait.min(interval)) => {}
+                _ = shutdown_receiver.changed(), if shutdown_receiver.has_changed().is_ok() => {
+                    if let Some(path) = &config.key_storage_path {
+                        store_on_shutdown(path, history, &provider).await;
+                    }
+                    break;
+                }
+            }
+
+            // servers sharing the file hold its lock until the rotated keys are stored,
+            // such that only the first of them rotates the keys and the others load them
+            let lock = match &config.key_storage_path {
+                Some(path) => {
+                    let lock = lock_or_warn(path).await;
+                    match load(path, history).await {
+                        Ok(stored) => provider = stored,
+                        Err(error) => warn!(?error, ?path, "could not load NTS keyset"),
+                    }
+                    lock
+                }
+                None => None,
+            };
+
+            if provider.rotated_at() + interval <= SystemTime::now() {
+                provider.rotate();
+                info!("rotated NTS keyset");
+
+                if let Some(path) = &config.key_storage_path {
+                    store_or_warn(path, &provider).await;
+                }
+            }
+
+            drop(lock);
+
+            if sender.send(provider.get()).is_err() {
+                // nobody is using the keys anymore
+                break;
+            }
+        }
+    });
+
+    (receiver, handle)
+}
+
+async fn load(path: &Path, history: usize) -> std::io::Result<KeySetProvider> {
+    let data = tokio::fs::read(path).await?;
+    KeySetProvider::load(&mut data.as_slice(), history)
+}
+
+async fn store(path: &Path, provider: &KeySetProvider) -> std::io::Result<()> {
+    let mut data = vec![];
+    provider.store(&mut data)?;
+
+    // write to a temporary file first, so other servers never read a partially written keyset
+    let tmp_path = path.with_extension("tmp");
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+
+    tokio::fs::rename(&tmp_path, path).await
+}
+
+/// Take an exclusive lock on the lock file next to the keyset file. The lock is released
+/// when the returned file is closed.
+async fn lock(path: &Path) -> std::io::Result<std::fs::File> {
+    let lock_path = path.with_extension("lock");
+
+    // flock blocks until other servers sharing the keyset release their lock
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(lock_path)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(file)
+    })
+    .await
+    .unwrap_or_else(|error| Err(std::io::Error::new(std::io::ErrorKind::Other, error)))
+}
+
+async fn lock_or_warn(path: &Path) -> Option<std::fs::File> {
+    match lock(path).await {
+        Ok(file) => Some(file),
+        Err(error) => {
+            warn!(?error, ?path, "could not lock NTS keyset");
+            None
+        }
+    }
+}
+
+async fn store_on_shutdown(path: &Path, history: usize, provider: &KeySetProvider) {
+    let _lock = lock_or_warn(path).await;
+
+    // another server sharing the file may have stored newer keys
+    if let Ok(stored) = load(path, history).await {
+        if stored.rotated_at() > provider.rotated_at() {
+            return;
+        }
+    }
+
+    match store(path, provider).await {
+        Ok(()) => info!(?path, "stored NTS keyset"),
+        Err(error) => warn!(?error, ?path, "could not store NTS keyset"),
+    }
+}
+
+async fn store_or_warn(path: &Path, provider: &KeySetProvider) {
+    if let Err(error) = store(path, provider).await {
+        warn!(?error, ?path, "could not store NTS keyset");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::DecodedServerCookie;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn keyset_storage() {
+        let path = std::env::temp_dir().join("ntp-test-keyset-1");
+        let _ = std::fs::remove_file(&path);
+
+        let config = KeysetConfig {
+            key_rotation_interval_secs: 3600,
+            key_retention_count: 2,
+            key_storage_path: Some(path.clone()),
+        };
+
+        let (_, shutdown_receiver) = watch::channel(false);
+        let _keyset = spawn(config.clone(), shutdown_receiver.clone()).await;
+        let stored = std::fs::read(&path).unwrap();
+
+        // a restarted server loads the same keys, without rotating them
+        let _keyset = spawn(config, shutdown_receiver).await;
+        assert_eq!(std::fs::read(&path).unwrap(), stored);
+
+        let loaded = load(&path, 2).await.unwrap();
+        let mut data = vec![];
+        loaded.store(&mut data).unwrap();
+        assert_eq!(data, stored);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keyset_rotation() {
+        let config = KeysetConfig {
+            key_rotation_interval_secs: 1,
+            key_retention_count: 2,
+            key_storage_path: None,
+        };
+
+        let (_shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let (mut receiver, _) = spawn(config, shutdown_receiver).await;
+        let first = receiver.borrow_and_update().clone();
+
+        tokio::time::timeout(Duration::from_secs(3), receiver.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        let second = receiver.borrow_and_update().clone();
+
+        assert!(!Arc::ptr_eq(&first, &second));
+    }
+
+    #[tokio::test]
+    async fn keyset_stored_on_shutdown() {
+        let path = std::env::temp_dir().join("ntp-test-keyset-2");
+        let _ = std::fs::remove_file(&path);
+
+        let config = KeysetConfig {
+            key_rotation_interval_secs: 3600,
+            key_retention_count: 2,
+            key_storage_path: Some(path.clone()),
+        };
+
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let (_keyset, handle) = spawn(config, shutdown_receiver).await;
+        let stored = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        shutdown_sender.send_replace(true);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), stored);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keyset_rotation_shared() {
+        let path = std::env::temp_dir().join("ntp-test-keyset-3");
+        let _ = std::fs::remove_file(&path);
+
+        let config = KeysetConfig {
+            key_rotation_interval_secs: 1,
+            key_retention_count: 2,
+            key_storage_path: Some(path.clone()),
+        };
+
+        // both servers load the same keys, and are due to rotate them at the same time
+        let mut data = vec![];
+        KeySetProvider::new(2).store(&mut data).unwrap();
+        std::fs::write(&path, data).unwrap();
+
+        let (_shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let (mut first, _) = spawn(config.clone(), shutdown_receiver.clone()).await;
+        let (mut second, _) = spawn(config, shutdown_receiver).await;
+
+        for receiver in [&mut first, &mut second] {
+            tokio::time::timeout(Duration::from_secs(3), receiver.changed())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        // only one of them rotated the keys, the other loaded the rotated keys
+        let cookie = DecodedServerCookie::test();
+        let first = first.borrow().clone();
+        let second = second.borrow().clone();
+        let encoded = first.encode_cookie(&cookie);
+        assert_eq!(second.decode_cookie(&encoded).unwrap(), cookie);
+        let encoded = second.encode_cookie(&cookie);
+        assert_eq!(first.decode_cookie(&encoded).unwrap(), cookie);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+}
@@ -1,16 +1,20 @@
 //#![forbid(unsafe_code)]
 
 pub mod config;
+mod drift;
 mod ipfilter;
 mod keyexchange;
+mod keyset;
 pub mod observer;
 mod peer;
+pub mod protocol;
+mod refclock;
 mod server;
 pub mod sockets;
 mod system;
 pub mod tracing;
 
-pub use config::dynamic::ConfigUpdate;
+pub use config::dynamic::{AddPeer, ConfigUpdate, PeerMode};
 pub use config::Config;
 pub use observer::{ObservablePeerState, ObservableState};
 pub use system::spawn;
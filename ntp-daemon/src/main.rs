@@ -1,9 +1,10 @@
 #![forbid(unsafe_code)]
 
 use clap::Parser;
-use ntp_daemon::config::{CmdArgs, Config};
+use ntp_daemon::config::{dynamic::ConfigSource, CmdArgs, Config};
 use std::{error::Error, sync::Arc};
-use tracing::debug;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info};
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
@@ -23,6 +24,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let finish_tracing_init =
         ntp_daemon::tracing::init(log_filter, args.log_format.unwrap_or_default());
 
+    // remember where the configuration came from, such that it can be reloaded
+    let source = ConfigSource {
+        file: args.config.clone(),
+        peers: args.peers.clone(),
+        servers: args.servers.clone(),
+        log_filter_override: has_log_override,
+    };
+
     let mut config = match Config::from_args(args.config, args.peers, args.servers).await {
         Ok(c) => c,
         Err(e) => {
@@ -49,23 +58,79 @@ async fn main() -> Result<(), Box<dyn Error>> {
     config.check();
 
     debug!("Configuration loaded, spawning daemon jobs");
-    let (main_loop_handle, channels) =
-        ntp_daemon::spawn(config.system, &config.peers, &config.servers).await?;
+    let (main_loop_handle, channels) = ntp_daemon::spawn(
+        config.system,
+        &config.clock,
+        &config.peers,
+        &config.refclocks,
+        &config.servers,
+        &config.nts_ke,
+        &config.keyset,
+        &config.keys,
+        config.leap_seconds.as_ref(),
+        config.drift_file.as_deref(),
+    )
+    .await?;
 
-    ntp_daemon::observer::spawn(
+    let observer_handle = ntp_daemon::observer::spawn(
         &config.observe,
         channels.peer_snapshots_receiver,
         channels.server_data_receiver,
         channels.system_snapshot_receiver,
+        channels.kernel_pps_receiver,
+        channels.peer_history_receiver,
+        channels.event_sender,
+        channels.shutdown_receiver.clone(),
     )
     .await;
 
-    ntp_daemon::config::dynamic::spawn(
+    let configure_handle = ntp_daemon::config::dynamic::spawn(
         config.configure,
+        source,
         channels.config_sender,
+        channels.config_change_sender,
         tracing_state.reload_handle,
+        channels.shutdown_receiver,
     )
     .await;
 
-    Ok(main_loop_handle.await??)
+    let mut main_loop_handle = main_loop_handle;
+    tokio::select! {
+        result = &mut main_loop_handle => {
+            if let Err(error) = result? {
+                // such as the panic threshold being exceeded, which needs human intervention
+                error!(%error, "The daemon stopped unexpectedly");
+                channels.shutdown_sender.send_replace(true);
+                let _ = observer_handle.await;
+                std::process::exit(exitcode::SOFTWARE);
+            }
+            return Ok(());
+        }
+        result = shutdown_signal() => result?,
+    }
+
+    // Let all tasks store their state and clean up after themselves. Errors of the
+    // observer and configurator are already logged by those tasks.
+    channels.shutdown_sender.send_replace(true);
+    main_loop_handle.await??;
+    let _ = observer_handle.await;
+    let _ = configure_handle.await;
+
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// Wait for a signal asking the daemon to stop
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("Received SIGINT, shutting down");
+        }
+    }
+
+    Ok(())
 }
@@ -1,12 +1,26 @@
+use crate::config::NormalizedAddress;
+use crate::peer::PeerStats;
+use crate::protocol::{Event, ProtocolError, ProtocolResult, Request, Response};
 use crate::server::ServerStats;
-use crate::{sockets::create_unix_socket, system::ServerData};
-use ntp_proto::{ObservablePeerTimedata, PollInterval, Reach, ReferenceId, SystemSnapshot};
+use crate::{
+    sockets::{create_unix_socket, MAX_CONNECTIONS},
+    system::ServerData,
+};
+use ntp_proto::{
+    FilterDecision, KernelPpsStatus, NtpDuration, NtpTimestamp, ObservablePeerTimedata,
+    PollInterval, Reach, ReferenceId, SelectionStatus, SystemSnapshot,
+};
 use prometheus_client::encoding::text::Encode;
+use std::collections::VecDeque;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::os::unix::fs::PermissionsExt;
-use tokio::task::JoinHandle;
-use tracing::error;
+use std::sync::Arc;
+use tokio::{
+    sync::{broadcast, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{error, warn};
 
 use serde::{Deserialize, Serialize};
 
@@ -15,6 +29,9 @@ pub struct ObservableState {
     pub system: SystemSnapshot,
     pub peers: Vec<ObservablePeerState>,
     pub servers: Vec<ObservableServerState>,
+    /// Statistics of the kernel pps discipline, when a pps device disciplines the clock
+    #[serde(default)]
+    pub kernel_pps: Option<KernelPpsStatus>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -56,19 +73,58 @@ pub enum ObservablePeerState {
         reachability: Reach,
         poll_interval: PollInterval,
         peer_id: ReferenceId,
+        /// Stratum of the peer itself
+        stratum: u8,
+        /// Reference id of the time source of the peer
+        reference_id: ReferenceId,
+        /// The outcome of the latest clock selection, if there has been one
+        selection: Option<SelectionStatus>,
+        stats: PeerStats,
         address: String,
     },
 }
 
+/// A measurement of a peer, and what the algorithm did with it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ObservableMeasurement {
+    pub offset: NtpDuration,
+    pub delay: NtpDuration,
+    /// The local time at which the measurement was made
+    pub localtime: NtpTimestamp,
+    pub decision: FilterDecision,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ObservablePeerHistory {
+    pub address: String,
+    /// The latest measurements, oldest first
+    pub measurements: VecDeque<ObservableMeasurement>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn spawn(
     config: &crate::config::ObserveConfig,
     peers_reader: tokio::sync::watch::Receiver<Vec<ObservablePeerState>>,
     server_reader: tokio::sync::watch::Receiver<Vec<ServerData>>,
     system_reader: tokio::sync::watch::Receiver<SystemSnapshot>,
+    kernel_pps_reader: tokio::sync::watch::Receiver<Option<KernelPpsStatus>>,
+    history_reader: tokio::sync::watch::Receiver<Vec<ObservablePeerHistory>>,
+    event_sender: broadcast::Sender<Event>,
+    shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 ) -> JoinHandle<std::io::Result<()>> {
     let config = config.clone();
     tokio::spawn(async move {
-        let result = observer(config, peers_reader, server_reader, system_reader).await;
+        let result = observer(
+            config,
+            peers_reader,
+            server_reader,
+            system_reader,
+            kernel_pps_reader,
+            history_reader,
+            event_sender,
+            shutdown_receiver,
+        )
+        .await;
         if let Err(ref e) = result {
             error!("Abnormal termination of state observer: {}", e);
         }
@@ -76,11 +132,82 @@ pub async fn spawn(
     })
 }
 
+/// The state of the daemon, as it is observed through the watch channels
+#[derive(Clone)]
+struct Observer {
+    peers_reader: tokio::sync::watch::Receiver<Vec<ObservablePeerState>>,
+    server_reader: tokio::sync::watch::Receiver<Vec<ServerData>>,
+    system_reader: tokio::sync::watch::Receiver<SystemSnapshot>,
+    kernel_pps_reader: tokio::sync::watch::Receiver<Option<KernelPpsStatus>>,
+    history_reader: tokio::sync::watch::Receiver<Vec<ObservablePeerHistory>>,
+}
+
+impl Observer {
+    fn handle(&self, request: Request) -> ProtocolResult<Response> {
+        let response = match request {
+            Request::GetState => Response::State(ObservableState {
+                peers: self.peers(),
+                system: self.system(),
+                servers: self.servers(),
+                kernel_pps: *self.kernel_pps_reader.borrow(),
+            }),
+            Request::GetSystem => Response::System(self.system()),
+            Request::GetPeers => Response::Peers(self.peers()),
+            Request::GetServers => Response::Servers(self.servers()),
+            Request::GetHistory(address) => Response::History(self.history(&address)),
+            // subscriptions are handled by the protocol itself
+            Request::Subscribe
+            | Request::UpdateConfig(_)
+            | Request::AddPeer(_)
+            | Request::RemovePeer(_) => {
+                return Err(ProtocolError::UnsupportedRequest);
+            }
+        };
+
+        Ok(response)
+    }
+
+    fn peers(&self) -> Vec<ObservablePeerState> {
+        self.peers_reader.borrow().to_owned()
+    }
+
+    fn system(&self) -> SystemSnapshot {
+        *self.system_reader.borrow()
+    }
+
+    /// The history of the peers with the given address, with or without the default port
+    fn history(&self, address: &str) -> Vec<ObservablePeerHistory> {
+        let normalized = NormalizedAddress::from_string_ntp(address.to_string())
+            .map(|normalized| normalized.to_string())
+            .unwrap_or_else(|_| address.to_string());
+
+        self.history_reader
+            .borrow()
+            .iter()
+            .filter(|history| history.address == address || history.address == normalized)
+            .cloned()
+            .collect()
+    }
+
+    fn servers(&self) -> Vec<ObservableServerState> {
+        self.server_reader
+            .borrow()
+            .iter()
+            .map(|s| s.into())
+            .collect()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn observer(
     config: crate::config::ObserveConfig,
     peers_reader: tokio::sync::watch::Receiver<Vec<ObservablePeerState>>,
     server_reader: tokio::sync::watch::Receiver<Vec<ServerData>>,
     system_reader: tokio::sync::watch::Receiver<SystemSnapshot>,
+    kernel_pps_reader: tokio::sync::watch::Receiver<Option<KernelPpsStatus>>,
+    history_reader: tokio::sync::watch::Receiver<Vec<ObservablePeerHistory>>,
+    event_sender: broadcast::Sender<Event>,
+    mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 ) -> std::io::Result<()> {
     let path = match config.path {
         Some(path) => path,
@@ -95,17 +222,49 @@ async fn observer(
     let permissions: std::fs::Permissions = PermissionsExt::from_mode(config.mode);
     std::fs::set_permissions(&path, permissions)?;
 
+    let observer = Observer {
+        peers_reader,
+        server_reader,
+        system_reader,
+        kernel_pps_reader,
+        history_reader,
+    };
+
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+
     loop {
-        let (mut stream, _addr) = peers_listener.accept().await?;
+        let (stream, _addr) = tokio::select! {
+            result = peers_listener.accept() => result?,
+            _ = shutdown_receiver.changed(), if shutdown_receiver.has_changed().is_ok() => break,
+        };
 
-        let observe = ObservableState {
-            peers: peers_reader.borrow().to_owned(),
-            system: *system_reader.borrow(),
-            servers: server_reader.borrow().iter().map(|s| s.into()).collect(),
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("too many observation socket connections, closing a new connection");
+                continue;
+            }
         };
 
-        crate::sockets::write_json(&mut stream, &observe).await?;
+        // a client may keep its connection open, so serve each client separately
+        let observer = observer.clone();
+        let event_sender = event_sender.clone();
+        tokio::spawn(async move {
+            let result = crate::protocol::serve(stream, Some(&event_sender), |request| {
+                std::future::ready(observer.handle(request))
+            })
+            .await;
+            drop(permit);
+
+            if let Err(error) = result {
+                warn!(?error, "observation socket connection failed");
+            }
+        });
     }
+
+    crate::sockets::remove_unix_socket(&path);
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -118,6 +277,9 @@ mod tests {
     };
     use tokio::{io::AsyncReadExt, net::UnixStream};
 
+    use crate::protocol::{Client, ClientError, Hello, PROTOCOL_VERSION};
+    use crate::sockets::write_message;
+
     use super::*;
 
     #[derive(Debug, Clone, Default)]
@@ -184,6 +346,10 @@ mod tests {
                 reachability: Reach::default(),
                 poll_interval: PollIntervalLimits::default().min,
                 peer_id: ReferenceId::from_ip("127.0.0.1".parse().unwrap()),
+                stratum: 1,
+                reference_id: ReferenceId::from_bytes(*b"GPS\0"),
+                selection: Some(SelectionStatus::SystemPeer),
+                stats: Default::default(),
                 address: "127.0.0.3:123".into(),
             },
         ]);
@@ -201,22 +367,53 @@ mod tests {
                 root_dispersion: NtpDuration::ZERO,
                 leap_indicator: NtpLeapIndicator::Leap59,
                 accumulated_steps: NtpDuration::ZERO,
+                next_leap: None,
+                tai_offset: None,
             },
         });
 
+        let kernel_pps = KernelPpsStatus {
+            signal: true,
+            frequency: 1e-6,
+            jitter: NtpDuration::from_seconds(1e-7),
+            stability: 1e-9,
+            calibration_count: 4,
+            jitter_count: 0,
+            error_count: 0,
+            stability_count: 0,
+        };
+        let (_, kernel_pps_reader) = tokio::sync::watch::channel(Some(kernel_pps));
+        let measurement = ObservableMeasurement {
+            offset: NtpDuration::from_seconds(1e-3),
+            delay: NtpDuration::from_seconds(2e-3),
+            localtime: NtpTimestamp::default(),
+            decision: FilterDecision::Accepted,
+        };
+        let (_, history_reader) = tokio::sync::watch::channel(vec![ObservablePeerHistory {
+            address: "127.0.0.3:123".into(),
+            measurements: [measurement.clone()].into(),
+        }]);
+        let (_, shutdown_receiver) = tokio::sync::watch::channel(false);
+
         let handle = tokio::spawn(async move {
-            observer(config, peers_reader, servers_reader, system_reader)
-                .await
-                .unwrap();
+            observer(
+                config,
+                peers_reader,
+                servers_reader,
+                system_reader,
+                kernel_pps_reader,
+                history_reader,
+                broadcast::channel(1).0,
+                shutdown_receiver,
+            )
+            .await
+            .unwrap();
         });
 
         tokio::time::sleep(Duration::from_millis(10)).await;
 
-        let mut reader = UnixStream::connect(path).await.unwrap();
-
-        let mut buf = vec![];
-        while reader.read_buf(&mut buf).await.unwrap() != 0 {}
-        let result: ObservableState = serde_json::from_slice(&buf).unwrap();
+        let mut client = Client::connect(path).await.unwrap();
+        let result = client.get_state().await.unwrap();
 
         // Deal with randomized order
         let mut count = 0;
@@ -226,6 +423,24 @@ mod tests {
             }
         }
         assert_eq!(count, 1);
+        assert_eq!(result.kernel_pps, Some(kernel_pps));
+
+        assert_eq!(client.get_system().await.unwrap().stratum, 1);
+
+        // the default port may be left out
+        let history = client.get_history("127.0.0.3").await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].measurements, [measurement]);
+        assert!(client.get_history("127.0.0.4").await.unwrap().is_empty());
+
+        // the configuration cannot be changed through the observation socket
+        let result = client
+            .change(&Request::RemovePeer("127.0.0.3".into()))
+            .await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Protocol(ProtocolError::UnsupportedRequest))
+        ));
 
         handle.abort();
     }
@@ -247,6 +462,10 @@ mod tests {
                 reachability: Reach::default(),
                 poll_interval: PollIntervalLimits::default().min,
                 peer_id: ReferenceId::from_ip("127.0.0.1".parse().unwrap()),
+                stratum: 1,
+                reference_id: ReferenceId::from_bytes(*b"GPS\0"),
+                selection: Some(SelectionStatus::SystemPeer),
+                stats: Default::default(),
                 address: "127.0.0.3:123".into(),
             },
         ]);
@@ -264,18 +483,40 @@ mod tests {
                 root_dispersion: NtpDuration::ZERO,
                 leap_indicator: NtpLeapIndicator::Leap59,
                 accumulated_steps: NtpDuration::ZERO,
+                next_leap: None,
+                tai_offset: None,
             },
         });
 
+        let (_, kernel_pps_reader) = tokio::sync::watch::channel(None);
+        let (_, history_reader) = tokio::sync::watch::channel(vec![]);
+        let (_, shutdown_receiver) = tokio::sync::watch::channel(false);
+
         let handle = tokio::spawn(async move {
-            observer(config, peers_reader, servers_reader, system_reader)
-                .await
-                .unwrap();
+            observer(
+                config,
+                peers_reader,
+                servers_reader,
+                system_reader,
+                kernel_pps_reader,
+                history_reader,
+                broadcast::channel(1).0,
+                shutdown_receiver,
+            )
+            .await
+            .unwrap();
         });
 
         tokio::time::sleep(Duration::from_millis(10)).await;
 
         let mut reader = UnixStream::connect(path).await.unwrap();
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+        };
+        write_message(&mut reader, &hello).await.unwrap();
+        write_message(&mut reader, &Request::GetState)
+            .await
+            .unwrap();
 
         // We do a small partial read of the data to test that whatever
         // happens, the observer doesnt keep a lock alive on either of
@@ -291,4 +532,56 @@ mod tests {
 
         handle.abort();
     }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-stream-10");
+        let config = crate::config::ObserveConfig {
+            path: Some(path.clone()),
+            mode: 0o700,
+        };
+
+        let (_, peers_reader) = tokio::sync::watch::channel(vec![]);
+        let (_, servers_reader) = tokio::sync::watch::channel(vec![]);
+        let (_, system_reader) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let (_, kernel_pps_reader) = tokio::sync::watch::channel(None);
+        let (_, history_reader) = tokio::sync::watch::channel(vec![]);
+        let (_, shutdown_receiver) = tokio::sync::watch::channel(false);
+
+        let handle = tokio::spawn(async move {
+            observer(
+                config,
+                peers_reader,
+                servers_reader,
+                system_reader,
+                kernel_pps_reader,
+                history_reader,
+                broadcast::channel(1).0,
+                shutdown_receiver,
+            )
+            .await
+            .unwrap();
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut clients = vec![];
+        for _ in 0..MAX_CONNECTIONS {
+            clients.push(Client::connect(&path).await.unwrap());
+        }
+
+        // further clients are disconnected
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        let mut buf = vec![];
+        assert_eq!(stream.read_to_end(&mut buf).await.unwrap(), 0);
+
+        // until a client leaves
+        clients.pop();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let mut client = Client::connect(&path).await.unwrap();
+        assert!(client.get_system().await.is_ok());
+
+        handle.abort();
+    }
 }
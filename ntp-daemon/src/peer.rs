@@ -3,19 +3,29 @@ use std::{
     marker::PhantomData,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
+    sync::Arc,
 };
 
 use ntp_proto::{
-    IgnoreReason, Measurement, NtpClock, NtpInstant, NtpPacket, NtpTimestamp, Peer, PeerNtsData,
-    PeerSnapshot, ReferenceId, SystemSnapshot, Update,
+    IgnoreReason, Measurement, NtpAssociationMode, NtpClock, NtpInstant, NtpPacket, NtpTimestamp,
+    Peer, PeerNtsData, PeerSnapshot, ReferenceId, SymmetricKey, SymmetricMode, SystemSnapshot,
+    Update,
 };
 use ntp_udp::UdpSocket;
 use rand::{thread_rng, Rng};
-use tracing::{debug, error, instrument, warn, Instrument, Span};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, instrument, trace, warn, Instrument, Span};
 
-use tokio::time::{Instant, Sleep};
+use tokio::{
+    sync::mpsc,
+    time::{Instant, Sleep},
+};
 
-use crate::{config::CombinedSystemConfig, system::PeerIndex};
+use crate::{
+    config::{CombinedSystemConfig, TimestampingMode},
+    server::WrappedCounter,
+    system::PeerIndex,
+};
 
 /// Trait needed to allow injecting of futures other than tokio::time::Sleep for testing
 pub trait Wait: Future<Output = ()> {
@@ -48,14 +58,132 @@ pub struct PeerChannels {
     pub msg_for_system_sender: tokio::sync::mpsc::Sender<MsgForSystem>,
     pub system_snapshot_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
     pub system_config_receiver: tokio::sync::watch::Receiver<CombinedSystemConfig>,
+    pub shutdown_receiver: tokio::sync::watch::Receiver<bool>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStats {
+    /// Polls for which the kernel did not report when they were sent, so the time just
+    /// before sending was used instead
+    pub send_timestamp_fallbacks: WrappedCounter,
+}
+
+/// A packet that a server received from the peer of a passive association
+#[derive(Debug)]
+pub struct ForwardedPacket {
+    pub data: Vec<u8>,
+    pub recv_timestamp: NtpTimestamp,
+}
+
+/// Sent by a server that received a symmetric active packet from a peer for
+/// which there is no passive association yet
+pub struct PassiveAssociationRequest {
+    /// The socket of the server, over which the passive association sends its packets
+    pub socket: Arc<UdpSocket>,
+    pub peer_addr: SocketAddr,
+    /// The packet that triggered the association
+    pub packet: ForwardedPacket,
+    /// Further packets from the peer, forwarded by the server
+    pub receiver: mpsc::Receiver<ForwardedPacket>,
+    /// The key that authenticated the packet. Unauthenticated packets never mobilize a
+    /// passive association, as anyone could then steer our clock.
+    pub symmetric_key: SymmetricKey,
+}
+
+enum PeerSocket {
+    /// A socket of our own, connected to the peer
+    Connected(UdpSocket),
+    /// The socket of a server, which forwards the packets the peer sends to it. A passive
+    /// association must answer from the address that the peer sends its packets to.
+    Server {
+        socket: Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+        receiver: mpsc::Receiver<ForwardedPacket>,
+    },
+    /// A socket that receives the broadcasts of a server, and a socket connected to that
+    /// server over which the requests to calibrate the delay are sent
+    Broadcast {
+        listener: UdpSocket,
+        client: UdpSocket,
+        server_addr: SocketAddr,
+    },
+}
+
+impl PeerSocket {
+    async fn send(&mut self, buf: &[u8]) -> std::io::Result<(usize, Option<NtpTimestamp>)> {
+        match self {
+            PeerSocket::Connected(socket) => socket.send(buf).await,
+            PeerSocket::Server {
+                socket, peer_addr, ..
+            } => socket.send_to_timestamped(buf, *peer_addr).await,
+            PeerSocket::Broadcast { client, .. } => client.send(buf).await,
+        }
+    }
+
+    fn send_timestamping(&self) -> bool {
+        match self {
+            PeerSocket::Connected(socket) => socket.send_timestamping(),
+            PeerSocket::Server { socket, .. } => socket.send_timestamping(),
+            PeerSocket::Broadcast { client, .. } => client.send_timestamping(),
+        }
+    }
+
+    async fn recv(
+        &mut self,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, SocketAddr, Option<NtpTimestamp>)> {
+        match self {
+            PeerSocket::Connected(socket) => socket.recv(buf).await,
+            PeerSocket::Server {
+                peer_addr,
+                receiver,
+                ..
+            } => match receiver.recv().await {
+                Some(packet) => {
+                    let size = packet.data.len().min(buf.len());
+                    buf[..size].copy_from_slice(&packet.data[..size]);
+                    Ok((size, *peer_addr, Some(packet.recv_timestamp)))
+                }
+                // the server stopped, so we can no longer reach the peer
+                None => Err(std::io::Error::from_raw_os_error(libc::ENETDOWN)),
+            },
+            PeerSocket::Broadcast {
+                listener,
+                client,
+                server_addr,
+            } => loop {
+                let mut broadcast_buf = [0_u8; 1024];
+                let mut response_buf = [0_u8; 1024];
+
+                let (result, data) = tokio::select! {
+                    result = listener.recv(&mut broadcast_buf) => (result, &broadcast_buf),
+                    result = client.recv(&mut response_buf) => (result, &response_buf),
+                };
+
+                match result {
+                    // other hosts may send to the broadcast address as well
+                    Ok((_, addr, _)) if addr != *server_addr => {
+                        trace!(?addr, "ignoring packet from other host");
+                    }
+                    Ok((size, addr, timestamp)) => {
+                        let size = size.min(buf.len());
+                        buf[..size].copy_from_slice(&data[..size]);
+                        return Ok((size, addr, timestamp));
+                    }
+                    Err(error) => return Err(error),
+                }
+            },
+        }
+    }
 }
 
 pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
     _wait: PhantomData<T>,
     index: PeerIndex,
     clock: C,
-    socket: UdpSocket,
+    socket: PeerSocket,
     channels: PeerChannels,
+    stats: PeerStats,
 
     peer: Peer,
 
@@ -74,6 +202,7 @@ pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
 enum PollResult {
     Ok,
     NetworkGone,
+    Demobilize,
 }
 
 #[derive(Debug)]
@@ -105,12 +234,61 @@ where
     async fn handle_poll(&mut self, poll_wait: &mut Pin<&mut T>) -> PollResult {
         let system_snapshot = *self.channels.system_snapshot_receiver.borrow();
         let config_snapshot = *self.channels.system_config_receiver.borrow_and_update();
+
+        if self.peer.symmetric_mode() == Some(SymmetricMode::Passive)
+            && !PeerSnapshot::from_peer(&self.peer).reach.is_reachable()
+        {
+            // passive associations only exist as long as the peer keeps sending packets
+            debug!("Demobilizing passive association with unreachable peer");
+            return PollResult::Demobilize;
+        }
+
+        if self.peer.is_broadcast_client() {
+            if !PeerSnapshot::from_peer(&self.peer).reach.is_reachable() {
+                // start over, listening for any server that broadcasts
+                warn!("Broadcast server became unreachable");
+                return PollResult::NetworkGone;
+            }
+
+            if !self.peer.wants_poll() {
+                // the delay is calibrated, from now on we only listen to the broadcasts
+                self.peer.skip_poll();
+                self.last_poll_sent = Instant::now();
+                self.update_poll_wait(poll_wait, system_snapshot);
+
+                let snapshot = PeerSnapshot::from_peer(&self.peer);
+                let msg = MsgForSystem::UpdatedSnapshot(self.index, snapshot);
+                self.channels.msg_for_system_sender.send(msg).await.ok();
+
+                return PollResult::Ok;
+            }
+        }
+
+        let now = match self.clock.now() {
+            Err(e) => {
+                // we cannot determine the origin_timestamp
+                error!(error = ?e, "There was an error retrieving the current time");
+
+                // report as no permissions, since this seems the most likely
+                std::process::exit(exitcode::NOPERM);
+            }
+            Ok(ts) => ts,
+        };
+
         let mut buf = [0; 1024];
-        let packet = match self.peer.generate_poll_message(
-            &mut buf,
-            system_snapshot,
-            &config_snapshot.system,
-        ) {
+        let result = match self.peer.symmetric_mode() {
+            Some(_) => self.peer.generate_symmetric_message(
+                &mut buf,
+                system_snapshot,
+                &config_snapshot.system,
+                now,
+            ),
+            None => {
+                self.peer
+                    .generate_poll_message(&mut buf, system_snapshot, &config_snapshot.system)
+            }
+        };
+        let packet = match result {
             Ok(packet) => packet,
             Err(e) => {
                 error!(error = ?e, "Could not generate poll message");
@@ -128,18 +306,7 @@ where
         let msg = MsgForSystem::UpdatedSnapshot(self.index, snapshot);
         self.channels.msg_for_system_sender.send(msg).await.ok();
 
-        match self.clock.now() {
-            Err(e) => {
-                // we cannot determine the origin_timestamp
-                error!(error = ?e, "There was an error retrieving the current time");
-
-                // report as no permissions, since this seems the most likely
-                std::process::exit(exitcode::NOPERM);
-            }
-            Ok(ts) => {
-                self.last_send_timestamp = Some(ts);
-            }
-        }
+        self.last_send_timestamp = Some(now);
 
         match self.socket.send(packet).await {
             Err(error) => {
@@ -154,7 +321,13 @@ where
                 }
             }
             Ok((_written, opt_send_timestamp)) => {
+                if opt_send_timestamp.is_none() && self.socket.send_timestamping() {
+                    self.stats.send_timestamp_fallbacks.inc();
+                }
+
                 // update the last_send_timestamp with the one given by the kernel, if available
+                let opt_send_timestamp = opt_send_timestamp
+                    .and_then(|timestamp| self.clock.convert_system_time(timestamp).ok());
                 self.last_send_timestamp = opt_send_timestamp.or(self.last_send_timestamp);
             }
         }
@@ -171,6 +344,18 @@ where
     ) -> PacketResult {
         let ntp_instant = NtpInstant::now();
 
+        // the packet was timestamped with the system clock
+        let recv_timestamp = match self.clock.convert_system_time(recv_timestamp) {
+            Ok(recv_timestamp) => recv_timestamp,
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "could not convert the receive timestamp; discarding packet"
+                );
+                return PacketResult::Ok;
+            }
+        };
+
         let system_snapshot = *self.channels.system_snapshot_receiver.borrow();
         let result = self.peer.handle_incoming(
             system_snapshot,
@@ -225,6 +410,10 @@ where
                             self.channels.msg_for_system_sender.send(MsgForSystem::NetworkIssue(self.index)).await.ok();
                             break;
                         }
+                        PollResult::Demobilize => {
+                            self.channels.msg_for_system_sender.send(MsgForSystem::MustDemobilize(self.index)).await.ok();
+                            break;
+                        }
                     }
                 },
                 result = self.socket.recv(&mut buf) => {
@@ -233,6 +422,10 @@ where
                         AcceptResult::Accept(packet, recv_timestamp) => {
                             let send_timestamp = match self.last_send_timestamp {
                                 Some(ts) => ts,
+                                // in a symmetric association, the peer may send the first packet
+                                None if self.peer.symmetric_mode().is_some() => NtpTimestamp::default(),
+                                // broadcasts are not a response to a packet of ours
+                                None if self.peer.is_broadcast_client() => NtpTimestamp::default(),
                                 None => {
                                     warn!("we received a message without having sent one; discarding");
                                     continue;
@@ -254,6 +447,10 @@ where
                 _ = self.channels.system_config_receiver.changed(), if self.channels.system_config_receiver.has_changed().is_ok() => {
                     self.peer.update_config(self.channels.system_config_receiver.borrow_and_update().system);
                 },
+                _ = self.channels.shutdown_receiver.changed(), if self.channels.shutdown_receiver.has_changed().is_ok() => {
+                    debug!("shutting down peer");
+                    break;
+                },
             }
         }
     }
@@ -263,7 +460,8 @@ impl<C> PeerTask<C, Sleep>
 where
     C: 'static + NtpClock + Send,
 {
-    #[instrument(skip(clock, channels))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(clock, channels, stats))]
     pub fn spawn(
         index: PeerIndex,
         addr: SocketAddr,
@@ -271,11 +469,30 @@ where
         network_wait_period: std::time::Duration,
         mut channels: PeerChannels,
         nts: Option<PeerNtsData>,
+        symmetric_key: Option<SymmetricKey>,
+        symmetric: bool,
+        timestamping: TimestampingMode,
+        stats: PeerStats,
     ) -> tokio::task::JoinHandle<()> {
         tokio::spawn(
             (async move {
                 let socket = match UdpSocket::client(unspecified_for(addr), addr).await {
-                    Ok(socket) => socket,
+                    Ok(mut socket) => {
+                        if let TimestampingMode::Hardware { interface } = timestamping {
+                            if let Err(error) = socket.enable_hardware_timestamping(interface) {
+                                warn!(
+                                    ?error,
+                                    "Could not enable hardware timestamping, falling back to software timestamping"
+                                );
+                            }
+                        }
+                        if !channels.system_config_receiver.borrow().send_timestamps {
+                            if let Err(error) = socket.disable_send_timestamping() {
+                                warn!(?error, "Could not disable send timestamping");
+                            }
+                        }
+                        socket
+                    }
                     Err(error) => {
                         warn!(?error, "Could not open socket");
                         tokio::time::sleep(network_wait_period).await;
@@ -295,16 +512,32 @@ where
 
                 let local_clock_time = NtpInstant::now();
                 let config_snapshot = *channels.system_config_receiver.borrow_and_update();
-                let peer = if let Some(nts) = nts {
-                    Peer::new_nts(
+                let peer = match (nts, symmetric_key) {
+                    (None, symmetric_key) if symmetric => Peer::new_symmetric(
+                        our_id,
+                        peer_id,
+                        local_clock_time,
+                        config_snapshot.system,
+                        SymmetricMode::Active,
+                        symmetric_key,
+                    ),
+                    (Some(nts), _) => Peer::new_nts(
                         our_id,
                         peer_id,
                         local_clock_time,
                         config_snapshot.system,
                         nts,
-                    )
-                } else {
-                    Peer::new(our_id, peer_id, local_clock_time, config_snapshot.system)
+                    ),
+                    (None, Some(symmetric_key)) => Peer::new_with_key(
+                        our_id,
+                        peer_id,
+                        local_clock_time,
+                        config_snapshot.system,
+                        symmetric_key,
+                    ),
+                    (None, None) => {
+                        Peer::new(our_id, peer_id, local_clock_time, config_snapshot.system)
+                    }
                 };
 
                 let poll_wait = tokio::time::sleep(std::time::Duration::default());
@@ -315,12 +548,221 @@ where
                     index,
                     clock,
                     channels,
+                    stats,
+                    socket: PeerSocket::Connected(socket),
+                    peer,
+                    last_send_timestamp: None,
+                    last_poll_sent: Instant::now(),
+                };
+
+                process.run(poll_wait).await
+            })
+            .instrument(Span::current()),
+        )
+    }
+
+    /// Spawn a passive association in response to a symmetric active packet that a server
+    /// received. The association ends when the peer stops sending packets.
+    #[instrument(skip(clock, channels, stats, request), fields(addr = %request.peer_addr))]
+    pub fn spawn_passive(
+        index: PeerIndex,
+        clock: C,
+        mut channels: PeerChannels,
+        stats: PeerStats,
+        request: PassiveAssociationRequest,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(
+            (async move {
+                let PassiveAssociationRequest {
                     socket,
+                    peer_addr,
+                    packet,
+                    receiver,
+                    symmetric_key,
+                } = request;
+
+                // Unwrap should be safe because the server socket is bound to a local address
+                let our_id = ReferenceId::from_ip((*socket).as_ref().local_addr().unwrap().ip());
+                let peer_id = ReferenceId::from_ip(peer_addr.ip());
+
+                let local_clock_time = NtpInstant::now();
+                let config_snapshot = *channels.system_config_receiver.borrow_and_update();
+                let peer = Peer::new_symmetric(
+                    our_id,
+                    peer_id,
+                    local_clock_time,
+                    config_snapshot.system,
+                    SymmetricMode::Passive,
+                    Some(symmetric_key),
+                );
+
+                let poll_wait = tokio::time::sleep(std::time::Duration::default());
+                tokio::pin!(poll_wait);
+
+                let mut process = PeerTask {
+                    _wait: PhantomData,
+                    index,
+                    clock,
+                    channels,
+                    stats,
+                    socket: PeerSocket::Server {
+                        socket,
+                        peer_addr,
+                        receiver,
+                    },
+                    peer,
+                    last_send_timestamp: None,
+                    last_poll_sent: Instant::now(),
+                };
+
+                // the packet that created the association is processed before the first poll,
+                // so that our answer can contain its timestamps
+                let packet_result = process
+                    .handle_packet(
+                        &mut poll_wait,
+                        &packet.data,
+                        NtpTimestamp::default(),
+                        packet.recv_timestamp,
+                    )
+                    .await;
+
+                if let PacketResult::Demobilize = packet_result {
+                    return;
+                }
+
+                // answer right away, instead of waiting for a full poll interval
+                poll_wait.as_mut().reset(Instant::now());
+
+                process.run(poll_wait).await
+            })
+            .instrument(Span::current()),
+        )
+    }
+
+    /// Spawn a client of the broadcast server that first sends to `addr`, a broadcast
+    /// address or multicast group
+    #[instrument(skip(clock, channels, stats))]
+    pub fn spawn_broadcast(
+        index: PeerIndex,
+        addr: SocketAddr,
+        clock: C,
+        network_wait_period: std::time::Duration,
+        mut channels: PeerChannels,
+        symmetric_key: SymmetricKey,
+        stats: PeerStats,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(
+            (async move {
+                let listener = match UdpSocket::broadcast_client(addr).await {
+                    Ok(listener) => listener,
+                    Err(error) => {
+                        warn!(?error, "Could not open socket");
+                        tokio::time::sleep(network_wait_period).await;
+                        channels
+                            .msg_for_system_sender
+                            .send(MsgForSystem::NetworkIssue(index))
+                            .await
+                            .ok();
+                        return;
+                    }
+                };
+
+                // the first server we hear with a valid MAC becomes our peer
+                let mut buf = [0_u8; 1024];
+                let (data, server_addr, recv_timestamp) = loop {
+                    let result = listener.recv(&mut buf).await;
+                    let source = result.as_ref().map(|(_, source, _)| *source).ok();
+
+                    match (accept_packet(result, &buf), source) {
+                        (AcceptResult::Accept(packet, recv_timestamp), Some(source)) => {
+                            let is_broadcast = matches!(
+                                NtpPacket::deserialize(packet, None),
+                                Ok(broadcast) if broadcast.mode() == NtpAssociationMode::Broadcast
+                                    && broadcast.verify_mac(packet, &symmetric_key)
+                            );
+                            if is_broadcast {
+                                break (packet.to_vec(), source, recv_timestamp);
+                            } else {
+                                trace!(?source, "ignoring unauthenticated packet");
+                            }
+                        }
+                        (AcceptResult::NetworkGone, _) => {
+                            channels
+                                .msg_for_system_sender
+                                .send(MsgForSystem::NetworkIssue(index))
+                                .await
+                                .ok();
+                            return;
+                        }
+                        _ => {}
+                    }
+                };
+                debug!(?server_addr, "Received first broadcast");
+
+                let client =
+                    match UdpSocket::client(unspecified_for(server_addr), server_addr).await {
+                        Ok(client) => client,
+                        Err(error) => {
+                            warn!(?error, "Could not open socket");
+                            tokio::time::sleep(network_wait_period).await;
+                            channels
+                                .msg_for_system_sender
+                                .send(MsgForSystem::NetworkIssue(index))
+                                .await
+                                .ok();
+                            return;
+                        }
+                    };
+
+                // Unwrap should be safe because we know the socket was bound to a local addres just before
+                let our_id = ReferenceId::from_ip(client.as_ref().local_addr().unwrap().ip());
+                let peer_id = ReferenceId::from_ip(server_addr.ip());
+
+                let local_clock_time = NtpInstant::now();
+                let config_snapshot = *channels.system_config_receiver.borrow_and_update();
+                let peer = Peer::new_broadcast_client(
+                    our_id,
+                    peer_id,
+                    local_clock_time,
+                    config_snapshot.system,
+                    symmetric_key,
+                );
+
+                let poll_wait = tokio::time::sleep(std::time::Duration::default());
+                tokio::pin!(poll_wait);
+
+                let mut process = PeerTask {
+                    _wait: PhantomData,
+                    index,
+                    clock,
+                    channels,
+                    stats,
+                    socket: PeerSocket::Broadcast {
+                        listener,
+                        client,
+                        server_addr,
+                    },
                     peer,
                     last_send_timestamp: None,
                     last_poll_sent: Instant::now(),
                 };
 
+                let packet_result = process
+                    .handle_packet(
+                        &mut poll_wait,
+                        &data,
+                        NtpTimestamp::default(),
+                        recv_timestamp,
+                    )
+                    .await;
+
+                if let PacketResult::Demobilize = packet_result {
+                    return;
+                }
+
+                // start calibrating the delay right away
+                poll_wait.as_mut().reset(Instant::now());
+
                 process.run(poll_wait).await
             })
             .instrument(Span::current()),
@@ -463,11 +905,12 @@ mod tests {
     struct TestClock {}
 
     impl NtpClock for TestClock {
-        type Error = std::time::SystemTimeError;
+        type Error = std::io::Error;
 
         fn now(&self) -> std::result::Result<NtpTimestamp, Self::Error> {
-            let cur =
-                std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
+            let cur = std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
             Ok(NtpTimestamp::from_seconds_nanos_since_ntp_era(
                 EPOCH_OFFSET.wrapping_add(cur.as_secs() as u32),
@@ -521,24 +964,40 @@ mod tests {
     ) {
         // Note: Ports must be unique among tests to deal with parallelism, hence
         // port_base
-        let socket = UdpSocket::client(
+        let mut socket = UdpSocket::client(
             SocketAddr::from((Ipv4Addr::LOCALHOST, port_base)),
             SocketAddr::from((Ipv4Addr::LOCALHOST, port_base + 1)),
         )
         .await
         .unwrap();
-        let test_socket = UdpSocket::client(
+        let mut test_socket = UdpSocket::client(
             SocketAddr::from((Ipv4Addr::LOCALHOST, port_base + 1)),
             SocketAddr::from((Ipv4Addr::LOCALHOST, port_base)),
         )
         .await
         .unwrap();
+
+        // When no other socket on the system uses timestamping, the kernel turns it on
+        // with a delay, and packets that arrive in the meantime have no receive
+        // timestamp. Wait until both sockets timestamp the packets they receive.
+        let mut buf = [0; 48];
+        loop {
+            socket.send(&buf).await.unwrap();
+            test_socket.send(&buf).await.unwrap();
+            let (_, _, test_timestamp) = test_socket.recv(&mut buf).await.unwrap();
+            let (_, _, timestamp) = socket.recv(&mut buf).await.unwrap();
+            if timestamp.is_some() && test_timestamp.is_some() {
+                break;
+            }
+        }
+
         let our_id = ReferenceId::from_ip(socket.as_ref().local_addr().unwrap().ip());
         let peer_id = ReferenceId::from_ip(socket.as_ref().peer_addr().unwrap().ip());
 
         let (_, system_snapshot_receiver) = tokio::sync::watch::channel(SystemSnapshot::default());
         let (_, mut system_config_receiver) =
             tokio::sync::watch::channel(CombinedSystemConfig::default());
+        let (_, shutdown_receiver) = tokio::sync::watch::channel(false);
         let (msg_for_system_sender, msg_for_system_receiver) = mpsc::channel(1);
 
         let local_clock_time = NtpInstant::now();
@@ -557,8 +1016,10 @@ mod tests {
                 msg_for_system_sender,
                 system_snapshot_receiver,
                 system_config_receiver,
+                shutdown_receiver,
             },
-            socket,
+            stats: Default::default(),
+            socket: PeerSocket::Connected(socket),
             peer,
             last_send_timestamp: None,
             last_poll_sent: Instant::now(),
@@ -633,7 +1094,8 @@ mod tests {
         let timestamp = timestamp.unwrap();
 
         let rec_packet = NtpPacket::deserialize(&buf, None).unwrap();
-        let send_packet = NtpPacket::timestamp_response(&system, rec_packet, timestamp, &clock);
+        let send_packet =
+            NtpPacket::timestamp_response(&system, rec_packet, timestamp, &clock, None);
 
         let serialized = serialize_packet_unencryped(&send_packet);
         socket.send(&serialized).await.unwrap();
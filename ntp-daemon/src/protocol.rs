@@ -0,0 +1,464 @@
+//! The protocol spoken over the observation and configuration sockets.
+//!
+//! Messages are JSON, one per line. A client starts by sending a [`Hello`] with the version of
+//! the protocol it speaks, which the daemon answers with a `Result<Hello, ProtocolError>`
+//! carrying its own version. After that, the client can send any number of [`Request`]s, each
+//! of which is answered with a `Result<Response, ProtocolError>`.
+//!
+//! The observation socket only answers requests for the state of the daemon, changes to the
+//! configuration are only accepted on the configuration socket.
+//!
+//! After a [`Request::Subscribe`] on the observation socket, the daemon no longer reads
+//! requests, but sends an [`Event`] per line whenever something happens.
+
+use std::{future::Future, net::SocketAddr, path::Path, time::Duration};
+
+use ntp_proto::{NtpDuration, SystemSnapshot};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncWrite, BufStream},
+    net::UnixStream,
+    sync::broadcast,
+};
+
+use crate::{
+    config::dynamic::{AddPeer, ConfigUpdate},
+    observer::{ObservablePeerHistory, ObservableServerState},
+    sockets::{read_message, write_message},
+    ObservablePeerState, ObservableState,
+};
+
+/// The version of the protocol. It changes when requests or responses change in a way that
+/// older clients or daemons cannot handle.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Requests are small, so a client cannot make the daemon buffer much more than one
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+/// Responses contain the state of all peers, which can be large
+const MAX_RESPONSE_SIZE: u64 = 16 * 1024 * 1024;
+/// Clients connect for a few requests at a time, so a client that sends nothing for this long
+/// is disconnected, and no longer occupies the socket
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The first message of a connection, in both directions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// The complete state of the daemon, answered with [`Response::State`]
+    GetState,
+    /// Answered with [`Response::System`]
+    GetSystem,
+    /// Answered with [`Response::Peers`]
+    GetPeers,
+    /// Answered with [`Response::Servers`]
+    GetServers,
+    /// The latest measurements of the peers with the given address, answered with
+    /// [`Response::History`]
+    GetHistory(String),
+    /// Answered with [`Response::Applied`]
+    UpdateConfig(ConfigUpdate),
+    /// Answered with [`Response::Applied`]
+    AddPeer(AddPeer),
+    /// Remove all configured peers with the given address, answered with [`Response::Applied`]
+    RemovePeer(String),
+    /// Answered with [`Response::Subscribed`], after which the connection only carries events
+    Subscribe,
+}
+
+impl Request {
+    /// Whether the request changes the daemon, and is therefore only
+    /// accepted on the configuration socket
+    pub fn is_change(&self) -> bool {
+        match self {
+            Request::GetState
+            | Request::GetSystem
+            | Request::GetPeers
+            | Request::GetServers
+            | Request::GetHistory(_)
+            | Request::Subscribe => false,
+            Request::UpdateConfig(_) | Request::AddPeer(_) | Request::RemovePeer(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    State(ObservableState),
+    System(SystemSnapshot),
+    Peers(Vec<ObservablePeerState>),
+    Servers(Vec<ObservableServerState>),
+    History(Vec<ObservablePeerHistory>),
+    /// The requested change was applied
+    Applied,
+    /// From now on, events are sent
+    Subscribed,
+}
+
+/// Something that happened in the daemon, sent to subscribers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    PeerAdded {
+        address: String,
+    },
+    PeerRemoved {
+        address: String,
+    },
+    ReachabilityChanged {
+        address: String,
+        reachable: bool,
+    },
+    /// The peer that the clock is synchronized to changed
+    SelectionChanged {
+        system_peer: String,
+    },
+    ClockStep {
+        offset: NtpDuration,
+    },
+    ClockSlew {
+        offset: NtpDuration,
+    },
+    /// The clock is no longer corrected and the daemon stops, see the
+    /// `panic-threshold` setting
+    PanicThresholdExceeded {
+        offset: NtpDuration,
+    },
+    /// The peer sent a Kiss-o'-Death, and is demobilized
+    KissOfDeath {
+        address: String,
+    },
+    /// Requests to the server were rate limited since the previous event for this server
+    RateLimited {
+        server: SocketAddr,
+        packets: u64,
+    },
+    /// The subscriber did not keep up, and missed some events
+    Lagged {
+        missed: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+pub enum ProtocolError {
+    #[error("protocol version {client} is not supported, the daemon speaks version {daemon}")]
+    UnsupportedVersion { client: u32, daemon: u32 },
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("this request is not accepted on this socket")]
+    UnsupportedRequest,
+    #[error("the change could not be applied: {0}")]
+    Failed(String),
+}
+
+pub type ProtocolResult<T> = Result<T, ProtocolError>;
+
+/// Answer the requests of a client with `handle`, until the client closes the connection.
+/// Clients can subscribe to `events` when given.
+pub(crate) async fn serve<F, R>(
+    stream: UnixStream,
+    events: Option<&broadcast::Sender<Event>>,
+    mut handle: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(Request) -> R,
+    R: Future<Output = ProtocolResult<Response>>,
+{
+    let mut stream = BufStream::new(stream);
+    let mut buffer = String::new();
+
+    let hello = match read_request::<Hello>(&mut stream, &mut buffer).await {
+        Ok(Some(hello)) => hello,
+        Ok(None) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            let error = ProtocolError::InvalidRequest(e.to_string());
+            return write_message(&mut stream, &ProtocolResult::<Hello>::Err(error)).await;
+        }
+        Err(e) => return Err(e),
+    };
+
+    if hello.version != PROTOCOL_VERSION {
+        let error = ProtocolError::UnsupportedVersion {
+            client: hello.version,
+            daemon: PROTOCOL_VERSION,
+        };
+        return write_message(&mut stream, &ProtocolResult::<Hello>::Err(error)).await;
+    }
+
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+    };
+    write_message(&mut stream, &ProtocolResult::Ok(hello)).await?;
+
+    loop {
+        let result = match read_request::<Request>(&mut stream, &mut buffer).await {
+            Ok(Some(Request::Subscribe)) if events.is_some() => {
+                // subscribe before answering, so no event after the answer is missed
+                let receiver = events.unwrap().subscribe();
+                write_message(&mut stream, &ProtocolResult::Ok(Response::Subscribed)).await?;
+                return send_events(&mut stream, receiver).await;
+            }
+            Ok(Some(request)) => handle(request).await,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                Err(ProtocolError::InvalidRequest(e.to_string()))
+            }
+            Err(e) => return Err(e),
+        };
+
+        write_message(&mut stream, &result).await?;
+    }
+}
+
+async fn read_request<T: serde::de::DeserializeOwned>(
+    stream: &mut BufStream<UnixStream>,
+    buffer: &mut String,
+) -> std::io::Result<Option<T>> {
+    let read = read_message(stream, buffer, MAX_REQUEST_SIZE);
+    match tokio::time::timeout(REQUEST_TIMEOUT, read).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+    }
+}
+
+async fn send_events<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    mut receiver: broadcast::Receiver<Event>,
+) -> std::io::Result<()> {
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => Event::Lagged { missed },
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+
+        match write_message(stream, &event).await {
+            Ok(()) => {}
+            // the subscriber went away
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error("the daemon closed the connection")]
+    Closed,
+    #[error("unexpected response from the daemon")]
+    UnexpectedResponse,
+}
+
+/// A connection to the observation or configuration socket of the daemon
+pub struct Client {
+    stream: BufStream<UnixStream>,
+    buffer: String,
+}
+
+impl Client {
+    /// Connect to a socket of the daemon, and check that it speaks our version of the protocol
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, ClientError> {
+        let stream = UnixStream::connect(path).await?;
+        let mut client = Client {
+            stream: BufStream::new(stream),
+            buffer: String::new(),
+        };
+
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+        };
+        write_message(&mut client.stream, &hello).await?;
+        client.receive::<Hello>().await?;
+
+        Ok(client)
+    }
+
+    async fn receive<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, ClientError> {
+        let response = read_message::<_, ProtocolResult<T>>(
+            &mut self.stream,
+            &mut self.buffer,
+            MAX_RESPONSE_SIZE,
+        );
+        match response.await? {
+            Some(result) => Ok(result?),
+            None => Err(ClientError::Closed),
+        }
+    }
+
+    pub async fn request(&mut self, request: &Request) -> Result<Response, ClientError> {
+        write_message(&mut self.stream, request).await?;
+        self.receive().await
+    }
+
+    pub async fn get_state(&mut self) -> Result<ObservableState, ClientError> {
+        match self.request(&Request::GetState).await? {
+            Response::State(state) => Ok(state),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    pub async fn get_system(&mut self) -> Result<SystemSnapshot, ClientError> {
+        match self.request(&Request::GetSystem).await? {
+            Response::System(system) => Ok(system),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    pub async fn get_peers(&mut self) -> Result<Vec<ObservablePeerState>, ClientError> {
+        match self.request(&Request::GetPeers).await? {
+            Response::Peers(peers) => Ok(peers),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    pub async fn get_servers(&mut self) -> Result<Vec<ObservableServerState>, ClientError> {
+        match self.request(&Request::GetServers).await? {
+            Response::Servers(servers) => Ok(servers),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// The latest measurements of the peers with the given address
+    pub async fn get_history(
+        &mut self,
+        address: &str,
+    ) -> Result<Vec<ObservablePeerHistory>, ClientError> {
+        match self.request(&Request::GetHistory(address.into())).await? {
+            Response::History(history) => Ok(history),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Subscribe to the events of the daemon, which are then read with [`Client::next_event`].
+    /// Only accepted on the observation socket.
+    pub async fn subscribe(&mut self) -> Result<(), ClientError> {
+        match self.request(&Request::Subscribe).await? {
+            Response::Subscribed => Ok(()),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Wait for the next event, after [`Client::subscribe`]
+    pub async fn next_event(&mut self) -> Result<Event, ClientError> {
+        match read_message(&mut self.stream, &mut self.buffer, MAX_RESPONSE_SIZE).await? {
+            Some(event) => Ok(event),
+            None => Err(ClientError::Closed),
+        }
+    }
+
+    /// Send a request that changes the daemon, which is only accepted on the
+    /// configuration socket. Returns once the change was applied.
+    pub async fn change(&mut self, request: &Request) -> Result<(), ClientError> {
+        match self.request(request).await? {
+            Response::Applied => Ok(()),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-stream-7");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, None, |request| async move {
+                match request {
+                    Request::GetPeers => Ok(Response::Peers(vec![ObservablePeerState::Nothing])),
+                    Request::RemovePeer(addr) => Err(ProtocolError::Failed(addr)),
+                    _ => Err(ProtocolError::UnsupportedRequest),
+                }
+            })
+            .await
+            .unwrap();
+        });
+
+        let mut client = Client::connect(&path).await.unwrap();
+
+        let peers = client.get_peers().await.unwrap();
+        assert!(matches!(peers[..], [ObservablePeerState::Nothing]));
+
+        assert!(matches!(
+            client.get_system().await,
+            Err(ClientError::Protocol(ProtocolError::UnsupportedRequest))
+        ));
+
+        // errors are reported per request, the connection stays usable
+        let result = client.change(&Request::RemovePeer("a".into())).await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Protocol(ProtocolError::Failed(addr))) if addr == "a"
+        ));
+        assert!(client.get_peers().await.is_ok());
+
+        // without events, subscribing is left to the handler
+        assert!(matches!(
+            client.subscribe().await,
+            Err(ClientError::Protocol(ProtocolError::UnsupportedRequest))
+        ));
+
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_version() {
+        let path = std::env::temp_dir().join("ntp-test-stream-8");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, None, |_| async { Ok(Response::Applied) })
+                .await
+                .unwrap();
+        });
+
+        let mut stream = BufStream::new(UnixStream::connect(&path).await.unwrap());
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+        };
+        write_message(&mut stream, &hello).await.unwrap();
+
+        let mut buffer = String::new();
+        let result: ProtocolResult<Hello> =
+            read_message(&mut stream, &mut buffer, MAX_RESPONSE_SIZE)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            result,
+            Err(ProtocolError::UnsupportedVersion {
+                client: PROTOCOL_VERSION + 1,
+                daemon: PROTOCOL_VERSION,
+            })
+        );
+
+        // the daemon closes the connection
+        server.await.unwrap();
+        let closed: Option<Request> = read_message(&mut stream, &mut buffer, MAX_RESPONSE_SIZE)
+            .await
+            .unwrap();
+        assert!(closed.is_none());
+    }
+}
@@ -0,0 +1,206 @@
+//! Local reference clocks, such as GPS receivers. Like a [`PeerTask`](crate::peer::PeerTask)
+//! for a network peer, a [`RefClockTask`] reads a reference clock every poll interval and
+//! hands its samples to the system as measurements.
+
+mod pps;
+mod shm;
+
+use ntp_proto::{
+    NtpInstant, NtpTimestamp, PollInterval, RefClockSample, RefClockSource, ReferenceId,
+    SystemSnapshot,
+};
+use tracing::{debug, instrument, warn, Instrument, Span};
+
+use crate::{
+    config::RefClockConfig,
+    peer::{MsgForSystem, PeerChannels},
+    system::PeerIndex,
+};
+
+pub use pps::{PpsDevice, PpsRefClock};
+pub use shm::ShmRefClock;
+
+/// A driver that reads the time from a local reference clock
+pub trait RefClockDriver: Send + 'static {
+    /// The newest sample of the clock, or `None` when the clock has not produced a new sample
+    /// since the previous call. Drivers that need another time source to complete their samples
+    /// use the state of the system for that.
+    fn sample(&mut self, system: &SystemSnapshot) -> std::io::Result<Option<RefClockSample>>;
+}
+
+/// Open the driver for a configured reference clock
+pub(crate) fn open(config: &RefClockConfig) -> std::io::Result<Box<dyn RefClockDriver>> {
+    match config {
+        RefClockConfig::Shm(config) => Ok(Box::new(ShmRefClock::new(config.unit)?)),
+        RefClockConfig::Pps(config) => {
+            let mut device = PpsDevice::open(&config.path)?;
+            if config.kernel_discipline {
+                device.bind_kernel_consumer()?;
+            }
+
+            Ok(Box::new(PpsRefClock::new(device, config.precision)))
+        }
+    }
+}
+
+/// Convert a unix timestamp of the system clock to an ntp timestamp
+pub(crate) fn unix_to_ntp_timestamp(seconds: i64, nanos: u32) -> NtpTimestamp {
+    // Unix uses an epoch located at 1/1/1970-00:00h (UTC) and NTP uses 1/1/1900-00:00h.
+    // This leads to an offset equivalent to 70 years in seconds
+    // there are 17 leap years between the two dates so the offset is
+    const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
+
+    // truncates the higher bits of the unix timestamp
+    let seconds = (seconds as u32).wrapping_add(EPOCH_OFFSET);
+
+    NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, nanos)
+}
+
+pub(crate) struct RefClockTask {
+    index: PeerIndex,
+    channels: PeerChannels,
+    source: RefClockSource,
+    driver: Box<dyn RefClockDriver>,
+}
+
+impl RefClockTask {
+    #[instrument(skip(driver, channels))]
+    pub fn spawn(
+        index: PeerIndex,
+        reference_id: ReferenceId,
+        driver: Box<dyn RefClockDriver>,
+        channels: PeerChannels,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(
+            (async move {
+                let mut process = RefClockTask {
+                    index,
+                    channels,
+                    source: RefClockSource::new(reference_id),
+                    driver,
+                };
+
+                process.run().await
+            })
+            .instrument(Span::current()),
+        )
+    }
+
+    async fn run(&mut self) {
+        loop {
+            // like peers, the reference clock is polled at the poll interval of the system
+            let poll_interval = self
+                .channels
+                .system_snapshot_receiver
+                .borrow()
+                .time_snapshot
+                .poll_interval;
+
+            self.handle_poll(poll_interval).await;
+
+            let shutdown = &mut self.channels.shutdown_receiver;
+            tokio::select! {
+                () = tokio::time::sleep(poll_interval.as_system_duration()) => {}
+                _ = shutdown.changed(), if shutdown.has_changed().is_ok() => {
+                    debug!("shutting down reference clock");
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn handle_poll(&mut self, poll_interval: PollInterval) {
+        self.source.poll(poll_interval);
+
+        let system = *self.channels.system_snapshot_receiver.borrow();
+        let msg = match self.driver.sample(&system) {
+            Ok(Some(sample)) => {
+                let (measurement, packet) = self.source.handle_sample(sample, NtpInstant::now());
+                MsgForSystem::NewMeasurement(
+                    self.index,
+                    self.source.snapshot(),
+                    measurement,
+                    packet,
+                )
+            }
+            Ok(None) => {
+                debug!("No new sample from reference clock");
+                MsgForSystem::UpdatedSnapshot(self.index, self.source.snapshot())
+            }
+            Err(error) => {
+                warn!(?error, "Could not read reference clock");
+                MsgForSystem::UpdatedSnapshot(self.index, self.source.snapshot())
+            }
+        };
+
+        self.channels.msg_for_system_sender.send(msg).await.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use ntp_proto::{NtpDuration, NtpLeapIndicator};
+    use tokio::sync::mpsc;
+
+    use crate::config::CombinedSystemConfig;
+
+    use super::*;
+
+    struct TestDriver {
+        samples: VecDeque<RefClockSample>,
+    }
+
+    impl RefClockDriver for TestDriver {
+        fn sample(&mut self, _system: &SystemSnapshot) -> std::io::Result<Option<RefClockSample>> {
+            Ok(self.samples.pop_front())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_samples_become_measurements() {
+        let local_time = NtpTimestamp::from_seconds_nanos_since_ntp_era(100, 0);
+        let sample = RefClockSample {
+            reference_time: local_time + NtpDuration::from_seconds(0.5),
+            local_time,
+            leap: NtpLeapIndicator::NoWarning,
+            precision: -20,
+        };
+
+        let (_, system_snapshot_receiver) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let (_, system_config_receiver) =
+            tokio::sync::watch::channel(CombinedSystemConfig::default());
+        let (_, shutdown_receiver) = tokio::sync::watch::channel(false);
+        let (msg_for_system_sender, mut msg_for_system_receiver) = mpsc::channel(1);
+
+        let mut process = RefClockTask {
+            index: PeerIndex::from_inner(0),
+            channels: PeerChannels {
+                msg_for_system_sender,
+                system_snapshot_receiver,
+                system_config_receiver,
+                shutdown_receiver,
+            },
+            source: RefClockSource::new(ReferenceId::from_bytes(*b"GPS\0")),
+            driver: Box::new(TestDriver {
+                samples: VecDeque::from([sample]),
+            }),
+        };
+
+        process.handle_poll(PollInterval::default()).await;
+        let msg = msg_for_system_receiver.recv().await.unwrap();
+        let (snapshot, measurement) = match msg {
+            MsgForSystem::NewMeasurement(_, snapshot, measurement, _) => (snapshot, measurement),
+            msg => panic!("expected a measurement, got {msg:?}"),
+        };
+        assert_eq!(snapshot.stratum, 0);
+        assert!(snapshot.reach.is_reachable());
+        assert_eq!(measurement.offset, NtpDuration::from_seconds(0.5));
+
+        // without a new sample, only the reachability changes
+        process.handle_poll(PollInterval::default()).await;
+        let msg = msg_for_system_receiver.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _)));
+    }
+}
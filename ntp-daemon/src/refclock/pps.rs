@@ -0,0 +1,294 @@
+use std::{fs::File, io, os::unix::prelude::AsRawFd, path::Path};
+
+use libc::{c_int, c_uint, c_ulong};
+use ntp_proto::{NtpTimestamp, RefClockSample, SystemSnapshot};
+use tracing::{debug, warn};
+
+use super::{unix_to_ntp_timestamp, RefClockDriver};
+
+// Capture the assert edge of the pulse, as timespec
+const PPS_CAPTUREASSERT: c_int = 0x01;
+const PPS_TSFMT_TSPEC: c_int = 0x1000;
+
+const IOC_WRITE: c_ulong = 1;
+const IOC_READ: c_ulong = 2;
+
+/// The ioctl request numbers of linux/pps.h. The argument size in these numbers is that of a
+/// pointer, as in the definitions of the header.
+const fn pps_ioctl(direction: c_ulong, number: c_ulong) -> c_ulong {
+    (direction << 30)
+        | ((std::mem::size_of::<usize>() as c_ulong) << 16)
+        | ((b'p' as c_ulong) << 8)
+        | number
+}
+
+const PPS_GETPARAMS: c_ulong = pps_ioctl(IOC_READ, 0xa1);
+const PPS_SETPARAMS: c_ulong = pps_ioctl(IOC_WRITE, 0xa2);
+const PPS_FETCH: c_ulong = pps_ioctl(IOC_READ | IOC_WRITE, 0xa4);
+const PPS_KC_BIND: c_ulong = pps_ioctl(IOC_WRITE, 0xa5);
+
+/// The kernel consumer that disciplines the system clock
+const PPS_KC_HARDPPS: c_int = 0;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsKtime {
+    sec: i64,
+    nsec: i32,
+    flags: c_uint,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsKparams {
+    api_version: c_int,
+    mode: c_int,
+    assert_off_tu: PpsKtime,
+    clear_off_tu: PpsKtime,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsKinfo {
+    assert_sequence: c_uint,
+    clear_sequence: c_uint,
+    assert_tu: PpsKtime,
+    clear_tu: PpsKtime,
+    current_mode: c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsFdata {
+    info: PpsKinfo,
+    timeout: PpsKtime,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsBindArgs {
+    tsformat: c_int,
+    edge: c_int,
+    consumer: c_int,
+}
+
+/// A pulse, timestamped by the system clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PpsPulse {
+    /// Number of the pulse, which increases by one with every pulse
+    pub sequence: u32,
+    pub timestamp: NtpTimestamp,
+}
+
+/// Reads the pulses of a pulse per second device
+pub trait PpsReader: Send + 'static {
+    /// The most recent pulse, or `None` when the device has not seen a pulse yet
+    fn fetch(&mut self) -> io::Result<Option<PpsPulse>>;
+}
+
+/// A pulse per second device of the kernel, read through the interface of RFC 2783
+pub struct PpsDevice {
+    file: File,
+    bound: bool,
+}
+
+impl PpsDevice {
+    /// Open the device, and make it capture the assert edge of the pulses
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let device = Self {
+            file: File::open(path)?,
+            bound: false,
+        };
+
+        let mut params = PpsKparams::default();
+        device.ioctl(PPS_GETPARAMS, &mut params)?;
+
+        if params.mode & PPS_CAPTUREASSERT == 0 {
+            // changing the parameters requires the CAP_SYS_TIME capability
+            params.mode |= PPS_CAPTUREASSERT | PPS_TSFMT_TSPEC;
+            device.ioctl(PPS_SETPARAMS, &mut params)?;
+        }
+
+        debug!(?path, mode = params.mode, "opened pps device");
+
+        Ok(device)
+    }
+
+    /// Bind the device to the kernel, so that the kernel can discipline the system clock with
+    /// the assert edge of its pulses. Binding requires the CAP_SYS_TIME capability, and only one
+    /// device can be bound at a time.
+    pub fn bind_kernel_consumer(&mut self) -> io::Result<()> {
+        self.bind(PPS_CAPTUREASSERT)?;
+        self.bound = true;
+
+        Ok(())
+    }
+
+    /// Binding without an edge unbinds the device
+    fn bind(&self, edge: c_int) -> io::Result<()> {
+        let mut args = PpsBindArgs {
+            tsformat: PPS_TSFMT_TSPEC,
+            edge,
+            consumer: PPS_KC_HARDPPS,
+        };
+
+        self.ioctl(PPS_KC_BIND, &mut args)
+    }
+
+    fn ioctl<T>(&self, request: c_ulong, argument: &mut T) -> io::Result<()> {
+        // Safety: the argument is the structure that the kernel expects for the request, and
+        // lives for the duration of the call
+        let result = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                request as _,
+                argument as *mut T as *mut libc::c_void,
+            )
+        };
+
+        if result == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for PpsDevice {
+    fn drop(&mut self) {
+        if self.bound {
+            if let Err(error) = self.bind(0) {
+                warn!(?error, "could not unbind pps device from the kernel");
+            }
+        }
+    }
+}
+
+impl PpsReader for PpsDevice {
+    fn fetch(&mut self) -> io::Result<Option<PpsPulse>> {
+        // with a timeout of zero, the fetch returns the most recent pulse without waiting
+        let mut data = PpsFdata::default();
+
+        self.ioctl(PPS_FETCH, &mut data)?;
+
+        if data.info.assert_sequence == 0 {
+            return Ok(None);
+        }
+
+        let timestamp = data.info.assert_tu;
+        Ok(Some(PpsPulse {
+            sequence: data.info.assert_sequence,
+            timestamp: unix_to_ntp_timestamp(timestamp.sec, timestamp.nsec as u32),
+        }))
+    }
+}
+
+/// A reference clock that turns the pulses of a pulse per second device into samples
+pub struct PpsRefClock<R = PpsDevice> {
+    reader: R,
+    precision: i8,
+    last_sequence: Option<u32>,
+}
+
+impl<R: PpsReader> PpsRefClock<R> {
+    pub fn new(reader: R, precision: i8) -> Self {
+        Self {
+            reader,
+            precision,
+            last_sequence: None,
+        }
+    }
+}
+
+impl<R: PpsReader> RefClockDriver for PpsRefClock<R> {
+    fn sample(&mut self, system: &SystemSnapshot) -> io::Result<Option<RefClockSample>> {
+        let pulse = match self.reader.fetch()? {
+            Some(pulse) => pulse,
+            None => return Ok(None),
+        };
+
+        if self.last_sequence.replace(pulse.sequence) == Some(pulse.sequence) {
+            // no new pulse since the previous sample
+            return Ok(None);
+        }
+
+        // The seconds are numbered by the system clock. Until another source has synchronized
+        // it, that clock can be off by more than half a second.
+        let leap = system.time_snapshot.leap_indicator;
+        if !leap.is_synchronized() {
+            debug!("Pulse ignored, there is no coarse time source to number the seconds");
+            return Ok(None);
+        }
+
+        Ok(Some(RefClockSample::from_pulse(
+            pulse.timestamp,
+            leap,
+            self.precision,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{NtpDuration, NtpLeapIndicator};
+
+    use super::*;
+
+    struct TestReader {
+        pulse: Option<PpsPulse>,
+    }
+
+    impl PpsReader for TestReader {
+        fn fetch(&mut self) -> io::Result<Option<PpsPulse>> {
+            Ok(self.pulse)
+        }
+    }
+
+    fn synchronized() -> SystemSnapshot {
+        let mut system = SystemSnapshot::default();
+        system.time_snapshot.leap_indicator = NtpLeapIndicator::NoWarning;
+        system
+    }
+
+    #[test]
+    fn test_pulse_sample() {
+        let second = NtpTimestamp::from_seconds_nanos_since_ntp_era(100, 0);
+        let mut clock = PpsRefClock::new(TestReader { pulse: None }, -24);
+
+        // no pulse seen yet
+        assert_eq!(clock.sample(&synchronized()).unwrap(), None);
+
+        clock.reader.pulse = Some(PpsPulse {
+            sequence: 1,
+            timestamp: second - NtpDuration::from_seconds(0.001),
+        });
+        let sample = clock.sample(&synchronized()).unwrap().unwrap();
+        assert_eq!(sample.reference_time, second);
+        assert_eq!(sample.precision, -24);
+        assert_eq!(sample.leap, NtpLeapIndicator::NoWarning);
+
+        // the same pulse is used once
+        assert_eq!(clock.sample(&synchronized()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_pulse_needs_coarse_time() {
+        let mut clock = PpsRefClock::new(
+            TestReader {
+                pulse: Some(PpsPulse {
+                    sequence: 1,
+                    timestamp: NtpTimestamp::from_seconds_nanos_since_ntp_era(100, 0),
+                }),
+            },
+            -20,
+        );
+
+        assert_eq!(clock.sample(&SystemSnapshot::default()).unwrap(), None);
+
+        clock.reader.pulse = Some(PpsPulse {
+            sequence: 2,
+            timestamp: NtpTimestamp::from_seconds_nanos_since_ntp_era(101, 0),
+        });
+        assert!(clock.sample(&synchronized()).unwrap().is_some());
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    io,
+    sync::atomic::{fence, Ordering},
+};
+
+use libc::{c_int, c_uint, time_t};
+use ntp_proto::{NtpLeapIndicator, NtpTimestamp, RefClockSample, SystemSnapshot};
+use tracing::{debug, warn};
+
+use super::{unix_to_ntp_timestamp, RefClockDriver};
+
+/// Key of the shared memory segment of unit 0, the key of unit `n` is `SHM_KEY_BASE + n`
+const SHM_KEY_BASE: libc::key_t = 0x4e545030;
+
+/// The layout of a shared memory segment, as defined by the SHM driver of ntpd
+#[repr(C)]
+struct ShmTime {
+    /// 0: the writer only sets `valid` after writing. 1: the writer also increments `count`
+    /// before and after writing, so that a reader can detect a concurrent write.
+    mode: c_int,
+    count: c_int,
+    clock_timestamp_sec: time_t,
+    clock_timestamp_usec: c_int,
+    receive_timestamp_sec: time_t,
+    receive_timestamp_usec: c_int,
+    leap: c_int,
+    precision: c_int,
+    nsamples: c_int,
+    valid: c_int,
+    clock_timestamp_nsec: c_uint,
+    receive_timestamp_nsec: c_uint,
+    dummy: [c_int; 8],
+}
+
+/// A reference clock that reads the shared memory segment that gpsd and ptp4l write their
+/// samples to, in the format of the SHM driver of ntpd.
+pub struct ShmRefClock {
+    id: c_int,
+    segment: *mut ShmTime,
+}
+
+// the segment is only accessed through &mut self
+unsafe impl Send for ShmRefClock {}
+
+impl ShmRefClock {
+    /// Attach to the segment of the given unit, creating it when it does not exist yet. Like
+    /// ntpd, the segments of units 0 and 1 are only accessible by root.
+    pub fn new(unit: u32) -> io::Result<Self> {
+        let permissions = if unit < 2 { 0o600 } else { 0o666 };
+
+        Self::attach(SHM_KEY_BASE.wrapping_add(unit as libc::key_t), permissions)
+    }
+
+    fn attach(key: libc::key_t, permissions: c_int) -> io::Result<Self> {
+        // Safety: shmget has no memory safety requirements
+        let id = unsafe {
+            libc::shmget(
+                key,
+                std::mem::size_of::<ShmTime>(),
+                libc::IPC_CREAT | permissions,
+            )
+        };
+        if id == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safety: the segment is at least as large as a ShmTime, and the kernel picks an
+        // address at which it does not overlap other memory
+        let segment = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+        if segment as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        debug!(key, id, "attached shared memory segment");
+
+        Ok(Self {
+            id,
+            segment: segment.cast(),
+        })
+    }
+
+    /// Read the segment following the protocol of its mode. Another process writes to the
+    /// segment at any moment, so all fields are read with volatile reads.
+    fn read(&mut self) -> Option<RefClockSample> {
+        let segment = self.segment;
+
+        macro_rules! read {
+            ($field:ident) => {
+                // Safety: the segment stays attached as long as self exists
+                unsafe { std::ptr::addr_of!((*segment).$field).read_volatile() }
+            };
+        }
+
+        if read!(valid) == 0 {
+            return None;
+        }
+
+        let mode = read!(mode);
+        let count = read!(count);
+        fence(Ordering::Acquire);
+
+        let clock_sec = read!(clock_timestamp_sec);
+        let clock_usec = read!(clock_timestamp_usec);
+        let clock_nsec = read!(clock_timestamp_nsec);
+        let receive_sec = read!(receive_timestamp_sec);
+        let receive_usec = read!(receive_timestamp_usec);
+        let receive_nsec = read!(receive_timestamp_nsec);
+        let leap = read!(leap);
+        let precision = read!(precision);
+
+        fence(Ordering::Acquire);
+
+        // Safety: the segment stays attached as long as self exists
+        unsafe { std::ptr::addr_of_mut!((*segment).valid).write_volatile(0) };
+
+        match mode {
+            0 => {}
+            1 if read!(count) == count => {}
+            1 => {
+                debug!("shared memory segment was written while reading it");
+                return None;
+            }
+            _ => {
+                warn!(mode, "shared memory segment has an unknown mode");
+                return None;
+            }
+        }
+
+        let leap = match leap {
+            0 => NtpLeapIndicator::NoWarning,
+            1 => NtpLeapIndicator::Leap61,
+            2 => NtpLeapIndicator::Leap59,
+            _ => NtpLeapIndicator::Unknown,
+        };
+
+        Some(RefClockSample {
+            reference_time: read_ntp_timestamp(clock_sec, clock_usec, clock_nsec),
+            local_time: read_ntp_timestamp(receive_sec, receive_usec, receive_nsec),
+            leap,
+            precision: precision as i8,
+        })
+    }
+
+    #[cfg(test)]
+    fn write(&mut self, mode: c_int, reference: libc::timespec, local: libc::timespec) {
+        let segment = self.segment;
+
+        macro_rules! write {
+            ($field:ident, $value:expr) => {
+                // Safety: the segment stays attached as long as self exists
+                unsafe { std::ptr::addr_of_mut!((*segment).$field).write_volatile($value) }
+            };
+        }
+
+        write!(mode, mode);
+        write!(count, 1);
+        write!(clock_timestamp_sec, reference.tv_sec);
+        write!(clock_timestamp_usec, (reference.tv_nsec / 1000) as c_int);
+        write!(clock_timestamp_nsec, reference.tv_nsec as c_uint);
+        write!(receive_timestamp_sec, local.tv_sec);
+        write!(receive_timestamp_usec, (local.tv_nsec / 1000) as c_int);
+        write!(receive_timestamp_nsec, local.tv_nsec as c_uint);
+        write!(leap, 0);
+        write!(precision, -20);
+        write!(valid, 1);
+    }
+}
+
+impl RefClockDriver for ShmRefClock {
+    fn sample(&mut self, _system: &SystemSnapshot) -> io::Result<Option<RefClockSample>> {
+        Ok(self.read())
+    }
+}
+
+impl Drop for ShmRefClock {
+    fn drop(&mut self) {
+        // Safety: the segment was attached by us, and is not used after this point
+        if unsafe { libc::shmdt(self.segment.cast()) } == -1 {
+            warn!(
+                error = ?io::Error::last_os_error(),
+                id = self.id,
+                "could not detach shared memory segment"
+            );
+        }
+    }
+}
+
+/// Writers that predate the nanosecond fields leave them at zero, in which case only the
+/// microsecond field is used
+fn read_ntp_timestamp(sec: time_t, usec: c_int, nsec: c_uint) -> NtpTimestamp {
+    let nanos = if nsec / 1000 == usec as c_uint {
+        nsec
+    } else {
+        usec as c_uint * 1000
+    };
+
+    // time_t is 32 bits on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    unix_to_ntp_timestamp(sec as i64, nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A segment that is not visible to other processes, and that is removed once detached
+    fn private_segment() -> ShmRefClock {
+        let clock = ShmRefClock::attach(libc::IPC_PRIVATE, 0o600).unwrap();
+
+        // Safety: removing a segment has no memory safety requirements
+        let result = unsafe { libc::shmctl(clock.id, libc::IPC_RMID, std::ptr::null_mut()) };
+        assert_eq!(result, 0);
+
+        clock
+    }
+
+    #[test]
+    fn test_read_sample() {
+        let mut clock = private_segment();
+
+        // a new segment contains no sample
+        assert_eq!(clock.sample(&SystemSnapshot::default()).unwrap(), None);
+
+        let reference = libc::timespec {
+            tv_sec: 1_600_000_000,
+            tv_nsec: 500_000_000,
+        };
+        let local = libc::timespec {
+            tv_sec: 1_600_000_000,
+            tv_nsec: 250_000_000,
+        };
+
+        for mode in [0, 1] {
+            clock.write(mode, reference, local);
+
+            let sample = clock.sample(&SystemSnapshot::default()).unwrap().unwrap();
+            let offset = sample.reference_time - sample.local_time;
+            assert!((offset.to_seconds() - 0.25).abs() < 1e-9);
+            assert_eq!(sample.leap, NtpLeapIndicator::NoWarning);
+            assert_eq!(sample.precision, -20);
+
+            // every sample is used once
+            assert_eq!(clock.sample(&SystemSnapshot::default()).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_microsecond_writer() {
+        let epoch = read_ntp_timestamp(0, 0, 0);
+
+        let timestamp = read_ntp_timestamp(0, 250_000, 0);
+        assert!(((timestamp - epoch).to_seconds() - 0.25).abs() < 1e-9);
+
+        let timestamp = read_ntp_timestamp(0, 250_000, 250_000_123);
+        assert!(((timestamp - epoch).to_seconds() - 0.250_000_123).abs() < 1e-9);
+    }
+}
@@ -1,17 +1,40 @@
 use std::{
+    collections::{HashMap, VecDeque},
     io::Cursor,
     net::{IpAddr, SocketAddr},
+    sync::{atomic::AtomicU64, Arc},
     time::{Duration, Instant},
 };
 
-use ntp_proto::{NtpAssociationMode, NtpClock, NtpPacket, NtpTimestamp, SystemSnapshot};
+use ntp_proto::{
+    DecodedServerCookie, KeySet, LeapSmear, NtpAssociationMode, NtpClock, NtpPacket, NtpTimestamp,
+    SymmetricKey, SystemSnapshot,
+};
 use ntp_udp::UdpSocket;
-use prometheus_client::metrics::{counter::Counter, gauge::Atomic};
+use prometheus_client::metrics::{
+    counter::Counter,
+    gauge::{Atomic, Gauge},
+};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use tokio::task::JoinHandle;
-use tracing::{error, info, instrument, trace, warn};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, instrument, trace, warn};
 
-use crate::config::{FilterAction, ServerConfig};
+use crate::{
+    config::{FilterAction, ServerConfig, SymmetricKeys, TimestampingMode},
+    peer::{ForwardedPacket, PassiveAssociationRequest},
+};
+
+/// The maximum number of passive associations a single server spawns
+const MAX_PASSIVE_ASSOCIATIONS: usize = 8;
+/// The number of packets that may be waiting to be handled by a passive association
+const PASSIVE_ASSOCIATION_BUFFER_SIZE: usize = 4;
+/// The number of clients for which the timestamps of the last response are kept
+const INTERLEAVED_CACHE_SIZE: usize = 4096;
+/// The number of responses to clients in interleaved mode whose transmit timestamp is awaited
+const MAX_PENDING_TRANSMITS: usize = 32;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ServerStats {
@@ -20,7 +43,13 @@ pub struct ServerStats {
     pub denied_packets: WrappedCounter,
     pub ignored_packets: WrappedCounter,
     pub rate_limited_packets: WrappedCounter,
+    pub nts_nak_packets: WrappedCounter,
+    pub crypto_nak_packets: WrappedCounter,
+    pub broadcast_packets: WrappedCounter,
     pub response_send_errors: WrappedCounter,
+    /// Offset of the served time from the local clock in seconds, while smearing a leap second
+    #[serde(default)]
+    pub leap_smear_offset: WrappedGauge,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -55,46 +84,114 @@ impl std::ops::Deref for WrappedCounter {
     }
 }
 
+#[derive(Default, Debug, Clone)]
+pub struct WrappedGauge(Gauge<f64, AtomicU64>);
+
+impl Serialize for WrappedGauge {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_f64(self.0.get())
+    }
+}
+
+impl<'de> Deserialize<'de> for WrappedGauge {
+    fn deserialize<D>(deserializer: D) -> Result<WrappedGauge, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let d: f64 = Deserialize::deserialize(deserializer)?;
+        let gauge: Gauge<f64, AtomicU64> = Default::default();
+        gauge.set(d);
+        Ok(WrappedGauge(gauge))
+    }
+}
+
+impl std::ops::Deref for WrappedGauge {
+    type Target = Gauge<f64, AtomicU64>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 pub struct ServerTask<C: 'static + NtpClock + Send> {
     config: ServerConfig,
     network_wait_period: std::time::Duration,
     system_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
     system: SystemSnapshot,
     client_cache: TimestampedCache<SocketAddr>,
+    interleaved_cache: InterleavedCache,
+    /// Responses to clients in interleaved mode of which the transmit timestamp is not yet
+    /// taken, with the client, the receive timestamp and the id of the packet
+    pending_transmits: VecDeque<(SocketAddr, NtpTimestamp, u32)>,
     clock: C,
+    leap_smear: Option<LeapSmear>,
+    keyset: watch::Receiver<Arc<KeySet>>,
+    keys: Arc<SymmetricKeys>,
     stats: ServerStats,
+    passive: HashMap<SocketAddr, mpsc::Sender<ForwardedPacket>>,
+    passive_sender: mpsc::Sender<PassiveAssociationRequest>,
+    shutdown_receiver: watch::Receiver<bool>,
+}
+
+/// How the response to an accepted request is authenticated
+#[derive(Debug)]
+enum Authentication {
+    None,
+    Nts(DecodedServerCookie),
+    Mac(SymmetricKey),
 }
 
 #[derive(Debug)]
 enum AcceptResult<'a> {
-    Accept(NtpPacket<'a>, SocketAddr, NtpTimestamp),
+    Accept(NtpPacket<'a>, SocketAddr, NtpTimestamp, Authentication),
     Ignore,
     Deny(NtpPacket<'a>, SocketAddr),
     RateLimit(NtpPacket<'a>, SocketAddr),
+    NtsNak(NtpPacket<'a>, SocketAddr),
+    CryptoNak(NtpPacket<'a>, SocketAddr, NtpTimestamp),
+    /// A packet of a symmetric active peer, handled by a passive association
+    Symmetric(&'a [u8], SocketAddr, NtpTimestamp, SymmetricKey),
     NetworkGone,
 }
 
 impl<C: 'static + NtpClock + Send> ServerTask<C> {
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         config: ServerConfig,
         stats: ServerStats,
         mut system_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
         clock: C,
+        keyset: watch::Receiver<Arc<KeySet>>,
+        keys: Arc<SymmetricKeys>,
+        passive_sender: mpsc::Sender<PassiveAssociationRequest>,
+        shutdown_receiver: watch::Receiver<bool>,
         network_wait_period: Duration,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
             let rate_limiting_cutoff = config.rate_limiting_cutoff;
             let rate_limiting_cache_size = config.rate_limiting_cache_size;
             let system = *system_receiver.borrow_and_update();
+            let leap_smear = config.leap_smear.map(LeapSmear::new);
 
             let mut process = ServerTask {
                 config,
+                leap_smear,
                 network_wait_period,
                 system,
                 system_receiver,
                 clock,
+                keyset,
+                keys,
                 client_cache: TimestampedCache::new(rate_limiting_cache_size),
+                interleaved_cache: InterleavedCache::new(INTERLEAVED_CACHE_SIZE),
+                pending_transmits: VecDeque::new(),
                 stats,
+                passive: HashMap::new(),
+                passive_sender,
+                shutdown_receiver,
             };
 
             process.serve(rate_limiting_cutoff).await
@@ -118,13 +215,31 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
     ))]
     async fn serve(&mut self, rate_limiting_cutoff: Duration) {
         let mut cur_socket = None;
+        let mut broadcast_timer = tokio::time::interval(self.config.broadcast_interval);
         loop {
             let socket = if let Some(ref socket) = cur_socket {
                 socket
             } else {
                 cur_socket = Some(loop {
                     match UdpSocket::server(self.config.addr).await {
-                        Ok(socket) => break socket,
+                        Ok(mut socket) => {
+                            if let TimestampingMode::Hardware { interface } =
+                                self.config.timestamping
+                            {
+                                if let Err(error) = socket.enable_hardware_timestamping(interface) {
+                                    warn!(
+                                        ?error,
+                                        "Could not enable hardware timestamping, falling back to software timestamping"
+                                    );
+                                }
+                            }
+                            if self.config.broadcast_addr.is_some() {
+                                if let Err(error) = socket.enable_broadcast() {
+                                    warn!(?error, "Could not enable broadcasting");
+                                }
+                            }
+                            break Arc::new(socket);
+                        }
                         Err(error) => {
                             warn!(?error, "Could not open server socket");
                             tokio::time::sleep(self.network_wait_period).await;
@@ -137,24 +252,136 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                 cur_socket.as_ref().unwrap()
             };
 
-            let mut buf = [0_u8; 48];
+            // NTS requests carry extension fields, so they are larger than a plain NTP packet
+            let mut buf = [0_u8; 1024];
             tokio::select! {
                 recv_res = socket.recv(&mut buf) => {
+                    self.take_pending_transmits(socket);
                     if !self.serve_packet(socket, &buf, recv_res, rate_limiting_cutoff).await {
+                        // the passive associations stop when they can no longer receive packets
+                        self.passive.clear();
+                        self.pending_transmits.clear();
                         cur_socket = None;
                     }
                 },
+                _ = broadcast_timer.tick(), if self.config.broadcast_addr.is_some() => {
+                    if let Some(broadcast_addr) = self.config.broadcast_addr {
+                        self.broadcast(socket, broadcast_addr).await;
+                    }
+                }
                 _ = self.system_receiver.changed(), if self.system_receiver.has_changed().is_ok() => {
                     self.system = *self.system_receiver.borrow_and_update();
                 }
+                _ = self.shutdown_receiver.changed(), if self.shutdown_receiver.has_changed().is_ok() => {
+                    debug!("shutting down server");
+                    break;
+                }
+            }
+        }
+    }
+
+    fn update_leap_smear_offset(&self) {
+        if let Some(leap_smear) = &self.leap_smear {
+            self.stats
+                .leap_smear_offset
+                .set(leap_smear.last_offset().to_seconds());
+        }
+    }
+
+    async fn broadcast(&mut self, socket: &UdpSocket, broadcast_addr: SocketAddr) {
+        let packet = NtpPacket::broadcast_message(
+            &self.system,
+            self.system.time_snapshot.poll_interval,
+            &self.clock,
+            self.leap_smear.as_mut(),
+        );
+        self.update_leap_smear_offset();
+
+        // clients only accept authenticated broadcasts, the key id is checked when the
+        // server is configured
+        let key = match self
+            .config
+            .broadcast_key_id
+            .and_then(|key_id| self.keys.get(key_id))
+        {
+            Some(key) => key,
+            None => {
+                error!("No key to authenticate broadcasts with");
+                return;
+            }
+        };
+
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+
+        if let Err(serialize_err) = packet.serialize_with_mac(&mut cursor, None, key) {
+            error!(error=?serialize_err, "Could not serialize broadcast");
+            return;
+        }
+
+        match socket
+            .send_to(
+                &cursor.get_ref()[0..cursor.position() as usize],
+                broadcast_addr,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.stats.broadcast_packets.inc();
+            }
+            Err(send_err) => {
+                self.stats.response_send_errors.inc();
+                warn!(error=?send_err, "Could not send broadcast packet");
+            }
+        }
+    }
+
+    // Packets are timestamped with the system clock, which need not be the clock we serve
+    fn convert_system_time(&self, timestamp: NtpTimestamp) -> Option<NtpTimestamp> {
+        match self.clock.convert_system_time(timestamp) {
+            Ok(timestamp) => Some(timestamp),
+            Err(error) => {
+                warn!(?error, "Could not convert a timestamp of the system clock");
+                None
             }
         }
     }
 
+    /// Complete the interleaved cache with the times at which earlier responses to clients in
+    /// interleaved mode left, as far as the kernel provides them by now
+    fn take_pending_transmits(&mut self, socket: &UdpSocket) {
+        let mut pending_transmits = std::mem::take(&mut self.pending_transmits);
+
+        pending_transmits.retain(|(peer_addr, receive_timestamp, id)| {
+            let transmit_timestamp = match socket.take_send_timestamp(*id) {
+                Some(transmit_timestamp) => transmit_timestamp,
+                None => return true,
+            };
+
+            if let Some(transmit_timestamp) = self.convert_system_time(transmit_timestamp) {
+                let transmit_timestamp = match &mut self.leap_smear {
+                    Some(leap_smear) => {
+                        transmit_timestamp + leap_smear.offset(&self.system, transmit_timestamp)
+                    }
+                    None => transmit_timestamp,
+                };
+                self.interleaved_cache.insert_transmit(
+                    *peer_addr,
+                    *receive_timestamp,
+                    transmit_timestamp,
+                );
+            }
+
+            false
+        });
+
+        self.pending_transmits = pending_transmits;
+    }
+
     async fn serve_packet(
         &mut self,
-        socket: &UdpSocket,
-        buf: &[u8; 48],
+        socket: &Arc<UdpSocket>,
+        buf: &[u8],
         recv_res: std::io::Result<(usize, SocketAddr, Option<NtpTimestamp>)>,
         rate_limiting_cutoff: Duration,
     ) -> bool {
@@ -162,30 +389,109 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         let accept_result = self.accept_packet(rate_limiting_cutoff, recv_res, buf);
 
         match accept_result {
-            AcceptResult::Accept(packet, peer_addr, recv_timestamp) => {
+            AcceptResult::Accept(packet, peer_addr, recv_timestamp, authentication) => {
+                let recv_timestamp = match self.convert_system_time(recv_timestamp) {
+                    Some(recv_timestamp) => recv_timestamp,
+                    None => {
+                        self.stats.ignored_packets.inc();
+                        return true;
+                    }
+                };
                 self.stats.accepted_packets.inc();
 
-                let response = NtpPacket::timestamp_response(
-                    &self.system,
-                    packet,
-                    recv_timestamp,
-                    &self.clock,
-                );
-
-                let mut buf = [0; 48];
+                // A client in interleaved mode refers to the receive timestamp of our previous
+                // response, and gets the time at which that response actually left. NTS clients
+                // never use interleaved mode.
+                let is_nts = matches!(authentication, Authentication::Nts(_));
+                let interleaved_request = !is_nts
+                    && self
+                        .interleaved_cache
+                        .is_interleaved_request(peer_addr, packet.origin_timestamp());
+                let previous_transmit = self
+                    .interleaved_cache
+                    .previous_transmit(peer_addr, packet.origin_timestamp());
+                let request_receive_timestamp = packet.receive_timestamp();
+
+                let mut buf = [0; 1024];
                 let mut cursor = Cursor::new(buf.as_mut_slice());
 
-                if let Err(serialize_err) = response.serialize(&mut cursor, None) {
+                let keyset = self.keyset.borrow().clone();
+                let response = match &authentication {
+                    Authentication::Nts(cookie) => NtpPacket::nts_timestamp_response(
+                        &self.system,
+                        packet,
+                        recv_timestamp,
+                        &self.clock,
+                        cookie,
+                        &keyset,
+                        self.leap_smear.as_mut(),
+                    ),
+                    Authentication::Mac(_) | Authentication::None => NtpPacket::timestamp_response(
+                        &self.system,
+                        packet,
+                        recv_timestamp,
+                        &self.clock,
+                        self.leap_smear.as_mut(),
+                    ),
+                };
+                self.update_leap_smear_offset();
+
+                // the client refers to the timestamps as they were sent, which may be smeared
+                let response_receive_timestamp = response.receive_timestamp();
+
+                let response = match previous_transmit {
+                    Some(transmit_timestamp) => {
+                        response.into_interleaved(request_receive_timestamp, transmit_timestamp)
+                    }
+                    None => response,
+                };
+
+                let serialize_result = match authentication {
+                    Authentication::Nts(cookie) => {
+                        response.serialize(&mut cursor, Some(&cookie.s2c()))
+                    }
+                    Authentication::Mac(key) => {
+                        response.serialize_with_mac(&mut cursor, None, &key)
+                    }
+                    Authentication::None => response.serialize(&mut cursor, None),
+                };
+
+                if let Err(serialize_err) = serialize_result {
                     error!(error=?serialize_err, "Could not serialize response");
                     return true;
                 }
 
-                if let Err(send_err) = socket
-                    .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
-                    .await
-                {
-                    self.stats.response_send_errors.inc();
-                    warn!(error=?send_err, "Could not send response packet");
+                if !is_nts {
+                    self.interleaved_cache
+                        .insert(peer_addr, response_receive_timestamp);
+                }
+
+                let response = &cursor.get_ref()[0..cursor.position() as usize];
+                if !interleaved_request {
+                    if let Err(send_err) = socket.send_to(response, peer_addr).await {
+                        self.stats.response_send_errors.inc();
+                        warn!(error=?send_err, "Could not send response packet");
+                    }
+                    return true;
+                }
+
+                // waiting for the time at which the response left would hold up the next
+                // request, so it is taken when the next packet arrives
+                match socket.send_to_with_id(response, peer_addr).await {
+                    Ok((_, id)) => {
+                        if self.pending_transmits.len() >= MAX_PENDING_TRANSMITS {
+                            self.pending_transmits.pop_front();
+                        }
+                        self.pending_transmits.push_back((
+                            peer_addr,
+                            response_receive_timestamp,
+                            id,
+                        ));
+                    }
+                    Err(send_err) => {
+                        self.stats.response_send_errors.inc();
+                        warn!(error=?send_err, "Could not send response packet");
+                    }
                 }
             }
             AcceptResult::Deny(packet, peer_addr) => {
@@ -232,6 +538,72 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                     warn!(error=?send_err, "Could not send response packet");
                 }
             }
+            AcceptResult::NtsNak(packet, peer_addr) => {
+                self.stats.nts_nak_packets.inc();
+                let response = NtpPacket::nts_nak_response(packet);
+
+                let mut buf = [0; 1024];
+                let mut cursor = Cursor::new(buf.as_mut_slice());
+
+                if let Err(serialize_err) = response.serialize(&mut cursor, None) {
+                    self.stats.response_send_errors.inc();
+                    error!(error=?serialize_err, "Could not serialize response");
+                    return true;
+                }
+                if let Err(send_err) = socket
+                    .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
+                    .await
+                {
+                    self.stats.response_send_errors.inc();
+                    warn!(error=?send_err, "Could not send NTS NAK packet");
+                }
+            }
+            AcceptResult::CryptoNak(packet, peer_addr, recv_timestamp) => {
+                let recv_timestamp = match self.convert_system_time(recv_timestamp) {
+                    Some(recv_timestamp) => recv_timestamp,
+                    None => {
+                        self.stats.ignored_packets.inc();
+                        return true;
+                    }
+                };
+                self.stats.crypto_nak_packets.inc();
+                let response = NtpPacket::crypto_nak_response(
+                    &self.system,
+                    packet,
+                    recv_timestamp,
+                    &self.clock,
+                    self.leap_smear.as_mut(),
+                );
+                self.update_leap_smear_offset();
+
+                let mut buf = [0; 52];
+                let mut cursor = Cursor::new(buf.as_mut_slice());
+
+                if let Err(serialize_err) = response.serialize(&mut cursor, None) {
+                    self.stats.response_send_errors.inc();
+                    error!(error=?serialize_err, "Could not serialize response");
+                    return true;
+                }
+                if let Err(send_err) = socket
+                    .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
+                    .await
+                {
+                    self.stats.response_send_errors.inc();
+                    warn!(error=?send_err, "Could not send crypto-NAK packet");
+                }
+            }
+            AcceptResult::Symmetric(data, peer_addr, recv_timestamp, symmetric_key) => {
+                let packet = ForwardedPacket {
+                    data: data.to_vec(),
+                    recv_timestamp,
+                };
+
+                if self.forward_symmetric(socket, peer_addr, packet, symmetric_key) {
+                    self.stats.accepted_packets.inc();
+                } else {
+                    self.stats.ignored_packets.inc();
+                }
+            }
             AcceptResult::Ignore => {
                 self.stats.ignored_packets.inc();
             }
@@ -239,27 +611,79 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         true
     }
 
+    /// Hand a symmetric active packet to the passive association with its sender, asking the
+    /// system to spawn that association first when there is none. Returns whether the packet
+    /// was forwarded.
+    fn forward_symmetric(
+        &mut self,
+        socket: &Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+        packet: ForwardedPacket,
+        symmetric_key: SymmetricKey,
+    ) -> bool {
+        // forget about associations that have been demobilized
+        self.passive.retain(|_, sender| !sender.is_closed());
+
+        if let Some(sender) = self.passive.get(&peer_addr) {
+            return match sender.try_send(packet) {
+                Ok(()) => true,
+                Err(error) => {
+                    warn!(%error, "Could not forward packet to passive association");
+                    false
+                }
+            };
+        }
+
+        if self.passive.len() >= MAX_PASSIVE_ASSOCIATIONS {
+            warn!(%peer_addr, "Too many passive associations, ignoring symmetric active peer");
+            return false;
+        }
+
+        let (sender, receiver) = mpsc::channel(PASSIVE_ASSOCIATION_BUFFER_SIZE);
+        let request = PassiveAssociationRequest {
+            socket: socket.clone(),
+            peer_addr,
+            packet,
+            receiver,
+            symmetric_key,
+        };
+
+        match self.passive_sender.try_send(request) {
+            Ok(()) => {
+                info!(%peer_addr, "Spawning passive association");
+                self.passive.insert(peer_addr, sender);
+                true
+            }
+            Err(error) => {
+                warn!(%error, "Could not spawn passive association");
+                false
+            }
+        }
+    }
+
     fn accept_packet<'a, 'b>(
         &'b mut self,
         rate_limiting_cutoff: Duration,
         result: Result<(usize, SocketAddr, Option<NtpTimestamp>), std::io::Error>,
-        buf: &'a [u8; 48],
+        buf: &'a [u8],
     ) -> AcceptResult<'a> {
         match result {
             Ok((size, peer_addr, Some(recv_timestamp))) if size >= 48 => {
-                // Note: packets are allowed to be bigger when including extensions.
-                // we don't expect them, but the client may still send them. The
-                // extra bytes are guaranteed safe to ignore. `recv` truncates the messages.
-                // Messages of fewer than 48 bytes are skipped entirely
+                // Note: packets are allowed to be bigger when including extensions,
+                // which are used for NTS. `recv` truncates messages that don't fit
+                // in the buffer. Messages of fewer than 48 bytes are skipped entirely
+                let buf = &buf[..size];
                 match self.filter(&peer_addr.ip()) {
                     Some(FilterAction::Deny) => {
                         match self.accept_data(buf, peer_addr, recv_timestamp) {
                             // We should send deny messages only to reasonable requests
                             // otherwise two servers could end up in a loop of sending
                             // deny's to each other.
-                            AcceptResult::Accept(packet, addr, _) => {
+                            AcceptResult::Accept(packet, addr, _, _) => {
                                 AcceptResult::Deny(packet, addr)
                             }
+                            // only allowed addresses get a passive association
+                            AcceptResult::Symmetric(..) => AcceptResult::Ignore,
                             v => v,
                         }
                     }
@@ -270,7 +694,7 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                         let too_soon = !self.client_cache.is_allowed(peer_addr, timestamp, cutoff);
 
                         match self.accept_data(buf, peer_addr, recv_timestamp) {
-                            AcceptResult::Accept(packet, _, _) if too_soon => {
+                            AcceptResult::Accept(packet, _, _, _) if too_soon => {
                                 AcceptResult::RateLimit(packet, peer_addr)
                             }
                             accept_result => accept_result,
@@ -309,16 +733,65 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
 
     fn accept_data<'a, 'b>(
         &'b self,
-        buf: &'a [u8; 48],
+        buf: &'a [u8],
         peer_addr: SocketAddr,
         recv_timestamp: NtpTimestamp,
     ) -> AcceptResult<'a> {
-        match NtpPacket::deserialize(buf, None) {
-            Ok(packet) => match packet.mode() {
-                NtpAssociationMode::Client => {
-                    trace!("NTP client request accepted from {}", peer_addr);
-                    AcceptResult::Accept(packet, peer_addr, recv_timestamp)
-                }
+        let keyset = self.keyset.borrow().clone();
+        match NtpPacket::deserialize_request(buf, &keyset) {
+            Ok((packet, cookie)) => match packet.mode() {
+                NtpAssociationMode::Client => match (cookie, packet.mac_key_id()) {
+                    (Some(Ok(cookie)), _) => {
+                        trace!("NTS client request accepted from {}", peer_addr);
+                        let authentication = Authentication::Nts(cookie);
+                        AcceptResult::Accept(packet, peer_addr, recv_timestamp, authentication)
+                    }
+                    (Some(Err(_)), _) => {
+                        trace!("NTS cookie from {} could not be decrypted", peer_addr);
+                        AcceptResult::NtsNak(packet, peer_addr)
+                    }
+                    (None, Some(key_id)) => match self.keys.get(key_id) {
+                        Some(key) if packet.verify_mac(buf, key) => {
+                            trace!(
+                                "authenticated NTP client request accepted from {}",
+                                peer_addr
+                            );
+                            let authentication = Authentication::Mac(key.clone());
+                            AcceptResult::Accept(packet, peer_addr, recv_timestamp, authentication)
+                        }
+                        _ => {
+                            trace!("MAC from {} could not be verified", peer_addr);
+                            AcceptResult::CryptoNak(packet, peer_addr, recv_timestamp)
+                        }
+                    },
+                    (None, None) => {
+                        trace!("NTP client request accepted from {}", peer_addr);
+                        let authentication = Authentication::None;
+                        AcceptResult::Accept(packet, peer_addr, recv_timestamp, authentication)
+                    }
+                },
+                NtpAssociationMode::SymmetricActive => match packet.mac_key_id() {
+                    Some(key_id) => match self.keys.get(key_id) {
+                        Some(key) if packet.verify_mac(buf, key) => {
+                            trace!("authenticated symmetric active packet from {}", peer_addr);
+                            let key = key.clone();
+                            AcceptResult::Symmetric(buf, peer_addr, recv_timestamp, key)
+                        }
+                        _ => {
+                            trace!("MAC from {} could not be verified", peer_addr);
+                            AcceptResult::Ignore
+                        }
+                    },
+                    None => {
+                        // passive associations are used for synchronization, so
+                        // unauthenticated peers could steer our clock
+                        trace!(
+                            "unauthenticated symmetric active packet ignored from {}",
+                            peer_addr
+                        );
+                        AcceptResult::Ignore
+                    }
+                },
                 _ => {
                     trace!(
                         "NTP packet with unkown mode {:?} ignored from {}",
@@ -336,6 +809,16 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
     }
 }
 
+fn cache_index<T: std::hash::Hash>(item: &T, length: usize) -> usize {
+    use std::hash::Hasher;
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::default();
+
+    item.hash(&mut hasher);
+
+    hasher.finish() as usize % length
+}
+
 /// A size-bounded cache where each entry is timestamped.
 ///
 /// The planned use is in rate limiting: we keep track of when a peer last checked in. If it checks
@@ -367,13 +850,7 @@ impl<T: std::hash::Hash + Eq> TimestampedCache<T> {
     }
 
     fn index(&self, item: &T) -> usize {
-        use std::hash::Hasher;
-
-        let mut hasher = std::collections::hash_map::DefaultHasher::default();
-
-        item.hash(&mut hasher);
-
-        hasher.finish() as usize % self.elements.len()
+        cache_index(item, self.elements.len())
     }
 
     fn is_allowed(&mut self, item: T, timestamp: Instant, cutoff: Duration) -> bool {
@@ -402,10 +879,77 @@ impl<T: std::hash::Hash + Eq> TimestampedCache<T> {
     }
 }
 
+/// The timestamps of the last response to each client, for interleaved mode: the time at
+/// which its request was received, and the time at which the response actually left. The
+/// latter is only known for clients that asked for an interleaved response.
+///
+/// Like the `TimestampedCache`, this is a vector indexed by a hash of the client address. A
+/// client whose entry is evicted by a collision simply gets a response in basic mode.
+#[derive(Debug)]
+struct InterleavedCache {
+    elements: Vec<Option<(SocketAddr, NtpTimestamp, Option<NtpTimestamp>)>>,
+}
+
+impl InterleavedCache {
+    fn new(length: usize) -> Self {
+        Self {
+            elements: vec![None; length],
+        }
+    }
+
+    fn entry(
+        &self,
+        addr: SocketAddr,
+        receive: NtpTimestamp,
+    ) -> Option<&(SocketAddr, NtpTimestamp, Option<NtpTimestamp>)> {
+        if self.elements.is_empty() {
+            return None;
+        }
+
+        self.elements[cache_index(&addr, self.elements.len())]
+            .as_ref()
+            .filter(|(entry_addr, entry_receive, _)| {
+                *entry_addr == addr && *entry_receive == receive
+            })
+    }
+
+    /// Whether `origin` is the receive timestamp of the previous response to `addr`, which
+    /// means that the client asks for an interleaved response
+    fn is_interleaved_request(&self, addr: SocketAddr, origin: NtpTimestamp) -> bool {
+        self.entry(addr, origin).is_some()
+    }
+
+    /// The transmit timestamp of the previous response to `addr`, provided that `origin` is the
+    /// receive timestamp of that response
+    fn previous_transmit(&self, addr: SocketAddr, origin: NtpTimestamp) -> Option<NtpTimestamp> {
+        self.entry(addr, origin)
+            .and_then(|(_, _, transmit)| *transmit)
+    }
+
+    fn insert(&mut self, addr: SocketAddr, receive: NtpTimestamp) {
+        if self.elements.is_empty() {
+            return;
+        }
+
+        let index = cache_index(&addr, self.elements.len());
+        self.elements[index] = Some((addr, receive, None));
+    }
+
+    /// Complete the entry of the response to `addr` with the time at which it left, unless
+    /// the entry was replaced in the meantime
+    fn insert_transmit(&mut self, addr: SocketAddr, receive: NtpTimestamp, transmit: NtpTimestamp) {
+        if self.entry(addr, receive).is_some() {
+            let index = cache_index(&addr, self.elements.len());
+            self.elements[index] = Some((addr, receive, Some(transmit)));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
+    use aes_siv::{aead::KeyInit, Aes128SivAead, Key};
     use ntp_proto::{NtpDuration, NtpLeapIndicator, PollInterval, PollIntervalLimits, ReferenceId};
 
     use crate::ipfilter::IpFilter;
@@ -418,11 +962,12 @@ mod tests {
     struct TestClock {}
 
     impl NtpClock for TestClock {
-        type Error = std::time::SystemTimeError;
+        type Error = std::io::Error;
 
         fn now(&self) -> std::result::Result<NtpTimestamp, Self::Error> {
-            let cur =
-                std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?;
+            let cur = std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
             Ok(NtpTimestamp::from_seconds_nanos_since_ntp_era(
                 EPOCH_OFFSET.wrapping_add(cur.as_secs() as u32),
@@ -467,6 +1012,45 @@ mod tests {
         }
     }
 
+    fn keyset() -> watch::Receiver<Arc<KeySet>> {
+        let (_, keyset) = watch::channel(Arc::new(KeySet::new()));
+        keyset
+    }
+
+    fn passive_sender() -> mpsc::Sender<PassiveAssociationRequest> {
+        let (sender, _) = mpsc::channel(1);
+        sender
+    }
+
+    fn shutdown_receiver() -> watch::Receiver<bool> {
+        let (_, receiver) = watch::channel(false);
+        receiver
+    }
+
+    /// A server task that is not running, to test its methods directly
+    fn test_server_task(config: ServerConfig, keys: SymmetricKeys) -> ServerTask<TestClock> {
+        let (_, system_receiver) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let rate_limiting_cache_size = config.rate_limiting_cache_size;
+
+        ServerTask {
+            config,
+            leap_smear: None,
+            network_wait_period: Duration::from_secs(1),
+            system: SystemSnapshot::default(),
+            system_receiver,
+            clock: TestClock {},
+            keyset: keyset(),
+            keys: Arc::new(keys),
+            client_cache: TimestampedCache::new(rate_limiting_cache_size),
+            interleaved_cache: InterleavedCache::new(INTERLEAVED_CACHE_SIZE),
+            pending_transmits: VecDeque::new(),
+            stats: ServerStats::default(),
+            passive: HashMap::new(),
+            passive_sender: passive_sender(),
+            shutdown_receiver: shutdown_receiver(),
+        }
+    }
+
     fn serialize_packet_unencryped(send_packet: &NtpPacket) -> [u8; 48] {
         let mut buf = [0; 48];
         let mut cursor = Cursor::new(buf.as_mut_slice());
@@ -487,6 +1071,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -496,6 +1085,10 @@ mod tests {
             Default::default(),
             system_snapshots,
             clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
             Duration::from_secs(1),
         );
 
@@ -522,6 +1115,44 @@ mod tests {
         server.abort();
     }
 
+    #[tokio::test]
+    async fn test_server_shutdown() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9026".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            TestClock {},
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver,
+            Duration::from_secs(1),
+        );
+
+        shutdown_sender.send_replace(true);
+        tokio::time::timeout(Duration::from_millis(100), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
     #[tokio::test]
     async fn test_server_filter_allow_deny() {
         let config = ServerConfig {
@@ -532,6 +1163,11 @@ mod tests {
             allowlist_action: FilterAction::Deny,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -541,6 +1177,10 @@ mod tests {
             Default::default(),
             system_snapshots,
             clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
             Duration::from_secs(1),
         );
 
@@ -578,6 +1218,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -587,6 +1232,10 @@ mod tests {
             Default::default(),
             system_snapshots,
             clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
             Duration::from_secs(1),
         );
 
@@ -618,6 +1267,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -627,6 +1281,10 @@ mod tests {
             Default::default(),
             system_snapshots,
             clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
             Duration::from_secs(1),
         );
 
@@ -663,6 +1321,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -672,6 +1335,10 @@ mod tests {
             Default::default(),
             system_snapshots,
             clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
             Duration::from_secs(1),
         );
 
@@ -709,6 +1376,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -718,6 +1390,10 @@ mod tests {
             Default::default(),
             system_snapshots,
             clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
             Duration::from_secs(1),
         );
 
@@ -749,6 +1425,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_millis(100),
             rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -758,6 +1439,10 @@ mod tests {
             Default::default(),
             system_snapshots,
             clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
             Duration::from_secs(1),
         );
 
@@ -826,6 +1511,11 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::default(),
             rate_limiting_cache_size: Default::default(),
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -835,6 +1525,10 @@ mod tests {
             Default::default(),
             system_snapshots,
             clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
             Duration::from_secs(1),
         );
 
@@ -861,6 +1555,489 @@ mod tests {
 
         server.abort();
     }
+
+    #[tokio::test]
+    async fn test_server_nts_nak() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9016".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let stats = ServerStats::default();
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            system_snapshots,
+            clock,
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
+            Duration::from_secs(1),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9017".parse().unwrap(),
+            "127.0.0.1:9016".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // a cookie that was not handed out by this server
+        let cookie = [0; 104];
+        let (packet, id) =
+            NtpPacket::nts_poll_message(&cookie, 2, PollIntervalLimits::default().min);
+
+        let c2s = Aes128SivAead::new(&Key::<Aes128SivAead>::from([0; 32]));
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize(&mut cursor, Some(&c2s)).unwrap();
+        let length = cursor.position() as usize;
+        socket.send(&buf[..length]).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (length, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = NtpPacket::deserialize(&buf[..length], None).unwrap();
+        assert!(packet.is_kiss_ntsn());
+        assert!(packet.valid_server_response(id, true));
+        assert_eq!(stats.nts_nak_packets.get(), 1);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_symmetric_key() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9018".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 0,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let stats = ServerStats::default();
+        let keys: SymmetricKeys = "1 SHA1 secret".parse().unwrap();
+        let key = keys.get(1).unwrap().clone();
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            system_snapshots,
+            clock,
+            keyset(),
+            Arc::new(keys),
+            passive_sender(),
+            shutdown_receiver(),
+            Duration::from_secs(1),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9019".parse().unwrap(),
+            "127.0.0.1:9018".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // a request signed with a known key gets a signed response
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet.serialize_with_mac(&mut cursor, None, &key).unwrap();
+        let length = cursor.position() as usize;
+        socket.send(&buf[..length]).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (length, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = NtpPacket::deserialize(&buf[..length], None).unwrap();
+        assert!(packet.valid_server_response(id, false));
+        assert!(packet.verify_mac(&buf[..length], &key));
+        assert_eq!(stats.accepted_packets.get(), 1);
+
+        // a request signed with an unknown key gets a crypto-NAK
+        let other_key =
+            SymmetricKey::new(1, ntp_proto::SymmetricKeyAlgorithm::Sha1, b"other".to_vec())
+                .unwrap();
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut buf = [0; 1024];
+        let mut cursor = Cursor::new(buf.as_mut_slice());
+        packet
+            .serialize_with_mac(&mut cursor, None, &other_key)
+            .unwrap();
+        let length = cursor.position() as usize;
+        socket.send(&buf[..length]).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (length, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = NtpPacket::deserialize(&buf[..length], None).unwrap();
+        assert!(packet.valid_server_response(id, false));
+        assert!(packet.is_crypto_nak());
+        assert_eq!(stats.crypto_nak_packets.get(), 1);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_broadcast() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9022".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 0,
+            broadcast_addr: Some("127.0.0.1:9023".parse().unwrap()),
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: Some(1),
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
+        };
+        let keys: SymmetricKeys = "1 SHA1 secret".parse().unwrap();
+        let key = keys.get(1).unwrap().clone();
+
+        let client = UdpSocket::broadcast_client("127.0.0.1:9023".parse().unwrap())
+            .await
+            .unwrap();
+        let socket = UdpSocket::server("127.0.0.1:9022".parse().unwrap())
+            .await
+            .unwrap();
+
+        // the broadcast is authenticated with the configured key
+        let mut server = test_server_task(config.clone(), keys);
+        server
+            .broadcast(&socket, "127.0.0.1:9023".parse().unwrap())
+            .await;
+        assert_eq!(server.stats.broadcast_packets.get(), 1);
+
+        let mut buf = [0; 1024];
+        let (length, addr, _) =
+            tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(addr, "127.0.0.1:9022".parse().unwrap());
+
+        let packet = NtpPacket::deserialize(&buf[..length], None).unwrap();
+        assert_eq!(packet.mode(), NtpAssociationMode::Broadcast);
+        assert_ne!(packet.transmit_timestamp(), NtpTimestamp::default());
+        assert!(packet.verify_mac(&buf[..length], &key));
+
+        // without the key, nothing is broadcast
+        let mut server = test_server_task(config, SymmetricKeys::default());
+        server
+            .broadcast(&socket, "127.0.0.1:9023".parse().unwrap())
+            .await;
+        assert_eq!(server.stats.broadcast_packets.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_interleaved() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9024".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 0,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            system_snapshots,
+            TestClock {},
+            keyset(),
+            Arc::new(SymmetricKeys::default()),
+            passive_sender(),
+            shutdown_receiver(),
+            Duration::from_secs(1),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9025".parse().unwrap(),
+            "127.0.0.1:9024".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // the first request is answered in basic mode
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        socket
+            .send(&serialize_packet_unencryped(&packet))
+            .await
+            .unwrap();
+
+        let mut buf = [0; 48];
+        tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let basic = NtpPacket::deserialize(&buf, None).unwrap().into_owned();
+        assert!(basic.valid_server_response(id, false));
+
+        // the server does not keep the transmit timestamp of a basic mode exchange, so a
+        // request that refers to that response falls back to basic mode
+        let (packet, id) = NtpPacket::interleaved_poll_message(
+            PollIntervalLimits::default().min,
+            basic.receive_timestamp(),
+        );
+        socket
+            .send(&serialize_packet_unencryped(&packet))
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let fallback = NtpPacket::deserialize(&buf, None).unwrap().into_owned();
+        assert!(fallback.valid_server_response(id, false));
+        assert!(!fallback.is_interleaved_response(id));
+
+        // the client asked for interleaved mode, so a request that refers to the fallback
+        // response gets its actual transmit timestamp
+        let (packet, id) = NtpPacket::interleaved_poll_message(
+            PollIntervalLimits::default().min,
+            fallback.receive_timestamp(),
+        );
+        socket
+            .send(&serialize_packet_unencryped(&packet))
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let interleaved = NtpPacket::deserialize(&buf, None).unwrap();
+        assert!(interleaved.valid_server_response(id, false));
+        assert!(interleaved.is_interleaved_response(id));
+        assert_eq!(interleaved.origin_timestamp(), packet.receive_timestamp());
+
+        let transmit_delay = interleaved.transmit_timestamp() - fallback.transmit_timestamp();
+        assert!(transmit_delay.to_seconds() >= 0.0 && transmit_delay.to_seconds() < 0.1);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_interleaved_send_timestamps() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9028".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 0,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
+        };
+
+        let socket = Arc::new(UdpSocket::server(config.addr).await.unwrap());
+        let mut server = test_server_task(config, SymmetricKeys::default());
+        let client_addr = "127.0.0.1:9029".parse().unwrap();
+        let recv_timestamp = TestClock {}.now().unwrap();
+
+        // a request in basic mode with a receive timestamp is answered without waiting for
+        // the send timestamp of the response
+        let (mut request, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        request.set_receive_timestamp(recv_timestamp);
+        let buf = serialize_packet_unencryped(&request);
+        let recv_res = Ok((buf.len(), client_addr, Some(recv_timestamp)));
+        assert!(
+            server
+                .serve_packet(&socket, &buf, recv_res, Duration::ZERO)
+                .await
+        );
+        assert!(server.pending_transmits.is_empty());
+
+        // only a request that refers to our previous response asks for the send timestamp
+        let (request, _) =
+            NtpPacket::interleaved_poll_message(PollIntervalLimits::default().min, recv_timestamp);
+        let buf = serialize_packet_unencryped(&request);
+        let recv_res = Ok((buf.len(), client_addr, Some(recv_timestamp)));
+        assert!(
+            server
+                .serve_packet(&socket, &buf, recv_res, Duration::ZERO)
+                .await
+        );
+        assert_eq!(server.pending_transmits.len(), 1);
+
+        // which is taken once the kernel provides it
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        server.take_pending_transmits(&socket);
+        assert!(server.pending_transmits.is_empty());
+        assert!(server
+            .interleaved_cache
+            .previous_transmit(client_addr, recv_timestamp)
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_server_symmetric_active() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9020".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 0,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
+        };
+        let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let clock = TestClock {};
+        let stats = ServerStats::default();
+        let (passive_sender, mut passive_receiver) = mpsc::channel(1);
+        let keys: SymmetricKeys = "1 SHA1 secret".parse().unwrap();
+        let key = keys.get(1).unwrap().clone();
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            system_snapshots,
+            clock.clone(),
+            keyset(),
+            Arc::new(keys),
+            passive_sender,
+            shutdown_receiver(),
+            Duration::from_secs(1),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9021".parse().unwrap(),
+            "127.0.0.1:9020".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let send_symmetric = |transmit| {
+            let mut buf = [0; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            let (packet, _) = NtpPacket::symmetric_message(
+                &SystemSnapshot::default(),
+                NtpAssociationMode::SymmetricActive,
+                PollIntervalLimits::default().min,
+                NtpTimestamp::default(),
+                NtpTimestamp::default(),
+                transmit,
+            );
+            packet.serialize_with_mac(&mut cursor, None, &key).unwrap();
+            let length = cursor.position() as usize;
+            buf[..length].to_vec()
+        };
+
+        // the first packet asks the system for a passive association
+        let transmit = clock.now().unwrap();
+        socket.send(&send_symmetric(transmit)).await.unwrap();
+
+        let mut request = tokio::time::timeout(Duration::from_millis(100), passive_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.peer_addr, "127.0.0.1:9021".parse().unwrap());
+        assert_eq!(request.symmetric_key.id(), 1);
+        let packet = NtpPacket::deserialize(&request.packet.data, None).unwrap();
+        assert_eq!(packet.transmit_timestamp(), transmit);
+
+        // later packets are forwarded to that association
+        let transmit = clock.now().unwrap();
+        socket.send(&send_symmetric(transmit)).await.unwrap();
+
+        let forwarded = tokio::time::timeout(Duration::from_millis(100), request.receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = NtpPacket::deserialize(&forwarded.data, None).unwrap();
+        assert_eq!(packet.transmit_timestamp(), transmit);
+        assert_eq!(stats.accepted_packets.get(), 2);
+
+        // no second association is requested for the same peer
+        assert!(passive_receiver.try_recv().is_err());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_symmetric_active_unauthenticated() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9022".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 0,
+            broadcast_addr: None,
+            broadcast_interval: Duration::from_secs(64),
+            broadcast_key_id: None,
+            timestamping: TimestampingMode::Software,
+            leap_smear: None,
+        };
+        let server = test_server_task(config, "1 SHA1 secret".parse().unwrap());
+
+        let (packet, _) = NtpPacket::symmetric_message(
+            &SystemSnapshot::default(),
+            NtpAssociationMode::SymmetricActive,
+            PollIntervalLimits::default().min,
+            NtpTimestamp::default(),
+            NtpTimestamp::default(),
+            TestClock {}.now().unwrap(),
+        );
+        let buf = serialize_packet_unencryped(&packet);
+
+        // without a MAC, the packet must not mobilize a passive association
+        let result = server.accept_data(
+            &buf,
+            "127.0.0.1:9023".parse().unwrap(),
+            NtpTimestamp::default(),
+        );
+        assert!(matches!(result, AcceptResult::Ignore));
+    }
 }
 
 #[cfg(test)]
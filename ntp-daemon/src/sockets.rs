@@ -1,32 +1,56 @@
 use std::path::Path;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::UnixListener;
-use tokio::net::UnixStream;
 
-pub async fn write_json<T>(stream: &mut UnixStream, value: &T) -> std::io::Result<()>
+/// Write a message as a single line of JSON
+pub async fn write_message<S, T>(stream: &mut S, value: &T) -> std::io::Result<()>
 where
+    S: AsyncWrite + Unpin,
     T: serde::Serialize,
 {
-    let bytes = serde_json::to_vec(value).unwrap();
-    stream.write_all(&bytes).await
+    // serde_json never puts a newline in its compact output
+    let mut bytes = serde_json::to_vec(value).unwrap();
+    bytes.push(b'\n');
+
+    stream.write_all(&bytes).await?;
+    stream.flush().await
 }
 
-pub async fn read_json<'a, T>(
-    stream: &mut UnixStream,
-    buffer: &'a mut Vec<u8>,
-) -> std::io::Result<T>
+/// Read a message written by [`write_message`] of at most `max_size` bytes. Returns `None` when
+/// the other side closed the connection, and an error of kind
+/// [`std::io::ErrorKind::InvalidData`] when the message is not valid. A longer message is an
+/// error of kind [`std::io::ErrorKind::Other`], after which the connection should be closed.
+pub async fn read_message<S, T>(
+    stream: &mut S,
+    buffer: &mut String,
+    max_size: u64,
+) -> std::io::Result<Option<T>>
 where
-    T: serde::Deserialize<'a>,
+    S: AsyncBufRead + Unpin,
+    T: serde::de::DeserializeOwned,
 {
     buffer.clear();
 
-    let n = stream.read_buf(buffer).await?;
-    buffer.truncate(n);
+    // the other side must not be able to make us buffer a line without end
+    let size = (&mut *stream).take(max_size).read_line(buffer).await?;
+    if size == 0 {
+        return Ok(None);
+    }
+    if size as u64 == max_size && !buffer.ends_with('\n') {
+        let msg = format!("message is longer than {max_size} bytes");
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, msg));
+    }
 
-    Ok(serde_json::from_slice(buffer).unwrap())
+    serde_json::from_str(buffer)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
 }
 
+/// Number of clients that are served at the same time on a socket of the daemon. Clients that
+/// connect while this many are served are disconnected right away.
+pub const MAX_CONNECTIONS: usize = 32;
+
 pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
     use std::io::{Error, ErrorKind};
 
@@ -60,9 +84,17 @@ pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
     Err(Error::new(ErrorKind::Other, msg))
 }
 
+/// Remove the file of a socket created by [`create_unix_socket`], when the socket is no longer used
+pub fn remove_unix_socket(path: &Path) {
+    if let Err(error) = std::fs::remove_file(path) {
+        tracing::warn!(?error, ?path, "Could not remove socket");
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use tokio::net::UnixListener;
+    use tokio::io::BufStream;
+    use tokio::net::{UnixListener, UnixStream};
 
     use super::*;
 
@@ -76,20 +108,66 @@ mod tests {
         let listener = UnixListener::bind(&path).unwrap();
         let mut writer = UnixStream::connect(&path).await.unwrap();
 
-        let (mut reader, _) = listener.accept().await.unwrap();
+        let (reader, _) = listener.accept().await.unwrap();
+        let mut reader = BufStream::new(reader);
 
         let object = vec![0usize, 10];
+        let other = vec!["a string\nwith a newline".to_string()];
 
-        write_json(&mut writer, &object).await.unwrap();
+        write_message(&mut writer, &object).await.unwrap();
+        write_message(&mut writer, &other).await.unwrap();
+        writer.write_all(b"not json\n").await.unwrap();
+        drop(writer);
 
-        let mut buf = Vec::new();
-        let output = read_json::<Vec<usize>>(&mut reader, &mut buf)
+        let mut buf = String::new();
+        let output = read_message::<_, Vec<usize>>(&mut reader, &mut buf, 1024)
             .await
             .unwrap();
+        assert_eq!(Some(object), output);
 
-        assert_eq!(object, output);
+        // messages are separated, even if they arrived together
+        let output = read_message::<_, Vec<String>>(&mut reader, &mut buf, 1024)
+            .await
+            .unwrap();
+        assert_eq!(Some(other), output);
+
+        let error = read_message::<_, Vec<usize>>(&mut reader, &mut buf, 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let output = read_message::<_, Vec<usize>>(&mut reader, &mut buf, 1024)
+            .await
+            .unwrap();
+        assert_eq!(None, output);
+    }
 
-        // the logic will automatically grow the buffer to the required size
-        assert!(!buf.is_empty());
+    #[tokio::test]
+    async fn read_message_too_long() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-stream-9");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut writer = UnixStream::connect(&path).await.unwrap();
+
+        let (reader, _) = listener.accept().await.unwrap();
+        let mut reader = BufStream::new(reader);
+
+        // a message of exactly the maximum size is accepted
+        write_message(&mut writer, &"a".repeat(6)).await.unwrap();
+        write_message(&mut writer, &"a".repeat(7)).await.unwrap();
+
+        let mut buf = String::new();
+        let output = read_message::<_, String>(&mut reader, &mut buf, 9)
+            .await
+            .unwrap();
+        assert_eq!(Some("a".repeat(6)), output);
+
+        let error = read_message::<_, String>(&mut reader, &mut buf, 9)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Other);
     }
 }
@@ -1,28 +1,54 @@
 use crate::{
-    config::{CombinedSystemConfig, NormalizedAddress, NtsPeerConfig},
-    config::{PeerConfig, PoolPeerConfig, ServerConfig, StandardPeerConfig},
+    config::dynamic::{ConfigChange, ConfigReload, ConfigResponse},
+    config::{
+        check_peer_key, BroadcastPeerConfig, ClockConfig, CombinedSystemConfig, NormalizedAddress,
+        NtsPeerConfig,
+    },
+    config::{
+        KeysetConfig, NtsKeConfig, PeerConfig, PoolPeerConfig, PpsRefClockConfig, RefClockConfig,
+        ServerConfig, StandardPeerConfig, SymmetricKeys, SymmetricPeerConfig, TimestampingMode,
+    },
     keyexchange::key_exchange,
+    observer::{ObservableMeasurement, ObservablePeerHistory},
     peer::PeerTask,
-    peer::{MsgForSystem, PeerChannels},
+    peer::{MsgForSystem, PassiveAssociationRequest, PeerChannels, PeerStats},
+    protocol::Event,
+    refclock::RefClockTask,
     server::{ServerStats, ServerTask},
     ObservablePeerState,
 };
 
-use std::{collections::HashMap, io::ErrorKind, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use ntp_os_clock::UnixNtpClock;
+use ntp_os_clock::{PtpHardwareClock, UnixNtpClock};
 use ntp_proto::{
-    DefaultTimeSyncController, KeyExchangeError, KeyExchangeResult, NtpClock, PeerNtsData,
-    PeerSnapshot, SystemSnapshot, TimeSyncController,
+    ClockCorrection, DefaultTimeSyncController, FilterDecision, KernelPpsStatus, KeyExchangeError,
+    KeyExchangeResult, KeySet, LeapSecondsFile, NtpClock, NtpLeapIndicator, PanicThresholdExceeded,
+    PeerNtsData, PeerSnapshot, SelectionStatus, SystemSnapshot, TimeSyncController,
 };
 use rustls::Certificate;
 use tokio::{
-    sync::mpsc::{self, Sender},
+    sync::{
+        broadcast,
+        mpsc::{self, Sender},
+    },
     task::JoinHandle,
 };
-use tracing::warn;
+use tracing::{info, warn};
 
 const NETWORK_WAIT_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+/// How often the clock frequency is written to the drift file
+const DRIFT_FILE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+/// How often the servers are checked for rate limited requests
+const RATE_LIMIT_EVENT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+/// How many measurements are kept per peer
+const MEASUREMENT_HISTORY_SIZE: usize = 64;
 
 pub struct DaemonChannels {
     pub config_receiver: tokio::sync::watch::Receiver<CombinedSystemConfig>,
@@ -30,46 +56,132 @@ pub struct DaemonChannels {
     pub peer_snapshots_receiver: tokio::sync::watch::Receiver<Vec<ObservablePeerState>>,
     pub server_data_receiver: tokio::sync::watch::Receiver<Vec<ServerData>>,
     pub system_snapshot_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
+    pub kernel_pps_receiver: tokio::sync::watch::Receiver<Option<KernelPpsStatus>>,
+    pub peer_history_receiver: tokio::sync::watch::Receiver<Vec<ObservablePeerHistory>>,
+    /// Subscribe to receive the events of the daemon
+    pub event_sender: broadcast::Sender<Event>,
+    /// Applies the peers and servers of a reloaded configuration
+    pub config_change_sender: mpsc::Sender<ConfigChange>,
+    /// Stops the daemon when `true` is sent
+    pub shutdown_sender: tokio::sync::watch::Sender<bool>,
+    pub shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 }
 
 /// Spawn the NTP daemon
+#[allow(clippy::too_many_arguments)]
 pub async fn spawn(
     config: CombinedSystemConfig,
+    clock_config: &ClockConfig,
     peer_configs: &[PeerConfig],
+    refclock_configs: &[RefClockConfig],
     server_configs: &[ServerConfig],
+    nts_ke_configs: &[NtsKeConfig],
+    keyset_config: &KeysetConfig,
+    keys: &SymmetricKeys,
+    leap_seconds: Option<&LeapSecondsFile>,
+    drift_file: Option<&Path>,
 ) -> std::io::Result<(JoinHandle<std::io::Result<()>>, DaemonChannels)> {
-    let clock = UnixNtpClock::new();
-    let (mut system, channels) = System::new(clock, config);
+    match clock_config {
+        ClockConfig::System => {
+            spawn_with_clock(
+                UnixNtpClock::new(),
+                config,
+                peer_configs,
+                refclock_configs,
+                server_configs,
+                nts_ke_configs,
+                keyset_config,
+                keys,
+                leap_seconds,
+                drift_file,
+            )
+            .await
+        }
+        ClockConfig::Ptp { path } => {
+            spawn_with_clock(
+                PtpHardwareClock::open(path)?,
+                config,
+                peer_configs,
+                refclock_configs,
+                server_configs,
+                nts_ke_configs,
+                keyset_config,
+                keys,
+                leap_seconds,
+                drift_file,
+            )
+            .await
+        }
+    }
+}
 
-    for peer_config in peer_configs {
-        match peer_config {
-            PeerConfig::Standard(StandardPeerConfig { addr }) => {
-                system.add_standard_peer(addr.clone()).await;
-            }
-            PeerConfig::Nts(NtsPeerConfig {
-                ke_addr,
-                certificates,
-            }) => {
-                if let Err(e) = system
-                    .add_nts_peer(ke_addr.clone(), certificates.clone())
-                    .await
-                {
-                    return Err(std::io::Error::new(ErrorKind::Other, e));
-                }
-            }
-            PeerConfig::Pool(PoolPeerConfig {
-                addr, max_peers, ..
-            }) => {
-                system.add_new_pool(addr.clone(), *max_peers).await;
-            }
+#[allow(clippy::too_many_arguments)]
+async fn spawn_with_clock<C: NtpClock>(
+    clock: C,
+    config: CombinedSystemConfig,
+    peer_configs: &[PeerConfig],
+    refclock_configs: &[RefClockConfig],
+    server_configs: &[ServerConfig],
+    nts_ke_configs: &[NtsKeConfig],
+    keyset_config: &KeysetConfig,
+    keys: &SymmetricKeys,
+    leap_seconds: Option<&LeapSecondsFile>,
+    drift_file: Option<&Path>,
+) -> std::io::Result<(JoinHandle<std::io::Result<()>>, DaemonChannels)> {
+    // the keyset is stored after the servers that use it have stopped
+    let (keyset_shutdown_sender, keyset_shutdown_receiver) = tokio::sync::watch::channel(false);
+    let (keyset, keyset_task) =
+        crate::keyset::spawn(keyset_config.clone(), keyset_shutdown_receiver).await;
+    let (mut system, channels) = System::new(clock, config, keyset, Arc::new(keys.clone()));
+    system.controller.update_leap_seconds(leap_seconds.cloned());
+
+    if let Some(path) = drift_file {
+        if let Some(frequency) = crate::drift::load(path).await {
+            system.controller.set_initial_frequency(frequency);
         }
+        system.drift_file = Some(path.to_owned());
+    }
+
+    for peer_config in peer_configs {
+        system.add_peer(peer_config.clone()).await?;
+    }
+
+    for refclock_config in refclock_configs {
+        system.add_refclock(refclock_config.clone())?;
     }
 
     for server_config in server_configs.iter() {
         system.add_server(server_config.to_owned()).await;
     }
 
-    let handle = tokio::spawn(async move { system.run().await });
+    let mut nts_ke_tasks = vec![];
+    for nts_ke_config in nts_ke_configs {
+        nts_ke_tasks.push(
+            crate::keyexchange::spawn(
+                nts_ke_config.clone(),
+                system.keyset.clone(),
+                channels.shutdown_receiver.clone(),
+            )
+            .await?,
+        );
+    }
+
+    let shutdown_receiver = channels.shutdown_receiver.clone();
+    let handle = tokio::spawn(async move {
+        let result = system.run().await;
+
+        // the key exchange servers only stop when a shutdown was requested, their
+        // errors are logged by their tasks
+        if *shutdown_receiver.borrow() {
+            for task in nts_ke_tasks {
+                let _ = task.await;
+            }
+        }
+        keyset_shutdown_sender.send_replace(true);
+        let _ = keyset_task.await;
+
+        result
+    });
 
     Ok((handle, channels))
 }
@@ -82,12 +194,24 @@ struct System<C: NtpClock> {
     system_snapshot_sender: tokio::sync::watch::Sender<SystemSnapshot>,
     peer_snapshots_sender: tokio::sync::watch::Sender<Vec<ObservablePeerState>>,
     server_data_sender: tokio::sync::watch::Sender<Vec<ServerData>>,
+    kernel_pps_sender: tokio::sync::watch::Sender<Option<KernelPpsStatus>>,
+    peer_history_sender: tokio::sync::watch::Sender<Vec<ObservablePeerHistory>>,
+    event_sender: broadcast::Sender<Event>,
 
     msg_for_system_rx: mpsc::Receiver<MsgForSystem>,
     spawn_task_rx: mpsc::Receiver<SpawnTask>,
+    passive_association_rx: mpsc::Receiver<PassiveAssociationRequest>,
+    passive_association_sender: mpsc::Sender<PassiveAssociationRequest>,
+    config_change_rx: mpsc::Receiver<ConfigChange>,
 
+    /// The configured peers, from which the running peers are spawned
+    peer_configs: Vec<PeerConfig>,
     peers: HashMap<PeerIndex, PeerState>,
     servers: Vec<ServerData>,
+    /// The tasks of the servers, in the same order as `servers`
+    server_tasks: Vec<JoinHandle<()>>,
+    /// The rate limited packets of the servers when last checked, in the same order as `servers`
+    server_rate_limited: Vec<u64>,
     spawner: Spawner,
     peer_indexer: PeerIndexIssuer,
     pool_indexer: PoolIndexIssuer,
@@ -96,12 +220,27 @@ struct System<C: NtpClock> {
 
     clock: C,
     controller: DefaultTimeSyncController<C, PeerIndex>,
+    /// The peer that the clock is synchronized to
+    system_peer: Option<PeerIndex>,
+    /// The reference clock whose pulses the kernel disciplines the clock with
+    kernel_pps: Option<PeerIndex>,
+    /// File in which the clock frequency is kept across restarts
+    drift_file: Option<PathBuf>,
+
+    keyset: tokio::sync::watch::Receiver<Arc<KeySet>>,
+    keys: Arc<SymmetricKeys>,
 }
 
 impl<C: NtpClock> System<C> {
     const MESSAGE_BUFFER_SIZE: usize = 32;
-
-    fn new(clock: C, config: CombinedSystemConfig) -> (Self, DaemonChannels) {
+    const EVENT_BUFFER_SIZE: usize = 64;
+
+    fn new(
+        clock: C,
+        config: CombinedSystemConfig,
+        keyset: tokio::sync::watch::Receiver<Arc<KeySet>>,
+        keys: Arc<SymmetricKeys>,
+    ) -> (Self, DaemonChannels) {
         // Setup system snapshot
         let system = SystemSnapshot {
             stratum: config.system.local_stratum,
@@ -114,10 +253,18 @@ impl<C: NtpClock> System<C> {
             tokio::sync::watch::channel(system);
         let (peer_snapshots_sender, peer_snapshots_receiver) = tokio::sync::watch::channel(vec![]);
         let (server_data_sender, server_data_receiver) = tokio::sync::watch::channel(vec![]);
+        let (kernel_pps_sender, kernel_pps_receiver) = tokio::sync::watch::channel(None);
+        let (peer_history_sender, peer_history_receiver) = tokio::sync::watch::channel(vec![]);
+        let (event_sender, _) = broadcast::channel(Self::EVENT_BUFFER_SIZE);
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+        let (config_change_sender, config_change_receiver) =
+            tokio::sync::mpsc::channel(Self::MESSAGE_BUFFER_SIZE);
         let (spawn_task_sender, spawn_task_receiver) =
             tokio::sync::mpsc::channel(Self::MESSAGE_BUFFER_SIZE);
         let (msg_for_system_sender, msg_for_system_receiver) =
             tokio::sync::mpsc::channel(Self::MESSAGE_BUFFER_SIZE);
+        let (passive_association_sender, passive_association_receiver) =
+            tokio::sync::mpsc::channel(Self::MESSAGE_BUFFER_SIZE);
 
         // Build System and its channels
         (
@@ -129,12 +276,21 @@ impl<C: NtpClock> System<C> {
                 system_snapshot_sender,
                 peer_snapshots_sender,
                 server_data_sender,
+                kernel_pps_sender,
+                peer_history_sender,
+                event_sender: event_sender.clone(),
 
                 msg_for_system_rx: msg_for_system_receiver,
                 spawn_task_rx: spawn_task_receiver,
+                passive_association_rx: passive_association_receiver,
+                passive_association_sender,
+                config_change_rx: config_change_receiver,
 
+                peer_configs: Default::default(),
                 peers: Default::default(),
                 servers: Default::default(),
+                server_tasks: Default::default(),
+                server_rate_limited: Default::default(),
                 spawner: Spawner {
                     pools: Default::default(),
                     sender: spawn_task_sender,
@@ -145,9 +301,15 @@ impl<C: NtpClock> System<C> {
                     msg_for_system_sender,
                     system_snapshot_receiver: system_snapshot_receiver.clone(),
                     system_config_receiver: config_receiver.clone(),
+                    shutdown_receiver: shutdown_receiver.clone(),
                 },
                 clock: clock.clone(),
                 controller: DefaultTimeSyncController::new(clock, config.system, config.algorithm),
+                system_peer: None,
+                kernel_pps: None,
+                drift_file: None,
+                keyset,
+                keys,
             },
             DaemonChannels {
                 config_receiver,
@@ -155,6 +317,12 @@ impl<C: NtpClock> System<C> {
                 peer_snapshots_receiver,
                 server_data_receiver,
                 system_snapshot_receiver,
+                kernel_pps_receiver,
+                peer_history_receiver,
+                event_sender,
+                config_change_sender,
+                shutdown_sender,
+                shutdown_receiver,
             },
         )
     }
@@ -162,6 +330,12 @@ impl<C: NtpClock> System<C> {
     async fn run(&mut self) -> std::io::Result<()> {
         //let mut snapshots = Vec::with_capacity(self.peers_rwlock.read().await.size());
 
+        let mut drift_interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + DRIFT_FILE_INTERVAL,
+            DRIFT_FILE_INTERVAL,
+        );
+        let mut rate_limit_interval = tokio::time::interval(RATE_LIMIT_EVENT_INTERVAL);
+
         loop {
             tokio::select! {
                 opt_msg_for_system = self.msg_for_system_rx.recv() => {
@@ -187,16 +361,179 @@ impl<C: NtpClock> System<C> {
                         }
                     }
                 }
+                opt_request = self.passive_association_rx.recv() => {
+                    // the system holds a sender itself, so the channel never closes
+                    if let Some(request) = opt_request {
+                        self.handle_passive_association(request);
+                    }
+                }
                 _ = self.config_receiver.changed(), if self.config_receiver.has_changed().is_ok() => {
                     self.handle_config_update();
                 }
+                opt_change = self.config_change_rx.recv() => {
+                    // the channel closes when the dynamic configurator stops
+                    if let Some(change) = opt_change {
+                        self.handle_config_change(change).await;
+                    }
+                }
+                _ = drift_interval.tick(), if self.drift_file.is_some() => {
+                    self.store_drift_file().await;
+                }
+                _ = rate_limit_interval.tick() => {
+                    self.check_rate_limiting();
+                }
+                _ = self.peer_channels.shutdown_receiver.changed(), if self.peer_channels.shutdown_receiver.has_changed().is_ok() => {
+                    // the peers and servers stop by themselves
+                    tracing::info!("shutting down");
+                    break;
+                }
+            }
+        }
+
+        self.store_drift_file().await;
+        self.disable_kernel_pps();
+
+        // we no longer keep the clock synchronized, so let other
+        // users of the clock know not to rely on it
+        if let Err(error) = self.clock.status_update(NtpLeapIndicator::Unknown) {
+            warn!(?error, "Could not mark the clock as unsynchronized");
+        }
+
+        Ok(())
+    }
+
+    async fn store_drift_file(&mut self) {
+        if let (Some(path), Some(frequency)) = (&self.drift_file, self.controller.frequency()) {
+            crate::drift::store_or_warn(path, frequency).await;
+        }
+    }
+
+    fn send_event(&self, event: Event) {
+        // Don't care if there are no subscribers
+        let _ = self.event_sender.send(event);
+    }
+
+    /// Report the servers that rate limited requests since the previous check
+    fn check_rate_limiting(&mut self) {
+        for (data, seen) in self.servers.iter().zip(self.server_rate_limited.iter_mut()) {
+            let rate_limited = data.stats.rate_limited_packets.get();
+            if rate_limited > *seen {
+                let _ = self.event_sender.send(Event::RateLimited {
+                    server: data.config.addr,
+                    packets: rate_limited - *seen,
+                });
+                *seen = rate_limited;
             }
         }
+    }
+
+    async fn handle_config_change(&mut self, change: ConfigChange) {
+        match change {
+            ConfigChange::Reload(reload) => self.handle_config_reload(reload).await,
+            ConfigChange::AddPeer(config, reply) => {
+                // the client may have gone away in the meantime
+                let _ = reply.send(self.handle_add_peer(config).await);
+            }
+            ConfigChange::RemovePeer(address, reply) => {
+                let _ = reply.send(self.handle_remove_peer(&address));
+            }
+        }
+
+        // Don't care if there is no receiver
+        let _ = self
+            .peer_snapshots_sender
+            .send(self.observe_peers().collect());
+    }
+
+    async fn handle_add_peer(&mut self, config: PeerConfig) -> ConfigResponse {
+        if self.peer_configs.contains(&config) {
+            return Err("the peer is already configured".into());
+        }
+
+        check_peer_key(&config, &self.keys).map_err(|e| e.to_string())?;
+
+        info!(?config, "adding peer");
+        self.add_peer(config).await.map_err(|e| e.to_string())
+    }
+
+    fn handle_remove_peer(&mut self, address: &str) -> ConfigResponse {
+        let configs: Vec<_> = self
+            .peer_configs
+            .iter()
+            .filter(|config| config.has_address(address))
+            .cloned()
+            .collect();
+
+        if configs.is_empty() {
+            return Err(format!("no peer with address {address} is configured"));
+        }
+
+        for config in &configs {
+            info!(?config, "removing peer");
+            self.remove_peer(config);
+        }
 
-        // the channel closed and has no more messages in it
         Ok(())
     }
 
+    async fn handle_config_reload(&mut self, reload: ConfigReload) {
+        let old_keys = std::mem::replace(&mut self.keys, Arc::new(reload.keys));
+        self.controller.update_leap_seconds(reload.leap_seconds);
+
+        // peers and servers that did not change keep running, so they keep their state. They
+        // authenticate with the keys they were started with, so they are restarted when those
+        // keys changed.
+        let (mut removed, mut added) = config_diff(&self.peer_configs, &reload.peers);
+        let restarted: Vec<_> = self
+            .peer_configs
+            .iter()
+            .filter(|config| reload.peers.contains(config))
+            .filter(|config| match config.key_id() {
+                Some(key_id) => old_keys.get(key_id) != self.keys.get(key_id),
+                None => false,
+            })
+            .cloned()
+            .collect();
+        removed.extend(restarted.iter().cloned());
+        added.extend(restarted);
+
+        for config in &removed {
+            info!(?config, "removing peer");
+            self.remove_peer(config);
+        }
+        for config in added {
+            info!(?config, "adding peer");
+            if let Err(error) = self.add_peer(config).await {
+                warn!(?error, "Could not add peer");
+            }
+        }
+
+        let running: Vec<_> = self
+            .servers
+            .iter()
+            .map(|data| data.config.clone())
+            .collect();
+        let (mut removed, mut added) = config_diff(&running, &reload.servers);
+        // servers answer requests authenticated with any key of the keyfile
+        if old_keys != self.keys {
+            let restarted: Vec<_> = running
+                .into_iter()
+                .filter(|config| reload.servers.contains(config))
+                .collect();
+            removed.extend(restarted.iter().cloned());
+            added.extend(restarted);
+        }
+
+        for config in &removed {
+            info!(?config, "removing server");
+            self.remove_server(config);
+        }
+        for config in added {
+            info!(?config, "adding server");
+            self.add_server(config).await;
+        }
+    }
+
     fn handle_config_update(&mut self) {
         let config = *self.config_receiver.borrow_and_update();
         self.controller
@@ -207,12 +544,38 @@ impl<C: NtpClock> System<C> {
     async fn handle_peer_update(&mut self, msg: MsgForSystem) -> std::io::Result<()> {
         tracing::debug!(?msg, "updating peer");
 
+        let index = match &msg {
+            MsgForSystem::MustDemobilize(index)
+            | MsgForSystem::NewMeasurement(index, ..)
+            | MsgForSystem::UpdatedSnapshot(index, _)
+            | MsgForSystem::NetworkIssue(index) => *index,
+        };
+        if !self.peers.contains_key(&index) {
+            // sent by a peer just before it was removed from the configuration
+            tracing::debug!(?index, "ignoring message of removed peer");
+            return Ok(());
+        }
+
         match msg {
             MsgForSystem::MustDemobilize(index) => {
+                // passive associations also demobilize once the peer stops sending
+                if let Some(state) = self.peers.get(&index) {
+                    if !matches!(state.peer_address, PeerAddress::Passive { .. }) {
+                        let address = state.peer_address.to_string();
+                        self.send_event(Event::KissOfDeath { address });
+                    }
+                }
                 self.handle_peer_demobilize(index);
             }
             MsgForSystem::NewMeasurement(index, snapshot, measurement, packet) => {
-                self.handle_peer_measurement(index, snapshot, measurement, packet);
+                if let Err(PanicThresholdExceeded { offset }) =
+                    self.handle_peer_measurement(index, snapshot, measurement, packet)
+                {
+                    return Err(std::io::Error::new(
+                        ErrorKind::Other,
+                        format!("the panic threshold was exceeded by an offset of {offset:?}"),
+                    ));
+                }
             }
             MsgForSystem::UpdatedSnapshot(index, snapshot) => {
                 self.handle_peer_snapshot(index, snapshot);
@@ -228,21 +591,74 @@ impl<C: NtpClock> System<C> {
             .peer_snapshots_sender
             .send(self.observe_peers().collect());
 
+        if self.kernel_pps.is_some() {
+            self.update_kernel_pps_status();
+        }
+
         Ok(())
     }
 
+    /// Stop letting the kernel discipline the clock from a pps signal
+    fn disable_kernel_pps(&mut self) {
+        if self.kernel_pps.take().is_some() {
+            if let Err(error) = self.clock.disable_kernel_pps() {
+                warn!(?error, "Could not disable kernel pps discipline");
+            }
+            self.controller.set_kernel_pps(false);
+
+            // Don't care if there is no receiver
+            let _ = self.kernel_pps_sender.send(None);
+        }
+    }
+
+    fn update_kernel_pps_status(&mut self) {
+        match self.clock.kernel_pps_status() {
+            Ok(status) => {
+                // Don't care if there is no receiver
+                let _ = self.kernel_pps_sender.send(Some(status));
+            }
+            Err(error) => warn!(?error, "Could not read kernel pps status"),
+        }
+    }
+
     async fn handle_peer_network_issue(&mut self, index: PeerIndex) -> std::io::Result<()> {
         // Restart the peer reusing its configuration.
         let config = self.peers.remove(&index).unwrap().peer_address;
+        self.send_event(Event::PeerRemoved {
+            address: config.to_string(),
+        });
         match config {
-            PeerAddress::Peer { address } => {
-                self.add_standard_peer_internal(address).await;
+            PeerAddress::Peer {
+                address,
+                key_id,
+                timestamping,
+            } => {
+                self.add_standard_peer_internal(address, key_id, timestamping)
+                    .await;
+            }
+            PeerAddress::Symmetric { address, key_id } => {
+                self.add_symmetric_peer(address, key_id).await;
+            }
+            PeerAddress::Broadcast { address, key_id } => {
+                self.add_broadcast_peer(address, key_id).await;
+            }
+            PeerAddress::Passive { .. } => {
+                // a passive association is spawned again once the peer sends a new packet
+                self.controller.peer_remove(index);
+            }
+            PeerAddress::RefClock { config } => {
+                self.controller.peer_remove(index);
+                if self.kernel_pps == Some(index) {
+                    self.disable_kernel_pps();
+                }
+                self.add_refclock(config)?;
             }
             PeerAddress::Nts {
-                address,
+                ke_address,
                 extra_certificates,
+                ..
             } => {
-                self.add_nts_peer(address, extra_certificates)
+                self.add_nts_peer(ke_address, extra_certificates)
                     .await
                     .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
             }
@@ -251,9 +667,11 @@ impl<C: NtpClock> System<C> {
                 index,
                 address,
                 max_peers,
+                timestamping,
                 ..
             } => {
-                self.add_to_pool(index, address, max_peers).await;
+                self.add_to_pool(index, address, max_peers, timestamping)
+                    .await;
             }
         }
 
@@ -261,13 +679,24 @@ impl<C: NtpClock> System<C> {
     }
 
     fn handle_peer_snapshot(&mut self, index: PeerIndex, snapshot: PeerSnapshot) {
-        self.controller.peer_update(
-            index,
-            snapshot
+        // the controller never gets the pulses that the kernel uses, see `handle_peer_measurement`
+        let usable = self.kernel_pps != Some(index)
+            && snapshot
                 .accept_synchronization(self.config.system.local_stratum)
-                .is_ok(),
-        );
-        self.peers.get_mut(&index).unwrap().snapshot = Some(snapshot);
+                .is_ok();
+        self.controller.peer_update(index, usable);
+        let state = self.peers.get_mut(&index).unwrap();
+        let was_reachable = state
+            .snapshot
+            .map(|snapshot| snapshot.reach.is_reachable())
+            .unwrap_or(false);
+        state.snapshot = Some(snapshot);
+
+        let reachable = snapshot.reach.is_reachable();
+        if reachable != was_reachable {
+            let address = state.peer_address.to_string();
+            self.send_event(Event::ReachabilityChanged { address, reachable });
+        }
     }
 
     fn handle_peer_measurement(
@@ -276,27 +705,110 @@ impl<C: NtpClock> System<C> {
         snapshot: PeerSnapshot,
         measurement: ntp_proto::Measurement,
         packet: ntp_proto::NtpPacket<'static>,
-    ) {
+    ) -> Result<(), PanicThresholdExceeded> {
         self.handle_peer_snapshot(index, snapshot);
+
+        // the kernel already uses these pulses, using them again would correct the clock twice
+        if self.kernel_pps == Some(index) {
+            tracing::trace!(?index, "pulse left to the kernel pps discipline");
+            return Ok(());
+        }
+
         let result = self.controller.peer_measurement(index, measurement, packet);
-        if let Some((used_peers, timedata)) = result {
-            self.system.update(
-                used_peers.iter().map(|v| {
-                    self.peers.get(v).and_then(|data| data.snapshot).expect(
-                        "Critical error: Peer used for synchronization that is not known to system",
-                    )
-                }),
-                timedata,
-                &self.config.system,
-            );
-            // Don't care if there is no receiver.
-            let _ = self.system_snapshot_sender.send(self.system);
+        if let Some(decision) = self.controller.peer_filter_decision(index) {
+            self.record_measurement(index, measurement, decision);
+        }
+        let update = match result {
+            Ok(update) => update,
+            Err(panic) => {
+                self.send_event(Event::PanicThresholdExceeded {
+                    offset: panic.offset,
+                });
+                return Err(panic);
+            }
+        };
+
+        if let Some((_, _, correction)) = update {
+            self.send_event(match correction {
+                ClockCorrection::Step(offset) => Event::ClockStep { offset },
+                ClockCorrection::Slew(offset) => Event::ClockSlew { offset },
+            });
         }
+
+        // the selection is also made when it does not lead to a correction of the clock
+        self.update_system_peer();
+
+        let (used_peers, timedata, _) = match update {
+            Some(update) => update,
+            None => return Ok(()),
+        };
+
+        self.system.update(
+            used_peers.iter().map(|v| {
+                self.peers.get(v).and_then(|data| data.snapshot).expect(
+                    "Critical error: Peer used for synchronization that is not known to system",
+                )
+            }),
+            timedata,
+            &self.config.system,
+        );
+        // Don't care if there is no receiver.
+        let _ = self.system_snapshot_sender.send(self.system);
+
+        Ok(())
+    }
+
+    /// The system peer is the peer with the [`SelectionStatus::SystemPeer`] status, so that
+    /// events agree with the selection reported in the observation socket
+    fn update_system_peer(&mut self) {
+        let system_peer = self.peers.keys().copied().find(|index| {
+            self.controller.peer_selection(*index) == Some(SelectionStatus::SystemPeer)
+        });
+
+        if self.system_peer != system_peer {
+            self.system_peer = system_peer;
+            if let Some(state) = system_peer.and_then(|index| self.peers.get(&index)) {
+                self.send_event(Event::SelectionChanged {
+                    system_peer: state.peer_address.to_string(),
+                });
+            }
+        }
+    }
+
+    fn record_measurement(
+        &mut self,
+        index: PeerIndex,
+        measurement: ntp_proto::Measurement,
+        decision: FilterDecision,
+    ) {
+        let history = &mut self.peers.get_mut(&index).unwrap().history;
+        if history.len() == MEASUREMENT_HISTORY_SIZE {
+            history.pop_front();
+        }
+        history.push_back(ObservableMeasurement {
+            offset: measurement.offset,
+            delay: measurement.delay,
+            localtime: measurement.localtime,
+            decision,
+        });
+
+        // Don't care if there is no receiver
+        let _ = self
+            .peer_history_sender
+            .send(self.observe_history().collect());
     }
 
     fn handle_peer_demobilize(&mut self, index: PeerIndex) {
         self.controller.peer_remove(index);
-        self.peers.remove(&index);
+        if let Some(state) = self.peers.remove(&index) {
+            let address = state.peer_address.to_string();
+            self.send_event(Event::PeerRemoved { address });
+            // Don't care if there is no receiver
+            let _ = self
+                .peer_history_sender
+                .send(self.observe_history().collect());
+        }
+        self.update_system_peer();
     }
 
     fn handle_spawn(
@@ -305,29 +817,174 @@ impl<C: NtpClock> System<C> {
         addr: SocketAddr,
         opt_nts: Option<PeerNtsData>,
     ) {
+        // the peer may have been removed from the configuration while its address was resolved
+        if !self
+            .peer_configs
+            .iter()
+            .any(|config| peer_address.is_configured_by(config))
+        {
+            tracing::debug!(
+                ?peer_address,
+                "not spawning peer that is no longer configured"
+            );
+            return;
+        }
+
         let index = self.peer_indexer.get();
 
+        let key_id = match &peer_address {
+            PeerAddress::Peer { key_id, .. } | PeerAddress::Symmetric { key_id, .. } => *key_id,
+            PeerAddress::Broadcast { key_id, .. } => Some(*key_id),
+            _ => None,
+        };
+        // key ids are checked against the keyfile when the peer is configured, so a peer
+        // never falls back to unauthenticated packets
+        let symmetric_key = match key_id.map(|key_id| (key_id, self.keys.get(key_id))) {
+            Some((_, Some(symmetric_key))) => Some(symmetric_key.clone()),
+            Some((key_id, None)) => {
+                tracing::error!(
+                    ?peer_address,
+                    key_id,
+                    "Not spawning peer, its key is not in the keyfile"
+                );
+                return;
+            }
+            None => None,
+        };
+        let symmetric = matches!(peer_address, PeerAddress::Symmetric { .. });
+        let timestamping = match &peer_address {
+            PeerAddress::Peer { timestamping, .. } | PeerAddress::Pool { timestamping, .. } => {
+                *timestamping
+            }
+            _ => TimestampingMode::Software,
+        };
+        let broadcast = matches!(peer_address, PeerAddress::Broadcast { .. });
+        let stats = PeerStats::default();
+
+        self.controller.peer_add(index);
+        let task = match symmetric_key {
+            Some(symmetric_key) if broadcast => PeerTask::spawn_broadcast(
+                index,
+                addr,
+                self.clock.clone(),
+                NETWORK_WAIT_PERIOD,
+                self.peer_channels.clone(),
+                symmetric_key,
+                stats.clone(),
+            ),
+            symmetric_key => PeerTask::spawn(
+                index,
+                addr,
+                self.clock.clone(),
+                NETWORK_WAIT_PERIOD,
+                self.peer_channels.clone(),
+                opt_nts,
+                symmetric_key,
+                symmetric,
+                timestamping,
+                stats.clone(),
+            ),
+        };
+
+        self.send_event(Event::PeerAdded {
+            address: peer_address.to_string(),
+        });
         self.peers.insert(
             index,
             PeerState {
                 snapshot: None,
                 peer_address,
+                stats,
+                task: Some(task),
+                history: Default::default(),
             },
         );
+
+        // Don't care if there is no receiver
+        let _ = self
+            .peer_snapshots_sender
+            .send(self.observe_peers().collect());
+    }
+
+    fn handle_passive_association(&mut self, request: PassiveAssociationRequest) {
+        let index = self.peer_indexer.get();
+        let stats = PeerStats::default();
+        let address = request.peer_addr;
+
         self.controller.peer_add(index);
-        PeerTask::spawn(
+        let task = PeerTask::spawn_passive(
             index,
-            addr,
             self.clock.clone(),
-            NETWORK_WAIT_PERIOD,
             self.peer_channels.clone(),
-            opt_nts,
+            stats.clone(),
+            request,
+        );
+
+        self.send_event(Event::PeerAdded {
+            address: address.to_string(),
+        });
+        self.peers.insert(
+            index,
+            PeerState {
+                snapshot: None,
+                peer_address: PeerAddress::Passive { address },
+                stats,
+                task: Some(task),
+                history: Default::default(),
+            },
+        );
+
+        // Don't care if there is no receiver
+        let _ = self
+            .peer_snapshots_sender
+            .send(self.observe_peers().collect());
+    }
+
+    /// Adds a local reference clock
+    fn add_refclock(&mut self, config: RefClockConfig) -> std::io::Result<()> {
+        let driver = crate::refclock::open(&config)?;
+        let index = self.peer_indexer.get();
+
+        if let RefClockConfig::Pps(PpsRefClockConfig {
+            kernel_discipline: true,
+            ..
+        }) = config
+        {
+            // like with hardware timestamping, fall back to only using the pulses as a source
+            match self.clock.enable_kernel_pps() {
+                Ok(()) => {
+                    self.kernel_pps = Some(index);
+                    self.controller.set_kernel_pps(true);
+                    self.update_kernel_pps_status();
+                }
+                Err(error) => warn!(?error, "Could not enable kernel pps discipline"),
+            }
+        }
+        let reference_id = config.reference_id();
+
+        self.controller.peer_add(index);
+        let task = RefClockTask::spawn(index, reference_id, driver, self.peer_channels.clone());
+
+        self.send_event(Event::PeerAdded {
+            address: config.to_string(),
+        });
+        self.peers.insert(
+            index,
+            PeerState {
+                snapshot: None,
+                peer_address: PeerAddress::RefClock { config },
+                stats: Default::default(),
+                task: Some(task),
+                history: Default::default(),
+            },
         );
 
         // Don't care if there is no receiver
         let _ = self
             .peer_snapshots_sender
             .send(self.observe_peers().collect());
+
+        Ok(())
     }
 
     #[cfg(test)]
@@ -338,7 +995,14 @@ impl<C: NtpClock> System<C> {
             index,
             PeerState {
                 snapshot: None,
-                peer_address: PeerAddress::Peer { address: addr },
+                peer_address: PeerAddress::Peer {
+                    address: addr,
+                    key_id: None,
+                    timestamping: TimestampingMode::Software,
+                },
+                stats: Default::default(),
+                task: None,
+                history: Default::default(),
             },
         );
         self.controller.peer_add(index);
@@ -346,23 +1010,107 @@ impl<C: NtpClock> System<C> {
         index
     }
 
+    /// Adds a configured peer, which is kept running until
+    /// it is removed from the configuration
+    async fn add_peer(&mut self, config: PeerConfig) -> std::io::Result<()> {
+        // the configuration must be known before the peers are spawned
+        self.peer_configs.push(config.clone());
+
+        let result = self.start_peer(config).await;
+        if result.is_err() {
+            self.peer_configs.pop();
+        }
+
+        result
+    }
+
+    async fn start_peer(&mut self, config: PeerConfig) -> std::io::Result<()> {
+        match config {
+            PeerConfig::Standard(StandardPeerConfig {
+                addr,
+                key_id,
+                timestamping,
+            }) => {
+                self.add_standard_peer_internal(addr, key_id, timestamping)
+                    .await;
+            }
+            PeerConfig::Symmetric(SymmetricPeerConfig { addr, key_id }) => {
+                self.add_symmetric_peer(addr, key_id).await;
+            }
+            PeerConfig::Broadcast(BroadcastPeerConfig { addr, key_id }) => {
+                self.add_broadcast_peer(addr, key_id).await;
+            }
+            PeerConfig::Nts(NtsPeerConfig {
+                ke_addr,
+                certificates,
+            }) => {
+                if let Err(e) = self.add_nts_peer(ke_addr, certificates).await {
+                    return Err(std::io::Error::new(ErrorKind::Other, e));
+                }
+            }
+            PeerConfig::Pool(PoolPeerConfig {
+                addr,
+                max_peers,
+                timestamping,
+            }) => {
+                self.add_new_pool(addr, max_peers, timestamping).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops the peers that were spawned for a configured peer
+    fn remove_peer(&mut self, config: &PeerConfig) {
+        self.peer_configs.retain(|configured| configured != config);
+
+        let indices: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.peer_address.is_configured_by(config))
+            .map(|(index, _)| *index)
+            .collect();
+
+        for index in indices {
+            if let Some(task) = self.peers.get(&index).and_then(|state| state.task.as_ref()) {
+                task.abort();
+            }
+            self.handle_peer_demobilize(index);
+        }
+    }
+
     /// Add a single standard peer
-    async fn add_standard_peer_internal(&mut self, address: NormalizedAddress) {
+    async fn add_standard_peer_internal(
+        &mut self,
+        address: NormalizedAddress,
+        key_id: Option<u32>,
+        timestamping: TimestampingMode,
+    ) {
         let config = SpawnConfig::Standard {
-            config: StandardPeerConfig { addr: address },
+            config: StandardPeerConfig {
+                addr: address,
+                key_id,
+                timestamping,
+            },
         };
 
         self.spawner.spawn(config).await;
     }
 
     /// Adds up to `max_peers` peers from a pool.
-    async fn add_new_pool(&mut self, address: NormalizedAddress, max_peers: usize) {
+    async fn add_new_pool(
+        &mut self,
+        address: NormalizedAddress,
+        max_peers: usize,
+        timestamping: TimestampingMode,
+    ) {
         // Each pool gets a unique index, because the `NormalizedAddress` may not be unique
         // Having two pools use the same address does not really do anything good, but we
         // want to make sure it does technically work.
         let index = self.pool_indexer.get();
 
-        self.add_to_pool(index, address, max_peers).await
+        self.add_to_pool(index, address, max_peers, timestamping)
+            .await
     }
 
     async fn add_to_pool(
@@ -370,12 +1118,18 @@ impl<C: NtpClock> System<C> {
         index: PoolIndex,
         address: NormalizedAddress,
         max_peers: usize,
+        timestamping: TimestampingMode,
     ) {
         let in_use: Vec<_> = self
             .peers
             .values()
             .filter_map(|v| match &v.peer_address {
-                PeerAddress::Peer { .. } | PeerAddress::Nts { .. } => None,
+                PeerAddress::Peer { .. }
+                | PeerAddress::Symmetric { .. }
+                | PeerAddress::Passive { .. }
+                | PeerAddress::Broadcast { .. }
+                | PeerAddress::RefClock { .. }
+                | PeerAddress::Nts { .. } => None,
                 PeerAddress::Pool {
                     index: pool_index,
                     address: peer_address,
@@ -394,6 +1148,7 @@ impl<C: NtpClock> System<C> {
             config: PoolPeerConfig {
                 addr: address,
                 max_peers,
+                timestamping,
             },
             in_use,
         };
@@ -401,9 +1156,28 @@ impl<C: NtpClock> System<C> {
         self.spawner.spawn(config).await;
     }
 
-    /// Adds a single peer (that is not part of a pool!)
-    async fn add_standard_peer(&mut self, address: NormalizedAddress) {
-        self.add_standard_peer_internal(address).await
+    /// Adds a peer with which we form a symmetric active association
+    async fn add_symmetric_peer(&mut self, address: NormalizedAddress, key_id: Option<u32>) {
+        let config = SpawnConfig::Symmetric {
+            config: SymmetricPeerConfig {
+                addr: address,
+                key_id,
+            },
+        };
+
+        self.spawner.spawn(config).await;
+    }
+
+    /// Adds a client of a broadcast server
+    async fn add_broadcast_peer(&mut self, address: NormalizedAddress, key_id: u32) {
+        let config = SpawnConfig::Broadcast {
+            config: BroadcastPeerConfig {
+                addr: address,
+                key_id,
+            },
+        };
+
+        self.spawner.spawn(config).await;
     }
 
     /// Adds a peer that will use NTS
@@ -412,7 +1186,12 @@ impl<C: NtpClock> System<C> {
         ke_address: NormalizedAddress,
         extra_certificates: Arc<[Certificate]>,
     ) -> Result<(), KeyExchangeError> {
-        let ke = key_exchange(ke_address.server_name, ke_address.port, &extra_certificates).await?;
+        let ke = key_exchange(
+            ke_address.server_name.clone(),
+            ke_address.port,
+            &extra_certificates,
+        )
+        .await?;
 
         let address = NormalizedAddress::from_string_ntp(format!("{}:{}", ke.remote, ke.port))?;
 
@@ -420,6 +1199,7 @@ impl<C: NtpClock> System<C> {
             ke,
             extra_certificates,
             address,
+            ke_address,
         };
 
         self.spawner.spawn(config).await;
@@ -433,13 +1213,28 @@ impl<C: NtpClock> System<C> {
             stats: stats.clone(),
             config: config.clone(),
         });
-        ServerTask::spawn(
+        let task = ServerTask::spawn(
             config,
             stats,
             self.peer_channels.system_snapshot_receiver.clone(),
             self.clock.clone(),
+            self.keyset.clone(),
+            self.keys.clone(),
+            self.passive_association_sender.clone(),
+            self.peer_channels.shutdown_receiver.clone(),
             NETWORK_WAIT_PERIOD,
         );
+        self.server_tasks.push(task);
+        self.server_rate_limited.push(0);
+        let _ = self.server_data_sender.send(self.servers.clone());
+    }
+
+    fn remove_server(&mut self, config: &ServerConfig) {
+        while let Some(position) = self.servers.iter().position(|data| data.config == *config) {
+            self.servers.remove(position);
+            self.server_tasks.remove(position).abort();
+            self.server_rate_limited.remove(position);
+        }
         let _ = self.server_data_sender.send(self.servers.clone());
     }
 
@@ -453,11 +1248,11 @@ impl<C: NtpClock> System<C> {
                             reachability: snapshot.reach,
                             poll_interval: snapshot.poll_interval,
                             peer_id: snapshot.peer_id,
-                            address: match &data.peer_address {
-                                PeerAddress::Peer { address } => address.to_string(),
-                                PeerAddress::Pool { address, .. } => address.to_string(),
-                                PeerAddress::Nts { address, .. } => address.to_string(),
-                            },
+                            stratum: snapshot.stratum,
+                            reference_id: snapshot.reference_id,
+                            selection: self.controller.peer_selection(*index),
+                            stats: data.stats.clone(),
+                            address: data.peer_address.to_string(),
                         }
                     } else {
                         ObservablePeerState::Nothing
@@ -466,6 +1261,24 @@ impl<C: NtpClock> System<C> {
                 .unwrap_or(ObservablePeerState::Nothing)
         })
     }
+
+    fn observe_history(&self) -> impl Iterator<Item = ObservablePeerHistory> + '_ {
+        self.peers
+            .values()
+            .filter(|data| !data.history.is_empty())
+            .map(|data| ObservablePeerHistory {
+                address: data.peer_address.to_string(),
+                measurements: data.history.clone(),
+            })
+    }
+}
+
+/// Split the configurations into those that are no longer present in `new`, and those that
+/// were not present in `old`. The configurations are compared as sets.
+fn config_diff<T: PartialEq + Clone>(old: &[T], new: &[T]) -> (Vec<T>, Vec<T>) {
+    let removed = old.iter().filter(|config| !new.contains(config)).cloned();
+    let added = new.iter().filter(|config| !old.contains(config)).cloned();
+    (removed.collect(), added.collect())
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
@@ -515,9 +1328,26 @@ impl PoolIndexIssuer {
 enum PeerAddress {
     Peer {
         address: NormalizedAddress,
+        key_id: Option<u32>,
+        timestamping: TimestampingMode,
+    },
+    Symmetric {
+        address: NormalizedAddress,
+        key_id: Option<u32>,
     },
+    /// Spawned by a server in response to a symmetric active peer
+    Passive { address: SocketAddr },
+    /// The broadcast address or multicast group on which we listen for a server
+    Broadcast {
+        address: NormalizedAddress,
+        key_id: u32,
+    },
+    /// A local reference clock
+    RefClock { config: RefClockConfig },
     Nts {
         address: NormalizedAddress,
+        /// The key exchange server that was configured for this peer
+        ke_address: NormalizedAddress,
         extra_certificates: Arc<[Certificate]>,
     },
     Pool {
@@ -525,13 +1355,81 @@ enum PeerAddress {
         address: NormalizedAddress,
         socket_address: std::net::SocketAddr,
         max_peers: usize,
+        timestamping: TimestampingMode,
     },
 }
 
+impl PeerAddress {
+    /// Whether the peer was spawned for the given configured peer
+    fn is_configured_by(&self, config: &PeerConfig) -> bool {
+        match (self, config) {
+            (
+                PeerAddress::Peer {
+                    address,
+                    key_id,
+                    timestamping,
+                },
+                PeerConfig::Standard(config),
+            ) => {
+                *address == config.addr
+                    && *key_id == config.key_id
+                    && *timestamping == config.timestamping
+            }
+            (PeerAddress::Symmetric { address, key_id }, PeerConfig::Symmetric(config)) => {
+                *address == config.addr && *key_id == config.key_id
+            }
+            (PeerAddress::Broadcast { address, key_id }, PeerConfig::Broadcast(config)) => {
+                *address == config.addr && *key_id == config.key_id
+            }
+            (
+                PeerAddress::Nts {
+                    ke_address,
+                    extra_certificates,
+                    ..
+                },
+                PeerConfig::Nts(config),
+            ) => *ke_address == config.ke_addr && *extra_certificates == config.certificates,
+            (
+                PeerAddress::Pool {
+                    address,
+                    max_peers,
+                    timestamping,
+                    ..
+                },
+                PeerConfig::Pool(config),
+            ) => {
+                *address == config.addr
+                    && *max_peers == config.max_peers
+                    && *timestamping == config.timestamping
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddress::Peer { address, .. } => address.fmt(f),
+            PeerAddress::Symmetric { address, .. } => address.fmt(f),
+            PeerAddress::Passive { address } => address.fmt(f),
+            PeerAddress::Broadcast { address, .. } => address.fmt(f),
+            PeerAddress::Pool { address, .. } => address.fmt(f),
+            PeerAddress::Nts { address, .. } => address.fmt(f),
+            PeerAddress::RefClock { config } => config.fmt(f),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct PeerState {
     snapshot: Option<PeerSnapshot>,
     peer_address: PeerAddress,
+    stats: PeerStats,
+    /// The task of the peer, which is stopped when the peer is removed from the configuration
+    task: Option<JoinHandle<()>>,
+    /// The latest measurements, oldest first
+    history: VecDeque<ObservableMeasurement>,
 }
 
 #[derive(Debug, Clone)]
@@ -558,10 +1456,17 @@ enum SpawnConfig {
         ke: KeyExchangeResult,
         extra_certificates: Arc<[Certificate]>,
         address: NormalizedAddress,
+        ke_address: NormalizedAddress,
     },
     Standard {
         config: StandardPeerConfig,
     },
+    Symmetric {
+        config: SymmetricPeerConfig,
+    },
+    Broadcast {
+        config: BroadcastPeerConfig,
+    },
     Pool {
         index: PoolIndex,
         config: PoolPeerConfig,
@@ -583,11 +1488,26 @@ impl Spawner {
         match config {
             SpawnConfig::Standard { config } => tokio::spawn(Self::spawn_standard(config, sender)),
 
+            SpawnConfig::Symmetric { config } => {
+                tokio::spawn(Self::spawn_symmetric(config, sender))
+            }
+
+            SpawnConfig::Broadcast { config } => {
+                tokio::spawn(Self::spawn_broadcast(config, sender))
+            }
+
             SpawnConfig::Nts {
                 ke,
                 extra_certificates,
                 address,
-            } => tokio::spawn(Self::spawn_nts(ke, address, extra_certificates, sender)),
+                ke_address,
+            } => tokio::spawn(Self::spawn_nts(
+                ke,
+                address,
+                ke_address,
+                extra_certificates,
+                sender,
+            )),
 
             SpawnConfig::Pool {
                 config,
@@ -600,16 +1520,69 @@ impl Spawner {
         }
     }
 
-    async fn spawn_standard(config: StandardPeerConfig, sender: Sender<SpawnTask>) {
-        let addr = loop {
-            match config.addr.lookup_host().await {
+    async fn spawn_standard(config: StandardPeerConfig, sender: Sender<SpawnTask>) {
+        let addr = Self::resolve(&config.addr).await;
+
+        let spawn_task = SpawnTask {
+            peer_address: PeerAddress::Peer {
+                address: config.addr,
+                key_id: config.key_id,
+                timestamping: config.timestamping,
+            },
+            address: addr,
+            nts: None,
+        };
+
+        if let Err(send_error) = sender.send(spawn_task).await {
+            tracing::error!(?send_error, "Receive half got disconnected");
+        }
+    }
+
+    async fn spawn_symmetric(config: SymmetricPeerConfig, sender: Sender<SpawnTask>) {
+        let addr = Self::resolve(&config.addr).await;
+
+        let spawn_task = SpawnTask {
+            peer_address: PeerAddress::Symmetric {
+                address: config.addr,
+                key_id: config.key_id,
+            },
+            address: addr,
+            nts: None,
+        };
+
+        if let Err(send_error) = sender.send(spawn_task).await {
+            tracing::error!(?send_error, "Receive half got disconnected");
+        }
+    }
+
+    async fn spawn_broadcast(config: BroadcastPeerConfig, sender: Sender<SpawnTask>) {
+        let addr = Self::resolve(&config.addr).await;
+
+        let spawn_task = SpawnTask {
+            peer_address: PeerAddress::Broadcast {
+                address: config.addr,
+                key_id: config.key_id,
+            },
+            address: addr,
+            nts: None,
+        };
+
+        if let Err(send_error) = sender.send(spawn_task).await {
+            tracing::error!(?send_error, "Receive half got disconnected");
+        }
+    }
+
+    /// Resolve the address of a peer, retrying until that succeeds
+    async fn resolve(address: &NormalizedAddress) -> SocketAddr {
+        loop {
+            match address.lookup_host().await {
                 Ok(mut addresses) => match addresses.next() {
                     None => {
                         warn!("Could not resolve peer address, retrying");
                         tokio::time::sleep(NETWORK_WAIT_PERIOD).await
                     }
                     Some(first) => {
-                        break first;
+                        return first;
                     }
                 },
                 Err(e) => {
@@ -617,24 +1590,13 @@ impl Spawner {
                     tokio::time::sleep(NETWORK_WAIT_PERIOD).await
                 }
             }
-        };
-
-        let spawn_task = SpawnTask {
-            peer_address: PeerAddress::Peer {
-                address: config.addr,
-            },
-            address: addr,
-            nts: None,
-        };
-
-        if let Err(send_error) = sender.send(spawn_task).await {
-            tracing::error!(?send_error, "Receive half got disconnected");
         }
     }
 
     async fn spawn_nts(
         ke: KeyExchangeResult,
         address: NormalizedAddress,
+        ke_address: NormalizedAddress,
         extra_certificates: Arc<[Certificate]>,
         sender: Sender<SpawnTask>,
     ) {
@@ -660,6 +1622,7 @@ impl Spawner {
         let spawn_task = SpawnTask {
             peer_address: PeerAddress::Nts {
                 address,
+                ke_address,
                 extra_certificates,
             },
             address: addr,
@@ -713,6 +1676,7 @@ impl Spawner {
                         address: config.addr.clone(),
                         socket_address: addr,
                         max_peers: config.max_peers,
+                        timestamping: config.timestamping,
                     },
                     address: addr,
                     nts: None,
@@ -804,6 +1768,74 @@ mod tests {
         }
     }
 
+    /// Records which operations that steer the clock are used
+    #[derive(Debug, Clone, Default)]
+    struct RecordingClock {
+        calls: Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+
+    impl RecordingClock {
+        fn record(&self, call: &'static str) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn calls(&self) -> Vec<&'static str> {
+            std::mem::take(&mut self.calls.lock().unwrap())
+        }
+    }
+
+    impl NtpClock for RecordingClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> std::result::Result<NtpTimestamp, Self::Error> {
+            Ok(NtpTimestamp::default())
+        }
+
+        fn set_frequency(&self, _freq: f64) -> Result<NtpTimestamp, Self::Error> {
+            self.record("set_frequency");
+            Ok(NtpTimestamp::default())
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<NtpTimestamp, Self::Error> {
+            self.record("step_clock");
+            Ok(NtpTimestamp::default())
+        }
+
+        fn enable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn disable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn ntp_algorithm_update(
+            &self,
+            _offset: NtpDuration,
+            _poll_interval: PollInterval,
+        ) -> Result<(), Self::Error> {
+            self.record("ntp_algorithm_update");
+            Ok(())
+        }
+
+        fn error_estimate_update(
+            &self,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn disable_kernel_pps(&self) -> Result<(), Self::Error> {
+            self.record("disable_kernel_pps");
+            Ok(())
+        }
+    }
+
     fn handle_spawn_no_nts<C: NtpClock>(
         system: &mut System<C>,
         peer_address: PeerAddress,
@@ -812,9 +1844,19 @@ mod tests {
         system.handle_spawn(peer_address, addr, None)
     }
 
+    fn test_keyset() -> tokio::sync::watch::Receiver<Arc<KeySet>> {
+        let (_, keyset) = tokio::sync::watch::channel(Arc::new(KeySet::new()));
+        keyset
+    }
+
     #[tokio::test]
     async fn test_peers() {
-        let (mut system, _) = System::new(TestClock {}, CombinedSystemConfig::default());
+        let (mut system, _) = System::new(
+            TestClock {},
+            CombinedSystemConfig::default(),
+            test_keyset(),
+            Default::default(),
+        );
 
         let mut indices = [PeerIndex { index: 0 }; 4];
 
@@ -923,16 +1965,230 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_config_diff() {
+        let (removed, added) = config_diff(&[1, 2, 3], &[3, 4, 1]);
+        assert_eq!(removed, vec![2]);
+        assert_eq!(added, vec![4]);
+    }
+
+    #[tokio::test]
+    async fn test_config_reload() {
+        let (mut system, _) = System::new(
+            TestClock {},
+            CombinedSystemConfig::default(),
+            test_keyset(),
+            Default::default(),
+        );
+
+        let peer = |addr: &str| {
+            PeerConfig::Standard(StandardPeerConfig {
+                addr: NormalizedAddress::new_unchecked(addr, 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
+            })
+        };
+
+        system.add_peer(peer("127.0.0.2")).await.unwrap();
+        system.add_peer(peer("127.0.0.3")).await.unwrap();
+        for _ in 0..2 {
+            let task = system.spawn_task_rx.recv().await.unwrap();
+            handle_spawn_no_nts(&mut system, task.peer_address, task.address);
+        }
+        assert_eq!(system.peers.len(), 2);
+
+        system
+            .handle_config_reload(ConfigReload {
+                peers: vec![peer("127.0.0.3"), peer("127.0.0.4")],
+                servers: vec![],
+                keys: Default::default(),
+                leap_seconds: None,
+            })
+            .await;
+
+        // the removed peer is stopped right away, the unchanged peer keeps running
+        assert_eq!(system.peers.len(), 1);
+        assert!(system
+            .peers
+            .values()
+            .all(|state| state.peer_address.is_configured_by(&peer("127.0.0.3"))));
+
+        let task = system.spawn_task_rx.recv().await.unwrap();
+        handle_spawn_no_nts(&mut system, task.peer_address, task.address);
+        assert_eq!(system.peers.len(), 2);
+
+        // a peer that is removed while it is spawned is not started
+        system
+            .handle_config_reload(ConfigReload {
+                peers: vec![peer("127.0.0.3")],
+                servers: vec![],
+                keys: Default::default(),
+                leap_seconds: None,
+            })
+            .await;
+        handle_spawn_no_nts(
+            &mut system,
+            PeerAddress::Peer {
+                address: NormalizedAddress::new_unchecked("127.0.0.4", 123),
+                key_id: None,
+                timestamping: TimestampingMode::Software,
+            },
+            "127.0.0.4:123".parse().unwrap(),
+        );
+        assert_eq!(system.peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_config_reload_keys() {
+        let keys: SymmetricKeys = "1 MD5 secret".parse().unwrap();
+        let (mut system, _) = System::new(
+            TestClock {},
+            CombinedSystemConfig::default(),
+            test_keyset(),
+            Arc::new(keys.clone()),
+        );
+
+        let peer = |addr: &str, key_id| {
+            PeerConfig::Standard(StandardPeerConfig {
+                addr: NormalizedAddress::new_unchecked(addr, 123),
+                key_id: Some(key_id),
+                timestamping: TimestampingMode::Software,
+            })
+        };
+
+        system.add_peer(peer("127.0.0.2", 1)).await.unwrap();
+        let task = system.spawn_task_rx.recv().await.unwrap();
+        handle_spawn_no_nts(&mut system, task.peer_address, task.address);
+        let index = *system.peers.keys().next().unwrap();
+
+        // a peer keeps running when its key did not change
+        system
+            .handle_config_reload(ConfigReload {
+                peers: vec![peer("127.0.0.2", 1)],
+                servers: vec![],
+                keys: keys.clone(),
+                leap_seconds: None,
+            })
+            .await;
+        assert!(system.peers.contains_key(&index));
+
+        // and is restarted with the new key when it did
+        system
+            .handle_config_reload(ConfigReload {
+                peers: vec![peer("127.0.0.2", 1)],
+                servers: vec![],
+                keys: "1 MD5 changed".parse().unwrap(),
+                leap_seconds: None,
+            })
+            .await;
+        assert!(system.peers.is_empty());
+        let task = system.spawn_task_rx.recv().await.unwrap();
+        handle_spawn_no_nts(&mut system, task.peer_address, task.address);
+        assert_eq!(system.peers.len(), 1);
+        assert!(!system.peers.contains_key(&index));
+
+        // a peer whose key is missing is not spawned without authentication
+        system.peer_configs.push(peer("127.0.0.3", 2));
+        handle_spawn_no_nts(
+            &mut system,
+            PeerAddress::Peer {
+                address: NormalizedAddress::new_unchecked("127.0.0.3", 123),
+                key_id: Some(2),
+                timestamping: TimestampingMode::Software,
+            },
+            "127.0.0.3:123".parse().unwrap(),
+        );
+        assert_eq!(system.peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_add_remove_peer() {
+        let (mut system, _) = System::new(
+            TestClock {},
+            CombinedSystemConfig::default(),
+            test_keyset(),
+            Default::default(),
+        );
+
+        let peer = PeerConfig::Standard(StandardPeerConfig {
+            addr: NormalizedAddress::new_unchecked("127.0.0.2", 123),
+            key_id: None,
+            timestamping: TimestampingMode::Software,
+        });
+
+        let (reply, response) = tokio::sync::oneshot::channel();
+        system
+            .handle_config_change(ConfigChange::AddPeer(peer.clone(), reply))
+            .await;
+        assert_eq!(response.await.unwrap(), Ok(()));
+
+        let task = system.spawn_task_rx.recv().await.unwrap();
+        handle_spawn_no_nts(&mut system, task.peer_address, task.address);
+        assert_eq!(system.peers.len(), 1);
+
+        let (reply, response) = tokio::sync::oneshot::channel();
+        system
+            .handle_config_change(ConfigChange::AddPeer(peer, reply))
+            .await;
+        assert!(response.await.unwrap().is_err());
+
+        // a peer with an unknown key is not added
+        let (reply, response) = tokio::sync::oneshot::channel();
+        let keyed_peer = PeerConfig::Standard(StandardPeerConfig {
+            addr: NormalizedAddress::new_unchecked("127.0.0.3", 123),
+            key_id: Some(1),
+            timestamping: TimestampingMode::Software,
+        });
+        system
+            .handle_config_change(ConfigChange::AddPeer(keyed_peer, reply))
+            .await;
+        assert!(response.await.unwrap().is_err());
+        assert_eq!(system.peer_configs.len(), 1);
+
+        let (reply, response) = tokio::sync::oneshot::channel();
+        system
+            .handle_config_change(ConfigChange::RemovePeer("127.0.0.3".into(), reply))
+            .await;
+        assert!(response.await.unwrap().is_err());
+
+        let (reply, response) = tokio::sync::oneshot::channel();
+        system
+            .handle_config_change(ConfigChange::RemovePeer("127.0.0.2".into(), reply))
+            .await;
+        assert_eq!(response.await.unwrap(), Ok(()));
+        assert!(system.peers.is_empty());
+        assert!(system.peer_configs.is_empty());
+    }
+
     #[tokio::test]
     async fn single_peer_pool() {
-        let (mut system, _) = System::new(TestClock {}, CombinedSystemConfig::default());
+        let (mut system, _) = System::new(
+            TestClock {},
+            CombinedSystemConfig::default(),
+            test_keyset(),
+            Default::default(),
+        );
 
         let peer_address = NormalizedAddress::new_unchecked("127.0.0.2", 123);
-        system.add_standard_peer(peer_address).await;
+        system
+            .add_peer(PeerConfig::Standard(StandardPeerConfig {
+                addr: peer_address,
+                key_id: None,
+                timestamping: TimestampingMode::Software,
+            }))
+            .await
+            .unwrap();
 
         let pool_address = NormalizedAddress::new_unchecked("127.0.0.1", 123);
         let max_peers = 1;
-        system.add_new_pool(pool_address.clone(), max_peers).await;
+        system
+            .add_peer(PeerConfig::Pool(PoolPeerConfig {
+                addr: pool_address.clone(),
+                max_peers,
+                timestamping: TimestampingMode::Software,
+            }))
+            .await
+            .unwrap();
 
         for _ in 0..2 {
             let task = system.spawn_task_rx.recv().await.unwrap();
@@ -959,10 +2215,22 @@ mod tests {
 
     #[tokio::test]
     async fn max_peers_bigger_than_pool_size() {
-        let (mut system, _) = System::new(TestClock {}, CombinedSystemConfig::default());
+        let (mut system, _) = System::new(
+            TestClock {},
+            CombinedSystemConfig::default(),
+            test_keyset(),
+            Default::default(),
+        );
 
         let peer_address = NormalizedAddress::new_unchecked("127.0.0.5", 123);
-        system.add_standard_peer(peer_address).await;
+        system
+            .add_peer(PeerConfig::Standard(StandardPeerConfig {
+                addr: peer_address,
+                key_id: None,
+                timestamping: TimestampingMode::Software,
+            }))
+            .await
+            .unwrap();
 
         let pool_address = NormalizedAddress::with_hardcoded_dns(
             "tweedegolf.nl",
@@ -970,7 +2238,14 @@ mod tests {
             vec!["127.0.0.1:123".parse().unwrap()],
         );
         let max_peers = 2;
-        system.add_new_pool(pool_address.clone(), max_peers).await;
+        system
+            .add_peer(PeerConfig::Pool(PoolPeerConfig {
+                addr: pool_address.clone(),
+                max_peers,
+                timestamping: TimestampingMode::Software,
+            }))
+            .await
+            .unwrap();
 
         for _ in 0..2 {
             let task = system.spawn_task_rx.recv().await.unwrap();
@@ -999,10 +2274,22 @@ mod tests {
 
     #[tokio::test]
     async fn simulate_pool() {
-        let (mut system, _) = System::new(TestClock {}, CombinedSystemConfig::default());
+        let (mut system, _) = System::new(
+            TestClock {},
+            CombinedSystemConfig::default(),
+            test_keyset(),
+            Default::default(),
+        );
 
         let peer_address = NormalizedAddress::new_unchecked("127.0.0.5", 123);
-        system.add_standard_peer(peer_address).await;
+        system
+            .add_peer(PeerConfig::Standard(StandardPeerConfig {
+                addr: peer_address,
+                key_id: None,
+                timestamping: TimestampingMode::Software,
+            }))
+            .await
+            .unwrap();
 
         let pool_address = NormalizedAddress::with_hardcoded_dns(
             "tweedegolf.nl",
@@ -1015,7 +2302,14 @@ mod tests {
             ],
         );
         let max_peers = 3;
-        system.add_new_pool(pool_address.clone(), max_peers).await;
+        system
+            .add_peer(PeerConfig::Pool(PoolPeerConfig {
+                addr: pool_address.clone(),
+                max_peers,
+                timestamping: TimestampingMode::Software,
+            }))
+            .await
+            .unwrap();
 
         for _ in 0..4 {
             let task = system.spawn_task_rx.recv().await.unwrap();
@@ -1037,4 +2331,273 @@ mod tests {
         // automatically selects another peer from the pool
         assert_eq!(system.peers.len(), 4);
     }
+
+    #[tokio::test]
+    async fn test_measurement_history() {
+        let (mut system, channels) = System::new(
+            TestClock {},
+            CombinedSystemConfig::default(),
+            test_keyset(),
+            Default::default(),
+        );
+        let history = channels.peer_history_receiver;
+
+        let index = system.create_test_peer(NormalizedAddress::new_unchecked("127.0.0.1", 123));
+        for seconds in 0..=MEASUREMENT_HISTORY_SIZE as u32 {
+            system
+                .handle_peer_update(MsgForSystem::NewMeasurement(
+                    index,
+                    peer_snapshot(),
+                    Measurement {
+                        delay: NtpDuration::from_seconds(0.1),
+                        offset: NtpDuration::from_seconds(0.),
+                        localtime: NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0),
+                        monotime: NtpInstant::now(),
+                    },
+                    NtpPacket::test(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        {
+            let history = history.borrow();
+            assert_eq!(history.len(), 1);
+            assert_eq!(history[0].address, "127.0.0.1:123");
+
+            // the oldest measurement was dropped
+            let measurements = &history[0].measurements;
+            assert_eq!(measurements.len(), MEASUREMENT_HISTORY_SIZE);
+            assert_eq!(
+                measurements[0].localtime,
+                NtpTimestamp::from_seconds_nanos_since_ntp_era(1, 0)
+            );
+            // the dispersion of so few measurements is too large to synchronize to
+            assert_eq!(measurements[0].decision, FilterDecision::Rejected);
+        }
+
+        system
+            .handle_peer_update(MsgForSystem::MustDemobilize(index))
+            .await
+            .unwrap();
+        assert!(history.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_selection_changed() {
+        let mut config = CombinedSystemConfig::default();
+        config.system.min_intersection_survivors = 1;
+        config.algorithm.distance_threshold = NtpDuration::from_seconds(10.0);
+        let (mut system, channels) =
+            System::new(TestClock {}, config, test_keyset(), Default::default());
+        // a known frequency makes small offsets slew the clock, so measurements are not ignored
+        system.controller.set_initial_frequency(0.0);
+        let mut events = channels.event_sender.subscribe();
+
+        let synced = system.create_test_peer(NormalizedAddress::new_unchecked("127.0.0.1", 123));
+        let unsynced = system.create_test_peer(NormalizedAddress::new_unchecked("127.0.0.2", 123));
+
+        let measurement = |index, stratum, seconds| {
+            MsgForSystem::NewMeasurement(
+                index,
+                PeerSnapshot {
+                    stratum,
+                    ..peer_snapshot()
+                },
+                Measurement {
+                    delay: NtpDuration::from_seconds(0.1),
+                    offset: NtpDuration::from_seconds(0.001),
+                    localtime: NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0),
+                    monotime: NtpInstant::now(),
+                },
+                NtpPacket::test(),
+            )
+        };
+
+        for seconds in 0..4 {
+            let msg = measurement(synced, 1, seconds);
+            system.handle_peer_update(msg).await.unwrap();
+        }
+        // an unusable peer makes the controller select from the other peers
+        let msg = measurement(unsynced, 16, 4);
+        system.handle_peer_update(msg).await.unwrap();
+
+        let selection = |system: &System<TestClock>, index| system.controller.peer_selection(index);
+        assert_eq!(system.system_peer, Some(synced));
+        assert_eq!(
+            selection(&system, synced),
+            Some(SelectionStatus::SystemPeer)
+        );
+        let mut received = vec![];
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert!(received.contains(&Event::SelectionChanged {
+            system_peer: "127.0.0.1:123".into()
+        }));
+
+        // a selection without a survivor does not correct the clock, but no peer is
+        // the system peer anymore
+        let msg = measurement(synced, 16, 5);
+        system.handle_peer_update(msg).await.unwrap();
+        assert_eq!(system.system_peer, None);
+        assert_eq!(selection(&system, synced), Some(SelectionStatus::Unusable));
+    }
+
+    #[tokio::test]
+    async fn test_kernel_pps() {
+        let mut config = CombinedSystemConfig::default();
+        config.system.min_intersection_survivors = 1;
+        config.algorithm.distance_threshold = NtpDuration::from_seconds(10.0);
+        let clock = RecordingClock::default();
+        let (mut system, channels) =
+            System::new(clock.clone(), config, test_keyset(), Default::default());
+
+        // pretend that the kernel disciplines the clock with the pulses of this peer
+        let pps = system.create_test_peer(NormalizedAddress::new_unchecked("127.0.0.1", 123));
+        system.kernel_pps = Some(pps);
+        system.controller.set_kernel_pps(true);
+        let other = system.create_test_peer(NormalizedAddress::new_unchecked("127.0.0.2", 123));
+        let unsynced = system.create_test_peer(NormalizedAddress::new_unchecked("127.0.0.3", 123));
+        clock.calls();
+
+        let measurement = |index, stratum, seconds| {
+            MsgForSystem::NewMeasurement(
+                index,
+                PeerSnapshot {
+                    stratum,
+                    ..peer_snapshot()
+                },
+                Measurement {
+                    delay: NtpDuration::from_seconds(0.1),
+                    offset: NtpDuration::from_seconds(1.0),
+                    localtime: NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0),
+                    monotime: NtpInstant::now(),
+                },
+                NtpPacket::test(),
+            )
+        };
+
+        // the pulses are not used a second time to correct the clock
+        for seconds in 0..4 {
+            system
+                .handle_peer_update(measurement(pps, 1, seconds))
+                .await
+                .unwrap();
+        }
+        assert!(system.peers[&pps].snapshot.is_some());
+        assert_eq!(clock.calls(), Vec::<&str>::new());
+
+        // other peers still correct the clock, but leave its frequency to the kernel
+        for seconds in 0..4 {
+            system
+                .handle_peer_update(measurement(other, 1, seconds))
+                .await
+                .unwrap();
+        }
+        // an unusable peer makes the controller select from the other peers
+        system
+            .handle_peer_update(measurement(unsynced, 16, 4))
+            .await
+            .unwrap();
+        let calls = clock.calls();
+        assert!(calls.contains(&"step_clock"));
+        assert!(!calls.contains(&"set_frequency"));
+
+        // the kernel stops disciplining the clock on shutdown
+        channels.shutdown_sender.send(true).unwrap();
+        system.run().await.unwrap();
+        assert_eq!(system.kernel_pps, None);
+        assert!(clock.calls().contains(&"disable_kernel_pps"));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_step() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-stream-events");
+        let observe_config = crate::config::ObserveConfig {
+            path: Some(path.clone()),
+            mode: 0o700,
+        };
+
+        let mut config = CombinedSystemConfig::default();
+        config.system.min_intersection_survivors = 1;
+        config.algorithm.distance_threshold = NtpDuration::from_seconds(10.0);
+        let (mut system, channels) =
+            System::new(TestClock {}, config, test_keyset(), Default::default());
+
+        let observer = crate::observer::spawn(
+            &observe_config,
+            channels.peer_snapshots_receiver,
+            channels.server_data_receiver,
+            channels.system_snapshot_receiver,
+            channels.kernel_pps_receiver,
+            channels.peer_history_receiver,
+            channels.event_sender,
+            channels.shutdown_receiver,
+        )
+        .await;
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let mut client = crate::protocol::Client::connect(&path).await.unwrap();
+        client.subscribe().await.unwrap();
+
+        let synced = system.create_test_peer(NormalizedAddress::new_unchecked("127.0.0.1", 123));
+        let unsynced = system.create_test_peer(NormalizedAddress::new_unchecked("127.0.0.2", 123));
+
+        let measurement = |index, stratum, seconds| {
+            MsgForSystem::NewMeasurement(
+                index,
+                PeerSnapshot {
+                    stratum,
+                    ..peer_snapshot()
+                },
+                Measurement {
+                    delay: NtpDuration::from_seconds(0.1),
+                    offset: NtpDuration::from_seconds(1.0),
+                    localtime: NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0),
+                    monotime: NtpInstant::now(),
+                },
+                NtpPacket::test(),
+            )
+        };
+
+        for seconds in 0..4 {
+            let msg = measurement(synced, 1, seconds);
+            system.handle_peer_update(msg).await.unwrap();
+        }
+
+        // an unusable peer makes the controller select from the other peers
+        let msg = measurement(unsynced, 16, 4);
+        system.handle_peer_update(msg).await.unwrap();
+
+        let mut events = vec![];
+        loop {
+            let event =
+                tokio::time::timeout(std::time::Duration::from_secs(1), client.next_event())
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+            if let Event::ClockStep { offset } = event {
+                assert_eq!(offset, NtpDuration::from_seconds(1.0));
+                break;
+            }
+            events.push(event);
+        }
+
+        assert!(events.contains(&Event::ReachabilityChanged {
+            address: "127.0.0.1:123".into(),
+            reachable: true,
+        }));
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            Event::SelectionChanged {
+                system_peer: "127.0.0.1:123".into()
+            }
+        );
+
+        observer.abort();
+    }
 }
@@ -33,14 +33,19 @@ pub struct Metrics {
     peer_poll_interval: Family<PeerLabels, Gauge<f64>>,
     peer_poll_interval_exp: Family<PeerLabels, Gauge<f64>>,
     peer_reachability_status: Family<PeerLabels, Gauge>,
+    peer_selection_status: Family<PeerLabels, Gauge>,
     peer_offset: Family<PeerLabels, Gauge<f64>>,
     peer_uncertainty: Family<PeerLabels, Gauge<f64>>,
     peer_delay: Family<PeerLabels, Gauge<f64>>,
+    peer_send_timestamp_fallbacks: Family<PeerLabels, Counter>,
     server_received_packets: Family<ServerLabels, Counter>,
     server_accepted_packets: Family<ServerLabels, Counter>,
     server_denied_packets: Family<ServerLabels, Counter>,
     server_ignored_packets: Family<ServerLabels, Counter>,
     server_rate_limited_packets: Family<ServerLabels, Counter>,
+    server_nts_nak_packets: Family<ServerLabels, Counter>,
+    server_crypto_nak_packets: Family<ServerLabels, Counter>,
+    server_broadcast_packets: Family<ServerLabels, Counter>,
     server_response_send_errors: Family<ServerLabels, Counter>,
 }
 
@@ -75,6 +80,8 @@ impl Metrics {
                 timedata,
                 reachability,
                 poll_interval,
+                selection,
+                stats,
                 address,
                 ..
             } = peer
@@ -96,6 +103,11 @@ impl Metrics {
                 self.peer_reachability_status
                     .get_or_create(&labels)
                     .set(reachability.reachability_score() as u64);
+                if let Some(selection) = selection {
+                    self.peer_selection_status
+                        .get_or_create(&labels)
+                        .set(*selection as u64);
+                }
                 self.peer_offset
                     .get_or_create(&labels)
                     .set(timedata.offset.to_seconds());
@@ -105,6 +117,10 @@ impl Metrics {
                 self.peer_uncertainty
                     .get_or_create(&labels)
                     .set(timedata.uncertainty.to_seconds());
+                self.peer_send_timestamp_fallbacks
+                    .get_or_create(&labels)
+                    .inner()
+                    .set(stats.send_timestamp_fallbacks.get());
             }
         }
 
@@ -133,6 +149,18 @@ impl Metrics {
                 .get_or_create(&labels)
                 .inner()
                 .set(server.stats.rate_limited_packets.get());
+            self.server_nts_nak_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.nts_nak_packets.get());
+            self.server_crypto_nak_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.crypto_nak_packets.get());
+            self.server_broadcast_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.broadcast_packets.get());
             self.server_response_send_errors
                 .get_or_create(&labels)
                 .inner()
@@ -208,6 +236,12 @@ impl Metrics {
             Box::new(self.peer_reachability_status.clone()),
         );
 
+        peer.register(
+            "selection_status",
+            "Outcome of the latest clock selection: 0 unusable, 1 unsynchronized, 2 too distant, 3 falseticker, 4 candidate, 5 survivor, 6 system peer",
+            Box::new(self.peer_selection_status.clone()),
+        );
+
         peer.register_with_unit(
             "offset",
             "Offset between the upstream server and system time",
@@ -229,6 +263,12 @@ impl Metrics {
             Box::new(self.peer_uncertainty.clone()),
         );
 
+        peer.register(
+            "send_timestamp_fallbacks",
+            "Number of polls for which no send timestamp was available from the kernel",
+            Box::new(self.peer_send_timestamp_fallbacks.clone()),
+        );
+
         let server = registry.sub_registry_with_prefix("server");
 
         server.register(
@@ -261,6 +301,24 @@ impl Metrics {
             Box::new(self.server_rate_limited_packets.clone()),
         );
 
+        server.register(
+            "nts_nak_packets",
+            "Number of NTS requests answered with a NTS NAK",
+            Box::new(self.server_nts_nak_packets.clone()),
+        );
+
+        server.register(
+            "crypto_nak_packets",
+            "Number of requests with an invalid MAC answered with a crypto-NAK",
+            Box::new(self.server_crypto_nak_packets.clone()),
+        );
+
+        server.register(
+            "broadcast_packets",
+            "Number of broadcast packets sent",
+            Box::new(self.server_broadcast_packets.clone()),
+        );
+
         server.register(
             "response_send_errors",
             "Number of packets where there was an error responding",
@@ -13,7 +13,10 @@ use axum::{
     Json, Router,
 };
 use clap::Parser;
-use ntp_daemon::{Config, ObservableState};
+use ntp_daemon::{
+    protocol::{Client, ClientError},
+    Config,
+};
 
 #[derive(Parser)]
 #[command(version = "0.2.0", about = "Serve ntpd-rs openmetrics via http")]
@@ -34,6 +37,8 @@ struct Cli {
 enum ServeError {
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("observation socket error: {0}")]
+    Client(#[from] ClientError),
 }
 
 impl IntoResponse for ServeError {
@@ -72,10 +77,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route(
             "/metrics",
             get(|| async {
-                let mut stream = tokio::net::UnixStream::connect(observation_socket_path).await?;
-                let mut msg = Vec::with_capacity(16 * 1024);
-                let output: ObservableState =
-                    ntp_daemon::sockets::read_json(&mut stream, &mut msg).await?;
+                let mut client = Client::connect(observation_socket_path).await?;
+                let output = client.get_state().await?;
                 let metrics = Metrics::default();
                 metrics.fill(&output);
                 let registry = metrics.registry();
@@ -5,9 +5,16 @@
 // is constructed in such a way that use of the public functions is
 // safe regardless of given arguments.
 
-use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval};
+mod ptp;
+
+use ntp_proto::{
+    ClockOperationUnsupported, KernelPpsStatus, NtpClock, NtpDuration, NtpLeapIndicator,
+    NtpTimestamp, PollInterval,
+};
 use thiserror::Error as ThisError;
 
+pub use ptp::PtpHardwareClock;
+
 #[derive(Debug, Copy, Clone, ThisError)]
 pub enum Error {
     #[error("Insufficient permissions to interact with the clock.")]
@@ -20,6 +27,12 @@ pub enum Error {
     NotSupported,
 }
 
+impl From<ClockOperationUnsupported> for Error {
+    fn from(_: ClockOperationUnsupported) -> Self {
+        Error::NotSupported
+    }
+}
+
 // Unix uses an epoch located at 1/1/1970-00:00h (UTC) and NTP uses 1/1/1900-00:00h.
 // This leads to an offset equivalent to 70 years in seconds
 // there are 17 leap years between the two dates so the offset is
@@ -144,6 +157,13 @@ impl NtpClock for UnixNtpClock {
         Ok(extract_current_time(&ntp_kapi_timex))
     }
 
+    fn get_frequency(&self) -> Result<f64, Self::Error> {
+        let mut timex = EMPTY_TIMEX;
+        adjtime(&mut timex)?;
+        // NTP Kapi gives the frequency in units of 2^-16 ppm
+        Ok(timex.freq as f64 / 65536e6)
+    }
+
     fn step_clock(&self, offset: ntp_proto::NtpDuration) -> Result<NtpTimestamp, Self::Error> {
         let mut timex = EMPTY_TIMEX;
         timex.modes = libc::ADJ_SETOFFSET | libc::MOD_NANO;
@@ -217,6 +237,57 @@ impl NtpClock for UnixNtpClock {
         }
         adjtime(&mut timex)
     }
+
+    fn enable_kernel_pps(&self) -> Result<(), Self::Error> {
+        let mut timex = EMPTY_TIMEX;
+        adjtime(&mut timex)?;
+        timex.modes = libc::MOD_STATUS;
+        // Use the pps signal for both the frequency
+        // and the phase of the clock
+        timex.status |= libc::STA_PPSFREQ | libc::STA_PPSTIME;
+        adjtime(&mut timex)
+    }
+
+    fn disable_kernel_pps(&self) -> Result<(), Self::Error> {
+        let mut timex = EMPTY_TIMEX;
+        adjtime(&mut timex)?;
+        timex.modes = libc::MOD_STATUS;
+        timex.status &= !libc::STA_PPSFREQ & !libc::STA_PPSTIME;
+        adjtime(&mut timex)
+    }
+
+    fn kernel_pps_status(&self) -> Result<KernelPpsStatus, Self::Error> {
+        let mut timex = EMPTY_TIMEX;
+        adjtime(&mut timex)?;
+
+        // The jitter is in microseconds, unless
+        // nanosecond resolution is enabled
+        let jitter = if timex.status & libc::STA_NANO != 0 {
+            timex.jitter as f64 * 1e-9
+        } else {
+            timex.jitter as f64 * 1e-6
+        };
+
+        Ok(KernelPpsStatus {
+            signal: timex.status & libc::STA_PPSSIGNAL != 0,
+            // NTP Kapi gives frequencies in units of 2^-16 ppm
+            frequency: timex.ppsfreq as f64 / 65536e6,
+            jitter: NtpDuration::from_seconds(jitter),
+            stability: timex.stabil as f64 / 65536e6,
+            calibration_count: timex.calcnt as u64,
+            jitter_count: timex.jitcnt as u64,
+            error_count: timex.errcnt as u64,
+            stability_count: timex.stbcnt as u64,
+        })
+    }
+
+    fn set_tai_offset(&self, tai_offset: i32) -> Result<(), Self::Error> {
+        let mut timex = EMPTY_TIMEX;
+        timex.modes = libc::ADJ_TAI;
+        // The offset is passed in the constant field
+        timex.constant = tai_offset as libc::c_long;
+        adjtime(&mut timex)
+    }
 }
 
 #[cfg(test)]
@@ -231,4 +302,10 @@ mod tests {
             NtpTimestamp::from_seconds_nanos_since_ntp_era(0, 0)
         );
     }
+
+    #[test]
+    fn test_kernel_pps_status_does_not_crash() {
+        let clock = UnixNtpClock::new();
+        assert!(clock.kernel_pps_status().is_ok());
+    }
 }
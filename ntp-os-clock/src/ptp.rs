@@ -0,0 +1,191 @@
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::prelude::AsRawFd,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval};
+
+use crate::{convert_errno, Error, EMPTY_TIMEX, EPOCH_OFFSET};
+
+// The frequency of a ptp hardware clock is limited
+// like that of the system clock by the kernel
+const MAX_FREQUENCY: f64 = 500e-6;
+
+// A ptp hardware clock has no phase locked loop in the
+// kernel. Instead, every update the clock is slewed
+// such that this many poll intervals remove the offset,
+const PHASE_INTERVALS: f64 = 2.0;
+// and the frequency is corrected such that this many
+// poll intervals would remove the offset.
+const FREQUENCY_INTERVALS: f64 = 16.0;
+
+/// NTP Clock that steers a ptp hardware clock, such as the clock of a network
+/// card, as a dynamic posix clock.
+// Implementation note: the dynamic posix clock is only valid while the file of
+// the clock is open, so all clones share that file.
+#[derive(Debug, Clone)]
+pub struct PtpHardwareClock {
+    file: Arc<File>,
+    // Frequency that compensates the drift of the clock, on top of which the
+    // phase of the clock is corrected.
+    frequency: Arc<Mutex<f64>>,
+}
+
+impl PtpHardwareClock {
+    /// Open a ptp hardware clock, such as `/dev/ptp0`
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        // Steering the clock requires write access
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        Ok(Self {
+            file: Arc::new(file),
+            frequency: Arc::new(Mutex::new(0.0)),
+        })
+    }
+
+    // The id of a dynamic posix clock is derived from its file descriptor,
+    // like FD_TO_CLOCKID in the kernel documentation.
+    fn clock_id(&self) -> libc::clockid_t {
+        ((!self.file.as_raw_fd()) << 3) | 3
+    }
+
+    fn adjtime(&self, timex: &mut libc::timex) -> Result<(), Error> {
+        // The clock_adjtime call is safe because the reference always
+        // points to a valid libc::timex.
+        if unsafe { libc::clock_adjtime(self.clock_id(), timex as *mut _) } == -1 {
+            Err(convert_errno())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn gettime(clock_id: libc::clockid_t) -> Result<NtpTimestamp, Error> {
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // The clock_gettime call is safe because the reference always
+        // points to a valid libc::timespec.
+        if unsafe { libc::clock_gettime(clock_id, &mut time) } == -1 {
+            return Err(convert_errno());
+        }
+
+        // Negative eras are completely valid, so any wrapping is
+        // perfectly reasonable here.
+        Ok(NtpTimestamp::from_seconds_nanos_since_ntp_era(
+            (time.tv_sec as u32).wrapping_add(EPOCH_OFFSET),
+            time.tv_nsec as u32,
+        ))
+    }
+
+    fn apply_frequency(&self, freq: f64) -> Result<NtpTimestamp, Error> {
+        let mut timex = EMPTY_TIMEX;
+        timex.modes = libc::ADJ_FREQUENCY;
+        // NTP Kapi expects frequency adjustment in units of 2^-16 ppm
+        // but our input is in units of seconds drift per second, so convert.
+        timex.freq = (freq.clamp(-MAX_FREQUENCY, MAX_FREQUENCY) * 65536e6) as libc::c_long;
+        self.adjtime(&mut timex)?;
+        self.now()
+    }
+}
+
+impl NtpClock for PtpHardwareClock {
+    type Error = Error;
+
+    fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+        Self::gettime(self.clock_id())
+    }
+
+    fn convert_system_time(&self, time: NtpTimestamp) -> Result<NtpTimestamp, Self::Error> {
+        // The hardware clock is read in between two reads of the system
+        // clock, so it is compared to the midpoint of those reads.
+        let before = Self::gettime(libc::CLOCK_REALTIME)?;
+        let clock = self.now()?;
+        let after = Self::gettime(libc::CLOCK_REALTIME)?;
+        let system = before + (after - before) / 2;
+
+        Ok(time + (clock - system))
+    }
+
+    fn set_frequency(&self, freq: f64) -> Result<NtpTimestamp, Self::Error> {
+        *self.frequency.lock().unwrap() = freq.clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        self.apply_frequency(freq)
+    }
+
+    fn get_frequency(&self) -> Result<f64, Self::Error> {
+        Ok(*self.frequency.lock().unwrap())
+    }
+
+    fn step_clock(&self, offset: NtpDuration) -> Result<NtpTimestamp, Self::Error> {
+        let mut timex = EMPTY_TIMEX;
+        timex.modes = libc::ADJ_SETOFFSET | libc::ADJ_NANO;
+        let (secs, nanos) = offset.as_seconds_nanos();
+        timex.time.tv_sec = secs as libc::time_t;
+        timex.time.tv_usec = nanos as libc::suseconds_t;
+        self.adjtime(&mut timex)?;
+        self.now()
+    }
+
+    // The phase locked loop of this clock is implemented
+    // by ntp_algorithm_update, so there is nothing to
+    // enable or disable.
+    fn enable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn disable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn ntp_algorithm_update(
+        &self,
+        offset: NtpDuration,
+        poll_interval: PollInterval,
+    ) -> Result<(), Self::Error> {
+        let interval = poll_interval.as_duration().to_seconds();
+        let offset = offset.to_seconds();
+
+        let mut frequency = self.frequency.lock().unwrap();
+        *frequency = (*frequency + offset / (FREQUENCY_INTERVALS * interval))
+            .clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+
+        self.apply_frequency(*frequency + offset / (PHASE_INTERVALS * interval))?;
+        Ok(())
+    }
+
+    // A ptp hardware clock keeps no error estimates or
+    // synchronization status, those are only reported
+    // for the system clock.
+    fn error_estimate_update(
+        &self,
+        _est_error: NtpDuration,
+        _max_error: NtpDuration,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    // Kernel pps and the tai offset are kept by the kernel for the
+    // system clock only, so the default implementations are used.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_a_clock() {
+        // a file that is not a dynamic posix clock has no valid clock id
+        let clock = PtpHardwareClock::open(Path::new("/dev/null")).unwrap();
+        assert!(matches!(clock.now(), Err(Error::Invalid)));
+        assert!(matches!(
+            clock.convert_system_time(NtpTimestamp::default()),
+            Err(Error::Invalid)
+        ));
+    }
+}
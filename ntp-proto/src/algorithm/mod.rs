@@ -3,7 +3,8 @@ use std::{fmt::Debug, hash::Hash};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
-    peer::Measurement, NtpClock, NtpDuration, NtpPacket, NtpTimestamp, SystemConfig, TimeSnapshot,
+    peer::Measurement, LeapSecondsFile, NtpClock, NtpDuration, NtpPacket, NtpTimestamp,
+    SystemConfig, TimeSnapshot,
 };
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -11,6 +12,8 @@ pub struct ObservablePeerTimedata {
     pub offset: NtpDuration,
     pub uncertainty: NtpDuration,
     pub delay: NtpDuration,
+    #[serde(default)]
+    pub jitter: NtpDuration,
 
     pub remote_delay: NtpDuration,
     pub remote_uncertainty: NtpDuration,
@@ -18,6 +21,57 @@ pub struct ObservablePeerTimedata {
     pub last_update: NtpTimestamp,
 }
 
+/// The clock controller refuses to correct the clock, because the correction
+/// suggested by the peers exceeds the panic threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanicThresholdExceeded {
+    pub offset: NtpDuration,
+}
+
+/// The outcome of the latest clock selection for a peer, ordered from least to most trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum SelectionStatus {
+    /// The peer cannot be used for synchronization, for example because of its stratum
+    Unusable,
+    /// The peer was rejected because it is not synchronized itself
+    Unsynchronized,
+    /// The peer was rejected because its root distance is too large
+    TooDistant,
+    /// The offset of the peer lies outside the interval that the majority of peers agree on
+    Falseticker,
+    /// The peer agrees with the majority, but was discarded by the clustering algorithm,
+    /// or too few peers agree to synchronize the clock
+    Candidate,
+    /// The peer is combined into the offset of the system
+    Survivor,
+    /// The peer is the best survivor, whose variables are used for the system
+    SystemPeer,
+}
+
+/// What the algorithm did with a measurement of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterDecision {
+    /// The measurement was made right after the clock was stepped
+    Ignored,
+    /// The measurement was kept, but an earlier one with a smaller delay is used instead
+    NotBest,
+    /// The peer cannot be used for synchronization, for example because of its stratum
+    Unusable,
+    /// The peer is not synchronized, or its root distance is too large
+    Rejected,
+    /// The statistics of the peer were updated with the measurement
+    Accepted,
+}
+
+/// How the clock was corrected in response to a measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockCorrection {
+    /// The clock jumped by the offset
+    Step(NtpDuration),
+    /// The clock is gradually moved by the offset
+    Slew(NtpDuration),
+}
+
 pub trait TimeSyncController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
     type AlgorithmConfig: Debug + Copy + DeserializeOwned;
 
@@ -25,6 +79,19 @@ pub trait TimeSyncController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
     fn new(clock: C, config: SystemConfig, algorithm_config: Self::AlgorithmConfig) -> Self;
     /// Update used system config
     fn update_config(&mut self, config: SystemConfig, algorithm_config: Self::AlgorithmConfig);
+    /// Update the leap seconds file used to confirm or overrule the
+    /// leap indicator of the system peer
+    fn update_leap_seconds(&mut self, leap_seconds: Option<LeapSecondsFile>);
+    /// Start from a clock frequency (in seconds per second) remembered
+    /// from a previous run, instead of measuring it anew
+    fn set_initial_frequency(&mut self, frequency: f64);
+    /// Notify the controller whether the kernel disciplines the clock from
+    /// a pps signal, in which case the controller leaves the frequency of
+    /// the clock to the kernel
+    fn set_kernel_pps(&mut self, kernel_pps: bool);
+    /// The clock frequency (in seconds per second) determined by the
+    /// controller, if it is synchronized
+    fn frequency(&self) -> Option<f64>;
     /// Notify the controller that there is a new peer
     fn peer_add(&mut self, id: PeerID);
     /// Notify the controller that a previous peer has gone
@@ -34,15 +101,21 @@ pub trait TimeSyncController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
     fn peer_update(&mut self, id: PeerID, usable: bool);
     /// Notify the controller of a new measurement from a peer.
     /// The list of peerIDs is used for loop detection, with the
-    /// first peerID given considered the primary peer used.
+    /// first peerID given considered the primary peer used,
+    /// followed by how the clock was corrected. Returns an error when the clock should not be trusted to
+    /// be corrected anymore.
     fn peer_measurement(
         &mut self,
         id: PeerID,
         measurement: Measurement,
         packet: NtpPacket<'static>,
-    ) -> Option<(Vec<PeerID>, TimeSnapshot)>;
+    ) -> Result<Option<(Vec<PeerID>, TimeSnapshot, ClockCorrection)>, PanicThresholdExceeded>;
     /// Get a snapshot of the timekeeping state of a peer.
     fn peer_snapshot(&self, id: PeerID) -> Option<ObservablePeerTimedata>;
+    /// What was done with the latest measurement of a peer.
+    fn peer_filter_decision(&self, id: PeerID) -> Option<FilterDecision>;
+    /// The outcome of the latest clock selection for a peer.
+    fn peer_selection(&self, id: PeerID) -> Option<SelectionStatus>;
 }
 
 mod standard;
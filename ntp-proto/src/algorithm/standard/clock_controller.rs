@@ -2,7 +2,7 @@ use crate::{
     packet::NtpLeapIndicator, time_types::PollInterval, NtpClock, NtpDuration, NtpInstant,
     SystemConfig, TimeSnapshot,
 };
-use tracing::{debug, error, info, instrument, trace};
+use tracing::{debug, error, info, instrument, trace, warn};
 
 use super::config::AlgorithmConfig;
 
@@ -12,8 +12,7 @@ const JITTER_AVG: f64 = 4.;
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum ClockState {
     StartupBlank,
-    // Needed when implementing frequency backups
-    #[allow(dead_code)]
+    // Frequency restored from a previous run, no measurement needed
     StartupFreq,
     MeasureFreq,
     Spike,
@@ -34,6 +33,8 @@ pub(super) struct ClockController<C: NtpClock> {
     offset: NtpDuration,
     jitter: NtpDuration,
     accumulated_steps: NtpDuration,
+    /// Whether the kernel disciplines the frequency of the clock from a pps signal
+    kernel_pps: bool,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -65,6 +66,46 @@ impl<C: NtpClock> ClockController<C> {
             offset: NtpDuration::ZERO,
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
+        }
+    }
+
+    /// While the kernel disciplines the clock from a pps signal, the frequency
+    /// of the clock is left to the kernel.
+    pub fn set_kernel_pps(&mut self, kernel_pps: bool) {
+        self.kernel_pps = kernel_pps;
+    }
+
+    /// Start from a frequency known from a previous run, such
+    /// that the initial frequency measurement can be skipped.
+    pub fn set_initial_frequency(&mut self, freq: f64) {
+        if self.kernel_pps {
+            debug!("Not setting initial frequency, the kernel disciplines it");
+            self.state = ClockState::StartupFreq;
+            return;
+        }
+
+        info!(freq = display(freq), "Setting initial frequency");
+        if let Err(e) = self.clock.set_frequency(freq) {
+            error!(error = %e, "Could not set clock frequency, exiting");
+            std::process::exit(exitcode::NOPERM);
+        }
+        self.state = ClockState::StartupFreq;
+    }
+
+    /// The current frequency of the clock, once it has been
+    /// determined by synchronizing to our peers.
+    pub fn frequency(&self) -> Option<f64> {
+        if !matches!(self.state, ClockState::Sync | ClockState::Spike) {
+            return None;
+        }
+
+        match self.clock.get_frequency() {
+            Ok(freq) => Some(freq),
+            Err(e) => {
+                warn!(error = %e, "Could not read clock frequency");
+                None
+            }
         }
     }
 
@@ -339,6 +380,11 @@ impl<C: NtpClock> ClockController<C> {
     }
 
     fn set_freq(&mut self, offset: NtpDuration, last_peer_update: NtpInstant) {
+        if self.kernel_pps {
+            debug!("Not setting frequency, the kernel disciplines it");
+            return;
+        }
+
         info!(
             freq = display(
                 offset.to_seconds()
@@ -388,6 +434,10 @@ mod tests {
             Ok(NtpTimestamp::from_fixed_int(0))
         }
 
+        fn get_frequency(&self) -> Result<f64, Self::Error> {
+            Ok(self.last_freq.borrow().unwrap_or_default())
+        }
+
         fn step_clock(&self, offset: NtpDuration) -> Result<NtpTimestamp, Self::Error> {
             *self.last_offset.borrow_mut() = Some(offset);
             Ok(NtpTimestamp::from_fixed_int(0))
@@ -446,6 +496,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         let ref_interval = controller.preferred_poll_interval;
@@ -560,6 +611,74 @@ mod tests {
         assert_eq!(*controller.clock.last_freq.borrow(), Some(1. / 1800.));
     }
 
+    #[test]
+    fn test_initial_frequency() {
+        let base = NtpInstant::now();
+        let config = SystemConfig::default();
+        let algo_config = AlgorithmConfig::default();
+        let system = TimeSnapshot::default();
+
+        let mut controller = ClockController::new(TestClock::default(), &system, &config);
+        assert_eq!(controller.frequency(), None);
+
+        controller.set_initial_frequency(1e-5);
+        assert_eq!(controller.state, ClockState::StartupFreq);
+        assert_eq!(*controller.clock.last_freq.borrow(), Some(1e-5));
+        assert_eq!(controller.frequency(), None);
+
+        // no frequency measurement is needed before syncing
+        controller.update(
+            &config,
+            &algo_config,
+            &system,
+            NtpDuration::from_seconds(0.001),
+            NtpDuration::from_seconds(0.02),
+            NtpDuration::from_seconds(0.03),
+            NtpLeapIndicator::NoWarning,
+            base + Duration::from_secs(1),
+        );
+
+        assert_eq!(controller.state, ClockState::Sync);
+        assert_eq!(controller.frequency(), Some(1e-5));
+    }
+
+    #[test]
+    fn test_kernel_pps_frequency() {
+        let system = TimeSnapshot::default();
+        let config = SystemConfig::default();
+        let algo_config = AlgorithmConfig::default();
+        let mut controller = ClockController::new(TestClock::default(), &system, &config);
+        let base = controller.last_update_time;
+        controller.set_kernel_pps(true);
+
+        // the frequency set at startup is left alone from now on
+        controller.clock.last_freq.replace(None);
+
+        controller.set_initial_frequency(1e-5);
+        assert_eq!(controller.state, ClockState::StartupFreq);
+        assert_eq!(*controller.clock.last_freq.borrow(), None);
+
+        controller.state = ClockState::MeasureFreq;
+        controller.update(
+            &config,
+            &algo_config,
+            &system,
+            NtpDuration::from_fixed_int(1 << 32),
+            NtpDuration::from_seconds(0.02),
+            NtpDuration::from_seconds(0.03),
+            NtpLeapIndicator::NoWarning,
+            base + Duration::from_secs(1801),
+        );
+
+        // the offset is still corrected
+        assert_eq!(controller.state, ClockState::Sync);
+        assert_eq!(
+            *controller.clock.last_offset.borrow(),
+            Some(NtpDuration::from_fixed_int(1 << 32))
+        );
+        assert_eq!(*controller.clock.last_freq.borrow(), None);
+    }
+
     #[test]
     fn test_startup_logic_freq() {
         let base = NtpInstant::now();
@@ -576,6 +695,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         controller.update(
@@ -612,6 +732,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         controller.update(
@@ -662,6 +783,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         controller.update(
@@ -715,6 +837,7 @@ mod tests {
             offset: NtpDuration::ZERO,
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -774,6 +897,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -866,6 +990,7 @@ mod tests {
             offset: NtpDuration::from_seconds(2e-3),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -954,6 +1079,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -979,6 +1105,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -1004,6 +1131,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -1029,6 +1157,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -1054,6 +1183,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -1079,6 +1209,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -1104,6 +1235,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
@@ -1129,6 +1261,7 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            kernel_pps: false,
         };
 
         assert_eq!(
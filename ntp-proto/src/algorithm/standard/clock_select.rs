@@ -1,10 +1,11 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::hash::Hash;
 
 use super::config::AlgorithmConfig;
 use super::peer::PeerTimeSnapshot;
 use crate::time_types::{FrequencyTolerance, NtpInstant};
-use crate::{NtpDuration, PollInterval, SystemConfig};
+use crate::{AcceptSynchronizationError, NtpDuration, PollInterval, SelectionStatus, SystemConfig};
 use tracing::{debug, instrument, trace, warn};
 
 #[derive(Debug, Clone)]
@@ -17,15 +18,24 @@ pub(super) struct FilterAndCombine<PeerID: Hash + Eq + Copy + Debug> {
 }
 
 impl<PeerID: Hash + Eq + Copy + Debug> FilterAndCombine<PeerID> {
-    #[instrument(skip(peers), fields(peers = debug(peers.iter().map(|peer| peer.0).collect::<Vec<_>>())))]
+    /// The outcome for every peer is recorded in `status`, also when no system peer is selected
+    #[instrument(skip(peers, status), fields(peers = debug(peers.iter().map(|peer| peer.0).collect::<Vec<_>>())))]
     pub fn run(
         config: &SystemConfig,
         algo_config: &AlgorithmConfig,
         peers: &[(PeerID, PeerTimeSnapshot)],
         local_clock_time: NtpInstant,
         system_poll: PollInterval,
+        status: &mut HashMap<PeerID, SelectionStatus>,
     ) -> Option<Self> {
-        let selection = clock_select(config, algo_config, peers, local_clock_time, system_poll)?;
+        let selection = clock_select(
+            config,
+            algo_config,
+            peers,
+            local_clock_time,
+            system_poll,
+            status,
+        )?;
 
         // the clustering algorithm (part of `clock_select`) sorts the peers, best peer first.
         // the first (and best) peer is chosen as the system peer, and its variables are used
@@ -36,6 +46,7 @@ impl<PeerID: Hash + Eq + Copy + Debug> FilterAndCombine<PeerID> {
         // it calls clock hopping). We'll have to see if that is something we should do too;
         // the spec text does not talk about keeping the existing system peer if it's in the candidate list
         let system_peer_snapshot = selection.survivors[0].peer.1;
+        status.insert(selection.survivors[0].peer.0, SelectionStatus::SystemPeer);
 
         let combined = clock_combine(
             &selection.survivors,
@@ -71,27 +82,40 @@ struct ClockSelect<'a, PeerID: Hash + Eq + Copy + Debug> {
     system_selection_jitter: NtpDuration,
 }
 
-#[instrument(skip(config, algo_config, local_clock_time, system_poll))]
+#[instrument(skip(config, algo_config, local_clock_time, system_poll, status))]
 fn clock_select<'a, PeerID: Hash + Eq + Copy + Debug>(
     config: &SystemConfig,
     algo_config: &AlgorithmConfig,
     peers: &'a [(PeerID, PeerTimeSnapshot)],
     local_clock_time: NtpInstant,
     system_poll: PollInterval,
+    status: &mut HashMap<PeerID, SelectionStatus>,
 ) -> Option<ClockSelect<'a, PeerID>> {
     let valid_associations = peers.iter().filter(|p| {
-        p.1.accept_synchronization(
+        let accepted = p.1.accept_synchronization(
             local_clock_time,
             algo_config.frequency_tolerance,
             algo_config.distance_threshold,
             system_poll,
-        )
-        .is_ok()
+        );
+
+        // valid peers are falsetickers until they are found to lie within the correctness interval
+        let peer_status = match accepted {
+            Ok(()) => SelectionStatus::Falseticker,
+            Err(AcceptSynchronizationError::Distance) => SelectionStatus::TooDistant,
+            Err(_) => SelectionStatus::Unsynchronized,
+        };
+        status.insert(p.0, peer_status);
+
+        accepted.is_ok()
     });
 
     let candidates = construct_candidate_list(algo_config, valid_associations, local_clock_time);
 
     let mut survivors = construct_survivors(algo_config, &candidates, local_clock_time);
+    for survivor in &survivors {
+        status.insert(survivor.peer.0, SelectionStatus::Candidate);
+    }
 
     trace!(survivors = debug(&survivors));
     if survivors.len() < config.min_intersection_survivors {
@@ -101,6 +125,9 @@ fn clock_select<'a, PeerID: Hash + Eq + Copy + Debug>(
 
     let system_selection_jitter =
         NtpDuration::from_seconds(cluster_algorithm(algo_config, &mut survivors));
+    for survivor in &survivors {
+        status.insert(survivor.peer.0, SelectionStatus::Survivor);
+    }
 
     Some(ClockSelect {
         survivors,
@@ -1284,6 +1311,63 @@ mod test {
         }
     }
 
+    #[test]
+    fn selection_status() {
+        let base = NtpInstant::now();
+
+        let config = SystemConfig::default();
+        let algo_config = AlgorithmConfig::default();
+
+        let statistics = PeerStatistics {
+            jitter: 0.001,
+            ..Default::default()
+        };
+        let peer = peer_time_snapshot(
+            statistics,
+            base,
+            NtpDuration::ZERO,
+            NtpDuration::from_seconds(0.01),
+        );
+
+        let mut peers = vec![(1, peer), (2, peer), (3, peer), (4, peer)];
+
+        let mut falseticker = peer;
+        falseticker.statistics.offset = NtpDuration::ONE;
+        peers.push((5, falseticker));
+
+        let mut unsynchronized = peer;
+        unsynchronized.leap_indicator = crate::NtpLeapIndicator::Unknown;
+        peers.push((6, unsynchronized));
+
+        let too_distant = peer_time_snapshot(
+            statistics,
+            base,
+            NtpDuration::ZERO,
+            NtpDuration::from_seconds(10.0),
+        );
+        peers.push((7, too_distant));
+
+        let mut status = HashMap::new();
+        let result = FilterAndCombine::run(
+            &config,
+            &algo_config,
+            &peers,
+            base,
+            PollIntervalLimits::default().min,
+            &mut status,
+        )
+        .unwrap();
+
+        let system_peer = result.system_peer_snapshot.0;
+        assert_eq!(status[&system_peer], SelectionStatus::SystemPeer);
+        for id in (1..=4).filter(|id| *id != system_peer) {
+            assert_eq!(status[&id], SelectionStatus::Survivor);
+        }
+        assert_eq!(status[&5], SelectionStatus::Falseticker);
+        assert_eq!(status[&6], SelectionStatus::Unsynchronized);
+        assert_eq!(status[&7], SelectionStatus::TooDistant);
+    }
+
     #[test]
     fn root_delay_dispersion_calculation() {
         let base = NtpInstant::now();
@@ -1311,6 +1395,7 @@ mod test {
             &[(1, peer)],
             base,
             PollIntervalLimits::default().min,
+            &mut HashMap::new(),
         )
         .unwrap();
         assert!(baseline_result.system_root_delay >= NtpDuration::from_seconds(0.002));
@@ -1322,6 +1407,7 @@ mod test {
             &[(1, peer)],
             base + Duration::from_secs(1000),
             PollIntervalLimits::default().min,
+            &mut HashMap::new(),
         )
         .unwrap();
         assert!(result.system_root_delay >= NtpDuration::from_seconds(0.002));
@@ -1345,6 +1431,7 @@ mod test {
             &[(1, peer)],
             base,
             PollIntervalLimits::default().min,
+            &mut HashMap::new(),
         )
         .unwrap();
         assert!(result.system_root_delay >= NtpDuration::from_seconds(0.002));
@@ -1368,6 +1455,7 @@ mod test {
             &[(1, peer)],
             base,
             PollIntervalLimits::default().min,
+            &mut HashMap::new(),
         )
         .unwrap();
         assert!(result.system_root_delay >= NtpDuration::from_seconds(0.002));
@@ -1391,6 +1479,7 @@ mod test {
             &[(1, peer)],
             base,
             PollIntervalLimits::default().min,
+            &mut HashMap::new(),
         )
         .unwrap();
         assert!(result.system_root_delay >= NtpDuration::from_seconds(0.002));
@@ -1414,6 +1503,7 @@ mod test {
             &[(1, peer)],
             base,
             PollIntervalLimits::default().min,
+            &mut HashMap::new(),
         )
         .unwrap();
         assert!(result.system_root_delay >= NtpDuration::from_seconds(0.002));
@@ -9,7 +9,7 @@ pub use clock_select::fuzz_find_interval;
 
 use std::{collections::HashMap, fmt::Debug, hash::Hash};
 
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use clock_controller::{ClockController, ClockUpdateResult};
 use clock_select::FilterAndCombine;
@@ -17,13 +17,14 @@ use filter::LastMeasurements;
 use peer::{PeerTimeSnapshot, PeerTimeState};
 
 use crate::{
-    Measurement, NtpClock, NtpDuration, NtpInstant, ObservablePeerTimedata, SystemConfig,
+    leap_seconds::leap_indicators, FilterDecision, LeapSecondsFile, Measurement, NtpClock,
+    NtpDuration, NtpInstant, NtpTimestamp, ObservablePeerTimedata, SelectionStatus, SystemConfig,
     TimeSnapshot,
 };
 
 use self::config::AlgorithmConfig;
 
-use super::TimeSyncController;
+use super::{ClockCorrection, PanicThresholdExceeded, TimeSyncController};
 
 #[derive(Debug)]
 pub struct StandardClockController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
@@ -34,12 +35,19 @@ pub struct StandardClockController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug
     config: SystemConfig,
     algo_config: AlgorithmConfig,
     last_reset: Option<NtpInstant>,
+    leap_seconds: Option<LeapSecondsFile>,
+    /// The tai offset last given to the clock
+    tai_offset: Option<i32>,
 }
 
 #[derive(Debug, Clone)]
 struct ControllerPeerState {
     timestate: PeerTimeState,
     usable: bool,
+    /// What was done with the latest measurement
+    decision: Option<FilterDecision>,
+    /// The outcome of the latest clock selection
+    selection: Option<SelectionStatus>,
 }
 
 impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> StandardClockController<C, PeerID> {
@@ -60,19 +68,58 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> StandardClockController<C, P
             self.timestate,
             &self.algo_config,
         );
-        update_result.is_none()
-            || !current_peerstate.usable
-            || PeerTimeSnapshot::from_timestate(&current_peerstate.timestate)
-                .accept_synchronization(
-                    now,
-                    self.algo_config.frequency_tolerance,
-                    self.algo_config.distance_threshold,
-                    self.timestate.poll_interval,
-                )
-                .is_err()
-    }
-
-    fn recalculate_clock(&mut self, now: NtpInstant) -> Option<(Vec<PeerID>, TimeSnapshot)> {
+        let decision = if update_result.is_none() {
+            FilterDecision::NotBest
+        } else if !current_peerstate.usable {
+            FilterDecision::Unusable
+        } else if PeerTimeSnapshot::from_timestate(&current_peerstate.timestate)
+            .accept_synchronization(
+                now,
+                self.algo_config.frequency_tolerance,
+                self.algo_config.distance_threshold,
+                self.timestate.poll_interval,
+            )
+            .is_err()
+        {
+            FilterDecision::Rejected
+        } else {
+            FilterDecision::Accepted
+        };
+        current_peerstate.decision = Some(decision);
+
+        decision != FilterDecision::Accepted
+    }
+
+    fn update_leap_seconds_state(&mut self, time: NtpTimestamp) {
+        let leap_seconds = self
+            .leap_seconds
+            .as_ref()
+            .filter(|leap_seconds| !leap_seconds.is_expired(time));
+        self.timestate.next_leap =
+            leap_seconds.and_then(|leap_seconds| leap_seconds.next_leap(time));
+        self.timestate.tai_offset =
+            leap_seconds.and_then(|leap_seconds| leap_seconds.tai_offset(time));
+    }
+
+    /// Give the tai offset of the leap seconds file to the clock, once the clock is synchronized
+    fn update_tai_offset(&mut self) {
+        let tai_offset = match self.timestate.tai_offset {
+            Some(tai_offset) if self.tai_offset != Some(tai_offset) => tai_offset,
+            _ => return,
+        };
+
+        // the offset is only set once, so a clock that cannot keep it is warned about once
+        self.tai_offset = Some(tai_offset);
+        match self.clock.set_tai_offset(tai_offset) {
+            Ok(()) => info!(tai_offset, "Set the tai offset of the clock"),
+            Err(error) => warn!(%error, "Could not set the tai offset of the clock"),
+        }
+    }
+
+    fn recalculate_clock(
+        &mut self,
+        now: NtpInstant,
+    ) -> Result<Option<(Vec<PeerID>, TimeSnapshot, ClockCorrection)>, PanicThresholdExceeded> {
         let snapshots: Vec<_> = self
             .peerstate
             .iter()
@@ -81,23 +128,40 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> StandardClockController<C, P
                 false => None,
             })
             .collect();
+        let mut selection = HashMap::new();
         let result = FilterAndCombine::run(
             &self.config,
             &self.algo_config,
             &snapshots,
             now,
             self.timestate.poll_interval,
+            &mut selection,
         );
+        // peers that were not given to the selection are unusable
+        for (index, state) in self.peerstate.iter_mut() {
+            state.selection = Some(
+                selection
+                    .get(index)
+                    .copied()
+                    .unwrap_or(SelectionStatus::Unusable),
+            );
+        }
         let clock_select = match result {
             Some(clock_select) => clock_select,
             None => {
                 info!("filter and combine did not produce a result");
-                return None;
+                return Ok(None);
             }
         };
         let offset_ms = clock_select.system_offset.to_seconds() * 1000.0;
         let jitter_ms = clock_select.system_jitter.to_seconds() * 1000.0;
         info!(offset_ms, jitter_ms, "Measured offset and jitter");
+        let time = self.clock.now().expect("Unable to get current time");
+        let (leap_indicator, clock_leap_indicator) = leap_indicators(
+            self.leap_seconds.as_ref(),
+            clock_select.system_peer_snapshot.1.leap_indicator,
+            time,
+        );
         let adjust_type = self.controller.update(
             &self.config,
             &self.algo_config,
@@ -105,35 +169,45 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> StandardClockController<C, P
             clock_select.system_offset,
             clock_select.system_root_delay,
             clock_select.system_root_dispersion,
-            clock_select.system_peer_snapshot.1.leap_indicator,
+            clock_leap_indicator,
             clock_select.system_peer_snapshot.1.time,
         );
         let offset_ms = self.controller.offset().to_seconds() * 1000.0;
         let jitter_ms = self.controller.jitter().to_seconds() * 1000.0;
         info!(offset_ms, jitter_ms, "Estimated clock offset and jitter");
-        match adjust_type {
+        let correction = match adjust_type {
             ClockUpdateResult::Panic => {
                 error!("Unusually large clock step suggested, please manually verify system clock and reference clock state and restart if appropriate.");
-                std::process::exit(exitcode::SOFTWARE);
+                return Err(PanicThresholdExceeded {
+                    offset: clock_select.system_offset,
+                });
             }
             ClockUpdateResult::Step => {
                 for (_, peerstate) in self.peerstate.iter_mut() {
                     peerstate.timestate.reset_measurements();
                 }
                 self.last_reset = Some(now);
+                Some(ClockCorrection::Step(clock_select.system_offset))
             }
-            _ => {}
-        }
-        if adjust_type != ClockUpdateResult::Ignore {
+            ClockUpdateResult::Slew => Some(ClockCorrection::Slew(clock_select.system_offset)),
+            ClockUpdateResult::Ignore => None,
+        };
+        if let Some(correction) = correction {
             self.timestate.poll_interval = self.controller.preferred_poll_interval();
-            self.timestate.leap_indicator = clock_select.system_peer_snapshot.1.leap_indicator;
+            self.timestate.leap_indicator = leap_indicator;
+            self.update_leap_seconds_state(time);
+            self.update_tai_offset();
             self.timestate.accumulated_steps = self.controller.accumulated_steps();
             self.timestate.root_delay = clock_select.system_root_delay;
             self.timestate.root_dispersion = clock_select.system_root_dispersion;
 
-            Some((vec![clock_select.system_peer_snapshot.0], self.timestate))
+            Ok(Some((
+                vec![clock_select.system_peer_snapshot.0],
+                self.timestate,
+                correction,
+            )))
         } else {
-            None
+            Ok(None)
         }
     }
 }
@@ -153,6 +227,8 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
             config,
             algo_config,
             last_reset: None,
+            leap_seconds: None,
+            tai_offset: None,
         }
     }
 
@@ -161,6 +237,25 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
         self.algo_config = algo_config;
     }
 
+    fn update_leap_seconds(&mut self, leap_seconds: Option<LeapSecondsFile>) {
+        self.leap_seconds = leap_seconds;
+        if let Ok(time) = self.clock.now() {
+            self.update_leap_seconds_state(time);
+        }
+    }
+
+    fn set_initial_frequency(&mut self, frequency: f64) {
+        self.controller.set_initial_frequency(frequency);
+    }
+
+    fn set_kernel_pps(&mut self, kernel_pps: bool) {
+        self.controller.set_kernel_pps(kernel_pps);
+    }
+
+    fn frequency(&self) -> Option<f64> {
+        self.controller.frequency()
+    }
+
     fn peer_add(&mut self, id: PeerID) {
         let time = NtpInstant::now();
         self.peerstate.insert(
@@ -173,6 +268,8 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
                     time,
                 },
                 usable: false,
+                decision: None,
+                selection: None,
             },
         );
     }
@@ -192,19 +289,22 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
         id: PeerID,
         measurement: crate::peer::Measurement,
         packet: crate::NtpPacket<'static>,
-    ) -> Option<(Vec<PeerID>, TimeSnapshot)> {
+    ) -> Result<Option<(Vec<PeerID>, TimeSnapshot, ClockCorrection)>, PanicThresholdExceeded> {
         let now = NtpInstant::now();
 
         // Ignore measurements within a second of the last reset
         if let Some(reset) = self.last_reset {
             if now.abs_diff(reset) < NtpDuration::ONE {
-                return None;
+                if let Some(state) = self.peerstate.get_mut(&id) {
+                    state.decision = Some(FilterDecision::Ignored);
+                }
+                return Ok(None);
             }
         }
 
         // Update peer's state and check if the clock needs recalculation
         if !self.run_peer_update(now, id, measurement, packet) {
-            return None;
+            return Ok(None);
         }
 
         self.recalculate_clock(now)
@@ -219,10 +319,157 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
                 uncertainty: snapshot.statistics.dispersion
                     + NtpDuration::from_seconds(snapshot.statistics.jitter),
                 delay: snapshot.statistics.delay,
+                jitter: NtpDuration::from_seconds(snapshot.statistics.jitter),
                 remote_delay: snapshot.root_delay,
                 remote_uncertainty: snapshot.root_dispersion,
                 last_update: self.clock.now().expect("Unable to get current time")
                     + NtpDuration::from_system_duration(snapshot.time.elapsed()),
             })
     }
+
+    fn peer_filter_decision(&self, id: PeerID) -> Option<FilterDecision> {
+        self.peerstate.get(&id).and_then(|state| state.decision)
+    }
+
+    fn peer_selection(&self, id: PeerID) -> Option<SelectionStatus> {
+        self.peerstate.get(&id).and_then(|state| state.selection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{NtpLeapIndicator, NtpPacket, PollInterval};
+
+    use super::*;
+
+    // 2017-01-01, the last leap second in the leap seconds file
+    const LEAP_2017: u32 = 3692217600;
+
+    /// Records the tai offsets given to the clock
+    #[derive(Debug, Clone, Default)]
+    struct TestClock {
+        now: Arc<Mutex<NtpTimestamp>>,
+        tai_offsets: Arc<Mutex<Vec<i32>>>,
+    }
+
+    impl TestClock {
+        fn set_now(&self, seconds: u32) {
+            *self.now.lock().unwrap() = NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0);
+        }
+
+        fn tai_offsets(&self) -> Vec<i32> {
+            self.tai_offsets.lock().unwrap().clone()
+        }
+    }
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(*self.now.lock().unwrap())
+        }
+
+        fn set_frequency(&self, _freq: f64) -> Result<NtpTimestamp, Self::Error> {
+            self.now()
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<NtpTimestamp, Self::Error> {
+            self.now()
+        }
+
+        fn enable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn disable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn ntp_algorithm_update(
+            &self,
+            _offset: NtpDuration,
+            _poll_interval: PollInterval,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn error_estimate_update(
+            &self,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_tai_offset(&self, tai_offset: i32) -> Result<(), Self::Error> {
+            self.tai_offsets.lock().unwrap().push(tai_offset);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tai_offset() {
+        let clock = TestClock::default();
+        clock.set_now(LEAP_2017 - 86400);
+
+        let config = SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        };
+        let algo_config = AlgorithmConfig {
+            distance_threshold: NtpDuration::from_seconds(10.0),
+            ..Default::default()
+        };
+        let mut controller =
+            StandardClockController::<_, usize>::new(clock.clone(), config, algo_config);
+        // a known frequency makes small offsets slew the clock
+        controller.set_initial_frequency(0.0);
+
+        let leap_seconds: LeapSecondsFile = include_str!("../../../testdata/leap-seconds.list")
+            .parse()
+            .unwrap();
+        controller.update_leap_seconds(Some(leap_seconds));
+
+        // the clock is not synchronized yet
+        assert_eq!(clock.tai_offsets(), Vec::<i32>::new());
+
+        let synced = 0;
+        let unusable = 1;
+        controller.peer_add(synced);
+        controller.peer_update(synced, true);
+        controller.peer_add(unusable);
+
+        let mut measure = |clock: &TestClock, id| {
+            let measurement = Measurement {
+                delay: NtpDuration::from_seconds(0.1),
+                offset: NtpDuration::from_seconds(0.001),
+                localtime: clock.now().unwrap(),
+                monotime: NtpInstant::now(),
+            };
+            controller
+                .peer_measurement(id, measurement, NtpPacket::test())
+                .unwrap()
+        };
+
+        for _ in 0..4 {
+            measure(&clock, synced);
+        }
+        // a measurement of an unusable peer makes the controller select from the others
+        assert!(measure(&clock, unusable).is_some());
+        assert_eq!(clock.tai_offsets(), vec![36]);
+
+        // the offset is only given again when it changes
+        assert!(measure(&clock, unusable).is_some());
+        assert_eq!(clock.tai_offsets(), vec![36]);
+
+        clock.set_now(LEAP_2017 + 86400);
+        assert!(measure(&clock, unusable).is_some());
+        assert_eq!(clock.tai_offsets(), vec![36, 37]);
+    }
 }
@@ -1,17 +1,64 @@
+use serde::{Deserialize, Serialize};
+
 use crate::{packet::NtpLeapIndicator, time_types::PollInterval, NtpDuration, NtpTimestamp};
 
+/// Statistics of the discipline of a clock by a pps signal, as kept by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KernelPpsStatus {
+    /// Whether the kernel currently receives a valid pps signal
+    pub signal: bool,
+    /// Frequency offset of the clock according to the pps signal, in seconds per second
+    pub frequency: f64,
+    /// Jitter of the pps signal
+    pub jitter: NtpDuration,
+    /// Stability of the pps frequency, in seconds per second
+    pub stability: f64,
+    /// Number of calibration intervals
+    pub calibration_count: u64,
+    /// Number of pulses rejected because their jitter was too large
+    pub jitter_count: u64,
+    /// Number of calibration errors
+    pub error_count: u64,
+    /// Number of calibrations in which the stability was too low
+    pub stability_count: u64,
+}
+
+/// Returned by the default implementations of the optional operations of
+/// [`NtpClock`], for clocks that do not support them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Clock operation is not supported by this clock")]
+pub struct ClockOperationUnsupported;
+
+impl From<ClockOperationUnsupported> for std::io::Error {
+    fn from(_: ClockOperationUnsupported) -> Self {
+        std::io::Error::from(std::io::ErrorKind::Unsupported)
+    }
+}
+
 /// Interface for a clock settable by the ntp implementation.
 /// This needs to be a trait as a single system can have multiple clocks
 /// which need different implementation for steering and/or now.
 pub trait NtpClock: Clone + Send + 'static {
-    type Error: std::error::Error;
+    type Error: std::error::Error + From<ClockOperationUnsupported>;
 
     // Get current time
     fn now(&self) -> Result<NtpTimestamp, Self::Error>;
 
+    // Packets are timestamped by the kernel with the system clock.
+    // Convert such a timestamp to the time of this clock, which is
+    // only needed for clocks other than the system clock.
+    fn convert_system_time(&self, time: NtpTimestamp) -> Result<NtpTimestamp, Self::Error> {
+        Ok(time)
+    }
+
     // Change the frequency of the clock, returning the time
     // at which the change was applied.
     fn set_frequency(&self, freq: f64) -> Result<NtpTimestamp, Self::Error>;
+    // Get the current frequency of the clock, including any
+    // corrections made by a built in clock discipline.
+    fn get_frequency(&self) -> Result<f64, Self::Error> {
+        Err(ClockOperationUnsupported.into())
+    }
     // Change the current time of the clock by offset. Returns
     // the time at which the change was applied.
     fn step_clock(&self, offset: NtpDuration) -> Result<NtpTimestamp, Self::Error>;
@@ -40,4 +87,26 @@ pub trait NtpClock: Clone + Send + 'static {
     // Change the indicators for upcoming leap seconds and
     // the clocks synchronization status.
     fn status_update(&self, leap_status: NtpLeapIndicator) -> Result<(), Self::Error>;
+
+    // A clock can be disciplined directly from a pps signal by the
+    // kernel, such that the pulses are not delayed by handing them to
+    // userspace. These functions enable/disable using the pps signal
+    // for both the frequency and the phase of the clock, and read back
+    // the statistics of that discipline. The pps device providing the
+    // signal is bound to the kernel separately.
+    fn enable_kernel_pps(&self) -> Result<(), Self::Error> {
+        Err(ClockOperationUnsupported.into())
+    }
+    fn disable_kernel_pps(&self) -> Result<(), Self::Error> {
+        Err(ClockOperationUnsupported.into())
+    }
+    fn kernel_pps_status(&self) -> Result<KernelPpsStatus, Self::Error> {
+        Err(ClockOperationUnsupported.into())
+    }
+
+    // Change the difference between TAI and UTC in seconds, which
+    // the clock keeps such that TAI can be derived from it.
+    fn set_tai_offset(&self, _tai_offset: i32) -> Result<(), Self::Error> {
+        Err(ClockOperationUnsupported.into())
+    }
 }
@@ -45,11 +45,11 @@ impl ReferenceId {
         *self == Self::KISS_NTSN
     }
 
-    pub(crate) fn to_bytes(self) -> [u8; 4] {
+    pub fn to_bytes(self) -> [u8; 4] {
         self.0.to_be_bytes()
     }
 
-    pub(crate) fn from_bytes(bits: [u8; 4]) -> ReferenceId {
+    pub fn from_bytes(bits: [u8; 4]) -> ReferenceId {
         ReferenceId(u32::from_be_bytes(bits))
     }
 }
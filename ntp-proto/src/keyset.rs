@@ -0,0 +1,463 @@
+use std::{
+    io::{Read, Write},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use aes_siv::{
+    aead::{Aead, Payload},
+    AeadCore, Aes128SivAead, Key, KeyInit, Nonce,
+};
+use rand::Rng;
+
+/// The AEAD algorithm we support, AEAD_AES_SIV_CMAC_256
+/// https://www.iana.org/assignments/aead-parameters/aead-parameters.xhtml
+pub(crate) const AEAD_AES_SIV_CMAC_256: u16 = 15;
+
+#[derive(Debug)]
+pub struct DecryptError;
+
+impl std::fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Could not decrypt cookie")
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// The keys that the server and a client agreed upon during key exchange.
+/// These are stored (encrypted) inside of the cookies that the server hands out.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DecodedServerCookie {
+    pub(crate) algorithm: u16,
+    pub(crate) c2s: Key<Aes128SivAead>,
+    pub(crate) s2c: Key<Aes128SivAead>,
+}
+
+impl DecodedServerCookie {
+    /// length of the plaintext: the algorithm id followed by the two keys
+    const PLAINTEXT_LEN: usize = 2 + 32 + 32;
+
+    fn plaintext(&self) -> [u8; Self::PLAINTEXT_LEN] {
+        let mut plaintext = [0; Self::PLAINTEXT_LEN];
+
+        plaintext[0..2].copy_from_slice(&self.algorithm.to_be_bytes());
+        plaintext[2..34].copy_from_slice(&self.c2s);
+        plaintext[34..66].copy_from_slice(&self.s2c);
+
+        plaintext
+    }
+
+    fn from_plaintext(plaintext: &[u8]) -> Result<Self, DecryptError> {
+        if plaintext.len() != Self::PLAINTEXT_LEN {
+            return Err(DecryptError);
+        }
+
+        let algorithm = u16::from_be_bytes([plaintext[0], plaintext[1]]);
+        if algorithm != AEAD_AES_SIV_CMAC_256 {
+            return Err(DecryptError);
+        }
+
+        Ok(Self {
+            algorithm,
+            c2s: *Key::<Aes128SivAead>::from_slice(&plaintext[2..34]),
+            s2c: *Key::<Aes128SivAead>::from_slice(&plaintext[34..66]),
+        })
+    }
+
+    pub fn c2s(&self) -> Aes128SivAead {
+        Aes128SivAead::new(&self.c2s)
+    }
+
+    pub fn s2c(&self) -> Aes128SivAead {
+        Aes128SivAead::new(&self.s2c)
+    }
+}
+
+#[cfg(any(test, feature = "ext-test"))]
+impl DecodedServerCookie {
+    pub fn test() -> Self {
+        Self {
+            algorithm: AEAD_AES_SIV_CMAC_256,
+            c2s: *Key::<Aes128SivAead>::from_slice(&[1; 32]),
+            s2c: *Key::<Aes128SivAead>::from_slice(&[2; 32]),
+        }
+    }
+}
+
+impl std::fmt::Debug for DecodedServerCookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecodedServerCookie")
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+/// A master key, together with its raw bytes so that it can be stored
+struct MasterKey {
+    key: Key<Aes128SivAead>,
+    cipher: Aes128SivAead,
+}
+
+impl MasterKey {
+    fn new(key: Key<Aes128SivAead>) -> Self {
+        Self {
+            key,
+            cipher: Aes128SivAead::new(&key),
+        }
+    }
+
+    fn random() -> Self {
+        let mut key: Key<Aes128SivAead> = Default::default();
+        rand::thread_rng().fill(key.as_mut_slice());
+
+        Self::new(key)
+    }
+}
+
+/// The set of master keys a server uses to encrypt its cookies
+pub struct KeySet {
+    keys: Vec<MasterKey>,
+    id_offset: u32,
+    primary: u32,
+}
+
+impl KeySet {
+    /// the key id is a 4 byte prefix of every cookie
+    const KEY_ID_LEN: usize = 4;
+    const NONCE_LEN: usize = 16;
+    /// the ciphertext is the plaintext followed by the 16 byte SIV tag
+    const CIPHERTEXT_LEN: usize = DecodedServerCookie::PLAINTEXT_LEN + 16;
+
+    /// Create a key set with a single, randomly generated, key
+    pub fn new() -> Self {
+        Self {
+            keys: vec![MasterKey::random()],
+            id_offset: rand::thread_rng().gen(),
+            primary: 0,
+        }
+    }
+
+    /// Encrypt the cookie with the primary key of this key set
+    pub fn encode_cookie(&self, cookie: &DecodedServerCookie) -> Vec<u8> {
+        let key_id = self.id_offset.wrapping_add(self.primary);
+        let cipher = &self.keys[self.primary as usize].cipher;
+
+        let nonce = Aes128SivAead::generate_nonce(rand::thread_rng());
+        let plaintext = cookie.plaintext();
+        let payload = Payload {
+            msg: &plaintext,
+            aad: &key_id.to_be_bytes(),
+        };
+
+        // encryption with AES-SIV can only fail on absurdly long inputs
+        let ciphertext = cipher.encrypt(&nonce, payload).unwrap();
+
+        let mut output = Vec::with_capacity(Self::KEY_ID_LEN + nonce.len() + ciphertext.len());
+        output.extend_from_slice(&key_id.to_be_bytes());
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&ciphertext);
+
+        output
+    }
+
+    /// Decrypt a cookie that was encrypted with any of the keys in this key set
+    pub fn decode_cookie(&self, cookie: &[u8]) -> Result<DecodedServerCookie, DecryptError> {
+        // cookies in extension fields are padded to a multiple of 4 bytes,
+        // so any bytes after the ciphertext are ignored
+        let cookie = cookie
+            .get(..Self::KEY_ID_LEN + Self::NONCE_LEN + Self::CIPHERTEXT_LEN)
+            .ok_or(DecryptError)?;
+
+        let (key_id_bytes, rest) = cookie.split_at(Self::KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(Self::NONCE_LEN);
+
+        let key_id = u32::from_be_bytes(key_id_bytes.try_into().unwrap());
+        let cipher = &self
+            .keys
+            .get(key_id.wrapping_sub(self.id_offset) as usize)
+            .ok_or(DecryptError)?
+            .cipher;
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: key_id_bytes,
+        };
+
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| DecryptError)?;
+
+        DecodedServerCookie::from_plaintext(&plaintext)
+    }
+}
+
+impl Default for KeySet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for KeySet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeySet")
+            .field("keys", &self.keys.len())
+            .field("id_offset", &self.id_offset)
+            .field("primary", &self.primary)
+            .finish()
+    }
+}
+
+/// Manages the rotation of the master keys in a [`KeySet`]. After a rotation,
+/// cookies encrypted with one of the `history` most recent old keys are still
+/// accepted.
+pub struct KeySetProvider {
+    current: Arc<KeySet>,
+    history: usize,
+    rotated_at: SystemTime,
+}
+
+impl KeySetProvider {
+    /// Create a provider with a single, randomly generated, key
+    pub fn new(history: usize) -> Self {
+        Self {
+            current: Arc::new(KeySet::new()),
+            history,
+            rotated_at: SystemTime::now(),
+        }
+    }
+
+    /// Make a freshly generated key the primary key, forgetting the keys
+    /// that fall outside of the history
+    pub fn rotate(&mut self) {
+        let mut keys: Vec<_> = self
+            .current
+            .keys
+            .iter()
+            .map(|key| MasterKey::new(key.key))
+            .collect();
+        keys.push(MasterKey::random());
+
+        let removed = keys.len().saturating_sub(self.history + 1);
+        keys.drain(..removed);
+
+        self.current = Arc::new(KeySet {
+            primary: (keys.len() - 1) as u32,
+            id_offset: self.current.id_offset.wrapping_add(removed as u32),
+            keys,
+        });
+        self.rotated_at = SystemTime::now();
+    }
+
+    /// The current key set
+    pub fn get(&self) -> Arc<KeySet> {
+        self.current.clone()
+    }
+
+    /// The time at which the current primary key was generated
+    pub fn rotated_at(&self) -> SystemTime {
+        self.rotated_at
+    }
+
+    /// Write the key set to `writer`, in a format that can be read by [`KeySetProvider::load`]
+    pub fn store(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let rotated_at = self
+            .rotated_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        writer.write_all(&rotated_at.as_secs().to_be_bytes())?;
+        writer.write_all(&self.current.id_offset.to_be_bytes())?;
+        writer.write_all(&self.current.primary.to_be_bytes())?;
+        writer.write_all(&(self.current.keys.len() as u32).to_be_bytes())?;
+        for key in &self.current.keys {
+            writer.write_all(&key.key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a key set that was written by [`KeySetProvider::store`]. Keys that
+    /// fall outside of the `history` are dropped.
+    pub fn load(reader: &mut impl Read, history: usize) -> std::io::Result<Self> {
+        fn invalid(message: &str) -> std::io::Error {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+        }
+
+        let mut buf = [0; 8];
+        reader.read_exact(&mut buf)?;
+        let rotated_at = SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(buf));
+
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf)?;
+        let id_offset = u32::from_be_bytes(buf);
+        reader.read_exact(&mut buf)?;
+        let primary = u32::from_be_bytes(buf) as usize;
+        reader.read_exact(&mut buf)?;
+        let count = u32::from_be_bytes(buf) as usize;
+
+        if primary >= count {
+            return Err(invalid("primary key is not part of the key set"));
+        }
+
+        let mut keys = vec![];
+        for _ in 0..count {
+            let mut key: Key<Aes128SivAead> = Default::default();
+            reader.read_exact(key.as_mut_slice())?;
+            keys.push(MasterKey::new(key));
+        }
+
+        // the primary key (and any key after it) is never dropped
+        let removed = count.saturating_sub(history + 1).min(primary);
+        keys.drain(..removed);
+
+        Ok(Self {
+            current: Arc::new(KeySet {
+                keys,
+                id_offset: id_offset.wrapping_add(removed as u32),
+                primary: (primary - removed) as u32,
+            }),
+            history,
+            rotated_at,
+        })
+    }
+}
+
+impl std::fmt::Debug for KeySetProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeySetProvider")
+            .field("current", &self.current)
+            .field("history", &self.history)
+            .field("rotated_at", &self.rotated_at)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cookie() -> DecodedServerCookie {
+        DecodedServerCookie {
+            algorithm: AEAD_AES_SIV_CMAC_256,
+            c2s: *Key::<Aes128SivAead>::from_slice(&[1; 32]),
+            s2c: *Key::<Aes128SivAead>::from_slice(&[2; 32]),
+        }
+    }
+
+    #[test]
+    fn roundtrip_cookie() {
+        let keyset = KeySet::new();
+
+        let encoded = keyset.encode_cookie(&test_cookie());
+        let decoded = keyset.decode_cookie(&encoded).unwrap();
+
+        assert_eq!(decoded, test_cookie());
+
+        // padding of the extension field is ignored
+        let mut padded = encoded.clone();
+        padded.extend_from_slice(&[0, 0]);
+        assert_eq!(keyset.decode_cookie(&padded).unwrap(), test_cookie());
+    }
+
+    #[test]
+    fn cookie_from_other_keyset() {
+        let keyset = KeySet::new();
+        let other = KeySet::new();
+
+        let encoded = other.encode_cookie(&test_cookie());
+        assert!(keyset.decode_cookie(&encoded).is_err());
+    }
+
+    #[test]
+    fn tampered_cookie() {
+        let keyset = KeySet::new();
+
+        let mut encoded = keyset.encode_cookie(&test_cookie());
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        assert!(keyset.decode_cookie(&encoded).is_err());
+
+        assert!(keyset.decode_cookie(&[]).is_err());
+        assert!(keyset.decode_cookie(&encoded[..10]).is_err());
+    }
+
+    #[test]
+    fn rotation_keeps_history() {
+        let mut provider = KeySetProvider::new(2);
+
+        let cookies: Vec<_> = (0..4)
+            .map(|_| {
+                let cookie = provider.get().encode_cookie(&test_cookie());
+                provider.rotate();
+                cookie
+            })
+            .collect();
+
+        let keyset = provider.get();
+        assert_eq!(keyset.keys.len(), 3);
+
+        // the cookies of the 2 most recent old keys are still accepted
+        assert!(keyset.decode_cookie(&cookies[0]).is_err());
+        assert!(keyset.decode_cookie(&cookies[1]).is_err());
+        assert_eq!(keyset.decode_cookie(&cookies[2]).unwrap(), test_cookie());
+        assert_eq!(keyset.decode_cookie(&cookies[3]).unwrap(), test_cookie());
+
+        let cookie = keyset.encode_cookie(&test_cookie());
+        assert_eq!(keyset.decode_cookie(&cookie).unwrap(), test_cookie());
+    }
+
+    #[test]
+    fn store_and_load() {
+        let mut provider = KeySetProvider::new(3);
+        let old_cookie = provider.get().encode_cookie(&test_cookie());
+        provider.rotate();
+        let cookie = provider.get().encode_cookie(&test_cookie());
+
+        let mut buffer = vec![];
+        provider.store(&mut buffer).unwrap();
+
+        let loaded = KeySetProvider::load(&mut buffer.as_slice(), 3).unwrap();
+        let keyset = loaded.get();
+        assert_eq!(keyset.decode_cookie(&old_cookie).unwrap(), test_cookie());
+        assert_eq!(keyset.decode_cookie(&cookie).unwrap(), test_cookie());
+
+        let rotated_at = |provider: &KeySetProvider| {
+            provider
+                .rotated_at()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        assert_eq!(rotated_at(&loaded), rotated_at(&provider));
+
+        // new cookies are encrypted with the same primary key
+        let new_cookie = keyset.encode_cookie(&test_cookie());
+        assert_eq!(
+            provider.get().decode_cookie(&new_cookie).unwrap(),
+            test_cookie()
+        );
+
+        // a smaller history drops the oldest keys
+        let loaded = KeySetProvider::load(&mut buffer.as_slice(), 0).unwrap();
+        let keyset = loaded.get();
+        assert!(keyset.decode_cookie(&old_cookie).is_err());
+        assert_eq!(keyset.decode_cookie(&cookie).unwrap(), test_cookie());
+    }
+
+    #[test]
+    fn load_invalid() {
+        let provider = KeySetProvider::new(1);
+        let mut buffer = vec![];
+        provider.store(&mut buffer).unwrap();
+
+        // truncated
+        let truncated = &buffer[..buffer.len() - 1];
+        assert!(KeySetProvider::load(&mut &truncated[..], 1).is_err());
+
+        // primary key out of range
+        let mut invalid = buffer.clone();
+        invalid[12..16].copy_from_slice(&1u32.to_be_bytes());
+        assert!(KeySetProvider::load(&mut invalid.as_slice(), 1).is_err());
+    }
+}
@@ -0,0 +1,449 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::{NtpLeapIndicator, NtpTimestamp};
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+// Unix uses an epoch located at 1/1/1970-00:00h (UTC) and NTP uses 1/1/1900-00:00h.
+// This leads to an offset equivalent to 70 years in seconds
+// there are 17 leap years between the two dates so the offset is
+const EPOCH_OFFSET: i64 = (70 * 365 + 17) * 86400;
+
+// Timestamps without a leap seconds file to relate them to are taken to be within 68 years
+// of 1/1/2020-00:00h (UTC), so from 1952 up to 2088
+const ERA_REFERENCE: i64 = 3786825600;
+
+/// A leap second that is scheduled by a leap seconds file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeapSecond {
+    /// The start of the day after the leap second, which is the first day of a month
+    pub time: NtpTimestamp,
+    /// Whether a second is inserted or deleted
+    pub leap: NtpLeapIndicator,
+    /// Difference between TAI and UTC after the leap second
+    pub tai_offset: i32,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum LeapSecondsFileError {
+    #[error("line {line}: expected a time and a tai offset")]
+    InvalidLine { line: usize },
+    #[error("the file does not contain its last update time")]
+    MissingUpdateTime,
+    #[error("the file does not contain its expiry time")]
+    MissingExpiryTime,
+    #[error("the file does not contain a hash")]
+    MissingHash,
+    #[error("the hash of the file does not match its contents")]
+    HashMismatch,
+}
+
+/// The leap seconds file as published by the IETF and NIST (`leap-seconds.list`), which
+/// lists all leap seconds and is valid until it expires
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeapSecondsFile {
+    /// Seconds since the ntp epoch of 1900
+    updated: i64,
+    expires: i64,
+    /// The moments at which the tai offset changes, and the offset from that moment on
+    offsets: Vec<(i64, i32)>,
+}
+
+impl LeapSecondsFile {
+    /// The time after which the file should not be used anymore
+    pub fn expires(&self) -> NtpTimestamp {
+        timestamp(self.expires)
+    }
+
+    pub fn is_expired(&self, now: NtpTimestamp) -> bool {
+        self.seconds(now) >= self.expires
+    }
+
+    /// Difference between TAI and UTC at the given time
+    pub fn tai_offset(&self, now: NtpTimestamp) -> Option<i32> {
+        let now = self.seconds(now);
+        self.offsets
+            .iter()
+            .rev()
+            .find(|(time, _)| *time <= now)
+            .map(|(_, offset)| *offset)
+    }
+
+    /// The first leap second after the given time
+    pub fn next_leap(&self, now: NtpTimestamp) -> Option<LeapSecond> {
+        let now = self.seconds(now);
+        self.offsets
+            .windows(2)
+            .find(|window| window[1].0 > now)
+            .map(|window| LeapSecond {
+                time: timestamp(window[1].0),
+                leap: if window[1].1 > window[0].1 {
+                    NtpLeapIndicator::Leap61
+                } else {
+                    NtpLeapIndicator::Leap59
+                },
+                tai_offset: window[1].1,
+            })
+    }
+
+    /// The leap indicator to advertise at the given time, which warns of a leap second during
+    /// the month at the end of which it occurs
+    pub fn leap_indicator(&self, now: NtpTimestamp) -> NtpLeapIndicator {
+        match self.next_leap(now) {
+            Some(leap) if self.seconds(now) >= month_start(self.seconds(leap.time)) => leap.leap,
+            _ => NtpLeapIndicator::NoWarning,
+        }
+    }
+
+    // Seconds since the ntp epoch, where the era of the timestamp is
+    // the one closest to the last update of the file
+    fn seconds(&self, now: NtpTimestamp) -> i64 {
+        let (seconds, _) = (now - timestamp(self.updated)).as_seconds_nanos();
+        self.updated + seconds as i64
+    }
+}
+
+/// Parses a leap seconds file in the format of `leap-seconds.list`. The file is rejected when
+/// its hash does not match its contents.
+impl FromStr for LeapSecondsFile {
+    type Err = LeapSecondsFileError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        let mut updated = None;
+        let mut expires = None;
+        let mut hash = None;
+        let mut offsets = vec![];
+
+        // the hash covers the digits of the update time, the expiry time and the data lines
+        let mut hasher = Sha1::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+
+            if let Some(value) = line.strip_prefix("#$") {
+                hasher.update(digits(value));
+                updated = value.trim().parse().ok();
+            } else if let Some(value) = line.strip_prefix("#@") {
+                hasher.update(digits(value));
+                expires = value.trim().parse().ok();
+            } else if let Some(value) = line.strip_prefix("#h") {
+                hash = parse_hash(value);
+            } else if !line.starts_with('#') {
+                let data = line.split('#').next().unwrap_or_default();
+                let parts: Vec<_> = data.split_whitespace().collect();
+                let (time, offset) = match parts[..] {
+                    [] => continue,
+                    [time, offset] => (time, offset),
+                    _ => return Err(LeapSecondsFileError::InvalidLine { line: line_number }),
+                };
+
+                let time = time.parse();
+                let offset = offset.parse();
+                match (time, offset) {
+                    (Ok(time), Ok(offset)) => offsets.push((time, offset)),
+                    _ => return Err(LeapSecondsFileError::InvalidLine { line: line_number }),
+                }
+
+                hasher.update(digits(data));
+            }
+        }
+
+        let updated = updated.ok_or(LeapSecondsFileError::MissingUpdateTime)?;
+        let expires = expires.ok_or(LeapSecondsFileError::MissingExpiryTime)?;
+        let hash = hash.ok_or(LeapSecondsFileError::MissingHash)?;
+
+        if hasher.finalize()[..] != hash[..] {
+            return Err(LeapSecondsFileError::HashMismatch);
+        }
+
+        offsets.sort_unstable();
+
+        Ok(LeapSecondsFile {
+            updated,
+            expires,
+            offsets,
+        })
+    }
+}
+
+fn digits(value: &str) -> Vec<u8> {
+    value.bytes().filter(u8::is_ascii_digit).collect()
+}
+
+/// The hash is written as five 32 bit words in hexadecimal, of which leading zeros can be
+/// omitted
+fn parse_hash(value: &str) -> Option<Vec<u8>> {
+    let words: Vec<_> = value.split_whitespace().collect();
+    if words.len() != 5 {
+        return None;
+    }
+
+    let mut hash = Vec::with_capacity(20);
+    for word in words {
+        hash.extend(u32::from_str_radix(word, 16).ok()?.to_be_bytes());
+    }
+
+    Some(hash)
+}
+
+fn timestamp(seconds: i64) -> NtpTimestamp {
+    // truncates to the era of the timestamp
+    NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds as u32, 0)
+}
+
+/// The day of the month of a number of seconds since the ntp epoch
+fn day_of_month(seconds: i64) -> i64 {
+    // Howard Hinnant's algorithm for converting days since the unix epoch to a civil date
+    let days = (seconds - EPOCH_OFFSET).div_euclid(SECONDS_PER_DAY) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+
+    day_of_year - (153 * month + 2) / 5 + 1
+}
+
+/// The start of the month that ends at the given start of a month
+fn month_start(next_month: i64) -> i64 {
+    next_month - day_of_month(next_month - 1) * SECONDS_PER_DAY
+}
+
+/// Whether the given time is during the last day of a month, at the end of which a leap second
+/// can occur
+pub(crate) fn is_last_day_of_month(now: NtpTimestamp) -> bool {
+    day_of_month(epoch_seconds(now) + SECONDS_PER_DAY) == 1
+}
+
+/// The end of the month of the given time, at which a leap second announced by the leap
+/// indicator occurs
+pub(crate) fn end_of_month(now: NtpTimestamp) -> NtpTimestamp {
+    let seconds = epoch_seconds(now);
+    let mut day = seconds - seconds.rem_euclid(SECONDS_PER_DAY) + SECONDS_PER_DAY;
+    while day_of_month(day) != 1 {
+        day += SECONDS_PER_DAY;
+    }
+
+    timestamp(day)
+}
+
+// Seconds since the ntp epoch, where the era of the timestamp is the one closest to the
+// era reference. Eras do not consist of whole days, so the era matters for the date.
+fn epoch_seconds(now: NtpTimestamp) -> i64 {
+    let (seconds, _) = (now - timestamp(ERA_REFERENCE)).as_seconds_nanos();
+    ERA_REFERENCE + seconds as i64
+}
+
+/// The leap indicator to advertise to clients and the leap indicator to give to the clock, given
+/// the leap indicator of the system peer. A leap seconds file that has not expired overrules the
+/// system peer. The clock inserts or deletes a leap second at the end of the day on which it is
+/// told to, so it is only told on the last day of the month.
+pub(crate) fn leap_indicators(
+    leap_seconds: Option<&LeapSecondsFile>,
+    peer_leap_indicator: NtpLeapIndicator,
+    now: NtpTimestamp,
+) -> (NtpLeapIndicator, NtpLeapIndicator) {
+    if !peer_leap_indicator.is_synchronized() {
+        return (peer_leap_indicator, peer_leap_indicator);
+    }
+
+    let leap_indicator = match leap_seconds {
+        Some(leap_seconds) if !leap_seconds.is_expired(now) => {
+            let leap_indicator = leap_seconds.leap_indicator(now);
+            if leap_indicator != peer_leap_indicator {
+                tracing::debug!(
+                    ?leap_indicator,
+                    ?peer_leap_indicator,
+                    "leap indicator of the system peer overruled by the leap seconds file"
+                );
+            }
+            leap_indicator
+        }
+        _ => peer_leap_indicator,
+    };
+
+    if is_last_day_of_month(now) {
+        (leap_indicator, leap_indicator)
+    } else {
+        (leap_indicator, NtpLeapIndicator::NoWarning)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2017-01-01, the last leap second
+    const LEAP_2017: i64 = 3692217600;
+    // 2027-01-01
+    const LEAP_2027: i64 = 4007750400;
+
+    /// A file with a fictional leap second at the end of 2026
+    const FILE: &str = "\
+#	A leap seconds file for testing
+#$	 3960835200
+#@	4023388800
+#
+2272060800	10	# 1 Jan 1972
+2287785600	11	# 1 Jul 1972
+3692217600	37	# 1 Jan 2017
+4007750400	38	# 1 Jan 2027
+#
+#h	1354b351 ad0c2fd3 5535c002 eaeab1df e595019d
+";
+
+    #[test]
+    fn test_parse_real_file() {
+        let file: LeapSecondsFile = include_str!("../testdata/leap-seconds.list")
+            .parse()
+            .unwrap();
+
+        assert_eq!(file.expires(), timestamp(3991593600));
+        assert_eq!(file.tai_offset(timestamp(LEAP_2017)), Some(37));
+        assert_eq!(file.tai_offset(timestamp(LEAP_2017 - 1)), Some(36));
+        assert_eq!(file.next_leap(timestamp(LEAP_2017)), None);
+        assert!(!file.is_expired(timestamp(3991593600 - 1)));
+        assert!(file.is_expired(timestamp(3991593600)));
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let file = FILE.replace("38", "39");
+        assert_eq!(
+            file.parse::<LeapSecondsFile>(),
+            Err(LeapSecondsFileError::HashMismatch)
+        );
+
+        let file = FILE.replace("#h", "#");
+        assert_eq!(
+            file.parse::<LeapSecondsFile>(),
+            Err(LeapSecondsFileError::MissingHash)
+        );
+    }
+
+    #[test]
+    fn test_next_leap() {
+        let file: LeapSecondsFile = FILE.parse().unwrap();
+
+        let leap = file.next_leap(timestamp(LEAP_2017)).unwrap();
+        assert_eq!(leap.time, timestamp(LEAP_2027));
+        assert_eq!(leap.leap, NtpLeapIndicator::Leap61);
+        assert_eq!(leap.tai_offset, 38);
+
+        assert_eq!(file.tai_offset(timestamp(LEAP_2027 - 1)), Some(37));
+        assert_eq!(file.tai_offset(timestamp(LEAP_2027)), Some(38));
+        assert_eq!(file.next_leap(timestamp(LEAP_2027)), None);
+    }
+
+    #[test]
+    fn test_leap_indicator() {
+        let file: LeapSecondsFile = FILE.parse().unwrap();
+
+        // december has 31 days
+        let december = LEAP_2027 - 31 * SECONDS_PER_DAY;
+        assert_eq!(
+            file.leap_indicator(timestamp(december - 1)),
+            NtpLeapIndicator::NoWarning
+        );
+        assert_eq!(
+            file.leap_indicator(timestamp(december)),
+            NtpLeapIndicator::Leap61
+        );
+        assert_eq!(
+            file.leap_indicator(timestamp(LEAP_2027 - 1)),
+            NtpLeapIndicator::Leap61
+        );
+        assert_eq!(
+            file.leap_indicator(timestamp(LEAP_2027)),
+            NtpLeapIndicator::NoWarning
+        );
+    }
+
+    #[test]
+    fn test_leap_indicators() {
+        let file: LeapSecondsFile = FILE.parse().unwrap();
+        let december = timestamp(LEAP_2027 - 31 * SECONDS_PER_DAY);
+        let last_day = timestamp(LEAP_2027 - SECONDS_PER_DAY);
+
+        // the file confirms the leap second, but the clock is only told on the last day
+        assert_eq!(
+            leap_indicators(Some(&file), NtpLeapIndicator::Leap61, december),
+            (NtpLeapIndicator::Leap61, NtpLeapIndicator::NoWarning)
+        );
+        assert_eq!(
+            leap_indicators(Some(&file), NtpLeapIndicator::NoWarning, last_day),
+            (NtpLeapIndicator::Leap61, NtpLeapIndicator::Leap61)
+        );
+
+        // the file votes against a leap second that it does not list
+        let june = timestamp(LEAP_2027 + 180 * SECONDS_PER_DAY);
+        assert_eq!(
+            leap_indicators(Some(&file), NtpLeapIndicator::Leap61, june),
+            (NtpLeapIndicator::NoWarning, NtpLeapIndicator::NoWarning)
+        );
+
+        // without a file, the system peer is followed
+        assert_eq!(
+            leap_indicators(None, NtpLeapIndicator::Leap59, last_day),
+            (NtpLeapIndicator::Leap59, NtpLeapIndicator::Leap59)
+        );
+        assert_eq!(
+            leap_indicators(None, NtpLeapIndicator::Leap59, december),
+            (NtpLeapIndicator::Leap59, NtpLeapIndicator::NoWarning)
+        );
+    }
+
+    #[test]
+    fn test_last_day_of_month() {
+        assert!(is_last_day_of_month(timestamp(LEAP_2017 - 1)));
+        assert!(is_last_day_of_month(timestamp(LEAP_2017 - SECONDS_PER_DAY)));
+        assert!(!is_last_day_of_month(timestamp(
+            LEAP_2017 - SECONDS_PER_DAY - 1
+        )));
+        assert!(!is_last_day_of_month(timestamp(LEAP_2017)));
+
+        // 2024 is a leap year, so february ends on the 29th
+        let march_2024 = 3918240000;
+        assert!(is_last_day_of_month(timestamp(march_2024 - 1)));
+        assert!(!is_last_day_of_month(timestamp(
+            march_2024 - SECONDS_PER_DAY - 1
+        )));
+
+        // dates after the ntp era rolls over in 2036
+        let january_2037 = 4323369600;
+        assert!(is_last_day_of_month(timestamp(january_2037 - 1)));
+        assert!(!is_last_day_of_month(timestamp(
+            january_2037 - SECONDS_PER_DAY - 1
+        )));
+        assert!(!is_last_day_of_month(timestamp(january_2037)));
+    }
+
+    #[test]
+    fn test_end_of_month() {
+        assert_eq!(end_of_month(timestamp(LEAP_2017 - 1)), timestamp(LEAP_2017));
+        assert_eq!(
+            end_of_month(timestamp(LEAP_2017 - 20 * SECONDS_PER_DAY)),
+            timestamp(LEAP_2017)
+        );
+
+        let march_2024 = 3918240000;
+        assert_eq!(
+            end_of_month(timestamp(march_2024 - 28 * SECONDS_PER_DAY)),
+            timestamp(march_2024)
+        );
+        assert_eq!(
+            end_of_month(timestamp(march_2024)),
+            timestamp(march_2024 + 31 * SECONDS_PER_DAY)
+        );
+
+        let january_2037 = 4323369600;
+        assert_eq!(
+            end_of_month(timestamp(january_2037 - 16 * SECONDS_PER_DAY)),
+            timestamp(january_2037)
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    leap_seconds::end_of_month, NtpDuration, NtpLeapIndicator, NtpTimestamp, SystemSnapshot,
+};
+
+/// How the leap second is spread over the smear window
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LeapSmearShape {
+    /// Served time runs at a constant rate during the window
+    #[default]
+    Linear,
+    /// The rate of served time changes gradually at the start and end of the window
+    Cosine,
+}
+
+fn default_smear_window() -> NtpDuration {
+    NtpDuration::from_seconds(86400.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LeapSmearConfig {
+    /// Length of the window centered on the leap second over which it is smeared
+    #[serde(default = "default_smear_window")]
+    pub window: NtpDuration,
+    #[serde(default)]
+    pub shape: LeapSmearShape,
+}
+
+impl Default for LeapSmearConfig {
+    fn default() -> Self {
+        Self {
+            window: default_smear_window(),
+            shape: LeapSmearShape::default(),
+        }
+    }
+}
+
+/// Spreads a leap second over a window of time, for serving time to clients that cannot handle
+/// a leap second. The local clock handles the leap second as usual; the smear is the offset that
+/// is added to the time of the local clock to obtain the served time.
+///
+/// During an inserted leap second the local clock repeats a second, so the same clock reading
+/// can occur both before and after the leap. The second pass is recognized when the clock is
+/// seen to run backwards, so the smear depends on the readings it was computed for earlier.
+#[derive(Debug, Clone)]
+pub struct LeapSmear {
+    config: LeapSmearConfig,
+    /// The leap second currently smeared, and the end of the month at which it occurs
+    leap: Option<(NtpTimestamp, NtpLeapIndicator)>,
+    /// The latest reading of the clock during the second before the leap
+    latest: Option<f64>,
+    /// Whether the clock has repeated the second before the leap
+    repeated: bool,
+    last_offset: NtpDuration,
+}
+
+impl LeapSmear {
+    pub fn new(config: LeapSmearConfig) -> Self {
+        Self {
+            config,
+            leap: None,
+            latest: None,
+            repeated: false,
+            last_offset: NtpDuration::ZERO,
+        }
+    }
+
+    /// The most recently computed offset
+    pub fn last_offset(&self) -> NtpDuration {
+        self.last_offset
+    }
+
+    /// The offset to add to the given reading of the local clock to get the smeared time
+    pub fn offset(&mut self, system: &SystemSnapshot, now: NtpTimestamp) -> NtpDuration {
+        self.last_offset = self.compute_offset(system, now);
+        self.last_offset
+    }
+
+    fn compute_offset(&mut self, system: &SystemSnapshot, now: NtpTimestamp) -> NtpDuration {
+        let half_window = self.config.window.to_seconds() / 2.0;
+
+        // once smearing has started, the leap is kept until the end of the window, even though
+        // the system stops announcing it at the leap
+        let smearing =
+            matches!(self.leap, Some((time, _)) if (now - time).to_seconds() >= -half_window);
+        if !smearing {
+            self.leap = scheduled_leap(system, now);
+            self.latest = None;
+            self.repeated = false;
+        }
+
+        let (time, leap) = match self.leap {
+            Some(leap) => leap,
+            None => return NtpDuration::ZERO,
+        };
+
+        // the reading of the clock relative to the leap
+        let reading = (now - time).to_seconds();
+
+        // Whether the leap already happened. For an inserted second, that is ambiguous during the
+        // second before the leap, which the clock repeats. A deleted second is skipped by the
+        // clock.
+        let after_leap = match leap {
+            NtpLeapIndicator::Leap61 if (-1.0..0.0).contains(&reading) => {
+                if matches!(self.latest, Some(latest) if latest > reading + 0.1) {
+                    self.repeated = true;
+                }
+                self.latest = Some(self.latest.map_or(reading, |latest| latest.max(reading)));
+                self.repeated
+            }
+            _ => reading >= -1.0,
+        };
+
+        // the direction in which the clock jumps at the leap
+        let jump = match leap {
+            NtpLeapIndicator::Leap59 => -1.0,
+            _ => 1.0,
+        };
+
+        // the time relative to the leap as if no leap happened
+        let elapsed = if after_leap { reading + jump } else { reading };
+
+        if after_leap && elapsed >= half_window {
+            self.leap = None;
+            return NtpDuration::ZERO;
+        }
+
+        let progress = ((elapsed + half_window) / self.config.window.to_seconds()).clamp(0.0, 1.0);
+        let smeared = match self.config.shape {
+            LeapSmearShape::Linear => progress,
+            LeapSmearShape::Cosine => (1.0 - (PI * progress).cos()) / 2.0,
+        };
+
+        if after_leap {
+            NtpDuration::from_seconds(jump * (1.0 - smeared))
+        } else {
+            NtpDuration::from_seconds(-jump * smeared)
+        }
+    }
+}
+
+/// The leap second scheduled by the leap seconds file, or otherwise announced by the leap
+/// indicator for the end of the current month
+fn scheduled_leap(
+    system: &SystemSnapshot,
+    now: NtpTimestamp,
+) -> Option<(NtpTimestamp, NtpLeapIndicator)> {
+    match (
+        system.time_snapshot.next_leap,
+        system.time_snapshot.leap_indicator,
+    ) {
+        (Some(leap), _) => Some((leap.time, leap.leap)),
+        (None, leap @ (NtpLeapIndicator::Leap61 | NtpLeapIndicator::Leap59)) => {
+            Some((end_of_month(now), leap))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2017-01-01, the last leap second
+    const LEAP_2017: u32 = 3692217600;
+
+    fn at(seconds: f64) -> NtpTimestamp {
+        NtpTimestamp::from_seconds_nanos_since_ntp_era(LEAP_2017, 0)
+            + NtpDuration::from_seconds(seconds)
+    }
+
+    fn announcing(leap: NtpLeapIndicator) -> SystemSnapshot {
+        let mut system = SystemSnapshot::default();
+        system.time_snapshot.leap_indicator = leap;
+        system
+    }
+
+    fn assert_offset(smear: &mut LeapSmear, system: &SystemSnapshot, reading: f64, offset: f64) {
+        let actual = smear.offset(system, at(reading)).to_seconds();
+        assert!(
+            (actual - offset).abs() < 1e-6,
+            "offset at {reading} is {actual}, expected {offset}"
+        );
+    }
+
+    #[test]
+    fn test_linear_insert() {
+        let mut smear = LeapSmear::new(LeapSmearConfig {
+            window: NtpDuration::from_seconds(1000.0),
+            shape: LeapSmearShape::Linear,
+        });
+        let system = announcing(NtpLeapIndicator::Leap61);
+
+        assert_offset(&mut smear, &system, -600.0, 0.0);
+        assert_offset(&mut smear, &system, -500.0, 0.0);
+        assert_offset(&mut smear, &system, -250.0, -0.25);
+        assert_offset(&mut smear, &system, -0.5, -0.4995);
+
+        // the clock repeats the second before the leap
+        assert_offset(&mut smear, &system, -0.9, 0.4999);
+        assert_offset(&mut smear, &system, -0.5, 0.4995);
+
+        // the system no longer announces the leap
+        let system = announcing(NtpLeapIndicator::NoWarning);
+        assert_offset(&mut smear, &system, 249.0, 0.25);
+        assert_offset(&mut smear, &system, 499.0, 0.0);
+        assert_offset(&mut smear, &system, 600.0, 0.0);
+    }
+
+    #[test]
+    fn test_cosine_delete() {
+        let mut smear = LeapSmear::new(LeapSmearConfig {
+            window: NtpDuration::from_seconds(1000.0),
+            shape: LeapSmearShape::Cosine,
+        });
+        let system = announcing(NtpLeapIndicator::Leap59);
+
+        assert_offset(&mut smear, &system, -500.0, 0.0);
+        assert_offset(&mut smear, &system, -250.0, 0.14644661);
+
+        // the clock skips the second before the leap
+        assert_offset(&mut smear, &system, 251.0, -0.14644661);
+        assert_offset(&mut smear, &system, 501.0, 0.0);
+    }
+
+    #[test]
+    fn test_served_time_is_continuous() {
+        let mut smear = LeapSmear::new(LeapSmearConfig {
+            window: NtpDuration::from_seconds(100.0),
+            shape: LeapSmearShape::Cosine,
+        });
+        let system = announcing(NtpLeapIndicator::Leap61);
+
+        let mut previous: Option<NtpTimestamp> = None;
+        for step in 0..1200 {
+            // the clock is one second behind after the leap
+            let elapsed = -60.05 + step as f64 * 0.1;
+            let reading = if elapsed >= 0.0 {
+                elapsed - 1.0
+            } else {
+                elapsed
+            };
+
+            let served = at(reading) + smear.offset(&system, at(reading));
+            if let Some(previous) = previous {
+                let step = (served - previous).to_seconds();
+                assert!(step > 0.08 && step < 0.12, "served time jumps by {step}");
+            }
+            previous = Some(served);
+        }
+    }
+
+    #[test]
+    fn test_leap_seconds_file() {
+        let mut smear = LeapSmear::new(LeapSmearConfig::default());
+
+        let mut system = SystemSnapshot::default();
+        system.time_snapshot.next_leap = Some(crate::LeapSecond {
+            time: at(0.0),
+            leap: NtpLeapIndicator::Leap61,
+            tai_offset: 37,
+        });
+
+        assert_offset(&mut smear, &system, -86400.0, 0.0);
+        assert_offset(&mut smear, &system, -21600.0, -0.25);
+    }
+}
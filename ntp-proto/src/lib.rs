@@ -5,18 +5,29 @@ mod clock;
 mod config;
 mod cookiestash;
 mod identifiers;
+mod keyset;
+mod leap_seconds;
+mod leap_smear;
 mod nts_record;
 mod packet;
 mod peer;
+mod refclock;
+mod symmetric_key;
 mod system;
 mod time_types;
 
 #[cfg(feature = "fuzz")]
 pub use algorithm::fuzz_find_interval;
-pub use algorithm::{DefaultTimeSyncController, ObservablePeerTimedata, TimeSyncController};
-pub use clock::NtpClock;
+pub use algorithm::{
+    ClockCorrection, DefaultTimeSyncController, FilterDecision, ObservablePeerTimedata,
+    PanicThresholdExceeded, SelectionStatus, TimeSyncController,
+};
+pub use clock::{ClockOperationUnsupported, KernelPpsStatus, NtpClock};
 pub use config::{StepThreshold, SystemConfig};
 pub use identifiers::ReferenceId;
+pub use keyset::{DecodedServerCookie, DecryptError, KeySet, KeySetProvider};
+pub use leap_seconds::{LeapSecond, LeapSecondsFile, LeapSecondsFileError};
+pub use leap_smear::{LeapSmear, LeapSmearConfig, LeapSmearShape};
 
 pub use packet::{NtpAssociationMode, NtpLeapIndicator, NtpPacket};
 #[cfg(feature = "fuzz")]
@@ -25,8 +36,10 @@ pub use peer::fuzz_measurement_from_packet;
 pub use peer::peer_snapshot;
 pub use peer::{
     AcceptSynchronizationError, IgnoreReason, Measurement, Peer, PeerNtsData, PeerSnapshot, Reach,
-    Update,
+    SymmetricMode, Update,
 };
+pub use refclock::{RefClockSample, RefClockSource};
+pub use symmetric_key::{InvalidKeyError, SymmetricKey, SymmetricKeyAlgorithm};
 pub use system::{SystemSnapshot, TimeSnapshot};
 #[cfg(feature = "fuzz")]
 pub use time_types::fuzz_duration_from_seconds;
@@ -35,5 +48,6 @@ pub use time_types::{
 };
 
 pub use nts_record::{
-    KeyExchangeClient, KeyExchangeError, KeyExchangeResult, NtsRecord, NtsRecordDecoder, WriteError,
+    KeyExchangeClient, KeyExchangeError, KeyExchangeResult, KeyExchangeServer, NtsRecord,
+    NtsRecordDecoder, WriteError,
 };
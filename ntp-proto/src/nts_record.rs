@@ -6,7 +6,11 @@ use std::{
 
 use aes_siv::{Aes128SivAead, KeyInit};
 
-use crate::{cookiestash::CookieStash, peer::PeerNtsData};
+use crate::{
+    cookiestash::CookieStash,
+    keyset::{DecodedServerCookie, KeySet, AEAD_AES_SIV_CMAC_256},
+    peer::PeerNtsData,
+};
 
 #[derive(Debug)]
 pub enum WriteError {
@@ -540,11 +544,12 @@ pub struct KeyExchangeClient {
     server_name: String,
 }
 
+const NTS_KEY_EXPORT_LABEL: &[u8] = b"EXPORTER-network-time-security";
+const NTS_KEY_EXPORT_AESSIV128_C2S_CONTEXT: &[u8] = &[0, 0, 0, 15, 0];
+const NTS_KEY_EXPORT_AESSIV128_S2C_CONTEXT: &[u8] = &[0, 0, 0, 15, 1];
+
 impl KeyExchangeClient {
     const NTP_DEFAULT_PORT: u16 = 123;
-    const NTS_KEY_EXPORT_LABEL: &'static [u8] = b"EXPORTER-network-time-security";
-    const NTS_KEY_EXPORT_AESSIV128_C2S_CONTEXT: &'static [u8] = &[0, 0, 0, 15, 0];
-    const NTS_KEY_EXPORT_AESSIV128_S2C_CONTEXT: &'static [u8] = &[0, 0, 0, 15, 1];
 
     pub fn wants_read(&mut self) -> bool {
         self.tls_connection.wants_read()
@@ -579,16 +584,16 @@ impl KeyExchangeClient {
                             let mut c2s: aead::Key<Aes128SivAead> = Default::default();
                             if let Err(e) = self.tls_connection.export_keying_material(
                                 &mut c2s,
-                                Self::NTS_KEY_EXPORT_LABEL,
-                                Some(Self::NTS_KEY_EXPORT_AESSIV128_C2S_CONTEXT),
+                                NTS_KEY_EXPORT_LABEL,
+                                Some(NTS_KEY_EXPORT_AESSIV128_C2S_CONTEXT),
                             ) {
                                 return ControlFlow::Break(Err(KeyExchangeError::Tls(e)));
                             }
                             let mut s2c: aead::Key<Aes128SivAead> = Default::default();
                             if let Err(e) = self.tls_connection.export_keying_material(
                                 &mut s2c,
-                                Self::NTS_KEY_EXPORT_LABEL,
-                                Some(Self::NTS_KEY_EXPORT_AESSIV128_S2C_CONTEXT),
+                                NTS_KEY_EXPORT_LABEL,
+                                Some(NTS_KEY_EXPORT_AESSIV128_S2C_CONTEXT),
                             ) {
                                 return ControlFlow::Break(Err(KeyExchangeError::Tls(e)));
                             }
@@ -644,6 +649,277 @@ impl KeyExchangeClient {
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+struct ServerKeyExchangeData {
+    algorithm: u16,
+}
+
+#[derive(Debug, Default)]
+struct KeyExchangeServerDecoder {
+    decoder: NtsRecordDecoder,
+    protocol_ids: Option<Vec<u16>>,
+    algorithm_ids: Option<Vec<u16>>,
+}
+
+impl KeyExchangeServerDecoder {
+    pub fn step_with_slice(
+        mut self,
+        bytes: &[u8],
+    ) -> ControlFlow<Result<ServerKeyExchangeData, KeyExchangeError>, Self> {
+        self.decoder.extend(bytes.iter().copied());
+
+        loop {
+            match self.decoder.step() {
+                Err(e) => return ControlFlow::Break(Err(e.into())),
+                Ok(Some(record)) => self = self.step_with_record(record)?,
+                Ok(None) => return ControlFlow::Continue(self),
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn step_with_record(
+        self,
+        record: NtsRecord,
+    ) -> ControlFlow<Result<ServerKeyExchangeData, KeyExchangeError>, Self> {
+        use ControlFlow::{Break, Continue};
+        use KeyExchangeError::*;
+        use NtsRecord::*;
+
+        let mut state = self;
+
+        match record {
+            EndOfMessage => {
+                // NTP4 has protocol id 0, it is the only protocol we support
+                const NTP4: u16 = 0;
+
+                match (state.protocol_ids, state.algorithm_ids) {
+                    (Some(protocol_ids), Some(algorithm_ids)) => {
+                        if !protocol_ids.contains(&NTP4) {
+                            Break(Err(NoValidProtocol))
+                        } else if !algorithm_ids.contains(&AEAD_AES_SIV_CMAC_256) {
+                            Break(Err(NoValidAlgorithm))
+                        } else {
+                            Break(Ok(ServerKeyExchangeData {
+                                algorithm: AEAD_AES_SIV_CMAC_256,
+                            }))
+                        }
+                    }
+                    // a request must contain both a next protocol and an aead algorithm record
+                    _ => Break(Err(BadRequest)),
+                }
+            }
+            NextProtocol { protocol_ids } => {
+                if state.protocol_ids.is_some() {
+                    Break(Err(BadRequest))
+                } else {
+                    state.protocol_ids = Some(protocol_ids);
+                    Continue(state)
+                }
+            }
+            AeadAlgorithm { algorithm_ids, .. } => {
+                if state.algorithm_ids.is_some() {
+                    Break(Err(BadRequest))
+                } else {
+                    state.algorithm_ids = Some(algorithm_ids);
+                    Continue(state)
+                }
+            }
+            // clients are not supposed to send errors or warnings
+            Error { .. } | Warning { .. } => Break(Err(BadRequest)),
+            // we don't support server/port negotiation, so these records are ignored
+            NewCookie { .. } | Server { .. } | Port { .. } => Continue(state),
+            Unknown { critical, .. } => {
+                if critical {
+                    Break(Err(UnrecognizedCriticalRecord))
+                } else {
+                    Continue(state)
+                }
+            }
+        }
+    }
+
+    fn new() -> Self {
+        Self::default()
+    }
+}
+
+pub struct KeyExchangeServer {
+    tls_connection: rustls::ServerConnection,
+    decoder: KeyExchangeServerDecoder,
+    keyset: Arc<KeySet>,
+}
+
+impl KeyExchangeServer {
+    /// The number of cookies we hand out to a client after a key exchange
+    const NUMBER_OF_COOKIES: usize = 8;
+
+    pub fn wants_read(&mut self) -> bool {
+        self.tls_connection.wants_read()
+    }
+
+    pub fn read_socket(&mut self, rd: &mut dyn Read) -> std::io::Result<usize> {
+        self.tls_connection.read_tls(rd)
+    }
+
+    pub fn wants_write(&mut self) -> bool {
+        self.tls_connection.wants_write()
+    }
+
+    pub fn write_socket(&mut self, wr: &mut dyn Write) -> std::io::Result<usize> {
+        self.tls_connection.write_tls(wr)
+    }
+
+    /// Process any received data. Once the request has been handled, the response is queued
+    /// and the tls connection is returned so the caller can flush the remaining data.
+    ///
+    /// Invalid requests are answered with the appropriate error records, that is not
+    /// considered a failure of the key exchange server.
+    pub fn progress(
+        mut self,
+    ) -> ControlFlow<Result<rustls::ServerConnection, KeyExchangeError>, Self> {
+        // Move any received data from tls to decoder
+        let mut buf = [0; 128];
+        loop {
+            if let Err(e) = self.tls_connection.process_new_packets() {
+                return ControlFlow::Break(Err(e.into()));
+            }
+            let read_result = self.tls_connection.reader().read(&mut buf);
+            match read_result {
+                Ok(0) => return ControlFlow::Break(Err(KeyExchangeError::IncompleteResponse)),
+                Ok(n) => {
+                    let decoder = std::mem::take(&mut self.decoder);
+                    self.decoder = match decoder.step_with_slice(&buf[..n]) {
+                        ControlFlow::Continue(decoder) => decoder,
+                        ControlFlow::Break(Ok(data)) => {
+                            return match self.send_response(data) {
+                                Ok(()) => ControlFlow::Break(Ok(self.tls_connection)),
+                                Err(e) => ControlFlow::Break(Err(e)),
+                            };
+                        }
+                        ControlFlow::Break(Err(error)) => {
+                            tracing::debug!(%error, "Invalid key exchange request");
+
+                            return match self.send_error_response(&error) {
+                                Ok(()) => ControlFlow::Break(Ok(self.tls_connection)),
+                                Err(e) => ControlFlow::Break(Err(e)),
+                            };
+                        }
+                    }
+                }
+                Err(e) => match e.kind() {
+                    std::io::ErrorKind::WouldBlock => return ControlFlow::Continue(self),
+                    _ => return ControlFlow::Break(Err(e.into())),
+                },
+            }
+        }
+    }
+
+    fn send_response(&mut self, data: ServerKeyExchangeData) -> Result<(), KeyExchangeError> {
+        let mut c2s: aead::Key<Aes128SivAead> = Default::default();
+        self.tls_connection.export_keying_material(
+            &mut c2s,
+            NTS_KEY_EXPORT_LABEL,
+            Some(NTS_KEY_EXPORT_AESSIV128_C2S_CONTEXT),
+        )?;
+        let mut s2c: aead::Key<Aes128SivAead> = Default::default();
+        self.tls_connection.export_keying_material(
+            &mut s2c,
+            NTS_KEY_EXPORT_LABEL,
+            Some(NTS_KEY_EXPORT_AESSIV128_S2C_CONTEXT),
+        )?;
+
+        let cookie = DecodedServerCookie {
+            algorithm: data.algorithm,
+            c2s,
+            s2c,
+        };
+
+        let mut records = vec![
+            NtsRecord::NextProtocol {
+                protocol_ids: vec![0],
+            },
+            NtsRecord::AeadAlgorithm {
+                critical: false,
+                algorithm_ids: vec![data.algorithm],
+            },
+        ];
+
+        for _ in 0..Self::NUMBER_OF_COOKIES {
+            records.push(NtsRecord::NewCookie {
+                cookie_data: self.keyset.encode_cookie(&cookie),
+            });
+        }
+
+        records.push(NtsRecord::EndOfMessage);
+
+        self.send_records(&records)
+    }
+
+    fn send_error_response(&mut self, error: &KeyExchangeError) -> Result<(), KeyExchangeError> {
+        let records: &[NtsRecord] = match error {
+            KeyExchangeError::UnrecognizedCriticalRecord => {
+                &[NtsRecord::Error { errorcode: 0 }, NtsRecord::EndOfMessage]
+            }
+            KeyExchangeError::BadRequest => {
+                &[NtsRecord::Error { errorcode: 1 }, NtsRecord::EndOfMessage]
+            }
+            // RFC 8915 section 4.1.2: respond with an empty next protocol record
+            KeyExchangeError::NoValidProtocol => &[
+                NtsRecord::NextProtocol {
+                    protocol_ids: vec![],
+                },
+                NtsRecord::EndOfMessage,
+            ],
+            // RFC 8915 section 4.1.5: respond with an empty aead algorithm record
+            KeyExchangeError::NoValidAlgorithm => &[
+                NtsRecord::NextProtocol {
+                    protocol_ids: vec![0],
+                },
+                NtsRecord::AeadAlgorithm {
+                    critical: false,
+                    algorithm_ids: vec![],
+                },
+                NtsRecord::EndOfMessage,
+            ],
+            _ => &[NtsRecord::Error { errorcode: 2 }, NtsRecord::EndOfMessage],
+        };
+
+        self.send_records(records)
+    }
+
+    fn send_records(&mut self, records: &[NtsRecord]) -> Result<(), KeyExchangeError> {
+        // We use an intermediary buffer to ensure that all records are sent at once.
+        let mut buffer = Vec::with_capacity(1024);
+        for record in records {
+            record.write(&mut buffer)?;
+        }
+        self.tls_connection.writer().write_all(&buffer)?;
+        self.tls_connection.send_close_notify();
+
+        Ok(())
+    }
+
+    pub fn new(
+        tls_config: Arc<rustls::ServerConfig>,
+        keyset: Arc<KeySet>,
+    ) -> Result<Self, KeyExchangeError> {
+        // Ensure we only accept ntske/1 as alpn
+        let mut tls_config = tls_config;
+        if tls_config.alpn_protocols != [b"ntske/1".to_vec()] {
+            let mut config = (*tls_config).clone();
+            config.alpn_protocols = vec![b"ntske/1".to_vec()];
+            tls_config = Arc::new(config);
+        }
+
+        Ok(KeyExchangeServer {
+            tls_connection: rustls::ServerConnection::new(tls_config)?,
+            decoder: KeyExchangeServerDecoder::new(),
+            keyset,
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -752,6 +1028,88 @@ mod test {
         assert_eq!(state.port, Some(port));
     }
 
+    fn server_roundtrip(records: &[NtsRecord]) -> Result<ServerKeyExchangeData, KeyExchangeError> {
+        let mut decoder = KeyExchangeServerDecoder::new();
+
+        for record in records {
+            let mut buffer = Vec::with_capacity(1024);
+            record.write(&mut buffer).unwrap();
+
+            decoder = match decoder.step_with_slice(&buffer) {
+                ControlFlow::Continue(decoder) => decoder,
+                ControlFlow::Break(result) => return result,
+            }
+        }
+
+        Err(KeyExchangeError::IncompleteResponse)
+    }
+
+    #[test]
+    fn server_accepts_client_records() {
+        let data = server_roundtrip(&NtsRecord::client_key_exchange_records()).unwrap();
+
+        assert_eq!(data.algorithm, AEAD_AES_SIV_CMAC_256);
+    }
+
+    #[test]
+    fn server_rejects_invalid_requests() {
+        let [protocol, algorithm, end] = NtsRecord::client_key_exchange_records();
+
+        assert!(matches!(
+            server_roundtrip(&[algorithm.clone(), end.clone()]),
+            Err(KeyExchangeError::BadRequest)
+        ));
+
+        assert!(matches!(
+            server_roundtrip(&[protocol.clone(), end.clone()]),
+            Err(KeyExchangeError::BadRequest)
+        ));
+
+        assert!(matches!(
+            server_roundtrip(&[protocol.clone(), protocol.clone()]),
+            Err(KeyExchangeError::BadRequest)
+        ));
+
+        let unknown = NtsRecord::Unknown {
+            record_type: 42,
+            critical: true,
+            data: vec![],
+        };
+        assert!(matches!(
+            server_roundtrip(&[protocol.clone(), unknown, algorithm.clone(), end.clone()]),
+            Err(KeyExchangeError::UnrecognizedCriticalRecord)
+        ));
+
+        let unknown = NtsRecord::Unknown {
+            record_type: 42,
+            critical: false,
+            data: vec![],
+        };
+        assert!(server_roundtrip(&[protocol, unknown, algorithm, end]).is_ok());
+    }
+
+    #[test]
+    fn server_no_valid_protocol_or_algorithm() {
+        let [protocol, algorithm, end] = NtsRecord::client_key_exchange_records();
+
+        let other_protocol = NtsRecord::NextProtocol {
+            protocol_ids: vec![42],
+        };
+        assert!(matches!(
+            server_roundtrip(&[other_protocol, algorithm, end.clone()]),
+            Err(KeyExchangeError::NoValidProtocol)
+        ));
+
+        let other_algorithm = NtsRecord::AeadAlgorithm {
+            critical: false,
+            algorithm_ids: vec![42],
+        };
+        assert!(matches!(
+            server_roundtrip(&[protocol, other_algorithm, end]),
+            Err(KeyExchangeError::NoValidAlgorithm)
+        ));
+    }
+
     const NTS_TIME_NL_RESPONSE: &[u8] = &[
         128, 1, 0, 2, 0, 0, 0, 4, 0, 2, 0, 15, 0, 5, 0, 104, 178, 15, 188, 164, 68, 107, 175, 34,
         77, 63, 18, 34, 122, 22, 95, 242, 175, 224, 29, 173, 58, 187, 47, 11, 245, 247, 119, 89, 5,
@@ -7,10 +7,26 @@ use aes_siv::{
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
-use crate::{NtpClock, NtpDuration, NtpTimestamp, PollInterval, ReferenceId, SystemSnapshot};
+use crate::{
+    keyset::{DecodedServerCookie, DecryptError, KeySet},
+    symmetric_key::SymmetricKey,
+    LeapSmear, NtpClock, NtpDuration, NtpTimestamp, PollInterval, ReferenceId, SystemSnapshot,
+};
 
 type Cipher = aes_siv::Aes128SivAead;
 
+/// The source of the keys for decrypting the encrypted extension field
+#[derive(Clone, Copy)]
+enum DecryptionKeys<'c> {
+    /// A client knows the keys from its key exchange
+    Client(Option<&'c Cipher>),
+    /// A server obtains the keys from the cookie the client sends along
+    Server(&'c KeySet),
+}
+
+/// The result of decoding the cookie of an NTS request: the keys when decoding succeeded
+type ServerCookie = Result<DecodedServerCookie, DecryptError>;
+
 #[derive(Debug)]
 pub enum PacketParsingError {
     InvalidVersion(u8),
@@ -486,10 +502,11 @@ impl<'a> ExtensionFieldData<'a> {
     fn deserialize(
         data: &'a [u8],
         header_size: usize,
-        cipher: Option<&Cipher>,
-    ) -> Result<(Self, usize), PacketParsingError> {
+        keys: DecryptionKeys,
+    ) -> Result<(Self, usize, Option<ServerCookie>), PacketParsingError> {
         let mut this = Self::default();
         let mut size = 0;
+        let mut server_cookie = None;
         for field in RawExtensionField::deserialize_sequence(
             &data[header_size..],
             Mac::MAXIMUM_SIZE,
@@ -500,18 +517,40 @@ impl<'a> ExtensionFieldData<'a> {
             match field.type_id {
                 ExtensionFieldTypeId::NtsEncryptedField => {
                     let encrypted = RawEncryptedField::from_message_bytes(field.message_bytes)?;
-                    let cipher = cipher.ok_or(PacketParsingError::DecryptError)?;
 
                     // TODO: Discuss whether we want to do this check
                     if !this.authenticated.is_empty() || !this.encrypted.is_empty() {
                         return Err(PacketParsingError::MalformedNtsExtensionFields);
                     }
 
-                    this.encrypted.extend(
-                        encrypted
-                            .decrypt(cipher, &data[..header_size + offset])?
-                            .into_iter(),
-                    );
+                    let aad = &data[..header_size + offset];
+                    let decrypted = match keys {
+                        DecryptionKeys::Client(cipher) => {
+                            let cipher = cipher.ok_or(PacketParsingError::DecryptError)?;
+                            encrypted.decrypt(cipher, aad)?
+                        }
+                        DecryptionKeys::Server(keyset) => {
+                            match Self::decrypt_with_cookie(
+                                &this.untrusted,
+                                keyset,
+                                &encrypted,
+                                aad,
+                            ) {
+                                Ok((cookie, decrypted)) => {
+                                    server_cookie = Some(Ok(cookie));
+                                    decrypted
+                                }
+                                Err(PacketParsingError::DecryptError) => {
+                                    // the client should get a NTS NAK, which needs the remaining fields
+                                    server_cookie = Some(Err(DecryptError));
+                                    continue;
+                                }
+                                Err(e) => return Err(e),
+                            }
+                        }
+                    };
+
+                    this.encrypted.extend(decrypted);
 
                     // All previous untrusted fields are now validated
                     this.authenticated.append(&mut this.untrusted);
@@ -519,7 +558,31 @@ impl<'a> ExtensionFieldData<'a> {
                 _ => this.untrusted.push(ExtensionField::decode(field)?),
             }
         }
-        Ok((this, size + header_size))
+        Ok((this, size + header_size, server_cookie))
+    }
+
+    /// Decrypt the encrypted field using the keys from the (first) cookie in the given fields
+    fn decrypt_with_cookie(
+        fields: &[ExtensionField],
+        keyset: &KeySet,
+        encrypted: &RawEncryptedField<'a>,
+        aad: &[u8],
+    ) -> Result<(DecodedServerCookie, Vec<ExtensionField<'a>>), PacketParsingError> {
+        let cookie = fields
+            .iter()
+            .find_map(|field| match field {
+                ExtensionField::NtsCookie(cookie) => Some(cookie),
+                _ => None,
+            })
+            .ok_or(PacketParsingError::DecryptError)?;
+
+        let cookie = keyset
+            .decode_cookie(cookie)
+            .map_err(|_| PacketParsingError::DecryptError)?;
+
+        let decrypted = encrypted.decrypt(&cookie.c2s(), aad)?;
+
+        Ok((cookie, decrypted))
     }
 }
 
@@ -740,6 +803,8 @@ struct NtpHeaderV3V4 {
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct RequestIdentifier {
     expected_origin_timestamp: NtpTimestamp,
+    /// Origin timestamp of an interleaved response, for requests that ask for one
+    interleaved_origin_timestamp: Option<NtpTimestamp>,
     uid: Option<[u8; 32]>,
 }
 
@@ -818,18 +883,98 @@ impl NtpHeaderV3V4 {
             packet,
             RequestIdentifier {
                 expected_origin_timestamp: transmit_timestamp,
+                interleaved_origin_timestamp: None,
                 uid: None,
             },
         )
     }
 
+    fn symmetric_message(
+        system: &SystemSnapshot,
+        mode: NtpAssociationMode,
+        poll_interval: PollInterval,
+        origin_timestamp: NtpTimestamp,
+        receive_timestamp: NtpTimestamp,
+        transmit_timestamp: NtpTimestamp,
+    ) -> (Self, RequestIdentifier) {
+        let packet = Self {
+            mode,
+            stratum: system.stratum,
+            reference_id: system.reference_id,
+            poll: poll_interval.as_log(),
+            precision: system.time_snapshot.precision.log2(),
+            root_delay: system.time_snapshot.root_delay,
+            root_dispersion: system.time_snapshot.root_dispersion,
+            origin_timestamp,
+            receive_timestamp,
+            // The peer uses our transmit timestamp to compute its measurements,
+            // so unlike in a poll message it must be the actual time.
+            transmit_timestamp,
+            ..Self::new()
+        };
+
+        (
+            packet,
+            RequestIdentifier {
+                expected_origin_timestamp: transmit_timestamp,
+                interleaved_origin_timestamp: None,
+                uid: None,
+            },
+        )
+    }
+
+    fn broadcast_message<C: NtpClock>(
+        system: &SystemSnapshot,
+        poll_interval: PollInterval,
+        clock: &C,
+        leap_smear: Option<&mut LeapSmear>,
+    ) -> Self {
+        Self {
+            leap: system.time_snapshot.leap_indicator,
+            mode: NtpAssociationMode::Broadcast,
+            stratum: system.stratum,
+            reference_id: system.reference_id,
+            poll: poll_interval.as_log(),
+            precision: system.time_snapshot.precision.log2(),
+            root_delay: system.time_snapshot.root_delay,
+            root_dispersion: system.time_snapshot.root_dispersion,
+            // Timestamp must be last to make it as accurate as possible.
+            transmit_timestamp: clock.now().expect("Failed to read time"),
+            ..Self::new()
+        }
+        .smeared(system, leap_smear)
+    }
+
+    fn reference_clock_sample(
+        reference_id: ReferenceId,
+        leap: NtpLeapIndicator,
+        precision: i8,
+        poll_interval: PollInterval,
+        reference_time: NtpTimestamp,
+    ) -> Self {
+        Self {
+            leap,
+            mode: NtpAssociationMode::Server,
+            stratum: 0,
+            reference_id,
+            poll: poll_interval.as_log(),
+            precision,
+            reference_timestamp: reference_time,
+            receive_timestamp: reference_time,
+            transmit_timestamp: reference_time,
+            ..Self::new()
+        }
+    }
+
     fn timestamp_response<C: NtpClock>(
         system: &SystemSnapshot,
         input: Self,
         recv_timestamp: NtpTimestamp,
         clock: &C,
+        leap_smear: Option<&mut LeapSmear>,
     ) -> Self {
         Self {
+            leap: system.time_snapshot.leap_indicator,
             mode: NtpAssociationMode::Server,
             stratum: system.stratum,
             origin_timestamp: input.transmit_timestamp,
@@ -843,6 +988,25 @@ impl NtpHeaderV3V4 {
             transmit_timestamp: clock.now().expect("Failed to read time"),
             ..Self::new()
         }
+        .smeared(system, leap_smear)
+    }
+
+    /// Smear the timestamps of a packet sent by a server. Clients of smeared time must not
+    /// apply the leap second themselves, so the leap second is no longer announced.
+    fn smeared(mut self, system: &SystemSnapshot, leap_smear: Option<&mut LeapSmear>) -> Self {
+        if let Some(leap_smear) = leap_smear {
+            // a broadcast has no receive timestamp
+            if self.receive_timestamp != NtpTimestamp::default() {
+                self.receive_timestamp += leap_smear.offset(system, self.receive_timestamp);
+            }
+            self.transmit_timestamp += leap_smear.offset(system, self.transmit_timestamp);
+
+            if self.leap.is_synchronized() {
+                self.leap = NtpLeapIndicator::NoWarning;
+            }
+        }
+
+        self
     }
 
     fn rate_limit_response(packet_from_client: Self) -> Self {
@@ -864,6 +1028,16 @@ impl NtpHeaderV3V4 {
             ..Self::new()
         }
     }
+
+    fn nts_nak_response(packet_from_client: Self) -> Self {
+        Self {
+            mode: NtpAssociationMode::Server,
+            stratum: 0, // indicates a kiss code
+            reference_id: ReferenceId::KISS_NTSN,
+            origin_timestamp: packet_from_client.transmit_timestamp,
+            ..Self::new()
+        }
+    }
 }
 
 impl<'a> NtpPacket<'a> {
@@ -879,6 +1053,28 @@ impl<'a> NtpPacket<'a> {
         data: &'a [u8],
         cipher: Option<&Cipher>,
     ) -> Result<Self, PacketParsingError> {
+        let (packet, _) = Self::deserialize_with_keys(data, DecryptionKeys::Client(cipher))?;
+
+        Ok(packet)
+    }
+
+    /// Deserialize a request received by a server. For NTS requests, the cookie sent by the client
+    /// is decrypted with the `keyset` to obtain the keys for the encrypted extension fields.
+    ///
+    /// The returned cookie is `None` for requests without NTS. For NTS requests it contains the
+    /// keys of the client, or an error when the cookie or the request could not be decrypted. In
+    /// the latter case the client should be sent a NTS NAK.
+    pub fn deserialize_request(
+        data: &'a [u8],
+        keyset: &KeySet,
+    ) -> Result<(Self, Option<ServerCookie>), PacketParsingError> {
+        Self::deserialize_with_keys(data, DecryptionKeys::Server(keyset))
+    }
+
+    fn deserialize_with_keys(
+        data: &'a [u8],
+        keys: DecryptionKeys,
+    ) -> Result<(Self, Option<ServerCookie>), PacketParsingError> {
         if data.is_empty() {
             return Err(PacketParsingError::IncorrectLength);
         }
@@ -893,16 +1089,19 @@ impl<'a> NtpPacket<'a> {
                 } else {
                     None
                 };
-                Ok(NtpPacket {
-                    header: NtpHeader::V3(header),
-                    efdata: ExtensionFieldData::default(),
-                    mac,
-                })
+                Ok((
+                    NtpPacket {
+                        header: NtpHeader::V3(header),
+                        efdata: ExtensionFieldData::default(),
+                        mac,
+                    },
+                    None,
+                ))
             }
             4 => {
                 let (header, header_size) = NtpHeaderV3V4::deserialize(data)?;
-                let (efdata, header_plus_fields_len) =
-                    ExtensionFieldData::deserialize(data, header_size, cipher)?;
+                let (efdata, header_plus_fields_len, cookie) =
+                    ExtensionFieldData::deserialize(data, header_size, keys)?;
 
                 let mac = if header_plus_fields_len != data.len() {
                     Some(Mac::deserialize(&data[header_plus_fields_len..])?)
@@ -910,11 +1109,14 @@ impl<'a> NtpPacket<'a> {
                     None
                 };
 
-                Ok(NtpPacket {
-                    header: NtpHeader::V4(header),
-                    efdata,
-                    mac,
-                })
+                Ok((
+                    NtpPacket {
+                        header: NtpHeader::V4(header),
+                        efdata,
+                        mac,
+                    },
+                    cookie,
+                ))
             }
             _ => Err(PacketParsingError::InvalidVersion(version)),
         }
@@ -937,6 +1139,39 @@ impl<'a> NtpPacket<'a> {
         &self,
         w: &mut Cursor<&mut [u8]>,
         cipher: Option<&Cipher>,
+    ) -> std::io::Result<()> {
+        self.serialize_without_mac(w, cipher)?;
+
+        if let Some(ref mac) = self.mac {
+            mac.serialize(w)?;
+        }
+
+        Ok(())
+    }
+
+    /// Serialize the packet, followed by a MAC over the serialized packet computed with `key`.
+    /// Any MAC already present in the packet is replaced.
+    pub fn serialize_with_mac(
+        &self,
+        w: &mut Cursor<&mut [u8]>,
+        cipher: Option<&Cipher>,
+        key: &SymmetricKey,
+    ) -> std::io::Result<()> {
+        let start = w.position() as usize;
+        self.serialize_without_mac(w, cipher)?;
+        let end = w.position() as usize;
+
+        let mac = Mac {
+            keyid: key.id(),
+            mac: Cow::Owned(key.sign(&w.get_ref()[start..end])),
+        };
+        mac.serialize(w)
+    }
+
+    fn serialize_without_mac(
+        &self,
+        w: &mut Cursor<&mut [u8]>,
+        cipher: Option<&Cipher>,
     ) -> std::io::Result<()> {
         match self.header {
             NtpHeader::V3(header) => header.serialize(w, 3)?,
@@ -948,13 +1183,35 @@ impl<'a> NtpPacket<'a> {
             NtpHeader::V4(_) => self.efdata.serialize(w, cipher)?,
         }
 
-        if let Some(ref mac) = self.mac {
-            mac.serialize(w)?;
-        }
-
         Ok(())
     }
 
+    /// The id of the key used for the MAC of this packet, if it has a MAC
+    pub fn mac_key_id(&self) -> Option<u32> {
+        self.mac.as_ref().map(|mac| mac.keyid)
+    }
+
+    /// A crypto-NAK is a MAC consisting of just a key id of 0. It signals that the
+    /// MAC of a request could not be verified.
+    pub fn is_crypto_nak(&self) -> bool {
+        matches!(&self.mac, Some(mac) if mac.keyid == 0 && mac.mac.is_empty())
+    }
+
+    /// Check that the MAC of this packet was computed with `key`. The `data` must be
+    /// the bytes this packet was deserialized from.
+    pub fn verify_mac(&self, data: &[u8], key: &SymmetricKey) -> bool {
+        match &self.mac {
+            Some(mac) if mac.keyid == key.id() => {
+                let mac_length = 4 + mac.mac.len();
+                match data.len().checked_sub(mac_length) {
+                    Some(length) => key.verify(&data[..length], &mac.mac),
+                    None => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
     pub fn nts_poll_message(
         cookie: &'a [u8],
         new_cookies: u8,
@@ -1004,11 +1261,112 @@ impl<'a> NtpPacket<'a> {
         )
     }
 
+    /// A poll message that asks the server for an interleaved response. The origin timestamp
+    /// is the receive timestamp of the last response of the server. The receive timestamp is a
+    /// random value, which the server echoes in the origin timestamp of its interleaved
+    /// response, such that our local time is not revealed.
+    pub fn interleaved_poll_message(
+        poll_interval: PollInterval,
+        origin_timestamp: NtpTimestamp,
+    ) -> (Self, RequestIdentifier) {
+        let (header, id) = NtpHeaderV3V4::poll_message(poll_interval);
+        let receive_timestamp = thread_rng().gen();
+        (
+            NtpPacket {
+                header: NtpHeader::V4(NtpHeaderV3V4 {
+                    origin_timestamp,
+                    receive_timestamp,
+                    ..header
+                }),
+                efdata: Default::default(),
+                mac: None,
+            },
+            RequestIdentifier {
+                interleaved_origin_timestamp: Some(receive_timestamp),
+                ..id
+            },
+        )
+    }
+
+    /// A packet of a symmetric association. The origin and receive timestamps are the
+    /// transmit timestamp of the last packet received from the peer and the time at
+    /// which we received it.
+    pub fn symmetric_message(
+        system: &SystemSnapshot,
+        mode: NtpAssociationMode,
+        poll_interval: PollInterval,
+        origin_timestamp: NtpTimestamp,
+        receive_timestamp: NtpTimestamp,
+        transmit_timestamp: NtpTimestamp,
+    ) -> (Self, RequestIdentifier) {
+        let (header, id) = NtpHeaderV3V4::symmetric_message(
+            system,
+            mode,
+            poll_interval,
+            origin_timestamp,
+            receive_timestamp,
+            transmit_timestamp,
+        );
+        (
+            NtpPacket {
+                header: NtpHeader::V4(header),
+                efdata: Default::default(),
+                mac: None,
+            },
+            id,
+        )
+    }
+
+    /// A packet sent by a broadcast server. Only its transmit timestamp is set, the clients
+    /// correct it with the delay they measured to the server.
+    pub fn broadcast_message<C: NtpClock>(
+        system: &SystemSnapshot,
+        poll_interval: PollInterval,
+        clock: &C,
+        leap_smear: Option<&mut LeapSmear>,
+    ) -> Self {
+        NtpPacket {
+            header: NtpHeader::V4(NtpHeaderV3V4::broadcast_message(
+                system,
+                poll_interval,
+                clock,
+                leap_smear,
+            )),
+            efdata: Default::default(),
+            mac: None,
+        }
+    }
+
+    /// A sample of a local reference clock, presented as the response of a stratum 0 server
+    /// that answered instantly
+    pub(crate) fn reference_clock_sample(
+        reference_id: ReferenceId,
+        leap: NtpLeapIndicator,
+        precision: i8,
+        poll_interval: PollInterval,
+        reference_time: NtpTimestamp,
+    ) -> NtpPacket<'static> {
+        NtpPacket {
+            header: NtpHeader::V4(NtpHeaderV3V4::reference_clock_sample(
+                reference_id,
+                leap,
+                precision,
+                poll_interval,
+                reference_time,
+            )),
+            efdata: Default::default(),
+            mac: None,
+        }
+    }
+
+    /// Response to a request from a client. With a leap smear, the timestamps in the response
+    /// are smeared around a leap second.
     pub fn timestamp_response<C: NtpClock>(
         system: &SystemSnapshot,
         input: Self,
         recv_timestamp: NtpTimestamp,
         clock: &C,
+        leap_smear: Option<&mut LeapSmear>,
     ) -> Self {
         match input.header {
             NtpHeader::V3(header) => NtpPacket {
@@ -1017,6 +1375,7 @@ impl<'a> NtpPacket<'a> {
                     header,
                     recv_timestamp,
                     clock,
+                    leap_smear,
                 )),
                 efdata: Default::default(),
                 mac: None,
@@ -1027,6 +1386,7 @@ impl<'a> NtpPacket<'a> {
                     header,
                     recv_timestamp,
                     clock,
+                    leap_smear,
                 )),
                 efdata: Default::default(),
                 mac: None,
@@ -1034,6 +1394,40 @@ impl<'a> NtpPacket<'a> {
         }
     }
 
+    /// Turn a response into an interleaved response. Its origin timestamp becomes the receive
+    /// timestamp of the request, and its transmit timestamp the time at which the previous
+    /// response to the same client actually left.
+    pub fn into_interleaved(
+        mut self,
+        request_receive_timestamp: NtpTimestamp,
+        previous_transmit_timestamp: NtpTimestamp,
+    ) -> Self {
+        match &mut self.header {
+            NtpHeader::V3(header) | NtpHeader::V4(header) => {
+                header.origin_timestamp = request_receive_timestamp;
+                header.transmit_timestamp = previous_transmit_timestamp;
+            }
+        }
+        self
+    }
+
+    /// Response to a request whose MAC could not be verified
+    pub fn crypto_nak_response<C: NtpClock>(
+        system: &SystemSnapshot,
+        input: Self,
+        recv_timestamp: NtpTimestamp,
+        clock: &C,
+        leap_smear: Option<&mut LeapSmear>,
+    ) -> Self {
+        Self {
+            mac: Some(Mac {
+                keyid: 0,
+                mac: Cow::Borrowed(&[]),
+            }),
+            ..Self::timestamp_response(system, input, recv_timestamp, clock, leap_smear)
+        }
+    }
+
     pub fn rate_limit_response(packet_from_client: Self) -> Self {
         match packet_from_client.header {
             NtpHeader::V3(header) => NtpPacket {
@@ -1065,6 +1459,112 @@ impl<'a> NtpPacket<'a> {
     }
 }
 
+impl<'a> NtpPacket<'a> {
+    /// Response to an NTS request whose cookie could be decrypted. The response echoes the unique
+    /// identifier and contains a fresh cookie for every cookie (placeholder) in the request. It
+    /// must be serialized with the s2c key from the cookie.
+    pub fn nts_timestamp_response<C: NtpClock>(
+        system: &SystemSnapshot,
+        input: Self,
+        recv_timestamp: NtpTimestamp,
+        clock: &C,
+        cookie: &DecodedServerCookie,
+        keyset: &KeySet,
+        leap_smear: Option<&mut LeapSmear>,
+    ) -> NtpPacket<'static> {
+        let header = match input.header {
+            NtpHeader::V3(header) => header,
+            NtpHeader::V4(header) => header,
+        };
+
+        // cookies in extension fields are padded to a multiple of 4 bytes
+        let padded_length = |length: usize| next_multiple_of(length as u16, 4) as usize;
+
+        let first_cookie = keyset.encode_cookie(cookie);
+        let cookie_length = padded_length(first_cookie.len());
+
+        let mut authenticated = vec![];
+        let mut requested_length = 0;
+
+        for field in input.efdata.authenticated {
+            match field {
+                ExtensionField::UniqueIdentifier(identifier) => authenticated.push(
+                    ExtensionField::UniqueIdentifier(Cow::Owned(identifier.into_owned())),
+                ),
+                ExtensionField::NtsCookie(old_cookie) => {
+                    requested_length += padded_length(old_cookie.len())
+                }
+                // RFC 8915, section 5.7: placeholders must be as large as the cookies
+                // that replace them, other placeholders are ignored
+                ExtensionField::NtsCookiePlaceholder {
+                    cookie_length: placeholder_length,
+                } => {
+                    if padded_length(placeholder_length as usize) == cookie_length {
+                        requested_length += cookie_length
+                    }
+                }
+                ExtensionField::Unknown { .. } => {}
+            }
+        }
+
+        // the new cookies take no more space than the cookie and placeholders in the
+        // request, such that the response is never larger than the request
+        let new_cookies = requested_length / cookie_length;
+        let encrypted = std::iter::once(first_cookie)
+            .chain(std::iter::repeat_with(|| keyset.encode_cookie(cookie)))
+            .take(new_cookies)
+            .map(|new_cookie| ExtensionField::NtsCookie(Cow::Owned(new_cookie)))
+            .collect();
+
+        NtpPacket {
+            header: NtpHeader::V4(NtpHeaderV3V4::timestamp_response(
+                system,
+                header,
+                recv_timestamp,
+                clock,
+                leap_smear,
+            )),
+            efdata: ExtensionFieldData {
+                authenticated,
+                encrypted,
+                untrusted: vec![],
+            },
+            mac: None,
+        }
+    }
+
+    /// Response to an NTS request whose cookie could not be decrypted. It only echoes the unique
+    /// identifier, as the client and server no longer agree on the keys.
+    pub fn nts_nak_response(packet_from_client: Self) -> NtpPacket<'static> {
+        let header = match packet_from_client.header {
+            NtpHeader::V3(header) => header,
+            NtpHeader::V4(header) => header,
+        };
+
+        let untrusted = packet_from_client
+            .efdata
+            .untrusted
+            .into_iter()
+            .filter_map(|field| match field {
+                ExtensionField::UniqueIdentifier(identifier) => Some(
+                    ExtensionField::UniqueIdentifier(Cow::Owned(identifier.into_owned())),
+                ),
+                _ => None,
+            })
+            .collect();
+
+        NtpPacket {
+            header: NtpHeader::V4(NtpHeaderV3V4::nts_nak_response(header)),
+            efdata: ExtensionFieldData {
+                authenticated: vec![],
+                encrypted: vec![],
+                untrusted,
+            },
+            mac: None,
+        }
+    }
+}
+
 impl<'a> NtpPacket<'a> {
     pub fn new_cookies<'b: 'a>(&'b self) -> impl Iterator<Item = Vec<u8>> + 'b {
         self.efdata.encrypted.iter().filter_map(|ef| match ef {
@@ -1122,6 +1622,13 @@ impl<'a> NtpPacket<'a> {
         }
     }
 
+    pub fn origin_timestamp(&self) -> NtpTimestamp {
+        match self.header {
+            NtpHeader::V3(header) => header.origin_timestamp,
+            NtpHeader::V4(header) => header.origin_timestamp,
+        }
+    }
+
     pub fn transmit_timestamp(&self) -> NtpTimestamp {
         match self.header {
             NtpHeader::V3(header) => header.transmit_timestamp,
@@ -1178,14 +1685,14 @@ impl<'a> NtpPacket<'a> {
                 return false;
             }
         }
-        match self.header {
-            NtpHeader::V3(header) => {
-                header.origin_timestamp == identifier.expected_origin_timestamp
-            }
-            NtpHeader::V4(header) => {
-                header.origin_timestamp == identifier.expected_origin_timestamp
-            }
-        }
+        self.origin_timestamp() == identifier.expected_origin_timestamp
+            || self.is_interleaved_response(identifier)
+    }
+
+    /// Whether this is an interleaved response to a request that asked for one. Such a
+    /// response carries the transmit timestamp of the previous response.
+    pub fn is_interleaved_response(&self, identifier: RequestIdentifier) -> bool {
+        identifier.interleaved_origin_timestamp == Some(self.origin_timestamp())
     }
 }
 
@@ -1624,4 +2131,448 @@ mod tests {
         ];
         assert!(NtpPacket::deserialize(&data, None).is_err());
     }
+
+    #[derive(Debug, Clone)]
+    struct TestClock {
+        now: NtpTimestamp,
+    }
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(self.now)
+        }
+
+        fn set_frequency(&self, _freq: f64) -> Result<NtpTimestamp, Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<NtpTimestamp, Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn disable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn enable_ntp_algorithm(&self) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn ntp_algorithm_update(
+            &self,
+            _offset: NtpDuration,
+            _poll_interval: PollInterval,
+        ) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn error_estimate_update(
+            &self,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+        ) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+
+        fn status_update(&self, _leap_status: NtpLeapIndicator) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by server");
+        }
+    }
+
+    fn nts_request(keyset: &KeySet, cookie: &DecodedServerCookie) -> (Vec<u8>, RequestIdentifier) {
+        let encoded = keyset.encode_cookie(cookie);
+        let (packet, id) = NtpPacket::nts_poll_message(&encoded, 3, PollInterval::default());
+
+        let mut buffer = [0u8; 1024];
+        let mut cursor = Cursor::new(buffer.as_mut_slice());
+        packet.serialize(&mut cursor, Some(&cookie.c2s())).unwrap();
+        let length = cursor.position() as usize;
+
+        (buffer[..length].to_vec(), id)
+    }
+
+    fn test_server_cookie() -> DecodedServerCookie {
+        DecodedServerCookie {
+            algorithm: 15,
+            c2s: *Key::<Aes128SivAead>::from_slice(&[1; 32]),
+            s2c: *Key::<Aes128SivAead>::from_slice(&[2; 32]),
+        }
+    }
+
+    #[test]
+    fn deserialize_nts_request() {
+        let keyset = KeySet::new();
+        let (data, _) = nts_request(&keyset, &test_server_cookie());
+
+        let (packet, cookie) = NtpPacket::deserialize_request(&data, &keyset).unwrap();
+        assert_eq!(cookie.unwrap().unwrap(), test_server_cookie());
+
+        // the unique identifier, cookie and 2 placeholders are authenticated
+        assert_eq!(packet.efdata.authenticated.len(), 4);
+        assert!(packet.efdata.untrusted.is_empty());
+
+        // requests without NTS don't have a cookie
+        let (request, _) = NtpPacket::poll_message(PollInterval::default());
+        let data = request.serialize_without_encryption_vec().unwrap();
+        let (_, cookie) = NtpPacket::deserialize_request(&data, &keyset).unwrap();
+        assert!(cookie.is_none());
+    }
+
+    #[test]
+    fn deserialize_nts_request_unknown_cookie() {
+        let keyset = KeySet::new();
+        let other_keyset = KeySet::new();
+        let (data, id) = nts_request(&other_keyset, &test_server_cookie());
+
+        let (packet, cookie) = NtpPacket::deserialize_request(&data, &keyset).unwrap();
+        assert!(matches!(cookie, Some(Err(DecryptError))));
+
+        // the response should be a NTS NAK with the unique identifier of the request
+        let response = NtpPacket::nts_nak_response(packet);
+        let data = response.serialize_without_encryption_vec().unwrap();
+        let response = NtpPacket::deserialize(&data, None).unwrap();
+
+        assert!(response.is_kiss_ntsn());
+        assert!(response.valid_server_response(id, true));
+    }
+
+    #[test]
+    fn deserialize_nts_request_wrong_keys() {
+        let keyset = KeySet::new();
+        let (mut data, _) = nts_request(&keyset, &test_server_cookie());
+
+        // corrupt the ciphertext of the encrypted extension field
+        let last = data.len() - 1;
+        data[last] ^= 1;
+
+        let (_, cookie) = NtpPacket::deserialize_request(&data, &keyset).unwrap();
+        assert!(matches!(cookie, Some(Err(DecryptError))));
+    }
+
+    #[test]
+    fn nts_timestamp_response_roundtrip() {
+        let keyset = KeySet::new();
+        let cookie = test_server_cookie();
+        let (data, id) = nts_request(&keyset, &cookie);
+
+        let (request, decoded) = NtpPacket::deserialize_request(&data, &keyset).unwrap();
+        let decoded = decoded.unwrap().unwrap();
+
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(200),
+        };
+        let response = NtpPacket::nts_timestamp_response(
+            &SystemSnapshot::default(),
+            request,
+            NtpTimestamp::from_fixed_int(100),
+            &clock,
+            &decoded,
+            &keyset,
+            None,
+        );
+
+        let mut buffer = [0u8; 1024];
+        let mut cursor = Cursor::new(buffer.as_mut_slice());
+        response
+            .serialize(&mut cursor, Some(&decoded.s2c()))
+            .unwrap();
+        let length = cursor.position() as usize;
+
+        // the client can only decrypt the response with its s2c key
+        assert!(NtpPacket::deserialize(&buffer[..length], Some(&cookie.c2s())).is_err());
+        let response = NtpPacket::deserialize(&buffer[..length], Some(&cookie.s2c())).unwrap();
+
+        assert!(response.valid_server_response(id, true));
+        assert_eq!(
+            response.receive_timestamp(),
+            NtpTimestamp::from_fixed_int(100)
+        );
+        assert_eq!(
+            response.transmit_timestamp(),
+            NtpTimestamp::from_fixed_int(200)
+        );
+
+        // one new cookie for the cookie and each of the placeholders
+        let new_cookies: Vec<_> = response.new_cookies().collect();
+        assert_eq!(new_cookies.len(), 3);
+        for new_cookie in new_cookies {
+            assert_eq!(keyset.decode_cookie(&new_cookie).unwrap(), cookie);
+        }
+    }
+
+    fn nts_request_with_placeholders(
+        keyset: &KeySet,
+        cookie: &DecodedServerCookie,
+        placeholder_lengths: &[u16],
+    ) -> Vec<u8> {
+        let encoded = keyset.encode_cookie(cookie);
+        let (mut packet, _) = NtpPacket::nts_poll_message(&encoded, 1, PollInterval::default());
+        for cookie_length in placeholder_lengths {
+            packet
+                .efdata
+                .authenticated
+                .push(ExtensionField::NtsCookiePlaceholder {
+                    cookie_length: *cookie_length,
+                });
+        }
+
+        let mut buffer = [0u8; 1024];
+        let mut cursor = Cursor::new(buffer.as_mut_slice());
+        packet.serialize(&mut cursor, Some(&cookie.c2s())).unwrap();
+        let length = cursor.position() as usize;
+
+        buffer[..length].to_vec()
+    }
+
+    fn nts_response_to(keyset: &KeySet, data: &[u8]) -> (usize, usize) {
+        let (request, decoded) = NtpPacket::deserialize_request(data, keyset).unwrap();
+        let decoded = decoded.unwrap().unwrap();
+
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(200),
+        };
+        let response = NtpPacket::nts_timestamp_response(
+            &SystemSnapshot::default(),
+            request,
+            NtpTimestamp::from_fixed_int(100),
+            &clock,
+            &decoded,
+            keyset,
+            None,
+        );
+
+        let mut buffer = [0u8; 1024];
+        let mut cursor = Cursor::new(buffer.as_mut_slice());
+        response
+            .serialize(&mut cursor, Some(&decoded.s2c()))
+            .unwrap();
+        let length = cursor.position() as usize;
+
+        let response = NtpPacket::deserialize(&buffer[..length], Some(&decoded.s2c())).unwrap();
+        (length, response.new_cookies().count())
+    }
+
+    #[test]
+    fn nts_timestamp_response_short_placeholder() {
+        let keyset = KeySet::new();
+        let cookie = test_server_cookie();
+
+        // placeholders that are smaller than a cookie are not answered
+        let data = nts_request_with_placeholders(&keyset, &cookie, &[16, 16]);
+        let (length, new_cookies) = nts_response_to(&keyset, &data);
+        assert_eq!(new_cookies, 1);
+        assert!(length <= data.len());
+
+        // neither are placeholders that are larger than a cookie
+        let cookie_length = keyset.encode_cookie(&cookie).len() as u16;
+        let data = nts_request_with_placeholders(&keyset, &cookie, &[cookie_length + 100]);
+        let (_, new_cookies) = nts_response_to(&keyset, &data);
+        assert_eq!(new_cookies, 1);
+    }
+
+    #[test]
+    fn nts_timestamp_response_many_placeholders() {
+        let keyset = KeySet::new();
+        let cookie = test_server_cookie();
+        let cookie_length = keyset.encode_cookie(&cookie).len() as u16;
+
+        // as many placeholders as fit in a request are all answered
+        let data = nts_request_with_placeholders(&keyset, &cookie, &[cookie_length; 7]);
+        let (length, new_cookies) = nts_response_to(&keyset, &data);
+        assert_eq!(new_cookies, 8);
+        assert!(length <= data.len());
+
+        // many small placeholders don't make the response any larger than the request
+        let data = nts_request_with_placeholders(&keyset, &cookie, &[16; 30]);
+        let (length, new_cookies) = nts_response_to(&keyset, &data);
+        assert_eq!(new_cookies, 1);
+        assert!(length <= data.len());
+    }
+
+    #[test]
+    fn mac_roundtrip() {
+        use crate::symmetric_key::SymmetricKeyAlgorithm;
+
+        let key = SymmetricKey::new(7, SymmetricKeyAlgorithm::Aes128Cmac, vec![1; 16]).unwrap();
+        let other_key =
+            SymmetricKey::new(8, SymmetricKeyAlgorithm::Aes128Cmac, vec![1; 16]).unwrap();
+
+        let (packet, _) = NtpPacket::poll_message(PollInterval::default());
+        let mut buffer = [0u8; 1024];
+        let mut cursor = Cursor::new(buffer.as_mut_slice());
+        packet.serialize_with_mac(&mut cursor, None, &key).unwrap();
+        let length = cursor.position() as usize;
+        assert_eq!(length, 48 + 4 + 16);
+
+        let data = &buffer[..length];
+        let received = NtpPacket::deserialize(data, None).unwrap();
+        assert_eq!(received.mac_key_id(), Some(7));
+        assert!(received.verify_mac(data, &key));
+        assert!(!received.verify_mac(data, &other_key));
+        assert!(!received.is_crypto_nak());
+
+        // any change to the packet invalidates the mac
+        let mut tampered = data.to_vec();
+        tampered[47] ^= 1;
+        let received = NtpPacket::deserialize(&tampered, None).unwrap();
+        assert!(!received.verify_mac(&tampered, &key));
+
+        // packets without mac are not valid either
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        let received = NtpPacket::deserialize(&data, None).unwrap();
+        assert_eq!(received.mac_key_id(), None);
+        assert!(!received.verify_mac(&data, &key));
+    }
+
+    #[test]
+    fn crypto_nak_response() {
+        let (packet, id) = NtpPacket::poll_message(PollInterval::default());
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(200),
+        };
+
+        let response = NtpPacket::crypto_nak_response(
+            &SystemSnapshot::default(),
+            packet,
+            NtpTimestamp::from_fixed_int(100),
+            &clock,
+            None,
+        );
+        let data = response.serialize_without_encryption_vec().unwrap();
+        assert_eq!(data.len(), 48 + 4);
+
+        let response = NtpPacket::deserialize(&data, None).unwrap();
+        assert!(response.is_crypto_nak());
+        assert_eq!(response.mac_key_id(), Some(0));
+        assert!(response.valid_server_response(id, false));
+    }
+
+    #[test]
+    fn broadcast_message() {
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(200),
+        };
+
+        let packet = NtpPacket::broadcast_message(
+            &SystemSnapshot::default(),
+            PollInterval::default(),
+            &clock,
+            None,
+        );
+        let data = packet.serialize_without_encryption_vec().unwrap();
+        let packet = NtpPacket::deserialize(&data, None).unwrap();
+
+        assert_eq!(packet.mode(), NtpAssociationMode::Broadcast);
+        assert_eq!(packet.origin_timestamp(), NtpTimestamp::default());
+        assert_eq!(
+            packet.transmit_timestamp(),
+            NtpTimestamp::from_fixed_int(200)
+        );
+    }
+
+    #[test]
+    fn response_leap_indicator() {
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(300),
+        };
+
+        let mut system = SystemSnapshot::default();
+        system.time_snapshot.leap_indicator = NtpLeapIndicator::Leap61;
+
+        let (request, _) = NtpPacket::poll_message(PollInterval::default());
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            NtpTimestamp::from_fixed_int(200),
+            &clock,
+            None,
+        );
+        assert_eq!(response.leap(), NtpLeapIndicator::Leap61);
+    }
+
+    #[test]
+    fn smeared_response() {
+        // 2017-01-01, at which a second was inserted
+        let leap = NtpTimestamp::from_seconds_nanos_since_ntp_era(3692217600, 0);
+        let clock = TestClock {
+            now: leap - NtpDuration::from_seconds(250.0),
+        };
+
+        let mut system = SystemSnapshot::default();
+        system.time_snapshot.leap_indicator = NtpLeapIndicator::Leap61;
+
+        let mut leap_smear = LeapSmear::new(crate::LeapSmearConfig {
+            window: NtpDuration::from_seconds(1000.0),
+            shape: crate::LeapSmearShape::Linear,
+        });
+
+        let (request, _) = NtpPacket::poll_message(PollInterval::default());
+        let response = NtpPacket::timestamp_response(
+            &system,
+            request,
+            leap - NtpDuration::from_seconds(500.0),
+            &clock,
+            Some(&mut leap_smear),
+        );
+
+        // the leap second is hidden from the client
+        assert_eq!(response.leap(), NtpLeapIndicator::NoWarning);
+        assert_eq!(
+            response.receive_timestamp(),
+            leap - NtpDuration::from_seconds(500.0)
+        );
+        let smear = (clock.now - response.transmit_timestamp()).to_seconds();
+        assert!((smear - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn interleaved_response() {
+        let clock = TestClock {
+            now: NtpTimestamp::from_fixed_int(300),
+        };
+
+        let (request, id) = NtpPacket::interleaved_poll_message(
+            PollInterval::default(),
+            NtpTimestamp::from_fixed_int(100),
+        );
+        assert_eq!(
+            request.origin_timestamp(),
+            NtpTimestamp::from_fixed_int(100)
+        );
+        let cookie = request.receive_timestamp();
+
+        let response = NtpPacket::timestamp_response(
+            &SystemSnapshot::default(),
+            request,
+            NtpTimestamp::from_fixed_int(200),
+            &clock,
+            None,
+        );
+        assert!(response.valid_server_response(id, false));
+        assert!(!response.is_interleaved_response(id));
+
+        let response = response.into_interleaved(cookie, NtpTimestamp::from_fixed_int(105));
+        let data = response.serialize_without_encryption_vec().unwrap();
+        let response = NtpPacket::deserialize(&data, None).unwrap();
+
+        assert!(response.valid_server_response(id, false));
+        assert!(response.is_interleaved_response(id));
+
+        // a request that did not ask for an interleaved response does not accept one
+        let (_, basic_id) = NtpPacket::poll_message(PollInterval::default());
+        assert!(!response.valid_server_response(basic_id, false));
+        assert!(!response.is_interleaved_response(basic_id));
+
+        assert_eq!(response.origin_timestamp(), cookie);
+        assert_eq!(
+            response.receive_timestamp(),
+            NtpTimestamp::from_fixed_int(200)
+        );
+        assert_eq!(
+            response.transmit_timestamp(),
+            NtpTimestamp::from_fixed_int(105)
+        );
+    }
 }
@@ -3,6 +3,7 @@ use std::io::Cursor;
 use crate::{
     cookiestash::CookieStash,
     packet::{NtpAssociationMode, RequestIdentifier},
+    symmetric_key::SymmetricKey,
     time_types::NtpInstant,
     NtpDuration, NtpPacket, NtpTimestamp, PollInterval, ReferenceId, SystemConfig, SystemSnapshot,
 };
@@ -12,6 +13,11 @@ use tracing::{debug, info, instrument, trace, warn};
 
 const MAX_STRATUM: u8 = 16;
 const POLL_WINDOW: std::time::Duration = std::time::Duration::from_secs(5);
+/// Number of client/server exchanges a broadcast client uses to calibrate its delay
+const BROADCAST_CALIBRATION_EXCHANGES: u8 = 4;
+/// Number of poll intervals after which a broadcast client calibrates its delay again,
+/// so that it follows changes in the route to the server
+const BROADCAST_RECALIBRATION_POLLS: u8 = 32;
 
 #[derive(Debug, thiserror::Error)]
 pub enum NtsError {
@@ -44,9 +50,57 @@ impl std::fmt::Debug for PeerNtsData {
     }
 }
 
+/// Our role in a symmetric association
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymmetricMode {
+    /// We initiated the association
+    Active,
+    /// The association was set up in response to a symmetric active packet of the peer
+    Passive,
+}
+
+#[derive(Debug)]
+struct SymmetricAssociation {
+    mode: SymmetricMode,
+    // Transmit timestamp of the last packet received from the peer, sent back as
+    // origin timestamp in our next packet.
+    remote_transmit: NtpTimestamp,
+    // Time at which we received that packet, sent back as receive timestamp.
+    local_receive: NtpTimestamp,
+}
+
+#[derive(Debug)]
+struct BroadcastAssociation {
+    // Round trip delay to the server found by the last completed calibration
+    delay: Option<NtpDuration>,
+    // Smallest round trip delay measured during the current calibration
+    calibration_delay: Option<NtpDuration>,
+    // Number of client/server exchanges of the current calibration that completed
+    exchanges: u8,
+    // Number of poll intervals since the last calibration completed
+    skipped_polls: u8,
+    // Transmit timestamp of the last broadcast, to reject duplicates and replays
+    last_transmit: NtpTimestamp,
+}
+
+/// The timestamps of the last client/server exchange. An interleaved response to the next
+/// request carries the time at which the server actually sent its response in this exchange.
+#[derive(Debug, Clone, Copy)]
+struct InterleavedExchange {
+    // Time at which our request left
+    send_timestamp: NtpTimestamp,
+    // Time at which the server received our request
+    server_receive: NtpTimestamp,
+    // Time at which we received the response
+    local_receive: NtpTimestamp,
+}
+
 #[derive(Debug)]
 pub struct Peer {
     nts: Option<PeerNtsData>,
+    symmetric_key: Option<SymmetricKey>,
+    symmetric: Option<SymmetricAssociation>,
+    broadcast: Option<BroadcastAssociation>,
 
     // Poll interval dictated by unreachability backoff
     backoff_interval: PollInterval,
@@ -60,6 +114,9 @@ pub struct Peer {
     // with any received response from the server to guard against replay
     // attacks and packet reordering.
     current_request_identifier: Option<(RequestIdentifier, NtpInstant)>,
+    // The last exchange with the server, referred to by our next request to ask the server
+    // for an interleaved response
+    previous_exchange: Option<InterleavedExchange>,
 
     stratum: u8,
     reference_id: ReferenceId,
@@ -86,13 +143,33 @@ impl Measurement {
         recv_timestamp: NtpTimestamp,
         local_clock_time: NtpInstant,
         precision: NtpDuration,
+    ) -> Self {
+        Self::from_timestamps(
+            send_timestamp,
+            packet.receive_timestamp(),
+            packet.transmit_timestamp(),
+            recv_timestamp,
+            local_clock_time,
+            precision,
+        )
+    }
+
+    /// A measurement from the four timestamps of an exchange: the times at which the request
+    /// was sent and received, and the times at which the response was sent and received
+    fn from_timestamps(
+        send_timestamp: NtpTimestamp,
+        server_receive_timestamp: NtpTimestamp,
+        server_transmit_timestamp: NtpTimestamp,
+        recv_timestamp: NtpTimestamp,
+        local_clock_time: NtpInstant,
+        precision: NtpDuration,
     ) -> Self {
         Self {
             delay: ((recv_timestamp - send_timestamp)
-                - (packet.transmit_timestamp() - packet.receive_timestamp()))
-            .max(precision),
-            offset: ((packet.receive_timestamp() - send_timestamp)
-                + (packet.transmit_timestamp() - recv_timestamp))
+                - (server_transmit_timestamp - server_receive_timestamp))
+                .max(precision),
+            offset: ((server_receive_timestamp - send_timestamp)
+                + (server_transmit_timestamp - recv_timestamp))
                 / 2,
             localtime: send_timestamp + (recv_timestamp - send_timestamp) / 2,
             monotime: local_clock_time,
@@ -130,6 +207,11 @@ impl Reach {
         self.0 != 0
     }
 
+    /// The reach register, with the most recent poll in the least significant bit
+    pub fn register(&self) -> u8 {
+        self.0
+    }
+
     /// We have just received a packet, so the peer is definitely reachable
     pub(crate) fn received_packet(&mut self) {
         self.0 |= 1;
@@ -137,7 +219,7 @@ impl Reach {
 
     /// A packet received some number of poll intervals ago is decreasingly relevant for
     /// determining that a peer is still reachable. We discount the packets received so far.
-    fn poll(&mut self) {
+    pub(crate) fn poll(&mut self) {
         self.0 <<= 1
     }
 
@@ -170,6 +252,8 @@ pub enum IgnoreReason {
     KissDemobilize,
     /// Received a matching NTS-Nack, no further action needed.
     KissNtsNack,
+    /// The MAC of the packet is missing or does not match our symmetric key
+    InvalidMac,
     /// The best packet is older than the peer's current time
     TooOld,
 }
@@ -273,12 +357,16 @@ impl Peer {
     ) -> Self {
         Self {
             nts: None,
+            symmetric_key: None,
+            symmetric: None,
+            broadcast: None,
 
             last_poll_interval: system_config.poll_limits.min,
             backoff_interval: system_config.poll_limits.min,
             remote_min_poll_interval: system_config.poll_limits.min,
 
             current_request_identifier: None,
+            previous_exchange: None,
             our_id,
             peer_id,
             reach: Default::default(),
@@ -304,6 +392,99 @@ impl Peer {
         }
     }
 
+    /// A peer whose packets are authenticated with a MAC computed with a symmetric key
+    #[instrument]
+    pub fn new_with_key(
+        our_id: ReferenceId,
+        peer_id: ReferenceId,
+        local_clock_time: NtpInstant,
+        system_config: SystemConfig,
+        symmetric_key: SymmetricKey,
+    ) -> Self {
+        Self {
+            symmetric_key: Some(symmetric_key),
+            ..Self::new(our_id, peer_id, local_clock_time, system_config)
+        }
+    }
+
+    /// A peer with which we exchange time in a symmetric association: both sides
+    /// send packets at their own pace, and every packet is both a request and a response.
+    #[instrument]
+    pub fn new_symmetric(
+        our_id: ReferenceId,
+        peer_id: ReferenceId,
+        local_clock_time: NtpInstant,
+        system_config: SystemConfig,
+        mode: SymmetricMode,
+        symmetric_key: Option<SymmetricKey>,
+    ) -> Self {
+        Self {
+            symmetric_key,
+            symmetric: Some(SymmetricAssociation {
+                mode,
+                remote_transmit: NtpTimestamp::default(),
+                local_receive: NtpTimestamp::default(),
+            }),
+            ..Self::new(our_id, peer_id, local_clock_time, system_config)
+        }
+    }
+
+    pub fn symmetric_mode(&self) -> Option<SymmetricMode> {
+        self.symmetric.as_ref().map(|symmetric| symmetric.mode)
+    }
+
+    /// A client of a broadcast server. It first polls the server a few times to measure
+    /// the delay, after which it only uses the broadcasts of the server, calibrating the
+    /// delay again every so often. All packets must be authenticated with `symmetric_key`.
+    #[instrument]
+    pub fn new_broadcast_client(
+        our_id: ReferenceId,
+        peer_id: ReferenceId,
+        local_clock_time: NtpInstant,
+        system_config: SystemConfig,
+        symmetric_key: SymmetricKey,
+    ) -> Self {
+        Self {
+            symmetric_key: Some(symmetric_key),
+            broadcast: Some(BroadcastAssociation {
+                delay: None,
+                calibration_delay: None,
+                exchanges: 0,
+                skipped_polls: 0,
+                last_transmit: NtpTimestamp::default(),
+            }),
+            ..Self::new(our_id, peer_id, local_clock_time, system_config)
+        }
+    }
+
+    pub fn is_broadcast_client(&self) -> bool {
+        self.broadcast.is_some()
+    }
+
+    /// Whether a poll message should be sent to the peer. Broadcast clients only poll
+    /// while they calibrate their delay.
+    pub fn wants_poll(&self) -> bool {
+        match &self.broadcast {
+            Some(broadcast) => broadcast.exchanges < BROADCAST_CALIBRATION_EXCHANGES,
+            None => true,
+        }
+    }
+
+    /// Register a poll interval in which no poll message was sent, so that a broadcast
+    /// server that stops broadcasting becomes unreachable.
+    pub fn skip_poll(&mut self) {
+        self.reach.poll();
+
+        if let Some(broadcast) = self.broadcast.as_mut() {
+            broadcast.skipped_polls = broadcast.skipped_polls.saturating_add(1);
+            if broadcast.skipped_polls >= BROADCAST_RECALIBRATION_POLLS {
+                // the previous delay stays in use until the new calibration completes
+                broadcast.skipped_polls = 0;
+                broadcast.exchanges = 0;
+            }
+        }
+    }
+
     pub fn update_config(&mut self, system_config: SystemConfig) {
         self.system_config = system_config;
     }
@@ -332,16 +513,77 @@ impl Peer {
                 })?;
                 NtpPacket::nts_poll_message(&cookie, nts.cookies.gap(), poll_interval)
             }
-            None => NtpPacket::poll_message(poll_interval),
+            None => match self.previous_exchange {
+                Some(previous) => {
+                    NtpPacket::interleaved_poll_message(poll_interval, previous.server_receive)
+                }
+                None => NtpPacket::poll_message(poll_interval),
+            },
         };
         self.current_request_identifier = Some((identifier, NtpInstant::now() + POLL_WINDOW));
 
         // Ensure we don't spam the remote with polls if it is not reachable
         self.backoff_interval = poll_interval.inc(system_config.poll_limits);
 
+        self.serialize_message(buf, packet)
+    }
+
+    /// Generate the next packet of a symmetric association. Its transmit timestamp is
+    /// used by the peer in its measurements, so it must be the current time.
+    ///
+    /// Peers that are not part of a symmetric association send a poll message instead.
+    pub fn generate_symmetric_message<'a>(
+        &mut self,
+        buf: &'a mut [u8],
+        system: SystemSnapshot,
+        system_config: &SystemConfig,
+        transmit_timestamp: NtpTimestamp,
+    ) -> Result<&'a [u8], std::io::Error> {
+        let (mode, origin_timestamp, receive_timestamp) = match &self.symmetric {
+            Some(symmetric) => (
+                match symmetric.mode {
+                    SymmetricMode::Active => NtpAssociationMode::SymmetricActive,
+                    SymmetricMode::Passive => NtpAssociationMode::SymmetricPassive,
+                },
+                symmetric.remote_transmit,
+                symmetric.local_receive,
+            ),
+            None => return self.generate_poll_message(buf, system, system_config),
+        };
+
+        self.reach.poll();
+
+        let poll_interval = self.current_poll_interval(system);
+        let (packet, identifier) = NtpPacket::symmetric_message(
+            &system,
+            mode,
+            poll_interval,
+            origin_timestamp,
+            receive_timestamp,
+            transmit_timestamp,
+        );
+
+        // The peer answers at its own poll interval, which may be much longer than ours
+        let validity = NtpInstant::now() + system_config.poll_limits.max.as_system_duration();
+        self.current_request_identifier = Some((identifier, validity));
+
+        self.backoff_interval = poll_interval.inc(system_config.poll_limits);
+
+        self.serialize_message(buf, packet)
+    }
+
+    fn serialize_message<'a>(
+        &self,
+        buf: &'a mut [u8],
+        packet: NtpPacket,
+    ) -> Result<&'a [u8], std::io::Error> {
         // Write packet to buffer
         let mut cursor = Cursor::new(buf);
-        packet.serialize(&mut cursor, self.nts.as_ref().map(|nts| &nts.c2s))?;
+        let cipher = self.nts.as_ref().map(|nts| &nts.c2s);
+        match &self.symmetric_key {
+            Some(key) => packet.serialize_with_mac(&mut cursor, cipher, key)?,
+            None => packet.serialize(&mut cursor, cipher)?,
+        }
         let used = cursor.position();
         let result = &cursor.into_inner()[..used as usize];
 
@@ -357,7 +599,8 @@ impl Peer {
         send_time: NtpTimestamp,
         recv_time: NtpTimestamp,
     ) -> Result<Update, IgnoreReason> {
-        let message = match NtpPacket::deserialize(message, self.nts.as_ref().map(|nts| &nts.s2c)) {
+        let data = message;
+        let message = match NtpPacket::deserialize(data, self.nts.as_ref().map(|nts| &nts.s2c)) {
             Ok(packet) => packet,
             Err(e) => {
                 warn!("received invalid packet: {}", e);
@@ -365,6 +608,26 @@ impl Peer {
             }
         };
 
+        if let Some(key) = &self.symmetric_key {
+            // Unauthenticated packets, including kiss codes, are not trusted at all
+            if !message.verify_mac(data, key) {
+                if message.is_crypto_nak() {
+                    warn!("Peer could not verify our MAC, check the symmetric key");
+                } else {
+                    debug!("Received packet with invalid MAC");
+                }
+                return Err(IgnoreReason::InvalidMac);
+            }
+        }
+
+        if self.symmetric.is_some() {
+            return self.handle_symmetric(system, message, local_clock_time, send_time, recv_time);
+        }
+
+        if self.broadcast.is_some() && message.mode() == NtpAssociationMode::Broadcast {
+            return self.handle_broadcast(message, local_clock_time, recv_time);
+        }
+
         let request_identifier = match self.current_request_identifier {
             Some((next_expected_origin, validity)) if validity >= NtpInstant::now() => {
                 next_expected_origin
@@ -421,8 +684,153 @@ impl Peer {
             warn!("Received packet with invalid mode");
             Err(IgnoreReason::InvalidMode)
         } else {
-            Ok(self.process_message(system, message, local_clock_time, send_time, recv_time))
+            // The origin timestamp of an interleaved response is the random receive timestamp
+            // of our request, the time at which we received the previous response is kept locally
+            let interleaved = self
+                .previous_exchange
+                .filter(|_| message.is_interleaved_response(request_identifier));
+
+            Ok(self.process_message(
+                system,
+                message,
+                local_clock_time,
+                send_time,
+                recv_time,
+                interleaved,
+            ))
+        }
+    }
+
+    fn handle_symmetric(
+        &mut self,
+        system: SystemSnapshot,
+        message: NtpPacket,
+        local_clock_time: NtpInstant,
+        send_time: NtpTimestamp,
+        recv_time: NtpTimestamp,
+    ) -> Result<Update, IgnoreReason> {
+        let symmetric = match &mut self.symmetric {
+            Some(symmetric) => symmetric,
+            None => unreachable!("only called for symmetric associations"),
+        };
+
+        if !matches!(
+            message.mode(),
+            NtpAssociationMode::SymmetricActive | NtpAssociationMode::SymmetricPassive
+        ) {
+            warn!("Received packet with invalid mode");
+            return Err(IgnoreReason::InvalidMode);
+        }
+
+        if message.transmit_timestamp() == NtpTimestamp::default()
+            || message.transmit_timestamp() == symmetric.remote_transmit
+        {
+            // A replayed or duplicated packet must not overwrite the timestamps we return
+            debug!("Received duplicate packet from peer");
+            return Err(IgnoreReason::InvalidPacketTime);
+        }
+
+        // Every packet of the peer is answered in our next packet, even when it is not a
+        // response to our last packet (for instance because our packets crossed).
+        symmetric.remote_transmit = message.transmit_timestamp();
+        symmetric.local_receive = recv_time;
+        self.reach.received_packet();
+
+        let is_response = match self.current_request_identifier {
+            Some((identifier, validity)) if validity >= NtpInstant::now() => {
+                message.valid_server_response(identifier, false)
+            }
+            _ => false,
+        };
+
+        if !is_response {
+            // The origin timestamp is not the transmit timestamp of our last packet, so
+            // the timestamps of this packet cannot be used for a measurement.
+            debug!("Received packet that is not a response to our last packet");
+            Ok(Update::BareUpdate(PeerSnapshot::from_peer(self)))
+        } else if message.is_kiss() {
+            warn!("Received kiss code from symmetric peer");
+            Err(IgnoreReason::KissIgnore)
+        } else if message.stratum() > MAX_STRATUM {
+            warn!(
+                "Received message from peer with excessive stratum {}",
+                message.stratum()
+            );
+            Err(IgnoreReason::InvalidStratum)
+        } else {
+            Ok(self.process_message(
+                system,
+                message,
+                local_clock_time,
+                send_time,
+                recv_time,
+                None,
+            ))
+        }
+    }
+
+    fn handle_broadcast(
+        &mut self,
+        message: NtpPacket,
+        local_clock_time: NtpInstant,
+        recv_time: NtpTimestamp,
+    ) -> Result<Update, IgnoreReason> {
+        let broadcast = match &mut self.broadcast {
+            Some(broadcast) => broadcast,
+            None => unreachable!("only called for broadcast clients"),
+        };
+
+        // a broadcast can be replayed by anyone that received it, its mac is still valid.
+        // So the transmit timestamp must be later than that of the last broadcast.
+        if message.transmit_timestamp() == NtpTimestamp::default()
+            || (broadcast.last_transmit != NtpTimestamp::default()
+                && message.transmit_timestamp() - broadcast.last_transmit <= NtpDuration::ZERO)
+        {
+            debug!("Received duplicate or replayed broadcast");
+            return Err(IgnoreReason::InvalidPacketTime);
+        }
+
+        if message.is_kiss() {
+            warn!("Received kiss code in broadcast");
+            return Err(IgnoreReason::KissIgnore);
+        }
+
+        if message.stratum() > MAX_STRATUM {
+            warn!(
+                "Received broadcast from server with excessive stratum {}",
+                message.stratum()
+            );
+            return Err(IgnoreReason::InvalidStratum);
         }
+
+        broadcast.last_transmit = message.transmit_timestamp();
+        self.reach.received_packet();
+
+        let delay = match broadcast.delay {
+            Some(delay) => delay,
+            None => {
+                // without a calibrated delay, the offset cannot be determined
+                debug!("Received broadcast before the delay was calibrated");
+                return Ok(Update::BareUpdate(PeerSnapshot::from_peer(self)));
+            }
+        };
+
+        self.stratum = message.stratum();
+        self.reference_id = message.reference_id();
+
+        // the packet was sent half a round trip before we received it
+        let measurement = Measurement {
+            delay,
+            offset: (message.transmit_timestamp() - recv_time) + delay / 2,
+            localtime: recv_time,
+            monotime: local_clock_time,
+        };
+
+        Ok(Update::NewMeasurement(
+            PeerSnapshot::from_peer(self),
+            measurement,
+            message.into_owned(),
+        ))
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -433,6 +841,7 @@ impl Peer {
         local_clock_time: NtpInstant,
         send_time: NtpTimestamp,
         recv_time: NtpTimestamp,
+        interleaved: Option<InterleavedExchange>,
     ) -> Update {
         trace!("Packet accepted for processing");
         // For reachability, mark that we have had a response
@@ -449,13 +858,45 @@ impl Peer {
         self.reference_id = message.reference_id();
 
         // generate a measurement
-        let measurement = Measurement::from_packet(
-            &message,
-            send_time,
-            recv_time,
-            local_clock_time,
-            system.time_snapshot.precision,
-        );
+        let measurement = match interleaved {
+            // the transmit timestamp of an interleaved response completes the previous exchange
+            Some(previous) => Measurement::from_timestamps(
+                previous.send_timestamp,
+                previous.server_receive,
+                message.transmit_timestamp(),
+                previous.local_receive,
+                local_clock_time,
+                system.time_snapshot.precision,
+            ),
+            None => Measurement::from_packet(
+                &message,
+                send_time,
+                recv_time,
+                local_clock_time,
+                system.time_snapshot.precision,
+            ),
+        };
+
+        // interleaved mode is only used with unauthenticated and MAC authenticated servers
+        if self.nts.is_none() && self.symmetric.is_none() {
+            self.previous_exchange = Some(InterleavedExchange {
+                send_timestamp: send_time,
+                server_receive: message.receive_timestamp(),
+                local_receive: recv_time,
+            });
+        }
+
+        if let Some(broadcast) = self.broadcast.as_mut() {
+            broadcast.exchanges = broadcast.exchanges.saturating_add(1);
+            broadcast.calibration_delay = Some(match broadcast.calibration_delay {
+                Some(delay) => delay.min(measurement.delay),
+                None => measurement.delay,
+            });
+
+            if broadcast.exchanges >= BROADCAST_CALIBRATION_EXCHANGES {
+                broadcast.delay = broadcast.calibration_delay.take();
+            }
+        }
 
         // Process new cookies
         if let Some(nts) = self.nts.as_mut() {
@@ -475,6 +916,7 @@ impl Peer {
     pub fn reset(&mut self) {
         // make sure in-flight messages are ignored
         self.current_request_identifier = None;
+        self.previous_exchange = None;
 
         info!(our_id = ?self.our_id, peer_id = ?self.peer_id, "Peer reset");
     }
@@ -483,12 +925,16 @@ impl Peer {
     pub(crate) fn test_peer() -> Self {
         Peer {
             nts: None,
+            symmetric_key: None,
+            symmetric: None,
+            broadcast: None,
 
             last_poll_interval: PollInterval::default(),
             backoff_interval: PollInterval::default(),
             remote_min_poll_interval: PollInterval::default(),
 
             current_request_identifier: None,
+            previous_exchange: None,
 
             peer_id: ReferenceId::from_int(0),
             our_id: ReferenceId::from_int(0),
@@ -746,6 +1192,403 @@ mod test {
             .is_err());
     }
 
+    #[test]
+    fn test_handle_incoming_mac() {
+        use crate::symmetric_key::SymmetricKeyAlgorithm;
+
+        let key = SymmetricKey::new(1, SymmetricKeyAlgorithm::Sha1, b"secret".to_vec()).unwrap();
+        let other_key =
+            SymmetricKey::new(1, SymmetricKeyAlgorithm::Sha1, b"public".to_vec()).unwrap();
+
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer();
+        peer.symmetric_key = Some(key.clone());
+
+        let system = SystemSnapshot::default();
+        let mut buf = [0; 1024];
+        let outgoingbuf = peer
+            .generate_poll_message(&mut buf, system, &SystemConfig::default())
+            .unwrap();
+        let outgoing = NtpPacket::deserialize(outgoingbuf, None).unwrap();
+        assert!(outgoing.verify_mac(outgoingbuf, &key));
+
+        let mut packet = NtpPacket::test();
+        packet.set_stratum(1);
+        packet.set_mode(NtpAssociationMode::Server);
+        packet.set_origin_timestamp(outgoing.transmit_timestamp());
+        packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+
+        let serialize_with_mac = |key: &SymmetricKey| {
+            let mut buf = [0; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize_with_mac(&mut cursor, None, key).unwrap();
+            let length = cursor.position() as usize;
+            buf[..length].to_vec()
+        };
+
+        for data in [
+            packet.serialize_without_encryption_vec().unwrap(),
+            serialize_with_mac(&other_key),
+        ] {
+            assert!(matches!(
+                peer.handle_incoming(
+                    system,
+                    &data,
+                    base + Duration::from_secs(1),
+                    NtpTimestamp::from_fixed_int(0),
+                    NtpTimestamp::from_fixed_int(400)
+                ),
+                Err(IgnoreReason::InvalidMac)
+            ));
+        }
+
+        assert!(peer
+            .handle_incoming(
+                system,
+                &serialize_with_mac(&key),
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_interleaved_exchange() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let timestamp = |seconds: u64| NtpTimestamp::from_fixed_int(seconds << 32);
+        let mut peer = Peer::test_peer();
+
+        // the first exchange is in basic mode, with a transmit timestamp that is too late
+        let mut buf = [0; 1024];
+        let data = peer
+            .generate_poll_message(&mut buf, system, &config)
+            .unwrap();
+        let request = NtpPacket::deserialize(data, None).unwrap();
+        assert_eq!(request.origin_timestamp(), NtpTimestamp::default());
+
+        let mut response = NtpPacket::test();
+        response.set_mode(NtpAssociationMode::Server);
+        response.set_stratum(1);
+        response.set_origin_timestamp(request.transmit_timestamp());
+        response.set_receive_timestamp(timestamp(110));
+        response.set_transmit_timestamp(timestamp(120));
+        let update = peer.handle_incoming(
+            system,
+            &response.serialize_without_encryption_vec().unwrap(),
+            base,
+            timestamp(100),
+            timestamp(120),
+        );
+        match update {
+            Ok(Update::NewMeasurement(_, measurement, _)) => {
+                assert_eq!(measurement.offset, NtpDuration::from_seconds(5.0));
+                assert_eq!(measurement.delay, NtpDuration::from_seconds(10.0));
+            }
+            other => panic!("expected a measurement, got {other:?}"),
+        }
+
+        // the next request refers to the previous exchange
+        let mut buf = [0; 1024];
+        let data = peer
+            .generate_poll_message(&mut buf, system, &config)
+            .unwrap();
+        let request = NtpPacket::deserialize(data, None).unwrap();
+        assert_eq!(request.origin_timestamp(), timestamp(110));
+
+        // our local time is not revealed, the receive timestamp is a random value
+        assert_ne!(request.receive_timestamp(), timestamp(120));
+
+        // the interleaved response has the actual transmit timestamp of the previous response
+        let mut response = NtpPacket::test();
+        response.set_mode(NtpAssociationMode::Server);
+        response.set_stratum(1);
+        response.set_origin_timestamp(request.receive_timestamp());
+        response.set_receive_timestamp(timestamp(210));
+        response.set_transmit_timestamp(timestamp(110));
+        let data = response.serialize_without_encryption_vec().unwrap();
+        let update = peer.handle_incoming(system, &data, base, timestamp(200), timestamp(220));
+        match update {
+            Ok(Update::NewMeasurement(_, measurement, _)) => {
+                assert_eq!(measurement.offset, NtpDuration::from_seconds(0.0));
+                assert_eq!(measurement.delay, NtpDuration::from_seconds(20.0));
+                assert_eq!(measurement.localtime, timestamp(110));
+            }
+            other => panic!("expected a measurement, got {other:?}"),
+        }
+
+        // like any response, an interleaved response is only accepted once
+        let update = peer.handle_incoming(system, &data, base, timestamp(200), timestamp(221));
+        assert!(matches!(update, Err(IgnoreReason::InvalidPacketTime)));
+
+        // a server that does not support interleaved mode answers in basic mode
+        let mut buf = [0; 1024];
+        let data = peer
+            .generate_poll_message(&mut buf, system, &config)
+            .unwrap();
+        let request = NtpPacket::deserialize(data, None).unwrap();
+        assert_eq!(request.origin_timestamp(), timestamp(210));
+        assert_ne!(request.receive_timestamp(), timestamp(220));
+
+        let mut response = NtpPacket::test();
+        response.set_mode(NtpAssociationMode::Server);
+        response.set_stratum(1);
+        response.set_origin_timestamp(request.transmit_timestamp());
+        response.set_receive_timestamp(timestamp(310));
+        response.set_transmit_timestamp(timestamp(310));
+        let update = peer.handle_incoming(
+            system,
+            &response.serialize_without_encryption_vec().unwrap(),
+            base,
+            timestamp(300),
+            timestamp(320),
+        );
+        match update {
+            Ok(Update::NewMeasurement(_, measurement, _)) => {
+                assert_eq!(measurement.offset, NtpDuration::from_seconds(0.0));
+                assert_eq!(measurement.delay, NtpDuration::from_seconds(20.0));
+                assert_eq!(measurement.localtime, timestamp(310));
+            }
+            other => panic!("expected a measurement, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_symmetric_exchange() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let timestamp = |seconds: u64| NtpTimestamp::from_fixed_int(seconds << 32);
+
+        let mut active = Peer::new_symmetric(
+            ReferenceId::from_int(1),
+            ReferenceId::from_int(2),
+            base,
+            config,
+            SymmetricMode::Active,
+            None,
+        );
+        let mut passive = Peer::new_symmetric(
+            ReferenceId::from_int(2),
+            ReferenceId::from_int(1),
+            base,
+            config,
+            SymmetricMode::Passive,
+            None,
+        );
+
+        // the first packet of the active side is not a response, but the passive side
+        // remembers its timestamps
+        let mut buf = [0; 1024];
+        let data = active
+            .generate_symmetric_message(&mut buf, system, &config, timestamp(100))
+            .unwrap()
+            .to_vec();
+        let packet = NtpPacket::deserialize(&data, None).unwrap();
+        assert_eq!(packet.mode(), NtpAssociationMode::SymmetricActive);
+        assert_eq!(packet.transmit_timestamp(), timestamp(100));
+
+        let update =
+            passive.handle_incoming(system, &data, base, NtpTimestamp::default(), timestamp(150));
+        assert!(matches!(update, Ok(Update::BareUpdate(_))));
+
+        // a duplicate is ignored
+        let update =
+            passive.handle_incoming(system, &data, base, NtpTimestamp::default(), timestamp(160));
+        assert!(matches!(update, Err(IgnoreReason::InvalidPacketTime)));
+
+        // the response of the passive side yields a measurement for the active side
+        let mut buf = [0; 1024];
+        let data = passive
+            .generate_symmetric_message(&mut buf, system, &config, timestamp(200))
+            .unwrap()
+            .to_vec();
+        let packet = NtpPacket::deserialize(&data, None).unwrap();
+        assert_eq!(packet.mode(), NtpAssociationMode::SymmetricPassive);
+        assert_eq!(packet.origin_timestamp(), timestamp(100));
+        assert_eq!(packet.receive_timestamp(), timestamp(150));
+
+        let update = active.handle_incoming(system, &data, base, timestamp(100), timestamp(250));
+        match update {
+            Ok(Update::NewMeasurement(_, measurement, _)) => {
+                assert_eq!(measurement.offset, NtpDuration::from_seconds(0.0));
+                assert_eq!(measurement.delay, NtpDuration::from_seconds(100.0));
+            }
+            other => panic!("expected a measurement, got {other:?}"),
+        }
+
+        // a client/server packet does not belong to a symmetric association
+        let mut response = NtpPacket::test();
+        response.set_mode(NtpAssociationMode::Server);
+        response.set_stratum(1);
+        response.set_transmit_timestamp(timestamp(300));
+        let update = active.handle_incoming(
+            system,
+            &response.serialize_without_encryption_vec().unwrap(),
+            base,
+            timestamp(100),
+            timestamp(350),
+        );
+        assert!(matches!(update, Err(IgnoreReason::InvalidMode)));
+    }
+
+    #[test]
+    fn test_broadcast_client() {
+        use crate::symmetric_key::SymmetricKeyAlgorithm;
+
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let timestamp = |seconds: u64| NtpTimestamp::from_fixed_int(seconds << 32);
+        let key = SymmetricKey::new(1, SymmetricKeyAlgorithm::Sha1, b"secret".to_vec()).unwrap();
+
+        let mut peer = Peer::new_broadcast_client(
+            ReferenceId::from_int(1),
+            ReferenceId::from_int(2),
+            base,
+            config,
+            key.clone(),
+        );
+
+        let serialize_with_mac = |packet: &NtpPacket| {
+            let mut buf = [0; 1024];
+            let mut cursor = Cursor::new(buf.as_mut_slice());
+            packet.serialize_with_mac(&mut cursor, None, &key).unwrap();
+            let length = cursor.position() as usize;
+            buf[..length].to_vec()
+        };
+
+        let mut broadcast = NtpPacket::test();
+        broadcast.set_mode(NtpAssociationMode::Broadcast);
+        broadcast.set_stratum(1);
+        broadcast.set_transmit_timestamp(timestamp(10));
+
+        // unauthenticated broadcasts are ignored
+        let update = peer.handle_incoming(
+            system,
+            &broadcast.serialize_without_encryption_vec().unwrap(),
+            base,
+            NtpTimestamp::default(),
+            timestamp(12),
+        );
+        assert!(matches!(update, Err(IgnoreReason::InvalidMac)));
+
+        // before the delay is calibrated, broadcasts only show that the server is reachable
+        let first_broadcast = serialize_with_mac(&broadcast);
+        let update = peer.handle_incoming(
+            system,
+            &first_broadcast,
+            base,
+            NtpTimestamp::default(),
+            timestamp(12),
+        );
+        assert!(matches!(update, Ok(Update::BareUpdate(_))));
+
+        let calibrate = |peer: &mut Peer, round_trips: [u64; 4]| {
+            for round_trip in round_trips {
+                assert!(peer.wants_poll());
+
+                let mut buf = [0; 1024];
+                let request = peer
+                    .generate_poll_message(&mut buf, system, &config)
+                    .unwrap();
+                let request = NtpPacket::deserialize(request, None).unwrap();
+
+                let mut response = NtpPacket::test();
+                response.set_mode(NtpAssociationMode::Server);
+                response.set_stratum(1);
+                response.set_origin_timestamp(request.transmit_timestamp());
+                response.set_receive_timestamp(timestamp(101));
+                response.set_transmit_timestamp(timestamp(101));
+
+                let update = peer.handle_incoming(
+                    system,
+                    &serialize_with_mac(&response),
+                    base,
+                    timestamp(100),
+                    timestamp(100 + round_trip),
+                );
+                assert!(matches!(update, Ok(Update::NewMeasurement(..))));
+            }
+            assert!(!peer.wants_poll());
+        };
+
+        // the smallest round trip delay is used
+        assert_eq!(BROADCAST_CALIBRATION_EXCHANGES, 4);
+        calibrate(&mut peer, [4, 2, 2, 2]);
+
+        broadcast.set_transmit_timestamp(timestamp(200));
+        let data = serialize_with_mac(&broadcast);
+        let update =
+            peer.handle_incoming(system, &data, base, NtpTimestamp::default(), timestamp(203));
+        match update {
+            Ok(Update::NewMeasurement(_, measurement, _)) => {
+                assert_eq!(measurement.delay, NtpDuration::from_seconds(2.0));
+                assert_eq!(measurement.offset, NtpDuration::from_seconds(-2.0));
+            }
+            other => panic!("expected a measurement, got {other:?}"),
+        }
+
+        // a duplicate broadcast is ignored
+        let update =
+            peer.handle_incoming(system, &data, base, NtpTimestamp::default(), timestamp(204));
+        assert!(matches!(update, Err(IgnoreReason::InvalidPacketTime)));
+
+        // so is an earlier broadcast that is replayed
+        let update = peer.handle_incoming(
+            system,
+            &first_broadcast,
+            base,
+            NtpTimestamp::default(),
+            timestamp(205),
+        );
+        assert!(matches!(update, Err(IgnoreReason::InvalidPacketTime)));
+
+        // after a while, the delay is calibrated again
+        for _ in 0..BROADCAST_RECALIBRATION_POLLS {
+            assert!(!peer.wants_poll());
+            peer.skip_poll();
+        }
+        assert!(peer.wants_poll());
+
+        // the previous delay remains in use during the calibration
+        broadcast.set_transmit_timestamp(timestamp(300));
+        let update = peer.handle_incoming(
+            system,
+            &serialize_with_mac(&broadcast),
+            base,
+            NtpTimestamp::default(),
+            timestamp(303),
+        );
+        match update {
+            Ok(Update::NewMeasurement(_, measurement, _)) => {
+                assert_eq!(measurement.delay, NtpDuration::from_seconds(2.0));
+            }
+            other => panic!("expected a measurement, got {other:?}"),
+        }
+
+        calibrate(&mut peer, [6, 4, 8, 4]);
+
+        broadcast.set_transmit_timestamp(timestamp(400));
+        let update = peer.handle_incoming(
+            system,
+            &serialize_with_mac(&broadcast),
+            base,
+            NtpTimestamp::default(),
+            timestamp(404),
+        );
+        match update {
+            Ok(Update::NewMeasurement(_, measurement, _)) => {
+                assert_eq!(measurement.delay, NtpDuration::from_seconds(4.0));
+                assert_eq!(measurement.offset, NtpDuration::from_seconds(-2.0));
+            }
+            other => panic!("expected a measurement, got {other:?}"),
+        }
+    }
+
     #[test]
     fn test_stratum_checks() {
         let base = NtpInstant::now();
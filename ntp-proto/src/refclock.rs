@@ -0,0 +1,174 @@
+use crate::{
+    packet::NtpLeapIndicator,
+    peer::{Measurement, PeerSnapshot, Reach},
+    time_types::PollInterval,
+    NtpDuration, NtpInstant, NtpPacket, NtpTimestamp, ReferenceId,
+};
+
+/// A single reading of a local reference clock, such as a GPS receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefClockSample {
+    /// The time according to the reference clock
+    pub reference_time: NtpTimestamp,
+    /// The time according to the system clock at the moment the reference clock was read
+    pub local_time: NtpTimestamp,
+    pub leap: NtpLeapIndicator,
+    /// Precision of the reference clock, as a power of two in seconds
+    pub precision: i8,
+}
+
+impl RefClockSample {
+    /// A sample of a pulse per second signal, of which the pulse is timestamped by the system
+    /// clock. A pulse marks the start of a second, but does not tell which second. The second is
+    /// numbered by the system clock, so that clock must be kept within half a second of the
+    /// true time by a coarse time source, such as a network peer or another reference clock.
+    pub fn from_pulse(pulse: NtpTimestamp, leap: NtpLeapIndicator, precision: i8) -> Self {
+        Self {
+            reference_time: pulse.nearest_second(),
+            local_time: pulse,
+            leap,
+            precision,
+        }
+    }
+}
+
+/// A local reference clock as a time source for the clock algorithm. Its samples are
+/// presented to the algorithm as measurements of a stratum 0 server, so that the system
+/// synchronized to it becomes a stratum 1 server.
+#[derive(Debug)]
+pub struct RefClockSource {
+    reference_id: ReferenceId,
+    reach: Reach,
+    poll_interval: PollInterval,
+}
+
+impl RefClockSource {
+    pub fn new(reference_id: ReferenceId) -> Self {
+        Self {
+            reference_id,
+            reach: Reach::default(),
+            poll_interval: PollInterval::default(),
+        }
+    }
+
+    /// Called once every poll interval, before the reference clock is read. The clock becomes
+    /// unreachable when it provides no samples for a number of polls.
+    pub fn poll(&mut self, poll_interval: PollInterval) {
+        self.reach.poll();
+        self.poll_interval = poll_interval;
+    }
+
+    /// The measurement and packet with which a sample is handed to the clock algorithm
+    pub fn handle_sample(
+        &mut self,
+        sample: RefClockSample,
+        local_clock_time: NtpInstant,
+    ) -> (Measurement, NtpPacket<'static>) {
+        self.reach.received_packet();
+
+        let measurement = Measurement {
+            // the sample is taken locally, so there is no network delay
+            delay: NtpDuration::ZERO,
+            offset: sample.reference_time - sample.local_time,
+            localtime: sample.local_time,
+            monotime: local_clock_time,
+        };
+
+        let packet = NtpPacket::reference_clock_sample(
+            self.reference_id,
+            sample.leap,
+            sample.precision,
+            self.poll_interval,
+            sample.reference_time,
+        );
+
+        (measurement, packet)
+    }
+
+    pub fn snapshot(&self) -> PeerSnapshot {
+        PeerSnapshot {
+            peer_id: self.reference_id,
+            our_id: ReferenceId::NONE,
+            poll_interval: self.poll_interval,
+            reach: self.reach,
+            stratum: 0,
+            reference_id: self.reference_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_as_measurement() {
+        let mut source = RefClockSource::new(ReferenceId::from_bytes(*b"GPS\0"));
+        assert!(!source.snapshot().reach.is_reachable());
+
+        let local_time = NtpTimestamp::from_seconds_nanos_since_ntp_era(100, 0);
+        let sample = RefClockSample {
+            reference_time: local_time + NtpDuration::from_seconds(0.25),
+            local_time,
+            leap: NtpLeapIndicator::NoWarning,
+            precision: -20,
+        };
+
+        source.poll(PollInterval::default());
+        let (measurement, packet) = source.handle_sample(sample, NtpInstant::now());
+
+        assert_eq!(measurement.delay, NtpDuration::ZERO);
+        assert_eq!(measurement.offset, NtpDuration::from_seconds(0.25));
+        assert_eq!(measurement.localtime, local_time);
+
+        assert_eq!(packet.stratum(), 0);
+        assert_eq!(packet.precision(), -20);
+        assert_eq!(packet.reference_id(), ReferenceId::from_bytes(*b"GPS\0"));
+        assert_eq!(packet.receive_timestamp(), sample.reference_time);
+        assert_eq!(packet.transmit_timestamp(), sample.reference_time);
+
+        let snapshot = source.snapshot();
+        assert!(snapshot.reach.is_reachable());
+        // a system synchronized to the reference clock is a stratum 1 server
+        assert!(snapshot.accept_synchronization(16).is_ok());
+    }
+
+    #[test]
+    fn test_pulse_numbering() {
+        let second = NtpTimestamp::from_seconds_nanos_since_ntp_era(100, 0);
+
+        // the system clock is ahead, so the pulse arrives after the start of the second
+        let pulse = second + NtpDuration::from_seconds(0.001);
+        let sample = RefClockSample::from_pulse(pulse, NtpLeapIndicator::NoWarning, -20);
+        assert_eq!(sample.reference_time, second);
+        assert_eq!(sample.local_time, pulse);
+        assert_eq!(sample.precision, -20);
+
+        // the system clock is behind, so the pulse arrives before the start of the second
+        let pulse = second - NtpDuration::from_seconds(0.001);
+        let sample = RefClockSample::from_pulse(pulse, NtpLeapIndicator::NoWarning, -20);
+        assert_eq!(sample.reference_time, second);
+    }
+
+    #[test]
+    fn test_unreachable_without_samples() {
+        let mut source = RefClockSource::new(ReferenceId::from_bytes(*b"SHM\0"));
+
+        let sample = RefClockSample {
+            reference_time: NtpTimestamp::default(),
+            local_time: NtpTimestamp::default(),
+            leap: NtpLeapIndicator::NoWarning,
+            precision: -20,
+        };
+        source.poll(PollInterval::default());
+        source.handle_sample(sample, NtpInstant::now());
+
+        for _ in 0..7 {
+            source.poll(PollInterval::default());
+            assert!(source.snapshot().reach.is_reachable());
+        }
+
+        source.poll(PollInterval::default());
+        assert!(!source.snapshot().reach.is_reachable());
+    }
+}
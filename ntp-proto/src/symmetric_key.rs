@@ -0,0 +1,212 @@
+use std::{fmt, str::FromStr};
+
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+/// The algorithms that can be used to authenticate packets with a symmetric key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymmetricKeyAlgorithm {
+    /// The MD5 digest of the key followed by the packet (RFC 5905)
+    Md5,
+    /// The SHA-1 digest of the key followed by the packet, as used by ntpd
+    Sha1,
+    /// AES-CMAC with a 128 bit key (RFC 8573)
+    Aes128Cmac,
+}
+
+impl SymmetricKeyAlgorithm {
+    fn key_length(&self) -> Option<usize> {
+        match self {
+            SymmetricKeyAlgorithm::Md5 | SymmetricKeyAlgorithm::Sha1 => None,
+            SymmetricKeyAlgorithm::Aes128Cmac => Some(16),
+        }
+    }
+}
+
+impl FromStr for SymmetricKeyAlgorithm {
+    type Err = InvalidKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "MD5" => Ok(SymmetricKeyAlgorithm::Md5),
+            "SHA1" => Ok(SymmetricKeyAlgorithm::Sha1),
+            "AES128CMAC" | "AES-128-CMAC" | "AES128" => Ok(SymmetricKeyAlgorithm::Aes128Cmac),
+            _ => Err(InvalidKeyError::UnknownAlgorithm(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum InvalidKeyError {
+    #[error("unknown algorithm {0}")]
+    UnknownAlgorithm(String),
+    #[error("key id 0 is reserved")]
+    ReservedKeyId,
+    #[error("the secret should be {expected} bytes long, not {actual}")]
+    InvalidLength { expected: usize, actual: usize },
+    #[error("the secret should not be empty")]
+    EmptySecret,
+}
+
+/// A key shared between a client and a server to authenticate their packets
+#[derive(Clone, PartialEq, Eq)]
+pub struct SymmetricKey {
+    id: u32,
+    algorithm: SymmetricKeyAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl SymmetricKey {
+    pub fn new(
+        id: u32,
+        algorithm: SymmetricKeyAlgorithm,
+        secret: Vec<u8>,
+    ) -> Result<Self, InvalidKeyError> {
+        // a MAC with key id 0 signals a crypto-NAK
+        if id == 0 {
+            return Err(InvalidKeyError::ReservedKeyId);
+        }
+
+        match algorithm.key_length() {
+            Some(expected) if expected != secret.len() => {
+                return Err(InvalidKeyError::InvalidLength {
+                    expected,
+                    actual: secret.len(),
+                })
+            }
+            None if secret.is_empty() => return Err(InvalidKeyError::EmptySecret),
+            _ => {}
+        }
+
+        Ok(Self {
+            id,
+            algorithm,
+            secret,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn algorithm(&self) -> SymmetricKeyAlgorithm {
+        self.algorithm
+    }
+
+    /// Compute the MAC of `data`
+    pub(crate) fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            SymmetricKeyAlgorithm::Md5 => {
+                let mut hasher = Md5::new();
+                hasher.update(&self.secret);
+                hasher.update(data);
+                hasher.finalize().to_vec()
+            }
+            SymmetricKeyAlgorithm::Sha1 => {
+                let mut hasher = Sha1::new();
+                hasher.update(&self.secret);
+                hasher.update(data);
+                hasher.finalize().to_vec()
+            }
+            SymmetricKeyAlgorithm::Aes128Cmac => {
+                // the key length is checked on construction
+                let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(&self.secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Check in constant time that `mac` is the MAC of `data`
+    pub(crate) fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        self.sign(data).ct_eq(mac).into()
+    }
+}
+
+impl fmt::Debug for SymmetricKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SymmetricKey")
+            .field("id", &self.id)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_algorithm() {
+        assert_eq!("md5".parse(), Ok(SymmetricKeyAlgorithm::Md5));
+        assert_eq!("SHA1".parse(), Ok(SymmetricKeyAlgorithm::Sha1));
+        assert_eq!("AES128CMAC".parse(), Ok(SymmetricKeyAlgorithm::Aes128Cmac));
+        assert!("sha256".parse::<SymmetricKeyAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn invalid_keys() {
+        use SymmetricKeyAlgorithm::*;
+
+        assert_eq!(
+            SymmetricKey::new(0, Md5, b"secret".to_vec()),
+            Err(InvalidKeyError::ReservedKeyId)
+        );
+        assert_eq!(
+            SymmetricKey::new(1, Sha1, vec![]),
+            Err(InvalidKeyError::EmptySecret)
+        );
+        assert_eq!(
+            SymmetricKey::new(1, Aes128Cmac, vec![0; 20]),
+            Err(InvalidKeyError::InvalidLength {
+                expected: 16,
+                actual: 20
+            })
+        );
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        for (algorithm, secret, length) in [
+            (SymmetricKeyAlgorithm::Md5, b"secret".to_vec(), 16),
+            (SymmetricKeyAlgorithm::Sha1, b"secret".to_vec(), 20),
+            (SymmetricKeyAlgorithm::Aes128Cmac, vec![7; 16], 16),
+        ] {
+            let key = SymmetricKey::new(1, algorithm, secret).unwrap();
+
+            let mac = key.sign(b"packet");
+            assert_eq!(mac.len(), length);
+            assert!(key.verify(b"packet", &mac));
+            assert!(!key.verify(b"packet!", &mac));
+            assert!(!key.verify(b"packet", &mac[1..]));
+        }
+    }
+
+    #[test]
+    fn aes_cmac_rfc4493() {
+        // test vector from RFC 4493, example 2
+        let key = SymmetricKey::new(
+            1,
+            SymmetricKeyAlgorithm::Aes128Cmac,
+            vec![
+                0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+                0x4f, 0x3c,
+            ],
+        )
+        .unwrap();
+
+        let message = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+        let expected = [
+            0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+            0x28, 0x7c,
+        ];
+
+        assert_eq!(key.sign(&message), expected);
+    }
+}
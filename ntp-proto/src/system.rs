@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{NtpDuration, NtpLeapIndicator, PeerSnapshot, PollInterval, ReferenceId, SystemConfig};
+use crate::{
+    LeapSecond, NtpDuration, NtpLeapIndicator, PeerSnapshot, PollInterval, ReferenceId,
+    SystemConfig,
+};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct TimeSnapshot {
@@ -16,6 +19,10 @@ pub struct TimeSnapshot {
     pub leap_indicator: NtpLeapIndicator,
     /// Total amount that the clock has stepped
     pub accumulated_steps: NtpDuration,
+    /// Next leap second scheduled by the leap seconds file
+    pub next_leap: Option<LeapSecond>,
+    /// Current difference between TAI and UTC, according to the leap seconds file
+    pub tai_offset: Option<i32>,
 }
 
 impl Default for TimeSnapshot {
@@ -27,6 +34,8 @@ impl Default for TimeSnapshot {
             root_dispersion: NtpDuration::ZERO,
             leap_indicator: NtpLeapIndicator::Unknown,
             accumulated_steps: NtpDuration::ZERO,
+            next_leap: None,
+            tai_offset: None,
         }
     }
 }
@@ -98,6 +98,13 @@ impl NtpTimestamp {
         NtpTimestamp::from_bits(timestamp.to_be_bytes())
     }
 
+    /// The whole second that is nearest to this timestamp
+    pub(crate) const fn nearest_second(self) -> Self {
+        NtpTimestamp {
+            timestamp: self.timestamp.wrapping_add(1 << 31) & !0xFFFF_FFFF,
+        }
+    }
+
     #[cfg(any(test, feature = "fuzz"))]
     pub(crate) const fn from_fixed_int(timestamp: u64) -> NtpTimestamp {
         NtpTimestamp { timestamp }
@@ -12,25 +12,69 @@ use std::mem;
 use std::net::SocketAddr;
 use std::option::Option;
 
-#[allow(dead_code)]
-pub fn interface_name(local_addr: SocketAddr) -> std::io::Result<Option<[libc::c_char; 16]>> {
-    let matches_inferface = |interface: &InterfaceAddress| match interface.address {
-        None => false,
-        Some(address) => address.ip() == local_addr.ip(),
-    };
+/// The name of a network interface, such as `eth0`
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InterfaceName {
+    // always ends in a nul byte
+    bytes: [u8; libc::IFNAMSIZ],
+}
+
+impl InterfaceName {
+    pub(crate) fn as_ifr_name(&self) -> [libc::c_char; libc::IFNAMSIZ] {
+        self.bytes.map(|byte| byte as libc::c_char)
+    }
 
-    if let Some(interface) = getifaddrs()?.find(matches_inferface) {
-        let mut ifrn_name = [0; 16];
+    fn as_str(&self) -> &str {
+        let len = self.bytes.iter().position(|byte| *byte == 0).unwrap_or(0);
 
-        let name = interface.interface_name;
+        // only ever constructed from a str
+        std::str::from_utf8(&self.bytes[..len]).unwrap_or_default()
+    }
+}
 
-        for (source, target) in name.as_bytes().iter().zip(ifrn_name.iter_mut()) {
-            *target = *source as libc::c_char;
+impl std::str::FromStr for InterfaceName {
+    type Err = std::io::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        // the name must fit in an ifreq, including the closing nul byte
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid network interface name",
+            ));
         }
 
-        Ok(Some(ifrn_name))
-    } else {
-        Ok(None)
+        let mut bytes = [0; libc::IFNAMSIZ];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+
+        Ok(InterfaceName { bytes })
+    }
+}
+
+impl std::fmt::Display for InterfaceName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::fmt::Debug for InterfaceName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("InterfaceName")
+            .field(&self.as_str())
+            .finish()
+    }
+}
+
+/// The interface that has the (ip address of the) given local address
+pub fn interface_name(local_addr: SocketAddr) -> std::io::Result<Option<InterfaceName>> {
+    let matches_inferface = |interface: &InterfaceAddress| match interface.address {
+        None => false,
+        Some(address) => address.ip() == local_addr.ip(),
+    };
+
+    match getifaddrs()?.find(matches_inferface) {
+        Some(interface) => interface.interface_name.parse().map(Some),
+        None => Ok(None),
     }
 }
 
@@ -183,6 +227,17 @@ mod tests {
         assert!(name.is_some());
     }
 
+    #[test]
+    fn parse_interface_name() {
+        let name: InterfaceName = "eth0".parse().unwrap();
+        assert_eq!(name.to_string(), "eth0");
+        assert_eq!(name.as_ifr_name()[..5], [101, 116, 104, 48, 0]);
+
+        assert!("".parse::<InterfaceName>().is_err());
+        assert!("a-very-long-name".parse::<InterfaceName>().is_err());
+        assert!("eth\u{0}0".parse::<InterfaceName>().is_err());
+    }
+
     #[test]
     fn decode_socket_addr_v4() {
         let sockaddr = libc::sockaddr {
@@ -4,4 +4,5 @@ mod interface_name;
 mod raw_socket;
 mod socket;
 
+pub use interface_name::InterfaceName;
 pub use socket::UdpSocket;
@@ -12,7 +12,10 @@
 /// All unsafe blocks are preceded with a comment explaining why that
 /// specific unsafe code should be safe within the context in which it
 /// is used.
+pub(crate) use bind_reuse_address::bind_reuse_address;
 pub(crate) use exceptional_condition_fd::exceptional_condition_fd;
+pub(crate) use hardware_timestamping::{bind_to_device, enable_hardware_timestamping, phc_index};
+pub(crate) use ptp_clock::PtpClock;
 pub(crate) use recv_message::{
     control_message_space, receive_message, ControlMessage, MessageQueue,
 };
@@ -51,12 +54,27 @@ mod set_timestamping_options {
         }
 
         if timestamping.tx_software {
+            options |= libc::SOF_TIMESTAMPING_TX_SOFTWARE;
+        }
+
+        if timestamping.rx_hardware || timestamping.tx_hardware {
+            // report the timestamps of the network card as they are, in the time of its clock
+            options |= libc::SOF_TIMESTAMPING_RAW_HARDWARE
+        }
+
+        if timestamping.rx_hardware {
+            options |= libc::SOF_TIMESTAMPING_RX_HARDWARE
+        }
+
+        if timestamping.tx_hardware {
+            options |= libc::SOF_TIMESTAMPING_TX_HARDWARE
+        }
+
+        if timestamping.tx_software || timestamping.tx_hardware {
             // - we want send timestamps
             // - return just the timestamp, don't send the full message along
             // - tag the timestamp with an ID
-            options |= libc::SOF_TIMESTAMPING_TX_SOFTWARE
-                | libc::SOF_TIMESTAMPING_OPT_TSONLY
-                | libc::SOF_TIMESTAMPING_OPT_ID;
+            options |= libc::SOF_TIMESTAMPING_OPT_TSONLY | libc::SOF_TIMESTAMPING_OPT_ID;
         }
 
         // for documentation on SO_TIMESTAMPING see
@@ -80,6 +98,102 @@ mod set_timestamping_options {
     }
 }
 
+mod bind_reuse_address {
+    use std::{net::SocketAddr, os::unix::prelude::FromRawFd};
+
+    use super::cerr;
+
+    /// Bind a new non-blocking UDP socket to `addr` with SO_REUSEADDR set. Sockets that all
+    /// set this option may bind the same port, as long as their addresses differ, for
+    /// instance a server on the unspecified address and a broadcast client on a broadcast
+    /// address.
+    pub(crate) fn bind_reuse_address(addr: SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+
+        // Safety:
+        // socket is safe to call with any arguments
+        let fd = cerr(unsafe {
+            libc::socket(
+                domain,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        })?;
+
+        // Safety:
+        // fd is a valid file descriptor that nothing else owns, so the socket can take
+        // ownership of it. From here on the socket closes the fd, also on errors.
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+        let enable: libc::c_int = 1;
+
+        // Safety:
+        // fd is valid for as long as socket exists. SOL_SOCKET + SO_REUSEADDR expect a *c_int
+        // as value, which &enable is. We own enable, hence the pointer is valid for the
+        // duration of the call, and option_len is the size of a c_int.
+        cerr(unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                &enable as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sockaddr = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+
+                // Safety:
+                // fd is valid for as long as socket exists, and the pointer to sockaddr, which
+                // we own, is valid for the duration of the call for the given length
+                cerr(unsafe {
+                    libc::bind(
+                        fd,
+                        &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                        std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    )
+                })?;
+            }
+            SocketAddr::V6(addr) => {
+                let sockaddr = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: addr.port().to_be(),
+                    sin6_flowinfo: addr.flowinfo(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    },
+                    sin6_scope_id: addr.scope_id(),
+                };
+
+                // Safety:
+                // fd is valid for as long as socket exists, and the pointer to sockaddr, which
+                // we own, is valid for the duration of the call for the given length
+                cerr(unsafe {
+                    libc::bind(
+                        fd,
+                        &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                        std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    )
+                })?;
+            }
+        }
+
+        Ok(socket)
+    }
+}
+
 mod recv_message {
     use std::{io::IoSliceMut, marker::PhantomData, net::SocketAddr, os::unix::prelude::AsRawFd};
 
@@ -212,7 +326,12 @@ mod recv_message {
     }
 
     pub(crate) enum ControlMessage {
-        Timestamping(libc::timespec),
+        /// The software and raw hardware timestamps of a packet. A timestamp that was not
+        /// generated is zero.
+        Timestamping {
+            software: libc::timespec,
+            hardware: libc::timespec,
+        },
         ReceiveError(libc::sock_extended_err),
         Other(libc::cmsghdr),
     }
@@ -242,11 +361,12 @@ mod recv_message {
                     // Safety:
                     // current_msg was constructed from a pointer that pointed to a valid
                     // control message.
-                    // SO_TIMESTAMPING always has a timespec in the data
+                    // SO_TIMESTAMPING always has a `struct scm_timestamping` in the data, which
+                    // holds three timespecs: software, deprecated, and raw hardware
                     let cmsg_data =
-                        unsafe { libc::CMSG_DATA(current_msg) } as *const libc::timespec;
-                    let timespec = unsafe { std::ptr::read_unaligned(cmsg_data) };
-                    ControlMessage::Timestamping(timespec)
+                        unsafe { libc::CMSG_DATA(current_msg) } as *const [libc::timespec; 3];
+                    let [software, _, hardware] = unsafe { std::ptr::read_unaligned(cmsg_data) };
+                    ControlMessage::Timestamping { software, hardware }
                 }
 
                 (libc::SOL_IP, libc::IP_RECVERR) | (libc::SOL_IPV6, libc::IPV6_RECVERR) => {
@@ -292,19 +412,21 @@ mod timestamping_config {
     pub(crate) struct TimestampingConfig {
         pub(crate) rx_software: bool,
         pub(crate) tx_software: bool,
+        pub(crate) rx_hardware: bool,
+        pub(crate) tx_hardware: bool,
     }
 
     #[repr(C)]
     #[allow(non_camel_case_types)]
     #[derive(Default)]
-    struct ethtool_ts_info {
-        cmd: u32,
-        so_timestamping: u32,
-        phc_index: u32,
-        tx_types: u32,
-        tx_reserved: [u32; 3],
-        rx_filters: u32,
-        rx_reserved: [u32; 3],
+    pub(super) struct ethtool_ts_info {
+        pub(super) cmd: u32,
+        pub(super) so_timestamping: u32,
+        pub(super) phc_index: u32,
+        pub(super) tx_types: u32,
+        pub(super) tx_reserved: [u32; 3],
+        pub(super) rx_filters: u32,
+        pub(super) rx_reserved: [u32; 3],
     }
 
     impl TimestampingConfig {
@@ -322,9 +444,9 @@ mod timestamping_config {
 
             let fd = udp_socket.as_raw_fd();
 
-            if let Some(ifr_name) = interface_name::interface_name(udp_socket.local_addr()?)? {
+            if let Some(interface) = interface_name::interface_name(udp_socket.local_addr()?)? {
                 let ifr: libc::ifreq = libc::ifreq {
-                    ifr_name,
+                    ifr_name: interface.as_ifr_name(),
                     ifr_ifru: libc::__c_anonymous_ifr_ifru {
                         ifru_data: (&mut tsi as *mut _) as *mut libc::c_char,
                     },
@@ -333,9 +455,13 @@ mod timestamping_config {
                 const SIOCETHTOOL: u64 = 0x8946;
                 cerr(unsafe { libc::ioctl(fd, SIOCETHTOOL as libc::c_ulong, &ifr) }).unwrap();
 
+                // hardware timestamping must be enabled on the interface first, see
+                // `enable_hardware_timestamping`
                 let support = Self {
                     rx_software: tsi.so_timestamping & libc::SOF_TIMESTAMPING_RX_SOFTWARE != 0,
                     tx_software: tsi.so_timestamping & libc::SOF_TIMESTAMPING_TX_SOFTWARE != 0,
+                    rx_hardware: false,
+                    tx_hardware: false,
                 };
 
                 // per the documentation of `SOF_TIMESTAMPING_RX_SOFTWARE`:
@@ -354,6 +480,193 @@ mod timestamping_config {
     }
 }
 
+mod hardware_timestamping {
+    use std::os::unix::prelude::AsRawFd;
+
+    use super::{cerr, timestamping_config::ethtool_ts_info};
+    use crate::InterfaceName;
+
+    // from linux/sockios.h, linux/ethtool.h and linux/net_tstamp.h
+    const SIOCSHWTSTAMP: libc::c_ulong = 0x89b0;
+    const SIOCETHTOOL: libc::c_ulong = 0x8946;
+    const ETHTOOL_GET_TS_INFO: u32 = 0x41;
+    const HWTSTAMP_TX_ON: libc::c_int = 1;
+    const HWTSTAMP_FILTER_ALL: libc::c_int = 1;
+
+    #[repr(C)]
+    #[allow(non_camel_case_types)]
+    struct hwtstamp_config {
+        flags: libc::c_int,
+        tx_type: libc::c_int,
+        rx_filter: libc::c_int,
+    }
+
+    /// Make the network card of an interface timestamp all packets it sends and receives.
+    /// This fails when the network card (or its driver) does not support hardware
+    /// timestamping, and without the CAP_NET_ADMIN capability.
+    ///
+    /// Note that this is a setting of the interface, not of the socket: it also applies to
+    /// (and overrides the hardware timestamping settings of) every other program that uses
+    /// the interface.
+    pub(crate) fn enable_hardware_timestamping(
+        udp_socket: &std::net::UdpSocket,
+        interface: InterfaceName,
+    ) -> std::io::Result<()> {
+        let mut config = hwtstamp_config {
+            flags: 0,
+            tx_type: HWTSTAMP_TX_ON,
+            rx_filter: HWTSTAMP_FILTER_ALL,
+        };
+
+        let ifr = libc::ifreq {
+            ifr_name: interface.as_ifr_name(),
+            ifr_ifru: libc::__c_anonymous_ifr_ifru {
+                ifru_data: (&mut config as *mut hwtstamp_config).cast::<libc::c_char>(),
+            },
+        };
+
+        // for documentation on SIOCSHWTSTAMP see section 3.1 of
+        // https://www.kernel.org/doc/Documentation/networking/timestamping.txt
+        // Safety:
+        // we have a reference to the socket, so fd is a valid file descriptor for the duration of
+        // the call. SIOCSHWTSTAMP expects a pointer to an ifreq, whose ifru_data points to a
+        // hwtstamp_config. Both are owned by this function and hence valid for the duration of
+        // the call. The kernel writes the applied configuration back into config, which we own
+        // mutably.
+        cerr(unsafe { libc::ioctl(udp_socket.as_raw_fd(), SIOCSHWTSTAMP, &ifr) })?;
+
+        Ok(())
+    }
+
+    /// The index of the ptp hardware clock of the network card of an interface, which is the
+    /// clock that the hardware timestamps are read from. Without such a clock, the network
+    /// card cannot timestamp packets.
+    pub(crate) fn phc_index(
+        udp_socket: &std::net::UdpSocket,
+        interface: InterfaceName,
+    ) -> std::io::Result<Option<u32>> {
+        let mut info = ethtool_ts_info {
+            cmd: ETHTOOL_GET_TS_INFO,
+            ..Default::default()
+        };
+
+        let ifr = libc::ifreq {
+            ifr_name: interface.as_ifr_name(),
+            ifr_ifru: libc::__c_anonymous_ifr_ifru {
+                ifru_data: (&mut info as *mut ethtool_ts_info).cast::<libc::c_char>(),
+            },
+        };
+
+        // Safety:
+        // we have a reference to the socket, so fd is a valid file descriptor for the duration of
+        // the call. SIOCETHTOOL expects a pointer to an ifreq, whose ifru_data points to an
+        // ethtool command, here an ethtool_ts_info. Both are owned by this function and hence
+        // valid for the duration of the call. The kernel writes its answer into info, which we
+        // own mutably.
+        cerr(unsafe { libc::ioctl(udp_socket.as_raw_fd(), SIOCETHTOOL, &ifr) })?;
+
+        // an index of -1 means that there is no clock
+        Ok(i32::try_from(info.phc_index).ok().map(|index| index as u32))
+    }
+
+    /// Only send and receive packets through the given interface
+    pub(crate) fn bind_to_device(
+        udp_socket: &std::net::UdpSocket,
+        interface: InterfaceName,
+    ) -> std::io::Result<()> {
+        let name = interface.as_ifr_name();
+
+        // Safety:
+        // we have a reference to the socket, so fd is a valid file descriptor for the duration of
+        // the call. SO_BINDTODEVICE reads a nul-terminated interface name of at most optlen bytes
+        // from optval, which points to an array that is owned by this function.
+        cerr(unsafe {
+            libc::setsockopt(
+                udp_socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                name.as_ptr().cast(),
+                name.len() as libc::socklen_t,
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+mod ptp_clock {
+    use std::os::unix::prelude::AsRawFd;
+
+    use super::cerr;
+
+    // from linux/ptp_clock.h
+    const PTP_MAX_SAMPLES: usize = 25;
+    const PTP_SYS_OFFSET: libc::c_ulong = 0x43403d05;
+
+    #[repr(C)]
+    #[allow(non_camel_case_types)]
+    #[derive(Clone, Copy, Default)]
+    struct ptp_clock_time {
+        sec: i64,
+        nsec: u32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    #[allow(non_camel_case_types)]
+    struct ptp_sys_offset {
+        n_samples: u32,
+        rsv: [u32; 3],
+        ts: [ptp_clock_time; 2 * PTP_MAX_SAMPLES + 1],
+    }
+
+    /// The number of times the clocks are compared to determine their offset
+    const SAMPLES: usize = 5;
+
+    /// A ptp hardware clock, such as the clock of a network card, from which hardware
+    /// timestamps are read
+    #[derive(Debug)]
+    pub(crate) struct PtpClock {
+        device: std::fs::File,
+    }
+
+    impl PtpClock {
+        pub(crate) fn open(index: u32) -> std::io::Result<Self> {
+            let device = std::fs::File::open(format!("/dev/ptp{index}"))?;
+
+            Ok(PtpClock { device })
+        }
+
+        /// The time of this clock minus the time of the system clock, in nanoseconds
+        pub(crate) fn system_offset(&self) -> std::io::Result<i64> {
+            let mut offset = ptp_sys_offset {
+                n_samples: SAMPLES as u32,
+                rsv: [0; 3],
+                ts: [ptp_clock_time::default(); 2 * PTP_MAX_SAMPLES + 1],
+            };
+
+            // Safety:
+            // we have a reference to the device, so fd is a valid file descriptor for the
+            // duration of the call. PTP_SYS_OFFSET expects a pointer to a ptp_sys_offset, which
+            // is owned by this function, and writes 2 * n_samples + 1 timestamps into it, which
+            // fit because n_samples is at most PTP_MAX_SAMPLES.
+            cerr(unsafe { libc::ioctl(self.device.as_raw_fd(), PTP_SYS_OFFSET, &mut offset) })?;
+
+            let nanos = |time: ptp_clock_time| time.sec * 1_000_000_000 + time.nsec as i64;
+
+            // the timestamps alternate between the system clock and this clock. The sample for
+            // which the system clock was read closest together is the most precise.
+            let ts = &offset.ts;
+            let (system_before, clock, system_after) = (0..SAMPLES)
+                .map(|i| (nanos(ts[2 * i]), nanos(ts[2 * i + 1]), nanos(ts[2 * i + 2])))
+                .min_by_key(|(before, _, after)| after - before)
+                .unwrap_or_default();
+
+            Ok(clock - (system_before + system_after) / 2)
+        }
+    }
+}
+
 mod exceptional_condition_fd {
     use std::os::unix::prelude::{AsRawFd, RawFd};
 
@@ -1,14 +1,28 @@
 #![forbid(unsafe_code)]
 
-use std::{io, net::SocketAddr, os::unix::prelude::RawFd};
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::prelude::RawFd,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
 
 use ntp_proto::NtpTimestamp;
-use tokio::io::unix::AsyncFd;
-use tracing::{debug, instrument, trace, warn};
-
-use crate::raw_socket::{
-    control_message_space, exceptional_condition_fd, receive_message, set_timestamping_options,
-    ControlMessage, MessageQueue, TimestampingConfig,
+use tokio::{io::unix::AsyncFd, sync::Notify};
+use tracing::{debug, info, instrument, trace, warn};
+
+use crate::{
+    interface_name::interface_name,
+    raw_socket::{
+        bind_reuse_address, bind_to_device, control_message_space, enable_hardware_timestamping,
+        exceptional_condition_fd, phc_index, receive_message, set_timestamping_options,
+        ControlMessage, MessageQueue, PtpClock, TimestampingConfig,
+    },
+    InterfaceName,
 };
 
 enum Timestamping {
@@ -17,20 +31,30 @@ enum Timestamping {
     AllSupported,
 }
 
+/// The number of send timestamps kept for other users of a shared socket
+const MAX_STASHED_SEND_TIMESTAMPS: usize = 32;
+
 pub struct UdpSocket {
     io: AsyncFd<std::net::UdpSocket>,
     exceptional_condition: AsyncFd<RawFd>,
-    send_counter: u32,
+    send_counter: AtomicU32,
     timestamping: TimestampingConfig,
+    /// The clock of the network card that makes the hardware timestamps
+    hardware_clock: Option<PtpClock>,
+    /// Send timestamps read from the error queue that belong to a packet of another task
+    /// sharing this socket, by the id the kernel tagged them with
+    stashed_send_timestamps: Mutex<VecDeque<(u32, NtpTimestamp)>>,
+    send_timestamp_stashed: Notify,
 }
 
 impl UdpSocket {
     #[instrument(level = "debug", skip(peer_addr))]
     pub async fn client(listen_addr: SocketAddr, peer_addr: SocketAddr) -> io::Result<UdpSocket> {
-        // disable tx timestamping for now (outside of tests)
+        // our supported kernel versions always have receive and software send timestamping
         let timestamping = TimestampingConfig {
             rx_software: true,
-            tx_software: false,
+            tx_software: true,
+            ..Default::default()
         };
 
         Self::client_with_timestamping(
@@ -71,26 +95,72 @@ impl UdpSocket {
         Ok(UdpSocket {
             exceptional_condition: exceptional_condition_fd(&socket)?,
             io: AsyncFd::new(socket)?,
-            send_counter: 0,
+            send_counter: AtomicU32::new(0),
             timestamping,
+            hardware_clock: None,
+            stashed_send_timestamps: Mutex::new(VecDeque::new()),
+            send_timestamp_stashed: Notify::new(),
         })
     }
 
     #[instrument(level = "debug")]
     pub async fn server(listen_addr: SocketAddr) -> io::Result<UdpSocket> {
-        let socket = tokio::net::UdpSocket::bind(listen_addr).await?;
+        // a broadcast client may listen on the same port, see `broadcast_client`
+        let socket = bind_reuse_address(listen_addr)?;
         debug!(
             local_addr = debug(socket.local_addr().unwrap()),
             "server socket bound"
         );
 
-        let socket = socket.into_std()?;
+        // our supported kernel versions always have receive and software send timestamping. Send
+        // timestamps of responses are needed to serve clients in interleaved mode
+        let timestamping = TimestampingConfig {
+            rx_software: true,
+            tx_software: true,
+            ..Default::default()
+        };
+
+        set_timestamping_options(&socket, timestamping)?;
+
+        Ok(UdpSocket {
+            exceptional_condition: exceptional_condition_fd(&socket)?,
+            io: AsyncFd::new(socket)?,
+            send_counter: AtomicU32::new(0),
+            timestamping,
+            hardware_clock: None,
+            stashed_send_timestamps: Mutex::new(VecDeque::new()),
+            send_timestamp_stashed: Notify::new(),
+        })
+    }
+
+    /// A socket that receives the packets a broadcast server sends to `broadcast_addr`,
+    /// which is either a broadcast address or a multicast group. The socket is bound to
+    /// that address, so that it only receives those packets, and it can share its port
+    /// with a server socket.
+    #[instrument(level = "debug")]
+    pub async fn broadcast_client(broadcast_addr: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = bind_reuse_address(broadcast_addr)?;
+        debug!(
+            local_addr = debug(socket.local_addr().unwrap()),
+            "broadcast client socket bound"
+        );
+
+        match broadcast_addr.ip() {
+            IpAddr::V4(group) if group.is_multicast() => {
+                socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+                debug!(?group, "joined multicast group");
+            }
+            IpAddr::V6(group) if group.is_multicast() => {
+                socket.join_multicast_v6(&group, 0)?;
+                debug!(?group, "joined multicast group");
+            }
+            _ => {}
+        }
 
-        // our supported kernel versions always have receive timestamping. Send timestamping for a
-        // server connection is not relevant, so we don't even bother with checking if it is supported
         let timestamping = TimestampingConfig {
             rx_software: true,
             tx_software: false,
+            ..Default::default()
         };
 
         set_timestamping_options(&socket, timestamping)?;
@@ -98,11 +168,108 @@ impl UdpSocket {
         Ok(UdpSocket {
             exceptional_condition: exceptional_condition_fd(&socket)?,
             io: AsyncFd::new(socket)?,
-            send_counter: 0,
+            send_counter: AtomicU32::new(0),
             timestamping,
+            hardware_clock: None,
+            stashed_send_timestamps: Mutex::new(VecDeque::new()),
+            send_timestamp_stashed: Notify::new(),
         })
     }
 
+    /// Allow sending packets to broadcast addresses with `send_to`. Sending to a
+    /// multicast group needs no extra permissions.
+    pub fn enable_broadcast(&self) -> io::Result<()> {
+        let socket = self.io.get_ref();
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => socket.set_broadcast(true),
+            // IPv6 has no broadcast, only multicast
+            SocketAddr::V6(_) => Ok(()),
+        }
+    }
+
+    /// Let the network card timestamp packets instead of the kernel. This needs a network card
+    /// that supports hardware timestamping on the given interface, or when no interface is
+    /// given, on the interface of the local address of the socket. The socket then only sends
+    /// and receives packets through the given interface. Packets that the network card did not
+    /// timestamp still get a software timestamp.
+    ///
+    /// The network card timestamps packets with its own clock. Those timestamps are converted
+    /// to the system clock, so that they can be compared with software timestamps and the
+    /// current time. A packet whose timestamp cannot be converted gets no timestamp at all.
+    ///
+    /// Hardware timestamping is enabled for all packets on the interface, which also changes
+    /// the hardware timestamping settings of any other program that uses the interface.
+    pub fn enable_hardware_timestamping(
+        &mut self,
+        interface: Option<InterfaceName>,
+    ) -> io::Result<()> {
+        let socket = self.io.get_ref();
+
+        let interface = match interface {
+            Some(interface) => interface,
+            None => interface_name(socket.local_addr()?)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "socket is not bound to the address of a network interface",
+                )
+            })?,
+        };
+
+        let phc_index = phc_index(socket, interface)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "network card has no clock to timestamp packets with",
+            )
+        })?;
+        let hardware_clock = PtpClock::open(phc_index)?;
+
+        enable_hardware_timestamping(socket, interface)?;
+        info!(
+            %interface,
+            "enabled hardware timestamping of all packets on the interface, for all programs that use it"
+        );
+
+        // the timestamps must come from the network card that we enabled them on
+        bind_to_device(socket, interface)?;
+
+        let timestamping = TimestampingConfig {
+            rx_software: true,
+            // the network card and the kernel would both report a send timestamp
+            tx_software: false,
+            rx_hardware: true,
+            tx_hardware: self.send_timestamping(),
+        };
+
+        set_timestamping_options(socket, timestamping)?;
+        self.timestamping = timestamping;
+        self.hardware_clock = Some(hardware_clock);
+
+        debug!("hardware timestamping enabled");
+
+        Ok(())
+    }
+
+    /// Stop fetching the time at which packets were sent, so `send` and
+    /// `send_to_timestamped` always return `None` as the send timestamp.
+    pub fn disable_send_timestamping(&mut self) -> io::Result<()> {
+        let timestamping = TimestampingConfig {
+            tx_software: false,
+            tx_hardware: false,
+            ..self.timestamping
+        };
+
+        set_timestamping_options(self.io.get_ref(), timestamping)?;
+        self.timestamping = timestamping;
+
+        Ok(())
+    }
+
+    /// Whether `send` and `send_to_timestamped` try to fetch the time at which a packet
+    /// was sent
+    pub fn send_timestamping(&self) -> bool {
+        self.timestamping.tx_software || self.timestamping.tx_hardware
+    }
+
     #[instrument(level = "trace", skip(self, buf), fields(
         local_addr = debug(self.as_ref().local_addr().unwrap()),
         peer_addr = debug(self.as_ref().peer_addr()),
@@ -110,20 +277,29 @@ impl UdpSocket {
     ))]
     pub async fn send(&mut self, buf: &[u8]) -> io::Result<(usize, Option<NtpTimestamp>)> {
         let send_size = self.send_help(buf).await?;
-        let expected_counter = self.send_counter;
-        self.send_counter = self.send_counter.wrapping_add(1);
+        let expected_counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+
+        self.send_timestamp(send_size, expected_counter).await
+    }
 
-        if self.timestamping.tx_software {
+    async fn send_timestamp(
+        &self,
+        send_size: usize,
+        expected_counter: u32,
+    ) -> io::Result<(usize, Option<NtpTimestamp>)> {
+        if self.send_timestamping() {
             // the send timestamp may never come set a very short timeout to prevent hanging forever.
             // We automatically fall back to a less accurate timestamp when this function returns None
             let timeout = std::time::Duration::from_millis(10);
 
             match tokio::time::timeout(timeout, self.fetch_send_timestamp(expected_counter)).await {
                 Err(_) => {
-                    warn!("Packet without timestamp");
+                    debug!("Packet without timestamp");
                     Ok((send_size, None))
                 }
-                Ok(send_timestamp) => Ok((send_size, Some(send_timestamp?))),
+                // the packet itself was sent, so only the timestamp is lost
+                Ok(Err(_)) => Ok((send_size, None)),
+                Ok(Ok(send_timestamp)) => Ok((send_size, Some(send_timestamp))),
             }
         } else {
             trace!("send timestamping not supported");
@@ -157,6 +333,12 @@ impl UdpSocket {
     async fn fetch_send_timestamp(&self, expected_counter: u32) -> io::Result<NtpTimestamp> {
         trace!("waiting for timestamp socket to become readable to fetch a send timestamp");
         loop {
+            // another task that shares this socket may have read our timestamp already
+            let stashed = self.send_timestamp_stashed.notified();
+            if let Some(send_timestamp) = self.take_stashed_send_timestamp(expected_counter) {
+                return Ok(send_timestamp);
+            }
+
             // Send timestamps are sent to the udp socket's error queue. Sadly, tokio does not
             // currently support awaiting whether there is something in the error queue
             // see https://github.com/tokio-rs/tokio/issues/4885.
@@ -164,11 +346,21 @@ impl UdpSocket {
             // Therefore, we manually configure an extra file descriptor to listen for POLLPRI on
             // the main udp socket. This `exceptional_condition` file descriptor becomes readable
             // when there is something in the error queue.
-            let mut guard = self.exceptional_condition.readable().await?;
-            match guard.try_io(|_| fetch_send_timestamp_help(self.io.get_ref(), expected_counter)) {
-                Ok(Ok(Some(send_timestamp))) => {
+            let mut guard = tokio::select! {
+                guard = self.exceptional_condition.readable() => guard?,
+                _ = stashed => continue,
+            };
+            match guard.try_io(|_| {
+                fetch_send_timestamp_help(self.io.get_ref(), self.hardware_clock.as_ref())
+            }) {
+                Ok(Ok(Some((counter, send_timestamp)))) if counter == expected_counter => {
                     return Ok(send_timestamp);
                 }
+                Ok(Ok(Some((counter, send_timestamp)))) => {
+                    trace!(counter, expected_counter, "Timestamp for other packet");
+                    self.stash_send_timestamp(counter, send_timestamp);
+                    continue;
+                }
                 Ok(Ok(None)) => {
                     continue;
                 }
@@ -184,11 +376,103 @@ impl UdpSocket {
         }
     }
 
+    /// Keep a send timestamp for the task that sent the packet, which shares this socket
+    fn stash_send_timestamp(&self, counter: u32, send_timestamp: NtpTimestamp) {
+        let mut stashed = self.stashed_send_timestamps.lock().unwrap();
+        if stashed.len() >= MAX_STASHED_SEND_TIMESTAMPS {
+            // nobody waits for the oldest timestamps anymore
+            stashed.pop_front();
+        }
+        stashed.push_back((counter, send_timestamp));
+        drop(stashed);
+
+        self.send_timestamp_stashed.notify_waiters();
+    }
+
+    fn take_stashed_send_timestamp(&self, counter: u32) -> Option<NtpTimestamp> {
+        let mut stashed = self.stashed_send_timestamps.lock().unwrap();
+        let index = stashed.iter().position(|(c, _)| *c == counter)?;
+        stashed
+            .remove(index)
+            .map(|(_, send_timestamp)| send_timestamp)
+    }
+
+    /// Move the send timestamps that are waiting in the error queue to the stash, so that
+    /// the error queue does not fill up with timestamps that nobody fetches
+    fn drain_send_timestamps(&self) {
+        loop {
+            match fetch_send_timestamp_help(self.io.get_ref(), self.hardware_clock.as_ref()) {
+                Ok(Some((counter, send_timestamp))) => {
+                    self.stash_send_timestamp(counter, send_timestamp)
+                }
+                Ok(None) => continue,
+                // the error queue is empty
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Send a packet without fetching the time at which it was sent
     #[instrument(level = "trace", skip(self, buf), fields(
         local_addr = debug(self.as_ref().local_addr().unwrap()),
         buf_size = buf.len(),
     ))]
     pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let send_size = self.send_to_help(buf, addr).await?;
+        self.send_counter.fetch_add(1, Ordering::Relaxed);
+
+        if self.send_timestamping() {
+            self.drain_send_timestamps();
+        }
+
+        Ok(send_size)
+    }
+
+    /// Send a packet, and fetch the time at which it was sent. Waiting for that time takes
+    /// a little while, so only use this when the send timestamp is actually needed.
+    #[instrument(level = "trace", skip(self, buf), fields(
+        local_addr = debug(self.as_ref().local_addr().unwrap()),
+        buf_size = buf.len(),
+    ))]
+    pub async fn send_to_timestamped(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> io::Result<(usize, Option<NtpTimestamp>)> {
+        let send_size = self.send_to_help(buf, addr).await?;
+        let expected_counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+
+        self.send_timestamp(send_size, expected_counter).await
+    }
+
+    /// Send a packet, and return the id with which the time at which it was sent can be taken
+    /// later with `take_send_timestamp`, without waiting for that time now
+    #[instrument(level = "trace", skip(self, buf), fields(
+        local_addr = debug(self.as_ref().local_addr().unwrap()),
+        buf_size = buf.len(),
+    ))]
+    pub async fn send_to_with_id(&self, buf: &[u8], addr: SocketAddr) -> io::Result<(usize, u32)> {
+        let send_size = self.send_to_help(buf, addr).await?;
+        let id = self.send_counter.fetch_add(1, Ordering::Relaxed);
+
+        if self.send_timestamping() {
+            self.drain_send_timestamps();
+        }
+
+        Ok((send_size, id))
+    }
+
+    /// The time at which the packet with the given id was sent, if the kernel has provided
+    /// it by now. Only the most recent send timestamps are kept, so take it soon.
+    pub fn take_send_timestamp(&self, id: u32) -> Option<NtpTimestamp> {
+        if self.send_timestamping() {
+            self.drain_send_timestamps();
+        }
+
+        self.take_stashed_send_timestamp(id)
+    }
+
+    async fn send_to_help(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
         trace!(size = buf.len(), ?addr, "sending bytes");
         loop {
             let mut guard = self.io.writable().await?;
@@ -220,7 +504,9 @@ impl UdpSocket {
         loop {
             trace!("waiting for socket to become readable");
             let mut guard = self.io.readable().await?;
-            let result = match guard.try_io(|inner| recv(inner.get_ref(), buf)) {
+            let result = match guard
+                .try_io(|inner| recv(inner.get_ref(), buf, self.hardware_clock.as_ref()))
+            {
                 Err(_would_block) => {
                     trace!("blocked after becoming readable, retrying");
                     continue;
@@ -247,6 +533,7 @@ impl AsRef<std::net::UdpSocket> for UdpSocket {
 fn recv(
     socket: &std::net::UdpSocket,
     buf: &mut [u8],
+    hardware_clock: Option<&PtpClock>,
 ) -> io::Result<(usize, SocketAddr, Option<NtpTimestamp>)> {
     let mut control_buf = [0; control_message_space::<[libc::timespec; 3]>()];
 
@@ -259,10 +546,10 @@ fn recv(
     // Loops through the control messages, but we should only get a single message in practice
     for msg in control_messages {
         match msg {
-            ControlMessage::Timestamping(timespec) => {
-                let timestamp = read_ntp_timestamp(timespec);
+            ControlMessage::Timestamping { software, hardware } => {
+                let timestamp = select_timestamp(software, hardware, hardware_clock);
 
-                return Ok((bytes_read as usize, sock_addr, Some(timestamp)));
+                return Ok((bytes_read as usize, sock_addr, timestamp));
             }
 
             ControlMessage::ReceiveError(_error) => {
@@ -281,10 +568,12 @@ fn recv(
     Ok((bytes_read as usize, sock_addr, None))
 }
 
+/// Read one message from the error queue, and return the send timestamp in it along with the
+/// id of the packet it belongs to
 fn fetch_send_timestamp_help(
     socket: &std::net::UdpSocket,
-    expected_counter: u32,
-) -> io::Result<Option<NtpTimestamp>> {
+    hardware_clock: Option<&PtpClock>,
+) -> io::Result<Option<(u32, NtpTimestamp)>> {
     // we get back two control messages: one with the timestamp (just like a receive timestamp),
     // and one error message with no error reason. The payload for this second message is kind of
     // undocumented.
@@ -303,30 +592,22 @@ fn fetch_send_timestamp_help(
         receive_message(socket, &mut [], &mut control_buf, MessageQueue::Error)?;
 
     let mut send_ts = None;
+    let mut counter = None;
     for msg in control_messages {
         match msg {
-            ControlMessage::Timestamping(timespec) => {
-                send_ts = Some(read_ntp_timestamp(timespec));
+            ControlMessage::Timestamping { software, hardware } => {
+                send_ts = select_timestamp(software, hardware, hardware_clock);
             }
 
             ControlMessage::ReceiveError(error) => {
                 // the timestamping does not set a message; if there is a message, that means
                 // something else is wrong, and we want to know about it.
                 if error.ee_errno as libc::c_int != libc::ENOMSG {
-                    warn!(
-                        expected_counter,
-                        error.ee_data, "error message on the MSG_ERRQUEUE"
-                    );
+                    warn!(error.ee_data, "error message on the MSG_ERRQUEUE");
                 }
 
-                // Check that this message belongs to the send we are interested in
-                if error.ee_data != expected_counter {
-                    warn!(
-                        error.ee_data,
-                        expected_counter, "Timestamp for unrelated packet"
-                    );
-                    return Ok(None);
-                }
+                // the id of the packet this timestamp belongs to
+                counter = Some(error.ee_data);
             }
 
             ControlMessage::Other(msg) => {
@@ -338,7 +619,50 @@ fn fetch_send_timestamp_help(
         }
     }
 
-    Ok(send_ts)
+    Ok(counter.zip(send_ts))
+}
+
+/// The hardware timestamp if the network card made one, and the software timestamp otherwise.
+/// A hardware timestamp is converted from the clock of the network card to the system clock;
+/// when that fails, the packet has no usable timestamp.
+fn select_timestamp(
+    software: libc::timespec,
+    hardware: libc::timespec,
+    hardware_clock: Option<&PtpClock>,
+) -> Option<NtpTimestamp> {
+    let is_set = |timespec: libc::timespec| timespec.tv_sec != 0 || timespec.tv_nsec != 0;
+
+    if is_set(hardware) {
+        match hardware_clock.map(PtpClock::system_offset) {
+            Some(Ok(offset)) => Some(read_ntp_timestamp(to_system_time(hardware, offset))),
+            Some(Err(error)) => {
+                warn!(
+                    ?error,
+                    "could not convert a hardware timestamp to the system clock"
+                );
+                None
+            }
+            None => {
+                warn!("hardware timestamp without a hardware clock");
+                None
+            }
+        }
+    } else if is_set(software) {
+        Some(read_ntp_timestamp(software))
+    } else {
+        None
+    }
+}
+
+/// Convert a timestamp of a clock to the system clock, given the offset of that clock
+/// relative to the system clock in nanoseconds
+fn to_system_time(timespec: libc::timespec, offset: i64) -> libc::timespec {
+    let nanos = timespec.tv_sec * 1_000_000_000 + timespec.tv_nsec - offset;
+
+    libc::timespec {
+        tv_sec: nanos.div_euclid(1_000_000_000) as _,
+        tv_nsec: nanos.rem_euclid(1_000_000_000) as _,
+    }
 }
 
 fn read_ntp_timestamp(timespec: libc::timespec) -> NtpTimestamp {
@@ -358,8 +682,6 @@ fn read_ntp_timestamp(timespec: libc::timespec) -> NtpTimestamp {
 
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
-
     use super::*;
 
     #[tokio::test]
@@ -472,6 +794,256 @@ mod tests {
         assert_eq!(buf, [2; 48]);
     }
 
+    #[tokio::test]
+    async fn test_server_send_timestamp() {
+        let a = UdpSocket::server("127.0.0.1:10006".parse().unwrap())
+            .await
+            .unwrap();
+        let mut b = UdpSocket::client(
+            "127.0.0.1:10007".parse().unwrap(),
+            "127.0.0.1:10006".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        b.send(&[1; 48]).await.unwrap();
+        let mut buf = [0; 48];
+        let (_, addr, _) = a.recv(&mut buf).await.unwrap();
+
+        let (ssend, tsend) = a.send_to_timestamped(&[2; 48], addr).await.unwrap();
+        let (srecv, _, trecv) = b.recv(&mut buf).await.unwrap();
+
+        assert_eq!(ssend, 48);
+        assert_eq!(srecv, 48);
+
+        let tsend = tsend.unwrap();
+        let trecv = trecv.unwrap();
+        let delta = trecv - tsend;
+        assert!(delta.to_seconds().abs() < 0.2);
+    }
+
+    #[tokio::test]
+    async fn test_shared_socket_send_timestamps() {
+        let a = std::sync::Arc::new(
+            UdpSocket::server("127.0.0.1:10020".parse().unwrap())
+                .await
+                .unwrap(),
+        );
+        let mut b = UdpSocket::client(
+            "127.0.0.1:10021".parse().unwrap(),
+            "127.0.0.1:10020".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        b.send(&[1; 48]).await.unwrap();
+        let mut buf = [0; 48];
+        let (_, addr, _) = a.recv(&mut buf).await.unwrap();
+
+        // a send that does not wait for its timestamp does not take the timestamp of another
+        a.send_to(&[2; 48], addr).await.unwrap();
+
+        // two owners of the socket each get the timestamp of their own packet
+        let (first, second) = tokio::join!(
+            a.send_to_timestamped(&[3; 48], addr),
+            a.send_to_timestamped(&[4; 48], addr),
+        );
+        let first = first.unwrap().1.unwrap();
+        let second = second.unwrap().1.unwrap();
+
+        let mut receive_timestamps = [None; 5];
+        for _ in 0..3 {
+            let (_, _, trecv) = b.recv(&mut buf).await.unwrap();
+            receive_timestamps[buf[0] as usize] = trecv;
+        }
+
+        let delta = receive_timestamps[3].unwrap() - first;
+        assert!(delta.to_seconds().abs() < 0.2);
+        let delta = receive_timestamps[4].unwrap() - second;
+        assert!(delta.to_seconds().abs() < 0.2);
+        assert!(a.stashed_send_timestamps.lock().unwrap().len() <= 1);
+    }
+
+    #[tokio::test]
+    async fn test_take_send_timestamp() {
+        let a = UdpSocket::server("127.0.0.1:10022".parse().unwrap())
+            .await
+            .unwrap();
+        let mut b = UdpSocket::client(
+            "127.0.0.1:10023".parse().unwrap(),
+            "127.0.0.1:10022".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        b.send(&[1; 48]).await.unwrap();
+        let mut buf = [0; 48];
+        let (_, addr, _) = a.recv(&mut buf).await.unwrap();
+
+        let (ssend, id) = a.send_to_with_id(&[2; 48], addr).await.unwrap();
+        let (srecv, _, _) = b.recv(&mut buf).await.unwrap();
+        assert_eq!(ssend, 48);
+        assert_eq!(srecv, 48);
+
+        // the timestamp is available once the packet was received
+        assert!(a.take_send_timestamp(id).is_some());
+
+        // and it is only taken once
+        assert_eq!(a.take_send_timestamp(id), None);
+    }
+
+    #[test]
+    fn test_to_system_time() {
+        let timespec = libc::timespec {
+            tv_sec: 100,
+            tv_nsec: 250_000_000,
+        };
+
+        let converted = to_system_time(timespec, 500_000_000);
+        assert_eq!((converted.tv_sec, converted.tv_nsec), (99, 750_000_000));
+
+        let converted = to_system_time(timespec, -2_000_000_000);
+        assert_eq!((converted.tv_sec, converted.tv_nsec), (102, 250_000_000));
+    }
+
+    #[test]
+    fn test_select_timestamp() {
+        let unset = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        let software = libc::timespec {
+            tv_sec: 100,
+            tv_nsec: 0,
+        };
+
+        assert_eq!(
+            select_timestamp(software, unset, None),
+            Some(read_ntp_timestamp(software))
+        );
+        assert_eq!(select_timestamp(unset, unset, None), None);
+
+        // a hardware timestamp is never mixed up with software timestamps
+        assert_eq!(select_timestamp(software, software, None), None);
+    }
+
+    #[tokio::test]
+    async fn test_hardware_timestamping_fallback() {
+        let mut a = UdpSocket::client(
+            "127.0.0.1:10008".parse().unwrap(),
+            "127.0.0.1:10009".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let mut b = UdpSocket::client(
+            "127.0.0.1:10009".parse().unwrap(),
+            "127.0.0.1:10008".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // the loopback interface has no network card to timestamp packets
+        assert!(a.enable_hardware_timestamping(None).is_err());
+        assert!(a
+            .enable_hardware_timestamping(Some("lo".parse().unwrap()))
+            .is_err());
+
+        // a socket bound to an unspecified address has no interface
+        let mut server = UdpSocket::server("0.0.0.0:10010".parse().unwrap())
+            .await
+            .unwrap();
+        let error = server.enable_hardware_timestamping(None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+
+        // software timestamps are still available
+        a.send(&[1; 48]).await.unwrap();
+        let mut buf = [0; 48];
+        let (size, _, ts) = b.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+        assert!(ts.is_some());
+
+        b.send(&[2; 48]).await.unwrap();
+        let (size, _, ts) = a.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+        assert!(ts.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_client_send_timestamp() {
+        let mut a = UdpSocket::client(
+            "127.0.0.1:10011".parse().unwrap(),
+            "127.0.0.1:10012".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let mut b = UdpSocket::client(
+            "127.0.0.1:10012".parse().unwrap(),
+            "127.0.0.1:10011".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let (_, tsend) = a.send(&[1; 48]).await.unwrap();
+        let mut buf = [0; 48];
+        let (_, _, trecv) = b.recv(&mut buf).await.unwrap();
+
+        let delta = trecv.unwrap() - tsend.unwrap();
+        assert!(delta.to_seconds().abs() < 0.2);
+
+        b.disable_send_timestamping().unwrap();
+        assert!(!b.send_timestamping());
+
+        let (size, tsend) = b.send(&[2; 48]).await.unwrap();
+        assert_eq!(size, 48);
+        assert!(tsend.is_none());
+
+        // receive timestamps are unaffected
+        let (_, _, trecv) = a.recv(&mut buf).await.unwrap();
+        assert!(trecv.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_client() {
+        let server = UdpSocket::server("127.0.0.1:10004".parse().unwrap())
+            .await
+            .unwrap();
+        server.enable_broadcast().unwrap();
+        let client = UdpSocket::broadcast_client("127.255.255.255:10005".parse().unwrap())
+            .await
+            .unwrap();
+
+        // a server can listen on the same port
+        let other_server = UdpSocket::server("0.0.0.0:10005".parse().unwrap())
+            .await
+            .unwrap();
+
+        server
+            .send_to(&[1; 48], "127.255.255.255:10005".parse().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0; 48];
+        let (size, addr, ts) = client.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+        assert_eq!(addr, "127.0.0.1:10004".parse().unwrap());
+        assert_eq!(buf, [1; 48]);
+        assert!(ts.is_some());
+
+        // the server receives broadcasts as well, but packets sent to the server are not
+        // received by the client
+        server
+            .send_to(&[2; 48], "127.0.0.1:10005".parse().unwrap())
+            .await
+            .unwrap();
+        other_server.recv(&mut buf).await.unwrap();
+        assert_eq!(buf, [1; 48]);
+        other_server.recv(&mut buf).await.unwrap();
+        assert_eq!(buf, [2; 48]);
+
+        let result =
+            tokio::time::timeout(std::time::Duration::from_millis(10), client.recv(&mut buf)).await;
+        assert!(result.is_err());
+    }
+
     #[tokio::test]
     async fn test_timestamping_reasonable() {
         let mut a = UdpSocket::client_with_timestamping(
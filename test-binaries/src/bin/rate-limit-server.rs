@@ -34,7 +34,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let packet = if delta < std::time::Duration::new(30, 0) {
             NtpPacket::rate_limit_response(parsed)
         } else {
-            NtpPacket::timestamp_response(&system, parsed, ntp_receive, &clock)
+            NtpPacket::timestamp_response(&system, parsed, ntp_receive, &clock, None)
         };
 
         let mut buf = [0; 48];
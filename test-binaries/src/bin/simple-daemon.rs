@@ -1,4 +1,6 @@
-use ntp_daemon::config::{CombinedSystemConfig, PeerConfig};
+use ntp_daemon::config::{
+    ClockConfig, CombinedSystemConfig, KeysetConfig, PeerConfig, SymmetricKeys,
+};
 use std::error::Error;
 
 #[tokio::main]
@@ -7,8 +9,19 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let peer_configs = [PeerConfig::try_from("0.0.0.0:8080").unwrap()];
 
-    let (handle, _) =
-        ntp_daemon::spawn(CombinedSystemConfig::default(), &peer_configs, &[]).await?;
+    let (handle, _) = ntp_daemon::spawn(
+        CombinedSystemConfig::default(),
+        &ClockConfig::default(),
+        &peer_configs,
+        &[],
+        &[],
+        &[],
+        &KeysetConfig::default(),
+        &SymmetricKeys::default(),
+        None,
+        None,
+    )
+    .await?;
 
     handle.await??;
 